@@ -0,0 +1,112 @@
+/// Worst-case encoded size of `len` bytes: one overhead byte per 254 bytes.
+pub const fn max_encoded_len(len: usize) -> usize {
+    len + len / 254 + 1
+}
+
+/// Consistent Overhead Byte Stuffing: encode `src` into `dst` without any
+/// zero byte. Returns the encoded length; the delimiter is not written.
+///
+/// Panics if `dst` is shorter than `max_encoded_len(src.len())`.
+pub fn encode(src: &[u8], dst: &mut [u8]) -> usize {
+    let mut code_at = 0;
+    let mut out = 1;
+    let mut code = 1u8;
+    for &byte in src {
+        if byte != 0 {
+            dst[out] = byte;
+            out += 1;
+            code += 1;
+        }
+        if byte == 0 || code == 0xFF {
+            dst[code_at] = code;
+            code_at = out;
+            out += 1;
+            code = 1;
+        }
+    }
+    dst[code_at] = code;
+    out
+}
+
+/// Decode one COBS block (without its delimiter) into `dst`. Returns the
+/// decoded length, or `None` for a malformed block or a too small `dst`.
+pub fn decode(src: &[u8], dst: &mut [u8]) -> Option<usize> {
+    let mut pos = 0;
+    let mut out = 0;
+    while pos < src.len() {
+        let code = usize::from(src[pos]);
+        if code == 0 || pos + code > src.len() {
+            return None;
+        }
+        let block = &src[pos + 1..pos + code];
+        dst.get_mut(out..out + block.len())?.copy_from_slice(block);
+        out += block.len();
+        pos += code;
+        // A full block (0xFF) carries no implicit zero, nor does the last one
+        if code != 0xFF && pos < src.len() {
+            *dst.get_mut(out)? = 0;
+            out += 1;
+        }
+    }
+    Some(out)
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use std::vec::Vec;
+
+    use super::*;
+
+    fn encoded(src: &[u8]) -> Vec<u8> {
+        let mut dst = [0u8; 600];
+        let len = encode(src, &mut dst);
+        dst[..len].to_vec()
+    }
+
+    fn round_trip(src: &[u8]) {
+        let block = encoded(src);
+        assert!(block.len() <= max_encoded_len(src.len()));
+        assert!(!block.contains(&0), "zero byte in {block:?}");
+        let mut out = [0u8; 600];
+        let len = decode(&block, &mut out).unwrap();
+        assert_eq!(&out[..len], src);
+    }
+
+    #[test]
+    fn known_encodings() {
+        assert_eq!(encoded(&[]), [1]);
+        assert_eq!(encoded(&[0]), [1, 1]);
+        assert_eq!(encoded(&[0, 0]), [1, 1, 1]);
+        assert_eq!(encoded(&[0x11, 0x22, 0x00, 0x33]), [3, 0x11, 0x22, 2, 0x33]);
+        assert_eq!(encoded(&[0x11, 0x00, 0x00, 0x00]), [2, 0x11, 1, 1, 1]);
+    }
+
+    #[test]
+    fn round_trips() {
+        round_trip(&[]);
+        round_trip(&[0]);
+        round_trip(&[0, 0, 0]);
+        round_trip(&[1, 2, 0, 3, 0]);
+        // Runs around the 254 byte block limit
+        for len in [253, 254, 255, 508, 509] {
+            let data: Vec<u8> = (0..len).map(|i| (i % 255 + 1) as u8).collect();
+            round_trip(&data);
+            let mut with_zeros = data.clone();
+            with_zeros[len / 2] = 0;
+            with_zeros.push(0);
+            round_trip(&with_zeros);
+        }
+    }
+
+    #[test]
+    fn malformed_blocks_are_rejected() {
+        let mut out = [0u8; 16];
+        // Code pointing past the end, and a zero code
+        assert_eq!(decode(&[5, 1, 2], &mut out), None);
+        assert_eq!(decode(&[2, 1, 0, 1], &mut out), None);
+        // Output buffer too small
+        assert_eq!(decode(&[4, 1, 2, 3], &mut out[..2]), None);
+    }
+}
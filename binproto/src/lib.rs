@@ -0,0 +1,459 @@
+#![no_std]
+
+#[cfg(feature = "std")]
+extern crate std;
+
+pub mod cobs;
+
+/// Longest message payload
+pub const MAX_PAYLOAD: usize = 32;
+/// Sequence number, type, payload and CRC-16
+pub const MAX_PACKET: usize = 2 + MAX_PAYLOAD + 2;
+/// Encoded packet plus the zero delimiter
+pub const MAX_FRAME: usize = cobs::max_encoded_len(MAX_PACKET) + 1;
+
+/// Frame checksum, CRC-16/MODBUS; shared with the firmware's Modbus RTU and settings
+pub use logic::crc::crc16;
+
+/// Message type byte. Host requests are below 0x80, device messages above.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum MessageType {
+    Ping = 0x01,
+    SetPower = 0x02,
+    SetSpeed = 0x03,
+    SetTelemetry = 0x04,
+    Ack = 0x80,
+    Nack = 0x81,
+    Telemetry = 0x82,
+}
+
+impl MessageType {
+    pub fn from_u8(value: u8) -> Option<Self> {
+        Some(match value {
+            0x01 => Self::Ping,
+            0x02 => Self::SetPower,
+            0x03 => Self::SetSpeed,
+            0x04 => Self::SetTelemetry,
+            0x80 => Self::Ack,
+            0x81 => Self::Nack,
+            0x82 => Self::Telemetry,
+            _ => return None,
+        })
+    }
+}
+
+/// Power source selection, as the SCPI `POWEr` commands.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PowerSource {
+    Off = 0,
+    DcDc = 1,
+    AcDc = 2,
+}
+
+impl PowerSource {
+    pub fn from_u8(value: u8) -> Option<Self> {
+        Some(match value {
+            0 => Self::Off,
+            1 => Self::DcDc,
+            2 => Self::AcDc,
+            _ => return None,
+        })
+    }
+}
+
+/// Why the device refused a request.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NackReason {
+    /// Not a request type the device handles
+    UnknownType = 1,
+    /// Payload of the wrong length or out of range
+    BadPayload = 2,
+    /// A command queue of the device was full; try again
+    Busy = 3,
+    /// Queued, but not applied in time; query the state with telemetry
+    Timeout = 4,
+}
+
+impl NackReason {
+    pub fn from_u8(value: u8) -> Option<Self> {
+        Some(match value {
+            1 => Self::UnknownType,
+            2 => Self::BadPayload,
+            3 => Self::Busy,
+            4 => Self::Timeout,
+            _ => return None,
+        })
+    }
+}
+
+/// Fault bits of `Telemetry::faults`, the same as the Modbus fault register
+pub mod fault {
+    pub const FAN_STALLED: u16 = 1 << 0;
+    pub const FAN_FAILED: u16 = 1 << 1;
+    pub const PWM_BREAK: u16 = 1 << 2;
+    pub const RAIL_UNDER: u16 = 1 << 3;
+    pub const RAIL_OVER: u16 = 1 << 4;
+}
+
+/// Periodic device state. Little-endian on the wire, in field order.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Telemetry {
+    pub uptime_ms: u32,
+    /// Averaged rail voltage
+    pub rail_mv: u16,
+    /// Internal sensor, 0.1 °C
+    pub temperature: i16,
+    /// External probe, 0.1 °C
+    pub probe_temperature: i16,
+    pub fan_rpm: u16,
+    /// Open-loop fan speed setpoint, percent
+    pub fan_speed: u8,
+    pub power: PowerSource,
+    pub led: bool,
+    pub cooling: bool,
+    /// See `fault`
+    pub faults: u16,
+}
+
+impl Telemetry {
+    pub const LEN: usize = 18;
+
+    fn encode(&self, out: &mut [u8]) -> usize {
+        out[0..4].copy_from_slice(&self.uptime_ms.to_le_bytes());
+        out[4..6].copy_from_slice(&self.rail_mv.to_le_bytes());
+        out[6..8].copy_from_slice(&self.temperature.to_le_bytes());
+        out[8..10].copy_from_slice(&self.probe_temperature.to_le_bytes());
+        out[10..12].copy_from_slice(&self.fan_rpm.to_le_bytes());
+        out[12] = self.fan_speed;
+        out[13] = self.power as u8;
+        out[14] = u8::from(self.led) | u8::from(self.cooling) << 1;
+        out[15] = 0;
+        out[16..18].copy_from_slice(&self.faults.to_le_bytes());
+        Self::LEN
+    }
+
+    fn decode(payload: &[u8]) -> Option<Self> {
+        if payload.len() != Self::LEN {
+            return None;
+        }
+        let word = |at: usize| [payload[at], payload[at + 1]];
+        Some(Self {
+            uptime_ms: u32::from_le_bytes([payload[0], payload[1], payload[2], payload[3]]),
+            rail_mv: u16::from_le_bytes(word(4)),
+            temperature: i16::from_le_bytes(word(6)),
+            probe_temperature: i16::from_le_bytes(word(8)),
+            fan_rpm: u16::from_le_bytes(word(10)),
+            fan_speed: payload[12],
+            power: PowerSource::from_u8(payload[13])?,
+            led: payload[14] & 1 != 0,
+            cooling: payload[14] & 2 != 0,
+            faults: u16::from_le_bytes(word(16)),
+        })
+    }
+}
+
+/// Decoded message. A reply carries the sequence number of its request;
+/// telemetry counts on its own.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Message {
+    /// Answered with `Ack`
+    Ping,
+    SetPower(PowerSource),
+    /// Fan speed in percent (0..=100); also switches cooling on
+    SetSpeed(u8),
+    /// Telemetry period in milliseconds, 0 stops it
+    SetTelemetry { interval_ms: u16 },
+    /// The request was applied
+    Ack,
+    Nack(NackReason),
+    Telemetry(Telemetry),
+}
+
+impl Message {
+    pub fn kind(&self) -> MessageType {
+        match self {
+            Message::Ping => MessageType::Ping,
+            Message::SetPower(_) => MessageType::SetPower,
+            Message::SetSpeed(_) => MessageType::SetSpeed,
+            Message::SetTelemetry { .. } => MessageType::SetTelemetry,
+            Message::Ack => MessageType::Ack,
+            Message::Nack(_) => MessageType::Nack,
+            Message::Telemetry(_) => MessageType::Telemetry,
+        }
+    }
+
+    fn encode_payload(&self, out: &mut [u8]) -> usize {
+        match self {
+            Message::Ping | Message::Ack => 0,
+            Message::SetPower(source) => {
+                out[0] = *source as u8;
+                1
+            }
+            Message::SetSpeed(percent) => {
+                out[0] = *percent;
+                1
+            }
+            Message::SetTelemetry { interval_ms } => {
+                out[..2].copy_from_slice(&interval_ms.to_le_bytes());
+                2
+            }
+            Message::Nack(reason) => {
+                out[0] = *reason as u8;
+                1
+            }
+            Message::Telemetry(telemetry) => telemetry.encode(out),
+        }
+    }
+
+    fn decode_payload(kind: MessageType, payload: &[u8]) -> Option<Self> {
+        Some(match (kind, payload) {
+            (MessageType::Ping, []) => Message::Ping,
+            (MessageType::Ack, []) => Message::Ack,
+            (MessageType::SetPower, [source]) => Message::SetPower(PowerSource::from_u8(*source)?),
+            (MessageType::SetSpeed, [percent]) if *percent <= 100 => Message::SetSpeed(*percent),
+            (MessageType::SetTelemetry, [low, high]) => Message::SetTelemetry {
+                interval_ms: u16::from_le_bytes([*low, *high]),
+            },
+            (MessageType::Nack, [reason]) => Message::Nack(NackReason::from_u8(*reason)?),
+            (MessageType::Telemetry, payload) => Message::Telemetry(Telemetry::decode(payload)?),
+            _ => return None,
+        })
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DecodeError {
+    /// Not valid COBS, or longer than `MAX_PACKET`
+    Framing,
+    /// Shorter than sequence number, type and CRC
+    TooShort,
+    Crc,
+    /// Intact packet of a type this version does not know; `seq` allows a Nack
+    UnknownType { seq: u8, kind: u8 },
+    /// Known type with a payload of the wrong size or out of range
+    BadPayload { seq: u8, kind: MessageType },
+}
+
+/// Encode `message` as one frame, delimiter included; returns its length.
+pub fn encode_frame(seq: u8, message: &Message, frame: &mut [u8; MAX_FRAME]) -> usize {
+    let mut packet = [0u8; MAX_PACKET];
+    packet[0] = seq;
+    packet[1] = message.kind() as u8;
+    let len = 2 + message.encode_payload(&mut packet[2..2 + MAX_PAYLOAD]);
+    let crc = crc16(&packet[..len]);
+    packet[len..len + 2].copy_from_slice(&crc.to_le_bytes());
+    let encoded = cobs::encode(&packet[..len + 2], frame);
+    frame[encoded] = 0;
+    encoded + 1
+}
+
+/// Decode one frame without its delimiter into the sequence number and
+/// message.
+pub fn decode_frame(frame: &[u8]) -> Result<(u8, Message), DecodeError> {
+    let mut packet = [0u8; MAX_PACKET];
+    let len = cobs::decode(frame, &mut packet).ok_or(DecodeError::Framing)?;
+    if len < 4 {
+        return Err(DecodeError::TooShort);
+    }
+    let (body, crc) = packet[..len].split_at(len - 2);
+    if crc16(body) != u16::from_le_bytes([crc[0], crc[1]]) {
+        return Err(DecodeError::Crc);
+    }
+    let (seq, kind) = (body[0], body[1]);
+    let kind = MessageType::from_u8(kind).ok_or(DecodeError::UnknownType { seq, kind })?;
+    let message =
+        Message::decode_payload(kind, &body[2..]).ok_or(DecodeError::BadPayload { seq, kind })?;
+    Ok((seq, message))
+}
+
+/// Splits a byte stream at the zero delimiters into frames.
+///
+/// Frames longer than `MAX_FRAME` are dropped up to the next delimiter, so a
+/// reader joining mid-stream resynchronises on the first zero byte.
+pub struct FrameReader {
+    buf: [u8; MAX_FRAME],
+    len: usize,
+    discarding: bool,
+}
+
+impl FrameReader {
+    pub const fn new() -> Self {
+        Self {
+            buf: [0; MAX_FRAME],
+            len: 0,
+            discarding: false,
+        }
+    }
+
+    /// Feed one byte; returns the frame (without delimiter) it completes.
+    pub fn push(&mut self, byte: u8) -> Option<&[u8]> {
+        if byte == 0 {
+            let len = core::mem::take(&mut self.len);
+            if core::mem::take(&mut self.discarding) || len == 0 {
+                return None;
+            }
+            return Some(&self.buf[..len]);
+        }
+        if self.discarding {
+            return None;
+        }
+        if self.len == MAX_FRAME {
+            self.len = 0;
+            self.discarding = true;
+            return None;
+        }
+        self.buf[self.len] = byte;
+        self.len += 1;
+        None
+    }
+}
+
+impl Default for FrameReader {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Frame `message` into a new vector, delimiter included.
+#[cfg(feature = "std")]
+pub fn encode_to_vec(seq: u8, message: &Message) -> std::vec::Vec<u8> {
+    let mut frame = [0u8; MAX_FRAME];
+    let len = encode_frame(seq, message, &mut frame);
+    frame[..len].to_vec()
+}
+
+/// Decode every complete frame in `bytes`, keeping the partial tail in
+/// `reader` for the next call.
+#[cfg(feature = "std")]
+pub fn decode_stream(
+    reader: &mut FrameReader,
+    bytes: &[u8],
+) -> std::vec::Vec<Result<(u8, Message), DecodeError>> {
+    bytes.iter().filter_map(|&byte| reader.push(byte).map(decode_frame)).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TELEMETRY: Telemetry = Telemetry {
+        uptime_ms: 0x0102_0304,
+        rail_mv: 3300,
+        temperature: -125,
+        probe_temperature: 456,
+        fan_rpm: 2400,
+        fan_speed: 75,
+        power: PowerSource::AcDc,
+        led: true,
+        cooling: false,
+        faults: fault::FAN_STALLED | fault::RAIL_OVER,
+    };
+
+    const MESSAGES: [Message; 11] = [
+        Message::Ping,
+        Message::SetPower(PowerSource::Off),
+        Message::SetPower(PowerSource::DcDc),
+        Message::SetSpeed(0),
+        Message::SetSpeed(100),
+        Message::SetTelemetry { interval_ms: 0 },
+        Message::SetTelemetry { interval_ms: 0xFF00 },
+        Message::Ack,
+        Message::Nack(NackReason::Busy),
+        Message::Nack(NackReason::Timeout),
+        Message::Telemetry(TELEMETRY),
+    ];
+
+    /// Build a frame from a raw packet (sequence number, type, payload),
+    /// appending a valid CRC.
+    fn raw_frame(body: &[u8]) -> ([u8; MAX_FRAME], usize) {
+        let mut packet = [0u8; MAX_PACKET];
+        packet[..body.len()].copy_from_slice(body);
+        let crc = crc16(body);
+        packet[body.len()..body.len() + 2].copy_from_slice(&crc.to_le_bytes());
+        let mut frame = [0u8; MAX_FRAME];
+        let len = cobs::encode(&packet[..body.len() + 2], &mut frame);
+        (frame, len)
+    }
+
+    #[test]
+    fn every_message_round_trips() {
+        for (seq, message) in MESSAGES.iter().enumerate() {
+            let mut frame = [0u8; MAX_FRAME];
+            let len = encode_frame(seq as u8, message, &mut frame);
+            assert_eq!(frame[len - 1], 0, "delimiter");
+            assert!(!frame[..len - 1].contains(&0));
+            assert_eq!(decode_frame(&frame[..len - 1]), Ok((seq as u8, *message)));
+        }
+    }
+
+    #[test]
+    fn telemetry_layout_is_little_endian() {
+        let mut payload = [0u8; MAX_PAYLOAD];
+        assert_eq!(TELEMETRY.encode(&mut payload), Telemetry::LEN);
+        assert_eq!(&payload[..4], &[0x04, 0x03, 0x02, 0x01]);
+        assert_eq!(&payload[6..8], &(-125i16).to_le_bytes());
+        assert_eq!(&payload[12..16], &[75, 2, 0b01, 0]);
+        assert_eq!(&payload[16..18], &[0x11, 0x00]);
+    }
+
+    #[test]
+    fn corrupt_frames_are_rejected() {
+        let mut frame = [0u8; MAX_FRAME];
+        let len = encode_frame(7, &Message::SetSpeed(50), &mut frame) - 1;
+
+        let mut flipped = frame;
+        flipped[len - 1] ^= 0x01;
+        let result = decode_frame(&flipped[..len]);
+        assert!(matches!(result, Err(DecodeError::Crc | DecodeError::Framing)));
+
+        assert_eq!(decode_frame(&[]), Err(DecodeError::TooShort));
+        assert_eq!(decode_frame(&[4, 1, 2, 3]), Err(DecodeError::TooShort));
+        assert_eq!(decode_frame(&[9, 1, 2]), Err(DecodeError::Framing));
+    }
+
+    #[test]
+    fn intact_frames_of_unknown_type_or_bad_payload_keep_the_sequence_number() {
+        let (frame, len) = raw_frame(&[3, 0x7F]);
+        let unknown = DecodeError::UnknownType { seq: 3, kind: 0x7F };
+        assert_eq!(decode_frame(&frame[..len]), Err(unknown));
+
+        for body in [&[4, 0x03, 101][..], &[4, 0x02, 3], &[4, 0x04, 1], &[4, 0x01, 0]] {
+            let (frame, len) = raw_frame(body);
+            let kind = MessageType::from_u8(body[1]).unwrap();
+            assert_eq!(decode_frame(&frame[..len]), Err(DecodeError::BadPayload { seq: 4, kind }));
+        }
+    }
+
+    #[test]
+    fn reader_splits_frames_and_resynchronises() {
+        let mut reader = FrameReader::new();
+        let mut frame = [0u8; MAX_FRAME];
+        let len = encode_frame(1, &Message::Ping, &mut frame);
+
+        // Leading delimiters and garbage longer than a frame are skipped
+        let mut frames = 0;
+        for &byte in [0u8, 0].iter().chain(&[0x55; MAX_FRAME + 5]).chain(&[0]) {
+            assert!(reader.push(byte).is_none());
+        }
+        for &byte in &frame[..len] {
+            if let Some(received) = reader.push(byte) {
+                assert_eq!(decode_frame(received), Ok((1, Message::Ping)));
+                frames += 1;
+            }
+        }
+        assert_eq!(frames, 1);
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn stream_decoding_keeps_partial_frames() {
+        let mut bytes = encode_to_vec(1, &Message::Ack);
+        bytes.extend(encode_to_vec(2, &Message::Telemetry(TELEMETRY)));
+        let (head, tail) = bytes.split_at(bytes.len() - 5);
+
+        let mut reader = FrameReader::new();
+        assert_eq!(decode_stream(&mut reader, head), [Ok((1, Message::Ack))]);
+        assert_eq!(decode_stream(&mut reader, tail), [Ok((2, Message::Telemetry(TELEMETRY)))]);
+    }
+}
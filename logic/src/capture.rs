@@ -0,0 +1,291 @@
+/// Number of rail samples kept in the circular buffer (one record).
+pub const CAPTURE_DEPTH: usize = 256;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum TriggerMode {
+    /// Sample at or above the trigger level
+    Level,
+    /// Crossing the trigger level upwards
+    Rising,
+    /// Crossing the trigger level downwards
+    Falling,
+    /// Any transition of the power task's `PowerState`
+    PowerChange,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum CaptureState {
+    Idle,
+    Armed,
+    Triggered,
+    Done,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct CaptureConfig {
+    pub mode: TriggerMode,
+    /// Trigger level in mV
+    pub level: u16,
+    /// Samples kept from before the trigger point
+    pub pre_trigger: usize,
+    /// Samples recorded from the trigger point on (trigger sample included)
+    pub post_trigger: usize,
+}
+
+impl CaptureConfig {
+    pub const DEFAULT: Self = Self {
+        mode: TriggerMode::PowerChange,
+        level: 760,
+        pre_trigger: CAPTURE_DEPTH / 4,
+        post_trigger: CAPTURE_DEPTH - CAPTURE_DEPTH / 4,
+    };
+
+    pub fn record_len(&self) -> usize {
+        self.pre_trigger + self.post_trigger
+    }
+}
+
+/// Scope-style capture of the rail voltage with a pre-trigger history.
+///
+/// While armed every sample goes into the ring buffer; once the trigger
+/// condition is met `post_trigger` more samples are written and the record
+/// is frozen until the next `arm()`.
+pub struct Capture {
+    config: CaptureConfig,
+    buffer: [u16; CAPTURE_DEPTH],
+    head: usize,
+    filled: usize,
+    remaining: usize,
+    previous: Option<u16>,
+    power_changed: bool,
+    state: CaptureState,
+}
+
+impl Capture {
+    pub const fn new() -> Self {
+        Self {
+            config: CaptureConfig::DEFAULT,
+            buffer: [0; CAPTURE_DEPTH],
+            head: 0,
+            filled: 0,
+            remaining: 0,
+            previous: None,
+            power_changed: false,
+            state: CaptureState::Idle,
+        }
+    }
+
+    pub fn config(&self) -> CaptureConfig {
+        self.config
+    }
+
+    /// Replace the configuration. Returns `false` if the record does not fit
+    /// in the buffer; a running capture is stopped.
+    pub fn configure(&mut self, config: CaptureConfig) -> bool {
+        if config.post_trigger == 0 || config.record_len() > CAPTURE_DEPTH {
+            return false;
+        }
+        self.config = config;
+        self.state = CaptureState::Idle;
+        true
+    }
+
+    pub fn state(&self) -> CaptureState {
+        self.state
+    }
+
+    pub fn arm(&mut self) {
+        self.head = 0;
+        self.filled = 0;
+        self.previous = None;
+        self.power_changed = false;
+        self.state = CaptureState::Armed;
+    }
+
+    /// Trigger on the next sample regardless of the configured mode.
+    pub fn force_trigger(&mut self) {
+        if self.state == CaptureState::Armed {
+            self.power_changed = true;
+        }
+    }
+
+    /// Called from the power task whenever the relays switch.
+    pub fn notify_power_change(&mut self) {
+        if self.state == CaptureState::Armed && self.config.mode == TriggerMode::PowerChange {
+            self.power_changed = true;
+        }
+    }
+
+    /// Feed one rail sample (mV).
+    pub fn push(&mut self, sample: u16) {
+        match self.state {
+            CaptureState::Idle | CaptureState::Done => return,
+            CaptureState::Armed => {
+                self.write(sample);
+                // Hold off until the pre-trigger part of the record is populated
+                if self.filled > self.config.pre_trigger && self.is_trigger(sample) {
+                    self.remaining = self.config.post_trigger - 1;
+                    self.state = if self.remaining == 0 {
+                        CaptureState::Done
+                    } else {
+                        CaptureState::Triggered
+                    };
+                }
+            }
+            CaptureState::Triggered => {
+                self.write(sample);
+                self.remaining -= 1;
+                if self.remaining == 0 {
+                    self.state = CaptureState::Done;
+                }
+            }
+        }
+        self.previous = Some(sample);
+    }
+
+    /// Samples of the finished record, oldest first (empty until `Done`).
+    pub fn record(&self) -> impl Iterator<Item = u16> + '_ {
+        let len = if self.state == CaptureState::Done {
+            self.config.record_len().min(self.filled)
+        } else {
+            0
+        };
+        let start = (self.head + CAPTURE_DEPTH - len) % CAPTURE_DEPTH;
+        (0..len).map(move |i| self.buffer[(start + i) % CAPTURE_DEPTH])
+    }
+
+    fn write(&mut self, sample: u16) {
+        self.buffer[self.head] = sample;
+        self.head = (self.head + 1) % CAPTURE_DEPTH;
+        self.filled = (self.filled + 1).min(CAPTURE_DEPTH);
+    }
+
+    fn is_trigger(&mut self, sample: u16) -> bool {
+        if self.power_changed {
+            self.power_changed = false;
+            return true;
+        }
+        let level = self.config.level;
+        match (self.config.mode, self.previous) {
+            (TriggerMode::Level, _) => sample >= level,
+            (TriggerMode::Rising, Some(previous)) => previous < level && sample >= level,
+            (TriggerMode::Falling, Some(previous)) => previous > level && sample <= level,
+            _ => false,
+        }
+    }
+}
+
+impl Default for Capture {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use super::*;
+    use std::vec::Vec;
+
+    fn armed(mode: TriggerMode) -> Capture {
+        let mut capture = Capture::new();
+        assert!(capture.configure(CaptureConfig {
+            mode,
+            level: 1000,
+            pre_trigger: 4,
+            post_trigger: 3,
+        }));
+        capture.arm();
+        capture
+    }
+
+    fn feed(capture: &mut Capture, samples: &[u16]) {
+        for &sample in samples {
+            capture.push(sample);
+        }
+    }
+
+    fn record(capture: &Capture) -> Vec<u16> {
+        capture.record().collect()
+    }
+
+    #[test]
+    fn rejects_records_that_do_not_fit() {
+        let mut capture = Capture::new();
+        let config = CaptureConfig::DEFAULT;
+        assert!(!capture.configure(CaptureConfig { post_trigger: 0, ..config }));
+        assert!(!capture.configure(CaptureConfig { pre_trigger: 65, ..config }));
+        assert!(capture.configure(CaptureConfig { pre_trigger: 0, ..config }));
+    }
+
+    #[test]
+    fn keeps_pre_and_post_trigger_samples() {
+        let mut capture = armed(TriggerMode::Rising);
+        feed(&mut capture, &[100, 200, 300, 400, 500, 1100]);
+        assert_eq!(capture.state(), CaptureState::Triggered);
+        assert!(record(&capture).is_empty());
+        feed(&mut capture, &[1200, 1300]);
+        assert_eq!(capture.state(), CaptureState::Done);
+        assert_eq!(record(&capture), [200, 300, 400, 500, 1100, 1200, 1300]);
+        // The record stays frozen until re-armed
+        feed(&mut capture, &[0, 0]);
+        assert_eq!(record(&capture), [200, 300, 400, 500, 1100, 1200, 1300]);
+    }
+
+    #[test]
+    fn holds_off_until_pre_trigger_is_filled() {
+        let mut capture = armed(TriggerMode::Level);
+        feed(&mut capture, &[1500, 1500, 1500, 1500]);
+        assert_eq!(capture.state(), CaptureState::Armed);
+        feed(&mut capture, &[1500]);
+        assert_eq!(capture.state(), CaptureState::Triggered);
+    }
+
+    #[test]
+    fn edge_modes_need_a_crossing() {
+        let mut capture = armed(TriggerMode::Rising);
+        feed(&mut capture, &[1500; 8]);
+        assert_eq!(capture.state(), CaptureState::Armed);
+        feed(&mut capture, &[900, 1000]);
+        assert_eq!(capture.state(), CaptureState::Triggered);
+
+        let mut capture = armed(TriggerMode::Falling);
+        feed(&mut capture, &[500; 8]);
+        assert_eq!(capture.state(), CaptureState::Armed);
+        feed(&mut capture, &[1100, 1000]);
+        assert_eq!(capture.state(), CaptureState::Triggered);
+    }
+
+    #[test]
+    fn power_change_and_forced_trigger() {
+        let mut capture = armed(TriggerMode::Rising);
+        feed(&mut capture, &[500; 5]);
+        // Relay switching only triggers in PowerChange mode
+        capture.notify_power_change();
+        feed(&mut capture, &[500]);
+        assert_eq!(capture.state(), CaptureState::Armed);
+        capture.force_trigger();
+        feed(&mut capture, &[500]);
+        assert_eq!(capture.state(), CaptureState::Triggered);
+
+        let mut capture = armed(TriggerMode::PowerChange);
+        feed(&mut capture, &[500; 5]);
+        capture.notify_power_change();
+        feed(&mut capture, &[700, 710, 720]);
+        assert_eq!(capture.state(), CaptureState::Done);
+        assert_eq!(record(&capture), [500, 500, 500, 500, 700, 710, 720]);
+    }
+
+    #[test]
+    fn idle_capture_ignores_samples() {
+        let mut capture = Capture::new();
+        capture.force_trigger();
+        feed(&mut capture, &[1500; 300]);
+        assert_eq!(capture.state(), CaptureState::Idle);
+        assert!(record(&capture).is_empty());
+    }
+}
@@ -0,0 +1,50 @@
+/// PWM duty cycle, independent of the timer resolution.
+///
+/// Stored in 0.01 % steps (0..=10 000), so a duty survives a change of the
+/// PWM frequency unchanged. Timer ticks are only computed by the PWM task,
+/// from the `max_duty` of the frequency currently applied.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Duty(u16);
+
+impl Duty {
+    /// Number of steps in 100 %
+    pub const SCALE: u16 = 10_000;
+    pub const ZERO: Self = Self(0);
+    pub const FULL: Self = Self(Self::SCALE);
+
+    /// Duty in 0.01 % steps, clamped to 100 %.
+    pub const fn from_raw(raw: u16) -> Self {
+        Self(if raw > Self::SCALE { Self::SCALE } else { raw })
+    }
+
+    pub const fn from_percent(percent: u16) -> Self {
+        Self::from_raw(if percent > 100 { Self::SCALE } else { percent * 100 })
+    }
+
+    /// Duty from a fraction of the period (0.0..=1.0); NaN maps to 0.
+    pub fn from_fraction(fraction: f32) -> Self {
+        let raw = fraction.clamp(0.0, 1.0) * f32::from(Self::SCALE) + 0.5;
+        // `as` saturates and maps NaN to 0
+        Self::from_raw(raw as u16)
+    }
+
+    pub const fn raw(self) -> u16 {
+        self.0
+    }
+
+    /// Rounded to the nearest percent.
+    pub const fn percent(self) -> u16 {
+        (self.0 + 50) / 100
+    }
+
+    pub fn fraction(self) -> f32 {
+        f32::from(self.0) / f32::from(Self::SCALE)
+    }
+
+    /// Compare value for a period of `max_duty` ticks, rounded to nearest.
+    pub fn to_ticks(self, max_duty: u16) -> u16 {
+        let scale = u32::from(Self::SCALE);
+        ((u32::from(self.0) * u32::from(max_duty) + scale / 2) / scale) as u16
+    }
+}
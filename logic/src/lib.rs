@@ -0,0 +1,12 @@
+#![no_std]
+
+pub mod capture;
+pub mod crc;
+pub mod duty;
+pub mod pid;
+pub mod power;
+pub mod session;
+pub mod stall;
+pub mod stats;
+pub mod thermal;
+pub mod trend;
@@ -0,0 +1,222 @@
+/// Fractional bits of the fixed-point gains and integrator (Q16.16).
+const FRAC_BITS: u32 = 16;
+
+/// Convert a gain given as a float (e.g. from SCPI) to Q16.16.
+pub fn gain_from_f32(value: f32) -> i32 {
+    (value * (1u32 << FRAC_BITS) as f32) as i32
+}
+
+pub fn gain_to_f32(value: i32) -> f32 {
+    value as f32 / (1u32 << FRAC_BITS) as f32
+}
+
+/// PID tuning. Gains are Q16.16: `kp` in %/RPM, `ki` in %/(RPM*s), `kd` in %*s/RPM.
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct PidConfig {
+    pub kp: i32,
+    pub ki: i32,
+    pub kd: i32,
+    /// Controller update period
+    pub period_ms: u32,
+}
+
+impl PidConfig {
+    pub const DEFAULT: Self = Self {
+        kp: 1311,  // 0.02 %/RPM
+        ki: 3277,  // 0.05 %/(RPM*s)
+        kd: 0,
+        period_ms: 200,
+    };
+}
+
+/// Fixed-point PID controller with output clamping and anti-windup.
+///
+/// The derivative acts on the measurement, not the error, so setpoint steps
+/// do not kick the output. The integrator is clamped to the output range and
+/// stops growing once the output saturates in the direction of the error.
+#[derive(Debug, Clone, Copy)]
+pub struct Pid {
+    config: PidConfig,
+    out_min: i32,
+    out_max: i32,
+    integral: i64,
+    previous: Option<i32>,
+}
+
+impl Pid {
+    pub fn new(config: PidConfig, out_min: i32, out_max: i32) -> Self {
+        Self {
+            config,
+            out_min,
+            out_max: out_max.max(out_min),
+            integral: 0,
+            previous: None,
+        }
+    }
+
+    pub fn config(&self) -> PidConfig {
+        self.config
+    }
+
+    /// Change tuning without dropping the integrator (bumpless for small changes).
+    pub fn set_config(&mut self, config: PidConfig) {
+        self.config = config;
+    }
+
+    pub fn set_limits(&mut self, out_min: i32, out_max: i32) {
+        self.out_min = out_min;
+        self.out_max = out_max.max(out_min);
+        self.integral = self.integral.clamp(self.min_q(), self.max_q());
+    }
+
+    pub fn reset(&mut self) {
+        self.integral = 0;
+        self.previous = None;
+    }
+
+    /// Preload the integrator so the first output equals `output` (e.g. the
+    /// current open-loop duty when switching to closed loop).
+    pub fn preload(&mut self, output: i32) {
+        self.integral = (i64::from(output) << FRAC_BITS).clamp(self.min_q(), self.max_q());
+        self.previous = None;
+    }
+
+    /// One controller step; call every `period_ms`. Returns the clamped output.
+    pub fn update(&mut self, setpoint: i32, measurement: i32) -> i32 {
+        let dt_ms = i64::from(self.config.period_ms.max(1));
+        let error = i64::from(setpoint - measurement);
+
+        let proportional = i64::from(self.config.kp) * error;
+        let derivative = match self.previous {
+            Some(previous) => {
+                -i64::from(self.config.kd) * i64::from(measurement - previous) * 1000 / dt_ms
+            }
+            None => 0,
+        };
+        self.previous = Some(measurement);
+
+        // Integrate only until the output reaches the limit in the direction
+        // of the error; what the integrator already holds is kept
+        let step = i64::from(self.config.ki) * error * dt_ms / 1000;
+        let headroom = proportional + derivative;
+        let mut integral = self.integral + step;
+        if error > 0 {
+            integral = integral.min((self.max_q() - headroom).max(self.integral));
+        } else if error < 0 {
+            integral = integral.max((self.min_q() - headroom).min(self.integral));
+        }
+        self.integral = integral.clamp(self.min_q(), self.max_q());
+
+        let output = (proportional + self.integral + derivative) >> FRAC_BITS;
+        output.clamp(i64::from(self.out_min), i64::from(self.out_max)) as i32
+    }
+
+    fn min_q(&self) -> i64 {
+        i64::from(self.out_min) << FRAC_BITS
+    }
+
+    fn max_q(&self) -> i64 {
+        i64::from(self.out_max) << FRAC_BITS
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use std::vec::Vec;
+
+    use super::*;
+
+    /// First-order fan: the speed settles towards a linear duty curve above
+    /// the start duty with a 1.5 s time constant.
+    struct Fan {
+        rpm: f32,
+    }
+
+    impl Fan {
+        const START_DUTY: f32 = 20.0;
+        const TAU_S: f32 = 1.5;
+
+        fn steady_rpm(duty: i32) -> f32 {
+            let duty = duty as f32;
+            if duty < Self::START_DUTY {
+                return 0.0;
+            }
+            800.0 + (duty - Self::START_DUTY) * (3400.0 - 800.0) / (100.0 - Self::START_DUTY)
+        }
+
+        fn step(&mut self, duty: i32, dt_s: f32) -> i32 {
+            self.rpm += (Self::steady_rpm(duty) - self.rpm) * dt_s / Self::TAU_S;
+            self.rpm as i32
+        }
+    }
+
+    /// Run the loop for `seconds`; returns the duty and RPM of every period.
+    fn run(pid: &mut Pid, fan: &mut Fan, target: i32, seconds: u32) -> Vec<(i32, i32)> {
+        let period_ms = pid.config().period_ms;
+        let dt_s = period_ms as f32 / 1000.0;
+        let mut rpm = fan.rpm as i32;
+        (0..seconds * 1000 / period_ms)
+            .map(|_| {
+                let duty = pid.update(target, rpm);
+                rpm = fan.step(duty, dt_s);
+                (duty, rpm)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn settles_on_target() {
+        let mut pid = Pid::new(PidConfig::DEFAULT, 20, 100);
+        let mut fan = Fan { rpm: 0.0 };
+        let trace = run(&mut pid, &mut fan, 2000, 60);
+
+        // The default tuning rises fast from standstill at some overshoot
+        let peak = trace.iter().map(|&(_, rpm)| rpm).max().unwrap();
+        assert!(peak < 2000 * 120 / 100, "overshoot to {peak} RPM");
+        for &(duty, rpm) in &trace[trace.len() - 25..] {
+            assert!((rpm - 2000).abs() <= 40, "{rpm} RPM after settling");
+            assert!((20..=100).contains(&duty));
+        }
+    }
+
+    #[test]
+    fn recovers_from_saturation_without_windup() {
+        let mut pid = Pid::new(PidConfig::DEFAULT, 20, 100);
+        let mut fan = Fan { rpm: 0.0 };
+        let trace = run(&mut pid, &mut fan, 5000, 30);
+        assert_eq!(trace.last().unwrap().0, 100, "unreachable target saturates");
+
+        // Within 15 s of lowering the target the fan is back near it
+        let trace = run(&mut pid, &mut fan, 1500, 15);
+        let (_, rpm) = *trace.last().unwrap();
+        assert!((rpm - 1500).abs() <= 30, "{rpm} RPM after lowering the target");
+    }
+
+    #[test]
+    fn preload_is_bumpless() {
+        let mut pid = Pid::new(PidConfig::DEFAULT, 20, 100);
+        pid.preload(60);
+        let rpm = Fan::steady_rpm(60) as i32;
+        assert_eq!(pid.update(rpm, rpm), 60);
+    }
+
+    #[test]
+    fn output_stays_within_limits() {
+        let config = PidConfig {
+            kp: gain_from_f32(1.0),
+            ..PidConfig::DEFAULT
+        };
+        let mut pid = Pid::new(config, 20, 100);
+        assert_eq!(pid.update(3000, 0), 100);
+        assert_eq!(pid.update(0, 3000), 20);
+    }
+
+    #[test]
+    fn gain_conversion_round_trips() {
+        assert_eq!(gain_from_f32(0.05), 3276);
+        assert!((gain_to_f32(gain_from_f32(0.02)) - 0.02).abs() < 1e-4);
+    }
+}
@@ -0,0 +1,37 @@
+/// Source connected by the power relays.
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum PowerState {
+    DCDC,
+    ACDC,
+    OFF,
+}
+
+impl PowerState {
+    pub const ACDC_THRESHOLD: u32 = 760;
+
+    fn from_voltage(voltage: u32) -> Self {
+        if voltage > Self::ACDC_THRESHOLD {
+            PowerState::ACDC
+        } else {
+            PowerState::DCDC
+        }
+    }
+
+    pub fn determine_state(message: u32, voltage: u32) -> Self {
+        match message {
+            1 => PowerState::DCDC,
+            2 => PowerState::ACDC,
+            3 => PowerState::OFF,
+            _ => Self::from_voltage(voltage),
+        }
+    }
+
+    pub fn get_led_delay(&self) -> u64 {
+        match self {
+            PowerState::ACDC => 500,
+            PowerState::DCDC => 100,
+            PowerState::OFF => 1000,
+        }
+    }
+}
@@ -0,0 +1,561 @@
+use embedded_io_async::{Read, Write};
+
+/// Bytes requested from the transport per read; at least one USB full-speed
+/// packet, which CDC reads need.
+const CHUNK_LEN: usize = 64;
+
+fn is_terminator(byte: u8) -> bool {
+    byte == b'\r' || byte == b'\n'
+}
+
+/// Tracks IEEE 488.2 definite-length block data (`#<digits><length><bytes>`)
+/// so that CR/LF bytes inside the payload do not end the command line.
+#[derive(Default)]
+struct BlockScanner {
+    header_at: Option<usize>,
+    remaining: usize,
+}
+
+impl BlockScanner {
+    /// Feed the line received so far, ending with the new byte; returns true
+    /// while that byte is part of a block payload.
+    fn in_payload(&mut self, line: &[u8]) -> bool {
+        let pos = line.len();
+        if self.remaining > 0 {
+            self.remaining -= 1;
+            return true;
+        }
+        match self.header_at {
+            None if line[pos - 1] == b'#' => self.header_at = Some(pos - 1),
+            None => {}
+            Some(start) => {
+                // `#0` (indefinite length) and `#H`/`#B`/`#Q` numbers are not scanned
+                let digits = usize::from(line[start + 1].wrapping_sub(b'0'));
+                if !(1..=9).contains(&digits) {
+                    self.header_at = None;
+                } else if pos == start + 2 + digits {
+                    self.header_at = None;
+                    self.remaining = line[start + 2..].iter().fold(0, |length, &digit| {
+                        length * 10 + usize::from(digit.wrapping_sub(b'0') % 10)
+                    });
+                }
+            }
+        }
+        false
+    }
+}
+
+/// Result of feeding one byte to a `LineBuffer`.
+pub enum Frame<'a> {
+    /// A complete, non-empty line without its terminator
+    Line(&'a [u8]),
+    /// A line longer than the buffer ended and was dropped
+    Overrun,
+}
+
+/// Assembles received bytes into lines of up to `N - 1` bytes.
+///
+/// CR, LF and CRLF all end a line; the empty line between CR and LF is not
+/// reported. Terminators inside block data do not count. Once a line
+/// overflows, the rest of it is discarded up to the next terminator.
+pub struct LineBuffer<const N: usize> {
+    buf: [u8; N],
+    len: usize,
+    block: BlockScanner,
+    discarding: bool,
+}
+
+impl<const N: usize> LineBuffer<N> {
+    pub const fn new() -> Self {
+        Self {
+            buf: [0; N],
+            len: 0,
+            block: BlockScanner {
+                header_at: None,
+                remaining: 0,
+            },
+            discarding: false,
+        }
+    }
+
+    /// Drop the partial line, e.g. after a transport error.
+    pub fn clear(&mut self) {
+        self.len = 0;
+        self.block = BlockScanner::default();
+        self.discarding = false;
+    }
+
+    pub fn push(&mut self, byte: u8) -> Option<Frame<'_>> {
+        if self.discarding {
+            if is_terminator(byte) {
+                self.discarding = false;
+                return Some(Frame::Overrun);
+            }
+            return None;
+        }
+        if self.len == N {
+            self.clear();
+            if is_terminator(byte) {
+                return Some(Frame::Overrun);
+            }
+            self.discarding = true;
+            return None;
+        }
+
+        self.buf[self.len] = byte;
+        self.len += 1;
+        if self.block.in_payload(&self.buf[..self.len]) || !is_terminator(byte) {
+            return None;
+        }
+
+        let len = self.len - 1;
+        self.len = 0;
+        self.block = BlockScanner::default();
+        if len == 0 {
+            return None;
+        }
+        Some(Frame::Line(&self.buf[..len]))
+    }
+}
+
+impl<const N: usize> Default for LineBuffer<N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Meaning of the lines of a session.
+// Sessions run on a single-threaded executor; their futures need not be `Send`
+#[allow(async_fn_in_trait)]
+pub trait LineHandler {
+    /// Handle one line, leaving the reply in `response`.
+    fn line(&mut self, line: &[u8]);
+
+    /// A line did not fit the buffer and was dropped; replaces the reply.
+    fn overrun(&mut self);
+
+    /// Wait until the reply may go out, e.g. until earlier commands took effect.
+    async fn settle(&mut self) {}
+
+    /// Reply to the last line or overrun (may be empty).
+    fn response(&self) -> &[u8];
+
+    /// The connection was closed or reopened: drop per-connection state.
+    fn reset(&mut self) {}
+
+    /// Whether `Session::run` should return after the reply just sent, e.g.
+    /// so the owner can change the transport settings.
+    fn pause(&mut self) -> bool {
+        false
+    }
+}
+
+/// Bus addressing in front of another handler.
+///
+/// A line may start with `@<address>` and a space. Only the unit with that
+/// address handles and answers it; `@0` reaches every unit and none answers.
+/// Lines without a prefix are handled by every unit and answered unless
+/// `silent_unaddressed` is set, as on a shared bus.
+pub struct Addressed<H> {
+    handler: H,
+    address: u8,
+    silent_unaddressed: bool,
+    /// Suppress the reply to the last line
+    silent: bool,
+}
+
+impl<H> Addressed<H> {
+    pub const fn new(handler: H) -> Self {
+        Self {
+            handler,
+            address: 1,
+            silent_unaddressed: false,
+            silent: false,
+        }
+    }
+
+    pub fn set_address(&mut self, address: u8) {
+        self.address = address;
+    }
+
+    pub fn set_silent_unaddressed(&mut self, silent: bool) {
+        self.silent_unaddressed = silent;
+    }
+}
+
+/// Split `@<address> rest` into the address and the rest; `None` for a
+/// malformed prefix.
+fn split_address(line: &[u8]) -> Option<(u8, &[u8])> {
+    let end = line.iter().position(|byte| byte.is_ascii_whitespace()).unwrap_or(line.len());
+    let digits = &line[1..end];
+    if digits.is_empty() || !digits.iter().all(u8::is_ascii_digit) {
+        return None;
+    }
+    let address = digits
+        .iter()
+        .try_fold(0u8, |value, &digit| value.checked_mul(10)?.checked_add(digit - b'0'))?;
+    Some((address, line[end..].trim_ascii_start()))
+}
+
+impl<H: LineHandler> LineHandler for Addressed<H> {
+    fn line(&mut self, line: &[u8]) {
+        if !line.starts_with(b"@") {
+            self.silent = self.silent_unaddressed;
+            self.handler.line(line);
+            return;
+        }
+        match split_address(line) {
+            Some((address, rest)) if !rest.is_empty() && address == self.address => {
+                self.silent = false;
+                self.handler.line(rest);
+            }
+            Some((0, rest)) if !rest.is_empty() => {
+                self.silent = true;
+                self.handler.line(rest);
+            }
+            // For another unit, or garbled: stay off the bus
+            _ => self.silent = true,
+        }
+    }
+
+    fn overrun(&mut self) {
+        // The address of a dropped line is unknown
+        self.silent = self.silent_unaddressed;
+        self.handler.overrun();
+    }
+
+    async fn settle(&mut self) {
+        self.handler.settle().await;
+    }
+
+    fn response(&self) -> &[u8] {
+        if self.silent {
+            return &[];
+        }
+        self.handler.response()
+    }
+
+    fn reset(&mut self) {
+        self.silent = false;
+        self.handler.reset();
+    }
+
+    fn pause(&mut self) -> bool {
+        self.handler.pause()
+    }
+}
+
+/// Why `Session::run` returned.
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Ended {
+    /// End of stream: the peer disconnected
+    Closed,
+    /// The handler asked for a pause; call `run` again to continue
+    Paused,
+}
+
+#[derive(Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum SessionError<R, W> {
+    Read(R),
+    Write(W),
+}
+
+/// Line-oriented command session over any `embedded_io_async` transport.
+///
+/// Framing comes from `LineBuffer`, the meaning of each line from `H`, so
+/// USART, USB CDC and later transports share one implementation.
+pub struct Session<H, const N: usize> {
+    lines: LineBuffer<N>,
+    handler: H,
+    echo: bool,
+    last: u8,
+    /// Replaces the LF (or CRLF) ending each response
+    terminator: Option<&'static [u8]>,
+    /// Last read from the transport, handled up to `pos`
+    chunk: [u8; CHUNK_LEN],
+    pos: usize,
+    len: usize,
+}
+
+impl<H: LineHandler, const N: usize> Session<H, N> {
+    pub const fn new(handler: H) -> Self {
+        Self {
+            lines: LineBuffer::new(),
+            handler,
+            echo: false,
+            last: 0,
+            terminator: None,
+            chunk: [0; CHUNK_LEN],
+            pos: 0,
+            len: 0,
+        }
+    }
+
+    /// Echo received bytes back (for terminals); line ends are echoed as CRLF.
+    pub fn set_echo(&mut self, echo: bool) {
+        self.echo = echo;
+    }
+
+    /// End responses with `terminator` instead of the handler's LF.
+    pub fn set_terminator(&mut self, terminator: &'static [u8]) {
+        self.terminator = Some(terminator);
+    }
+
+    pub fn handler_mut(&mut self) -> &mut H {
+        &mut self.handler
+    }
+
+    /// Start over for a new connection: partial line and handler state.
+    pub fn reset(&mut self) {
+        self.lines.clear();
+        self.last = 0;
+        self.pos = 0;
+        self.len = 0;
+        self.handler.reset();
+    }
+
+    /// Serve lines until the transport fails, reaches end of stream or the
+    /// handler asks for a pause.
+    ///
+    /// Each response is written and flushed before the next line is handled,
+    /// so responses leave in command order; `settle` may hold one back. Bytes
+    /// received after a paused line are kept for the next call. A read error
+    /// drops the partial line.
+    pub async fn run<R: Read, W: Write>(
+        &mut self,
+        rx: &mut R,
+        tx: &mut W,
+    ) -> Result<Ended, SessionError<R::Error, W::Error>> {
+        loop {
+            if self.pos == self.len {
+                self.len = match rx.read(&mut self.chunk).await {
+                    Ok(0) => return Ok(Ended::Closed),
+                    Ok(n) => n,
+                    Err(e) => {
+                        self.lines.clear();
+                        return Err(SessionError::Read(e));
+                    }
+                };
+                self.pos = 0;
+                if self.echo {
+                    self.echo_chunk(tx).await.map_err(SessionError::Write)?;
+                }
+            }
+
+            while self.pos < self.len {
+                let byte = self.chunk[self.pos];
+                self.pos += 1;
+                match self.lines.push(byte) {
+                    None => continue,
+                    Some(Frame::Line(line)) => self.handler.line(line),
+                    Some(Frame::Overrun) => self.handler.overrun(),
+                }
+                self.handler.settle().await;
+                self.respond(tx).await.map_err(SessionError::Write)?;
+                if self.handler.pause() {
+                    return Ok(Ended::Paused);
+                }
+            }
+        }
+    }
+
+    async fn respond<W: Write>(&mut self, tx: &mut W) -> Result<(), W::Error> {
+        let response = self.handler.response();
+        if response.is_empty() {
+            return Ok(());
+        }
+        match (self.terminator, response.strip_suffix(b"\n")) {
+            (Some(terminator), Some(body)) => {
+                tx.write_all(body.strip_suffix(b"\r").unwrap_or(body)).await?;
+                tx.write_all(terminator).await?;
+            }
+            _ => tx.write_all(response).await?,
+        }
+        tx.flush().await
+    }
+
+    async fn echo_chunk<W: Write>(&mut self, tx: &mut W) -> Result<(), W::Error> {
+        let mut out = [0u8; 2 * CHUNK_LEN];
+        let mut len = 0;
+        for &byte in &self.chunk[..self.len] {
+            if !is_terminator(byte) {
+                out[len] = byte;
+                len += 1;
+            } else if !(byte == b'\n' && self.last == b'\r') {
+                out[len..len + 2].copy_from_slice(b"\r\n");
+                len += 2;
+            }
+            self.last = byte;
+        }
+        tx.write_all(&out[..len]).await?;
+        tx.flush().await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use core::convert::Infallible;
+    use std::vec::Vec;
+
+    use embassy_futures::block_on;
+
+    use super::*;
+
+    /// Feed `input` byte by byte; lines come back as `Ok`, overruns as `Err(())`.
+    fn frames<const N: usize>(input: &[u8]) -> Vec<Result<Vec<u8>, ()>> {
+        let mut lines = LineBuffer::<N>::new();
+        input
+            .iter()
+            .filter_map(|&byte| match lines.push(byte)? {
+                Frame::Line(line) => Some(Ok(line.to_vec())),
+                Frame::Overrun => Some(Err(())),
+            })
+            .collect()
+    }
+
+    fn lines(input: &[u8]) -> Vec<Vec<u8>> {
+        frames::<32>(input).into_iter().map(Result::unwrap).collect()
+    }
+
+    #[test]
+    fn any_line_ending_ends_a_line() {
+        assert_eq!(lines(b"A\rB\nC\r\nD"), [&b"A"[..], b"B", b"C"]);
+        assert!(lines(b"\r\n\n\r").is_empty());
+    }
+
+    #[test]
+    fn line_endings_inside_block_data_are_payload() {
+        assert_eq!(lines(b"DATA #14\r\n\0x\nNEXT\n"), [&b"DATA #14\r\n\0x"[..], b"NEXT"]);
+        assert_eq!(lines(b"T #211ab\ncdef\r\nxy\nZ\n"), [&b"T #211ab\ncdef\r\nxy"[..], b"Z"]);
+    }
+
+    #[test]
+    fn unscanned_blocks_do_not_hide_line_endings() {
+        // Indefinite length, non-decimal numbers and a lone `#`
+        assert_eq!(lines(b"A #0\nB #HFF\nC #\n"), [&b"A #0"[..], b"B #HFF", b"C #"]);
+    }
+
+    #[test]
+    fn overlong_lines_are_dropped_up_to_the_terminator() {
+        let input = b"0123456789\nOK\n0123456\nLAST\n";
+        let expected = [Err(()), Ok(b"OK".to_vec()), Ok(b"0123456".to_vec()), Ok(b"LAST".to_vec())];
+        assert_eq!(frames::<8>(input), expected);
+    }
+
+    #[test]
+    fn clear_drops_the_partial_line() {
+        let mut lines = LineBuffer::<16>::new();
+        for &byte in b"#15ab" {
+            assert!(lines.push(byte).is_none());
+        }
+        lines.clear();
+        assert!(lines.push(b'\n').is_none());
+        lines.push(b'X');
+        assert!(matches!(lines.push(b'\n'), Some(Frame::Line(b"X"))));
+    }
+
+    #[test]
+    fn addresses_are_split_from_the_line() {
+        assert_eq!(split_address(b"@12 *IDN?"), Some((12, &b"*IDN?"[..])));
+        assert_eq!(split_address(b"@0   LED ON"), Some((0, &b"LED ON"[..])));
+        assert_eq!(split_address(b"@7"), Some((7, &b""[..])));
+        assert_eq!(split_address(b"@255 X"), Some((255, &b"X"[..])));
+        assert_eq!(split_address(b"@256 X"), None);
+        assert_eq!(split_address(b"@ X"), None);
+        assert_eq!(split_address(b"@1a X"), None);
+    }
+
+    /// Answers every line with `>` and the line, an overrun with `OVERRUN`.
+    #[derive(Default)]
+    struct Reply {
+        response: Vec<u8>,
+        resets: u32,
+    }
+
+    impl LineHandler for Reply {
+        fn line(&mut self, line: &[u8]) {
+            self.response.clear();
+            self.response.push(b'>');
+            self.response.extend_from_slice(line);
+            self.response.push(b'\n');
+        }
+
+        fn overrun(&mut self) {
+            self.response = b"OVERRUN\n".to_vec();
+        }
+
+        fn response(&self) -> &[u8] {
+            &self.response
+        }
+
+        fn reset(&mut self) {
+            self.resets += 1;
+        }
+    }
+
+    /// Collects everything written
+    struct Sink(Vec<u8>);
+
+    impl embedded_io_async::ErrorType for Sink {
+        type Error = Infallible;
+    }
+
+    impl Write for Sink {
+        async fn write(&mut self, buf: &[u8]) -> Result<usize, Infallible> {
+            self.0.extend_from_slice(buf);
+            Ok(buf.len())
+        }
+    }
+
+    /// Run `input` through a session until end of stream; returns the output.
+    fn serve<H: LineHandler>(session: &mut Session<H, 16>, mut input: &[u8]) -> Vec<u8> {
+        let mut output = Sink(Vec::new());
+        let ended = block_on(session.run(&mut input, &mut output));
+        assert!(matches!(ended, Ok(Ended::Closed)));
+        output.0
+    }
+
+    #[test]
+    fn session_answers_each_line_in_order() {
+        let mut session = Session::<_, 16>::new(Reply::default());
+        let output = serve(&mut session, b"ONE\r\nTWO\n0123456789abcdefXYZ\nTHREE");
+        assert_eq!(output, b">ONE\n>TWO\nOVERRUN\n");
+    }
+
+    #[test]
+    fn session_echoes_and_replaces_the_terminator() {
+        let mut session = Session::<_, 16>::new(Reply::default());
+        session.set_echo(true);
+        session.set_terminator(b"\r\n");
+        let output = serve(&mut session, b"A\rB\r\n");
+        assert_eq!(output, b"A\r\nB\r\n>A\r\n>B\r\n");
+    }
+
+    #[test]
+    fn addressed_lines_reach_only_their_unit() {
+        let mut handler = Addressed::new(Reply::default());
+        handler.set_address(5);
+        let mut session = Session::<_, 16>::new(handler);
+
+        let output = serve(&mut session, b"@5 A\n@6 B\n@0 C\nD\n@x E\n@5\n");
+        assert_eq!(output, b">A\n>D\n");
+
+        session.handler_mut().set_silent_unaddressed(true);
+        let output = serve(&mut session, b"D\n@05 F\n");
+        assert_eq!(output, b">F\n");
+    }
+
+    #[test]
+    fn reset_reaches_the_handler() {
+        let mut session = Session::<_, 16>::new(Addressed::new(Reply::default()));
+        serve(&mut session, b"@1 A");
+        session.reset();
+        let output = serve(&mut session, b"\n");
+        assert!(output.is_empty());
+        assert_eq!(session.handler_mut().handler.resets, 1);
+    }
+}
@@ -0,0 +1,217 @@
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct StallConfig {
+    /// A driven fan without rotation for this long counts as stalled
+    pub timeout_s: u16,
+    /// Restart attempts before the failure is latched
+    pub retries: u8,
+    /// Length of the 100 % restart kick
+    pub kick_ms: u16,
+    /// Switch the power source OFF when the failure is latched
+    pub power_off: bool,
+}
+
+impl StallConfig {
+    pub const DEFAULT: Self = Self {
+        timeout_s: 5,
+        retries: 3,
+        kick_ms: 1000,
+        power_off: false,
+    };
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum FanFault {
+    Ok,
+    /// Stalled, restart attempts in progress
+    Stalled,
+    /// Restarts exhausted; latched until cleared
+    Failed,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum StallAction {
+    None,
+    /// Drive the fan at 100 % for `kick_ms`
+    Kick,
+    /// The fan recovered after a restart
+    Recovered,
+    /// Latch the failure
+    Fail,
+}
+
+/// Stall supervision: counts how long a driven fan stays without rotation,
+/// asks for restart kicks and latches a failure once they are used up.
+///
+/// The restart counter only resets after the fan turned for a full timeout,
+/// so a fan that spins briefly on each kick and stops again still fails.
+#[derive(Debug, Clone, Copy)]
+pub struct StallDetector {
+    stalled_since_ms: Option<u64>,
+    spinning_since_ms: Option<u64>,
+    kicks: u8,
+    failed: bool,
+}
+
+impl StallDetector {
+    pub const fn new() -> Self {
+        Self {
+            stalled_since_ms: None,
+            spinning_since_ms: None,
+            kicks: 0,
+            failed: false,
+        }
+    }
+
+    pub fn fault(&self) -> FanFault {
+        if self.failed {
+            FanFault::Failed
+        } else if self.kicks > 0 {
+            FanFault::Stalled
+        } else {
+            FanFault::Ok
+        }
+    }
+
+    pub fn clear(&mut self) {
+        *self = Self::new();
+    }
+
+    /// One supervision step. `driven` is true while the duty is above the
+    /// start threshold, `spinning` while rotation is detected.
+    pub fn update(
+        &mut self,
+        config: &StallConfig,
+        driven: bool,
+        spinning: bool,
+        now_ms: u64,
+    ) -> StallAction {
+        if self.failed {
+            return StallAction::None;
+        }
+        let timeout_ms = u64::from(config.timeout_s) * 1000;
+
+        if !driven {
+            self.stalled_since_ms = None;
+            self.spinning_since_ms = None;
+            return StallAction::None;
+        }
+
+        if spinning {
+            self.stalled_since_ms = None;
+            let since = *self.spinning_since_ms.get_or_insert(now_ms);
+            if self.kicks > 0 && now_ms.saturating_sub(since) >= timeout_ms {
+                self.kicks = 0;
+                return StallAction::Recovered;
+            }
+            return StallAction::None;
+        }
+
+        self.spinning_since_ms = None;
+        let since = *self.stalled_since_ms.get_or_insert(now_ms);
+        if now_ms.saturating_sub(since) < timeout_ms {
+            return StallAction::None;
+        }
+
+        self.stalled_since_ms = None;
+        if self.kicks < config.retries {
+            self.kicks += 1;
+            StallAction::Kick
+        } else {
+            self.failed = true;
+            StallAction::Fail
+        }
+    }
+}
+
+impl Default for StallDetector {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CONFIG: StallConfig = StallConfig::DEFAULT;
+
+    /// Drive a stalled fan until the detector asks for something.
+    fn stall(detector: &mut StallDetector, from_ms: u64) -> (StallAction, u64) {
+        let mut now = from_ms;
+        loop {
+            let action = detector.update(&CONFIG, true, false, now);
+            if action != StallAction::None {
+                return (action, now);
+            }
+            now += 100;
+        }
+    }
+
+    #[test]
+    fn stall_needs_a_full_timeout() {
+        let mut detector = StallDetector::new();
+        assert_eq!(detector.update(&CONFIG, true, false, 0), StallAction::None);
+        assert_eq!(detector.update(&CONFIG, true, false, 4999), StallAction::None);
+        assert_eq!(detector.update(&CONFIG, true, false, 5000), StallAction::Kick);
+        assert_eq!(detector.fault(), FanFault::Stalled);
+    }
+
+    #[test]
+    fn undriven_or_spinning_fan_restarts_the_timer() {
+        let mut detector = StallDetector::new();
+        assert_eq!(detector.update(&CONFIG, true, false, 0), StallAction::None);
+        assert_eq!(detector.update(&CONFIG, false, false, 3000), StallAction::None);
+        assert_eq!(detector.update(&CONFIG, true, false, 4000), StallAction::None);
+        assert_eq!(detector.update(&CONFIG, true, true, 6000), StallAction::None);
+        assert_eq!(detector.update(&CONFIG, true, false, 7000), StallAction::None);
+        assert_eq!(detector.update(&CONFIG, true, false, 11_999), StallAction::None);
+        assert_eq!(detector.update(&CONFIG, true, false, 12_000), StallAction::Kick);
+    }
+
+    #[test]
+    fn kicks_until_retries_are_used_up() {
+        let mut detector = StallDetector::new();
+        let mut now = 0;
+        for _ in 0..CONFIG.retries {
+            let (action, at) = stall(&mut detector, now);
+            assert_eq!(action, StallAction::Kick);
+            // A brief spin on the kick does not reset the retry count
+            assert_eq!(detector.update(&CONFIG, true, true, at + 100), StallAction::None);
+            now = at + 200;
+        }
+        let (action, _) = stall(&mut detector, now);
+        assert_eq!(action, StallAction::Fail);
+        assert_eq!(detector.fault(), FanFault::Failed);
+    }
+
+    #[test]
+    fn recovers_after_spinning_a_full_timeout() {
+        let mut detector = StallDetector::new();
+        let (action, at) = stall(&mut detector, 0);
+        assert_eq!(action, StallAction::Kick);
+        assert_eq!(detector.update(&CONFIG, true, true, at + 100), StallAction::None);
+        assert_eq!(detector.update(&CONFIG, true, true, at + 5099), StallAction::None);
+        assert_eq!(detector.update(&CONFIG, true, true, at + 5100), StallAction::Recovered);
+        assert_eq!(detector.fault(), FanFault::Ok);
+    }
+
+    #[test]
+    fn failure_stays_latched_until_cleared() {
+        let config = StallConfig { retries: 0, ..CONFIG };
+        let mut detector = StallDetector::new();
+        assert_eq!(detector.update(&config, true, false, 0), StallAction::None);
+        assert_eq!(detector.update(&config, true, false, 5000), StallAction::Fail);
+        assert_eq!(detector.update(&config, true, true, 6000), StallAction::None);
+        assert_eq!(detector.update(&config, true, true, 20_000), StallAction::None);
+        assert_eq!(detector.update(&config, false, false, 30_000), StallAction::None);
+        assert_eq!(detector.fault(), FanFault::Failed);
+
+        detector.clear();
+        assert_eq!(detector.fault(), FanFault::Ok);
+        assert_eq!(detector.update(&config, true, false, 40_000), StallAction::None);
+        assert_eq!(detector.update(&config, true, false, 45_000), StallAction::Fail);
+    }
+}
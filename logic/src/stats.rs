@@ -0,0 +1,162 @@
+/// Linear conversion from raw ADC counts to millivolts: `raw * num / den`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Scale {
+    pub num: u32,
+    pub den: u32,
+}
+
+/// Statistics of one measurement window, all in mV.
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct WindowStats {
+    pub min: u32,
+    pub max: u32,
+    pub mean: u32,
+    pub rms: u32,
+    pub std_dev: u32,
+    /// Peak-to-peak (max - min)
+    pub ripple: u32,
+}
+
+impl WindowStats {
+    pub const EMPTY: Self = Self {
+        min: 0,
+        max: 0,
+        mean: 0,
+        rms: 0,
+        std_dev: 0,
+        ripple: 0,
+    };
+}
+
+/// Integer accumulator for a window of raw samples.
+///
+/// Only sums are kept, so the window length is not limited by RAM; the
+/// scaling to mV is done once in `finish` with 128-bit intermediates so the
+/// fractional part of the mean survives the division.
+#[derive(Debug, Clone, Copy)]
+pub struct WindowAccumulator {
+    count: u32,
+    sum: u64,
+    sum_sq: u64,
+    min: u16,
+    max: u16,
+}
+
+impl WindowAccumulator {
+    pub const fn new() -> Self {
+        Self {
+            count: 0,
+            sum: 0,
+            sum_sq: 0,
+            min: u16::MAX,
+            max: 0,
+        }
+    }
+
+    pub fn push(&mut self, sample: u16) {
+        self.count += 1;
+        self.sum += u64::from(sample);
+        self.sum_sq += u64::from(sample) * u64::from(sample);
+        self.min = self.min.min(sample);
+        self.max = self.max.max(sample);
+    }
+
+    pub fn count(&self) -> u32 {
+        self.count
+    }
+
+    pub fn finish(&self, scale: Scale) -> WindowStats {
+        if self.count == 0 || scale.den == 0 {
+            return WindowStats::EMPTY;
+        }
+        let n = u128::from(self.count);
+        let num = u128::from(scale.num);
+        let den = u128::from(scale.den);
+        let to_mv = |raw: u16| (u128::from(raw) * num / den) as u32;
+
+        let mean = (u128::from(self.sum) * num / (den * n)) as u32;
+        let mean_sq = u128::from(self.sum_sq) * num * num / (den * den * n);
+        // n * sum_sq - sum^2 == n^2 * variance, never negative for exact sums
+        let spread = n * u128::from(self.sum_sq) - u128::from(self.sum) * u128::from(self.sum);
+        let variance = spread * num * num / (den * den * n * n);
+
+        let min = to_mv(self.min);
+        let max = to_mv(self.max);
+        WindowStats {
+            min,
+            max,
+            mean,
+            rms: (mean_sq as u64).isqrt() as u32,
+            std_dev: (variance as u64).isqrt() as u32,
+            ripple: max - min,
+        }
+    }
+}
+
+impl Default for WindowAccumulator {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const UNITY: Scale = Scale { num: 1, den: 1 };
+
+    fn accumulate(samples: &[u16]) -> WindowAccumulator {
+        let mut window = WindowAccumulator::new();
+        for &sample in samples {
+            window.push(sample);
+        }
+        window
+    }
+
+    #[test]
+    fn empty_window_or_scale_gives_empty_stats() {
+        assert_eq!(WindowAccumulator::new().finish(UNITY), WindowStats::EMPTY);
+        let window = accumulate(&[100]);
+        assert_eq!(window.finish(Scale { num: 1, den: 0 }), WindowStats::EMPTY);
+    }
+
+    #[test]
+    fn window_statistics() {
+        let window = accumulate(&[10, 20, 30, 40]);
+        assert_eq!(window.count(), 4);
+        assert_eq!(
+            window.finish(UNITY),
+            WindowStats {
+                min: 10,
+                max: 40,
+                mean: 25,
+                // sqrt(750) and sqrt(125)
+                rms: 27,
+                std_dev: 11,
+                ripple: 30,
+            }
+        );
+    }
+
+    #[test]
+    fn scales_to_millivolts() {
+        let scale = Scale { num: 3300, den: 4096 };
+        let stats = accumulate(&[2048, 2048, 4096, 4096]).finish(scale);
+        assert_eq!(stats.min, 1650);
+        assert_eq!(stats.max, 3300);
+        assert_eq!(stats.mean, 2475);
+        assert_eq!(stats.std_dev, 825);
+        assert_eq!(stats.ripple, 1650);
+    }
+
+    #[test]
+    fn mean_keeps_the_fraction_of_raw_counts() {
+        // Scaling each sample first would give (333 + 666) / 2 = 499
+        let stats = accumulate(&[1, 2]).finish(Scale { num: 1000, den: 3 });
+        assert_eq!(stats.mean, 500);
+        assert_eq!(stats.min, 333);
+        assert_eq!(stats.max, 666);
+    }
+}
@@ -0,0 +1,223 @@
+pub const CURVE_POINTS: usize = 4;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum TemperatureSource {
+    /// STM32 internal sensor (die temperature)
+    Internal,
+    /// Analog probe on PA1
+    External,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct CurvePoint {
+    /// 0.1 °C
+    pub temperature: i32,
+    /// Fan speed, percent
+    pub speed: u16,
+}
+
+/// Thermal policy settings. Temperatures in 0.1 °C.
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct ThermalConfig {
+    pub enabled: bool,
+    pub source: TemperatureSource,
+    /// Fan curve, sorted by temperature; the first point is the turn-on temperature
+    pub curve: [CurvePoint; CURVE_POINTS],
+    pub points: usize,
+    /// The fan turns off below `curve[0].temperature - hysteresis`
+    pub hysteresis: i32,
+    /// Once started the fan runs at least this long
+    pub min_run_s: u32,
+    /// At or above this temperature the fan runs at 100 % regardless of the curve
+    pub critical: i32,
+}
+
+impl ThermalConfig {
+    pub const DEFAULT: Self = Self {
+        enabled: false,
+        source: TemperatureSource::Internal,
+        curve: [
+            CurvePoint { temperature: 400, speed: 30 },
+            CurvePoint { temperature: 550, speed: 100 },
+            CurvePoint { temperature: 550, speed: 100 },
+            CurvePoint { temperature: 550, speed: 100 },
+        ],
+        points: 2,
+        hysteresis: 30,
+        min_run_s: 30,
+        critical: 700,
+    };
+
+    /// Replace the curve; points must be in ascending temperature order.
+    pub fn set_curve(&mut self, curve: &[CurvePoint]) -> bool {
+        if curve.is_empty() || curve.len() > CURVE_POINTS {
+            return false;
+        }
+        if curve.windows(2).any(|pair| pair[0].temperature > pair[1].temperature) {
+            return false;
+        }
+        if curve.iter().any(|point| point.speed > 100) {
+            return false;
+        }
+        self.curve[..curve.len()].copy_from_slice(curve);
+        self.points = curve.len();
+        true
+    }
+
+    pub fn curve(&self) -> &[CurvePoint] {
+        &self.curve[..self.points]
+    }
+
+    /// Piecewise-linear speed for `temperature`, clamped to the end points.
+    pub fn curve_speed(&self, temperature: i32) -> u16 {
+        let curve = self.curve();
+        let first = curve[0];
+        let last = curve[curve.len() - 1];
+        if temperature <= first.temperature {
+            return first.speed;
+        }
+        if temperature >= last.temperature {
+            return last.speed;
+        }
+        for pair in curve.windows(2) {
+            let (lo, hi) = (pair[0], pair[1]);
+            if temperature <= hi.temperature {
+                let span = hi.temperature - lo.temperature;
+                if span == 0 {
+                    return hi.speed;
+                }
+                let delta = i32::from(hi.speed) - i32::from(lo.speed);
+                let speed = i32::from(lo.speed) + delta * (temperature - lo.temperature) / span;
+                return speed as u16;
+            }
+        }
+        last.speed
+    }
+}
+
+/// Fan state machine: hysteresis on start/stop and a minimum run time.
+#[derive(Debug, Clone, Copy)]
+pub struct ThermalPolicy {
+    started_at_ms: Option<u64>,
+}
+
+impl ThermalPolicy {
+    pub const fn new() -> Self {
+        Self { started_at_ms: None }
+    }
+
+    /// Fan speed for `temperature` at time `now_ms`; `None` means fan off.
+    pub fn evaluate(
+        &mut self,
+        config: &ThermalConfig,
+        temperature: i32,
+        now_ms: u64,
+    ) -> Option<u16> {
+        if temperature >= config.critical {
+            self.started_at_ms.get_or_insert(now_ms);
+            return Some(100);
+        }
+
+        let turn_on = config.curve()[0].temperature;
+        match self.started_at_ms {
+            None if temperature >= turn_on => {
+                self.started_at_ms = Some(now_ms);
+            }
+            Some(started) if temperature < turn_on - config.hysteresis => {
+                let ran_ms = now_ms.saturating_sub(started);
+                if ran_ms >= u64::from(config.min_run_s) * 1000 {
+                    self.started_at_ms = None;
+                }
+            }
+            _ => {}
+        }
+
+        self.started_at_ms.map(|_| config.curve_speed(temperature))
+    }
+}
+
+impl Default for ThermalPolicy {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config() -> ThermalConfig {
+        ThermalConfig {
+            enabled: true,
+            ..ThermalConfig::DEFAULT
+        }
+    }
+
+    #[test]
+    fn interpolates_between_points() {
+        let mut config = config();
+        assert!(config.set_curve(&[
+            CurvePoint { temperature: 400, speed: 20 },
+            CurvePoint { temperature: 500, speed: 60 },
+            CurvePoint { temperature: 600, speed: 100 },
+        ]));
+        assert_eq!(config.curve_speed(300), 20);
+        assert_eq!(config.curve_speed(400), 20);
+        assert_eq!(config.curve_speed(450), 40);
+        assert_eq!(config.curve_speed(500), 60);
+        assert_eq!(config.curve_speed(575), 90);
+        assert_eq!(config.curve_speed(800), 100);
+    }
+
+    #[test]
+    fn rejects_bad_curves() {
+        let mut config = config();
+        assert!(!config.set_curve(&[]));
+        assert!(!config.set_curve(&[
+            CurvePoint { temperature: 500, speed: 50 },
+            CurvePoint { temperature: 400, speed: 60 },
+        ]));
+        assert!(!config.set_curve(&[CurvePoint { temperature: 400, speed: 101 }]));
+        assert_eq!(config.curve(), ThermalConfig::DEFAULT.curve());
+    }
+
+    #[test]
+    fn hysteresis_between_turn_on_and_turn_off() {
+        let config = ThermalConfig { min_run_s: 0, ..config() };
+        let mut policy = ThermalPolicy::new();
+        assert_eq!(policy.evaluate(&config, 399, 0), None);
+        assert_eq!(policy.evaluate(&config, 400, 1000), Some(30));
+        // Inside the band the fan keeps running at the first point's speed
+        assert_eq!(policy.evaluate(&config, 370, 2000), Some(30));
+        assert_eq!(policy.evaluate(&config, 369, 3000), None);
+        // And it stays off until the turn-on temperature again
+        assert_eq!(policy.evaluate(&config, 399, 4000), None);
+        assert_eq!(policy.evaluate(&config, 400, 5000), Some(30));
+    }
+
+    #[test]
+    fn runs_at_least_min_run_time() {
+        let config = config();
+        let mut policy = ThermalPolicy::new();
+        assert_eq!(policy.evaluate(&config, 450, 10_000), Some(53));
+        assert_eq!(policy.evaluate(&config, 200, 39_999), Some(30));
+        assert_eq!(policy.evaluate(&config, 200, 40_000), None);
+    }
+
+    #[test]
+    fn critical_temperature_overrides_curve() {
+        let mut config = config();
+        assert!(config.set_curve(&[
+            CurvePoint { temperature: 400, speed: 20 },
+            CurvePoint { temperature: 800, speed: 60 },
+        ]));
+        let mut policy = ThermalPolicy::new();
+        assert_eq!(policy.evaluate(&config, 700, 0), Some(100));
+        // Starting through the override also counts toward the minimum run time
+        assert_eq!(policy.evaluate(&config, 200, 29_999), Some(20));
+        assert_eq!(policy.evaluate(&config, 200, 30_000), None);
+    }
+}
@@ -0,0 +1,305 @@
+use crate::power::PowerState;
+
+/// Records kept in RAM. A day of per-minute records (1440 x 20 bytes, 28 KiB)
+/// does not fit in the 20 KiB of RAM next to the heap, so the ring holds 144
+/// (2.9 KiB) and the default interval is 10 minutes: the log covers 24 h.
+/// `TRENd:INTerval 60` trades that for per-minute detail over 2.4 h.
+pub const TREND_CAPACITY: usize = 144;
+pub const DEFAULT_INTERVAL_S: u32 = 600;
+
+/// Time covered by a full log at the default interval, in seconds.
+pub const DEFAULT_SPAN_S: u32 = TREND_CAPACITY as u32 * DEFAULT_INTERVAL_S;
+
+/// One reading of every trended measurement, taken by the trend task.
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct TrendSample {
+    pub rail_mv: u32,
+    /// 0.1 °C
+    pub temperature: i32,
+    /// Measured fan speed
+    pub fan_rpm: u16,
+    pub source: PowerState,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct MinMaxAvg<T> {
+    pub min: T,
+    pub max: T,
+    pub avg: T,
+}
+
+/// Aggregate of one interval, kept compact (20 bytes).
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct TrendRecord {
+    pub rail_mv: MinMaxAvg<u16>,
+    pub temperature: MinMaxAvg<i16>,
+    pub fan_rpm: MinMaxAvg<u16>,
+    /// Source active at the end of the interval
+    pub source: PowerState,
+    /// Number of source changes within the interval
+    pub switches: u8,
+}
+
+impl TrendRecord {
+    const EMPTY: Self = Self {
+        rail_mv: MinMaxAvg { min: 0, max: 0, avg: 0 },
+        temperature: MinMaxAvg { min: 0, max: 0, avg: 0 },
+        fan_rpm: MinMaxAvg { min: 0, max: 0, avg: 0 },
+        source: PowerState::OFF,
+        switches: 0,
+    };
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Series {
+    count: u32,
+    sum: i64,
+    min: i32,
+    max: i32,
+}
+
+impl Series {
+    const fn new() -> Self {
+        Self {
+            count: 0,
+            sum: 0,
+            min: i32::MAX,
+            max: i32::MIN,
+        }
+    }
+
+    fn push(&mut self, value: i32) {
+        self.count += 1;
+        self.sum += i64::from(value);
+        self.min = self.min.min(value);
+        self.max = self.max.max(value);
+    }
+
+    fn finish<T: TryFrom<i32> + Copy>(&self, lo: T, hi: T) -> MinMaxAvg<T> {
+        let narrow = |value: i32| T::try_from(value).unwrap_or(if value < 0 { lo } else { hi });
+        let avg = (self.sum / i64::from(self.count.max(1))) as i32;
+        MinMaxAvg {
+            min: narrow(self.min),
+            max: narrow(self.max),
+            avg: narrow(avg),
+        }
+    }
+}
+
+/// Folds the samples of one interval into a `TrendRecord`.
+#[derive(Debug, Clone, Copy)]
+pub struct TrendAggregator {
+    rail_mv: Series,
+    temperature: Series,
+    fan_rpm: Series,
+    source: Option<PowerState>,
+    switches: u8,
+}
+
+impl TrendAggregator {
+    pub const fn new() -> Self {
+        Self {
+            rail_mv: Series::new(),
+            temperature: Series::new(),
+            fan_rpm: Series::new(),
+            source: None,
+            switches: 0,
+        }
+    }
+
+    pub fn push(&mut self, sample: TrendSample) {
+        self.rail_mv.push(sample.rail_mv.min(i32::MAX as u32) as i32);
+        self.temperature.push(sample.temperature);
+        self.fan_rpm.push(i32::from(sample.fan_rpm));
+        if self.source.is_some_and(|source| source != sample.source) {
+            self.switches = self.switches.saturating_add(1);
+        }
+        self.source = Some(sample.source);
+    }
+
+    /// Close the interval; `None` if no sample was pushed. The aggregator is reset.
+    pub fn finish(&mut self) -> Option<TrendRecord> {
+        let source = self.source?;
+        let record = TrendRecord {
+            rail_mv: self.rail_mv.finish(u16::MIN, u16::MAX),
+            temperature: self.temperature.finish(i16::MIN, i16::MAX),
+            fan_rpm: self.fan_rpm.finish(u16::MIN, u16::MAX),
+            source,
+            switches: self.switches,
+        };
+        *self = Self::new();
+        Some(record)
+    }
+}
+
+impl Default for TrendAggregator {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Fixed-size ring of interval records, oldest overwritten first.
+pub struct TrendLog {
+    records: [TrendRecord; TREND_CAPACITY],
+    head: usize,
+    len: usize,
+    interval_s: u32,
+}
+
+impl TrendLog {
+    pub const fn new() -> Self {
+        Self {
+            records: [TrendRecord::EMPTY; TREND_CAPACITY],
+            head: 0,
+            len: 0,
+            interval_s: DEFAULT_INTERVAL_S,
+        }
+    }
+
+    pub fn interval_s(&self) -> u32 {
+        self.interval_s
+    }
+
+    /// Change the aggregation interval. Records of different intervals are not
+    /// mixed, so the log is cleared.
+    pub fn set_interval_s(&mut self, interval_s: u32) {
+        self.interval_s = interval_s.max(1);
+        self.clear();
+    }
+
+    pub fn clear(&mut self) {
+        self.head = 0;
+        self.len = 0;
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn push(&mut self, record: TrendRecord) {
+        self.records[self.head] = record;
+        self.head = (self.head + 1) % TREND_CAPACITY;
+        self.len = (self.len + 1).min(TREND_CAPACITY);
+    }
+
+    /// Record `index`, counted from the oldest one.
+    pub fn get(&self, index: usize) -> Option<&TrendRecord> {
+        if index >= self.len {
+            return None;
+        }
+        let start = (self.head + TREND_CAPACITY - self.len) % TREND_CAPACITY;
+        Some(&self.records[(start + index) % TREND_CAPACITY])
+    }
+}
+
+impl Default for TrendLog {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sample(rail_mv: u32, temperature: i32, fan_rpm: u16, source: PowerState) -> TrendSample {
+        TrendSample {
+            rail_mv,
+            temperature,
+            fan_rpm,
+            source,
+        }
+    }
+
+    fn record(rail_avg: u16) -> TrendRecord {
+        TrendRecord {
+            rail_mv: MinMaxAvg { min: 0, max: 0, avg: rail_avg },
+            ..TrendRecord::EMPTY
+        }
+    }
+
+    #[test]
+    fn aggregates_min_max_avg() {
+        let mut aggregator = TrendAggregator::new();
+        aggregator.push(sample(3300, -50, 1200, PowerState::DCDC));
+        aggregator.push(sample(3200, 250, 1800, PowerState::DCDC));
+        aggregator.push(sample(3400, 100, 1500, PowerState::DCDC));
+
+        let record = aggregator.finish().unwrap();
+        assert_eq!(record.rail_mv, MinMaxAvg { min: 3200, max: 3400, avg: 3300 });
+        assert_eq!(record.temperature, MinMaxAvg { min: -50, max: 250, avg: 100 });
+        assert_eq!(record.fan_rpm, MinMaxAvg { min: 1200, max: 1800, avg: 1500 });
+        assert_eq!(record.source, PowerState::DCDC);
+        assert_eq!(record.switches, 0);
+    }
+
+    #[test]
+    fn counts_source_switches_and_keeps_the_last_source() {
+        let mut aggregator = TrendAggregator::new();
+        for source in [PowerState::OFF, PowerState::DCDC, PowerState::DCDC, PowerState::ACDC] {
+            aggregator.push(sample(0, 0, 0, source));
+        }
+        let record = aggregator.finish().unwrap();
+        assert_eq!(record.switches, 2);
+        assert_eq!(record.source, PowerState::ACDC);
+    }
+
+    #[test]
+    fn finish_resets_and_skips_empty_intervals() {
+        let mut aggregator = TrendAggregator::new();
+        assert_eq!(aggregator.finish(), None);
+
+        aggregator.push(sample(1000, 0, 0, PowerState::OFF));
+        aggregator.finish().unwrap();
+        assert_eq!(aggregator.finish(), None);
+
+        aggregator.push(sample(2000, 0, 0, PowerState::OFF));
+        assert_eq!(aggregator.finish().unwrap().rail_mv.min, 2000);
+    }
+
+    #[test]
+    fn saturates_values_outside_the_record_range() {
+        let mut aggregator = TrendAggregator::new();
+        aggregator.push(sample(70_000, 40_000, 0, PowerState::OFF));
+        aggregator.push(sample(70_000, -40_000, 0, PowerState::OFF));
+        let record = aggregator.finish().unwrap();
+        assert_eq!(record.rail_mv.max, u16::MAX);
+        assert_eq!(record.temperature, MinMaxAvg { min: i16::MIN, max: i16::MAX, avg: 0 });
+    }
+
+    #[test]
+    fn log_keeps_the_newest_records_oldest_first() {
+        let mut log = TrendLog::new();
+        assert!(log.is_empty());
+        for avg in 0..TREND_CAPACITY as u16 + 10 {
+            log.push(record(avg));
+        }
+        assert_eq!(log.len(), TREND_CAPACITY);
+        assert_eq!(log.get(0).unwrap().rail_mv.avg, 10);
+        assert_eq!(log.get(TREND_CAPACITY - 1).unwrap().rail_mv.avg, TREND_CAPACITY as u16 + 9);
+        assert_eq!(log.get(TREND_CAPACITY), None);
+    }
+
+    #[test]
+    fn default_log_spans_a_day() {
+        assert_eq!(DEFAULT_SPAN_S, 24 * 3600);
+    }
+
+    #[test]
+    fn changing_the_interval_clears_the_log() {
+        let mut log = TrendLog::new();
+        assert_eq!(log.interval_s(), DEFAULT_INTERVAL_S);
+        log.push(record(1));
+        log.set_interval_s(0);
+        assert_eq!(log.interval_s(), 1);
+        assert!(log.is_empty());
+        assert_eq!(log.get(0), None);
+    }
+}
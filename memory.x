@@ -0,0 +1,8 @@
+/* STM32F103C8: 64K flash, 20K RAM.
+   The last 1K flash page holds the persisted settings (src/settings.rs),
+   so it is left out of the FLASH region the linker may fill. */
+MEMORY
+{
+  FLASH : ORIGIN = 0x08000000, LENGTH = 63K
+  RAM   : ORIGIN = 0x20000000, LENGTH = 20K
+}
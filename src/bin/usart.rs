@@ -4,14 +4,15 @@
 use defmt::*;
 use embassy_executor::Spawner;
 use embassy_stm32::gpio::{Level, Output, Speed};
-use embassy_stm32::usart::{BufferedUart, Config};
+use embassy_stm32::usart::{BufferedUart, BufferedUartRx, BufferedUartTx, Config};
 use embassy_stm32::{bind_interrupts, peripherals, usart};
 use embassy_time::{Duration, Timer};
-use embedded_io_async::{Read, Write};
-use heapless::String;
+use embedded_io_async::Write;
 
 use embassy_sync::blocking_mutex::raw::ThreadModeRawMutex;
-use embassy_sync::channel::{Channel, Receiver, Sender};
+use embassy_sync::channel::{Channel, Sender};
+
+use logic::session::{LineHandler, Session};
 
 use {defmt_rtt as _, panic_probe as _};
 
@@ -23,9 +24,6 @@ bind_interrupts!(struct Irqs {
 static mut TX_BUF: [u8; 256] = [0; 256];
 static mut RX_BUF: [u8; 256] = [0; 256];
 
-// Channel to send messages from RX to TX
-static TX_MESSAGE_CHANNEL: Channel<ThreadModeRawMutex, heapless::String<64>, 4> = Channel::new();
-
 // Channel for LED control
 static LED_CHANNEL: Channel<ThreadModeRawMutex, LedState, 4> = Channel::new();
 
@@ -56,96 +54,55 @@ async fn toggle_led(mut led: Output<'static>) {
     }
 }
 
-#[embassy_executor::task]
-async fn rx_task(
-    mut rx: embassy_stm32::usart::BufferedUartRx<'static>,
-    tx_sender: Sender<'static, ThreadModeRawMutex, heapless::String<64>, 4>,
+/// Plain-text LED commands: `led on`, `led off`, `led toggle` (or `toggle`)
+struct LedCommands {
     led_sender: Sender<'static, ThreadModeRawMutex, LedState, 4>,
-) {
-    let mut buf = [0u8; 64];
-    let mut pos = 0;
+    reply: &'static [u8],
+}
 
-    info!("RX task started");
+impl LineHandler for LedCommands {
+    fn line(&mut self, line: &[u8]) {
+        info!("Received");
+        self.reply = b"";
+        let state = match line {
+            b"led on" => LedState::On,
+            b"led off" => LedState::Off,
+            b"led toggle" | b"toggle" => LedState::Toggle,
+            _ => return,
+        };
+        let _ = self.led_sender.try_send(state);
+    }
 
-    loop {
-        let mut byte = [0u8; 1];
-        if let Ok(()) = rx.read_exact(&mut byte).await {
-            let b = byte[0];
-
-            if pos >= buf.len() {
-                info!("RX buffer overflow, resetting");
-                pos = 0;
-                continue;
-            }
+    fn overrun(&mut self) {
+        info!("RX buffer overflow, line dropped");
+        self.reply = b"Error: Line too long!\r\n";
+    }
 
-            buf[pos] = b;
-            pos += 1;
-
-            if b == b'\n' || b == b'\r' {
-                if pos > 1 {
-                    let message_len = if buf[pos - 1] == b'\n' || buf[pos - 1] == b'\r' {
-                        pos - 1
-                    } else {
-                        pos
-                    };
-
-                    if message_len > 0 {
-                        let mut received_string = String::<64>::new();
-
-                        for &byte in &buf[..message_len] {
-                            if received_string.push(byte as char).is_err() {
-                                warn!("String conversion overflow");
-                                break;
-                            }
-                        }
-
-                        info!("Received");
-
-                        let mut echo_string = String::<64>::new();
-                        echo_string.push_str(&received_string).unwrap();
-                        echo_string.push_str("\r\n").unwrap();
-
-                        tx_sender.send(echo_string).await;
-
-                        let command = received_string;
-
-                        if command == "led on" {
-                            led_sender.send(LedState::On).await;
-                        } else if command == "led off" {
-                            led_sender.send(LedState::Off).await;
-                        } else if command == "led toggle" || command == "toggle" {
-                            led_sender.send(LedState::Toggle).await;
-                        }
-                    }
-                }
-                pos = 0; // reset buffer
-            }
-        } else {
-            // Handle read error
-            Timer::after(Duration::from_millis(100)).await;
-        }
+    fn response(&self) -> &[u8] {
+        self.reply
     }
 }
 
 #[embassy_executor::task]
-async fn tx_task(
-    mut tx: embassy_stm32::usart::BufferedUartTx<'static>,
-    rx: Receiver<'static, ThreadModeRawMutex, heapless::String<64>, 4>,
+async fn serial_session(
+    mut rx: BufferedUartRx<'static>,
+    mut tx: BufferedUartTx<'static>,
+    led_sender: Sender<'static, ThreadModeRawMutex, LedState, 4>,
 ) {
-    info!("TX task started");
-    loop {
-        let msg = rx.receive().await;
+    let mut session: Session<_, 64> = Session::new(LedCommands {
+        led_sender,
+        reply: b"",
+    });
+    // Typed on a terminal: show what was received
+    session.set_echo(true);
 
-        // Convert heapless string to bytes and send
-        if let Err(e) = tx.write_all(msg.as_bytes()).await {
-            error!("TX write error: {:?}", e);
-        }
+    info!("Session started");
 
-        if let Err(e) = tx.flush().await {
-            error!("TX flush error: {:?}", e);
+    loop {
+        if let Err(e) = session.run(&mut rx, &mut tx).await {
+            warn!("Transport error: {:?}", e);
+            Timer::after(Duration::from_millis(100)).await;
         }
-        info!("Sended");
-        Timer::after(Duration::from_millis(100)).await;
     }
 }
 
@@ -170,18 +127,14 @@ async fn main(_spawner: Spawner) {
         .unwrap()
     };
 
-    let (mut tx, mut rx) = usart.split();
+    let (mut tx, rx) = usart.split();
 
     let led = Output::new(p.PC13, Level::High, Speed::Low);
 
-    // Get senders/receivers
-    let tx_sender = TX_MESSAGE_CHANNEL.sender();
-    let tx_receiver = TX_MESSAGE_CHANNEL.receiver();
     let led_sender = LED_CHANNEL.sender();
 
-    _spawner.spawn(rx_task(rx, tx_sender, led_sender).unwrap());
     let _ = tx.write_all(b"led toggle\r\n").await;
-    _spawner.spawn(tx_task(tx, tx_receiver).unwrap());
+    _spawner.spawn(serial_session(rx, tx, led_sender).unwrap());
     _spawner.spawn(toggle_led(led).unwrap());
 
     // Keep main alive
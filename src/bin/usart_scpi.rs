@@ -4,18 +4,20 @@
 use defmt::*;
 use embassy_executor::Spawner;
 use embassy_stm32::gpio::{Level, Output, Speed};
-use embassy_stm32::usart::{BufferedUart, Config};
+use embassy_stm32::usart::{BufferedUart, BufferedUartRx, BufferedUartTx, Config};
 use embassy_stm32::{bind_interrupts, peripherals, usart, Peri};
 use embassy_sync::blocking_mutex::raw::ThreadModeRawMutex;
-use embassy_sync::channel::{Channel, Receiver, Sender};
+use embassy_sync::channel::Channel;
 use embassy_time::{Duration, Timer};
-use embedded_io_async::{Read, Write};
-
-use alloc::vec::Vec;
-use heapless::String;
 
 use scpi::{cmd_nquery, cmd_qonly, error::Error, tree::prelude::*, Branch, Leaf, Root};
 
+#[path = "../scpi_session.rs"]
+mod scpi_session;
+
+use logic::session::{self, Session};
+use scpi_session::{ScpiHandler, SessionDevice};
+
 use embedded_alloc::TlsfHeap as Heap;
 #[global_allocator]
 static HEAP: Heap = Heap::empty();
@@ -37,8 +39,6 @@ bind_interrupts!(struct Irqs {
 static mut TX_BUF: [u8; 256] = [0; 256];
 static mut RX_BUF: [u8; 256] = [0; 256];
 
-// Channel to send messages from RX to TX
-static TX_MESSAGE_CHANNEL: Channel<ThreadModeRawMutex, heapless::String<64>, 4> = Channel::new();
 static LED_CHANNEL: Channel<ThreadModeRawMutex, LedState, 4> = Channel::new();
 
 // Example of SIMPLE DEVICE
@@ -49,6 +49,9 @@ impl Device for MyDevice {
     }
 }
 
+// No per-session state
+impl SessionDevice for MyDevice {}
+
 struct IdnCommand;
 impl Command<MyDevice> for IdnCommand {
     // Allow only queries
@@ -157,115 +160,19 @@ async fn blinky(led: Peri<'static, peripherals::PC13>) {
 }
 
 #[embassy_executor::task]
-async fn rx_task(
-    mut rx: embassy_stm32::usart::BufferedUartRx<'static>,
-    tx_sender: Sender<'static, ThreadModeRawMutex, heapless::String<64>, 4>,
-) {
-    let mut buf = [0u8; 64];
-    let mut pos = 0;
-
-    info!("RX task started");
+async fn serial_session(mut rx: BufferedUartRx<'static>, mut tx: BufferedUartTx<'static>) {
+    let mut session: Session<_, 64> = Session::new(ScpiHandler::new(&MYTREE, MyDevice));
 
-    let mut device: MyDevice = MyDevice;
+    info!("Session started");
 
     loop {
-        let mut byte = [0u8; 1];
-        if let Ok(()) = rx.read_exact(&mut byte).await {
-            let b = byte[0];
-            info!("Get message");
-            if pos >= buf.len() {
-                info!("RX buffer overflow, resetting");
-                pos = 0;
-                continue;
-            }
-
-            buf[pos] = b;
-            pos += 1;
-
-            if b == b'\n' || b == b'\r' {
-                if pos > 1 {
-                    let message_len = if buf[pos - 1] == b'\n' || buf[pos - 1] == b'\r' {
-                        pos - 1
-                    } else {
-                        pos
-                    };
-
-                    if message_len > 0 {
-                        let mut received_string = String::<64>::new();
-
-                        for &byte in &buf[..message_len] {
-                            if received_string.push(byte as char).is_err() {
-                                warn!("String conversion overflow");
-                                break;
-                            }
-                        }
-
-                        // Run SCPI command tree
-                        info!("Received");
-
-                        let command = received_string;
-
-                        let mut context = Context::default();
-                        let mut response: Vec<u8> = Vec::new();
-
-                        let res = MYTREE.run(
-                            &command.as_bytes(),
-                            &mut device,
-                            &mut context,
-                            &mut response,
-                        );
-
-                        match res {
-                            Ok(()) => {
-                                // Forward response to TX task (ASCII-only for this demo)
-                                let mut out = String::<64>::new();
-                                for &b in response.as_slice() {
-                                    if out.push(b as char).is_err() {
-                                        break;
-                                    }
-                                }
-                                let _ = tx_sender.try_send(out);
-                            }
-                            Err(e) => {
-                                warn!("SCPI run error");
-                                let mut out = String::<64>::new();
-                                let _ = out.push_str("ERR\r\n");
-                                let _ = tx_sender.try_send(out);
-                            }
-                        }
-                    }
-                }
-                pos = 0; // reset buffer
-            }
-        } else {
-            // Handle read error
+        if let Err(e) = session.run(&mut rx, &mut tx).await {
+            warn!("Transport error: {:?}", e);
             Timer::after(Duration::from_millis(100)).await;
         }
     }
 }
 
-#[embassy_executor::task]
-async fn tx_task(
-    mut tx: embassy_stm32::usart::BufferedUartTx<'static>,
-    rx: Receiver<'static, ThreadModeRawMutex, heapless::String<64>, 4>,
-) {
-    info!("TX task started");
-    loop {
-        let msg = rx.receive().await;
-
-        // Convert heapless string to bytes and send
-        if let Err(e) = tx.write_all(msg.as_bytes()).await {
-            error!("TX write error: {:?}", e);
-        }
-
-        if let Err(e) = tx.flush().await {
-            error!("TX flush error: {:?}", e);
-        }
-        info!("Sended");
-        Timer::after(Duration::from_millis(100)).await;
-    }
-}
-
 #[embassy_executor::main]
 async fn main(spawner: Spawner) {
     // Initialize allocator before any use of heap (Vec, Box, etc.)
@@ -294,12 +201,7 @@ async fn main(spawner: Spawner) {
 
     let (tx, rx) = usart.split();
 
-    // Get senders/receivers
-    let tx_sender = TX_MESSAGE_CHANNEL.sender();
-    let tx_receiver = TX_MESSAGE_CHANNEL.receiver();
-
-    spawner.spawn(rx_task(rx, tx_sender).unwrap());
-    spawner.spawn(tx_task(tx, tx_receiver).unwrap());
+    spawner.spawn(serial_session(rx, tx).unwrap());
     spawner.spawn(blinky(p.PC13).unwrap());
 
     loop {
@@ -9,15 +9,19 @@ use embassy_stm32::time::Hertz;
 use embassy_stm32::usb::{Driver, Instance};
 use embassy_stm32::{bind_interrupts, peripherals, usb, Config};
 use embassy_time::Timer;
-use embassy_usb::class::cdc_acm::{CdcAcmClass, State};
+use embassy_usb::class::cdc_acm::{CdcAcmClass, Receiver, Sender, State};
 use embassy_usb::driver::EndpointError;
 use embassy_usb::Builder;
+use embedded_io_async::{ErrorKind, ErrorType, Read, Write};
 use {defmt_rtt as _, panic_probe as _};
 
+use logic::session::{LineHandler, Session};
+
 bind_interrupts!(struct Irqs {
     USB_LP_CAN1_RX0 => usb::InterruptHandler<peripherals::USB>;
 });
 
+#[derive(Debug)]
 struct Disconnected {}
 
 impl From<EndpointError> for Disconnected {
@@ -29,48 +33,76 @@ impl From<EndpointError> for Disconnected {
     }
 }
 
-const LF: u8 = b'\n';
+impl embedded_io_async::Error for Disconnected {
+    fn kind(&self) -> ErrorKind {
+        ErrorKind::NotConnected
+    }
+}
 
-async fn echo<'d, T: Instance + 'd>(
-    class: &mut CdcAcmClass<'d, Driver<'d, T>>,
-) -> Result<(), Disconnected> {
-    let mut buf = [0; 64];
-    let mut line_buffer = [0; 256]; // Buffer for building the complete line
-    let mut line_index = 0;
+/// CDC receive half as an `embedded_io_async` reader. Reads must offer at
+/// least one packet (64 bytes), which the session does.
+struct UsbRx<'d, T: Instance>(Receiver<'d, Driver<'d, T>>);
 
-    // Send welcome message
-    let welcome_msg = "STM32F103 Blue Pill ready! Type something and press Enter.\r\n";
-    class.write_packet(welcome_msg.as_bytes()).await?;
+impl<'d, T: Instance> ErrorType for UsbRx<'d, T> {
+    type Error = Disconnected;
+}
 
-    loop {
-        let n = class.read_packet(&mut buf).await?;
+impl<'d, T: Instance> Read for UsbRx<'d, T> {
+    async fn read(&mut self, buf: &mut [u8]) -> Result<usize, Disconnected> {
+        Ok(self.0.read_packet(buf).await?)
+    }
+}
 
-        for &byte in &buf[..n] {
-            if line_index >= line_buffer.len() {
-                // Buffer overflow - reset and send error
-                let error_msg = "\r\nError: Line too long!\r\n";
-                class.write_packet(error_msg.as_bytes()).await?;
-                line_index = 0;
-            }
+/// CDC send half as an `embedded_io_async` writer, one packet per write.
+struct UsbTx<'d, T: Instance> {
+    sender: Sender<'d, Driver<'d, T>>,
+    /// The last packet was full, so the host waits for more until a short one
+    packet_full: bool,
+}
 
-            // Store the byte
-            line_buffer[line_index] = byte;
-            line_index += 1;
-
-            // Check if we received a newline
-            if byte == LF {
-                // Send the complete line back
-                class.write_packet(&line_buffer[0..line_index]).await?;
-                if let Ok(text) = core::str::from_utf8(&line_buffer) {
-                    info!("Received text: {}", text);
-                } else {
-                    info!("Received binary data: {:x}", line_buffer);
-                }
-
-                // Reset line buffer
-                line_index = 0;
-            }
+impl<'d, T: Instance> ErrorType for UsbTx<'d, T> {
+    type Error = Disconnected;
+}
+
+impl<'d, T: Instance> Write for UsbTx<'d, T> {
+    async fn write(&mut self, buf: &[u8]) -> Result<usize, Disconnected> {
+        let n = buf.len().min(usize::from(self.sender.max_packet_size()));
+        self.sender.write_packet(&buf[..n]).await?;
+        self.packet_full = n == usize::from(self.sender.max_packet_size());
+        Ok(n)
+    }
+
+    async fn flush(&mut self) -> Result<(), Disconnected> {
+        // Terminate the transfer with a zero-length packet
+        if self.packet_full {
+            self.sender.write_packet(&[]).await?;
+            self.packet_full = false;
         }
+        Ok(())
+    }
+}
+
+/// Echoes what is typed and logs every complete line.
+struct LineLogger {
+    reply: &'static [u8],
+}
+
+impl LineHandler for LineLogger {
+    fn line(&mut self, line: &[u8]) {
+        if let Ok(text) = core::str::from_utf8(line) {
+            info!("Received text: {}", text);
+        } else {
+            info!("Received binary data: {:x}", line);
+        }
+        self.reply = b"";
+    }
+
+    fn overrun(&mut self) {
+        self.reply = b"\r\nError: Line too long!\r\n";
+    }
+
+    fn response(&self) -> &[u8] {
+        self.reply
     }
 }
 
@@ -132,7 +164,7 @@ async fn main(_spawner: Spawner) {
     );
 
     // Create classes on the builder.
-    let mut class = CdcAcmClass::new(&mut builder, &mut state, 64);
+    let class = CdcAcmClass::new(&mut builder, &mut state, 64);
 
     // Build the builder.
     let mut usb = builder.build();
@@ -141,11 +173,23 @@ async fn main(_spawner: Spawner) {
     let usb_fut = usb.run();
 
     // Do stuff with the class!
+    let (sender, receiver) = class.split();
+    let mut rx = UsbRx(receiver);
+    let mut tx = UsbTx {
+        sender,
+        packet_full: false,
+    };
+    let mut session: Session<_, 256> = Session::new(LineLogger { reply: b"" });
+    session.set_echo(true);
     let echo_fut = async {
         loop {
-            class.wait_connection().await;
+            rx.0.wait_connection().await;
             info!("Connected");
-            let _ = echo(&mut class).await;
+            session.reset();
+            let welcome_msg = "STM32F103 Blue Pill ready! Type something and press Enter.\r\n";
+            if tx.write_all(welcome_msg.as_bytes()).await.is_ok() && tx.flush().await.is_ok() {
+                let _ = session.run(&mut rx, &mut tx).await;
+            }
             info!("Disconnected");
         }
     };
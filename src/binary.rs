@@ -0,0 +1,214 @@
+use binproto::{
+    decode_frame, encode_frame, DecodeError, FrameReader, Message, NackReason, PowerSource,
+    Telemetry, MAX_FRAME,
+};
+use defmt::*;
+use embassy_futures::select::{select, Either};
+use embassy_time::{Duration, Instant, Timer};
+use embedded_io_async::{Read, Write};
+
+use crate::completion::CommandTracker;
+use crate::modbus::fault_flags;
+use crate::session::SessionError;
+use crate::shared::{
+    CommandChannel, CoolingState, PowerState, COOLING_CHANNEL, COOLING_ON, FAN_RPM, FAN_SPEED,
+    LED_ON, POWER_CHANNEL, POWER_SOURCE, PROBE_TEMPERATURE, RAIL_STATS, SPEED_CHANNEL,
+    TEMPERATURE,
+};
+
+/// Telemetry period of a new session
+pub const DEFAULT_TELEMETRY_MS: u16 = 1000;
+
+fn check_room<T>(channel: &CommandChannel<T>) -> Result<(), NackReason> {
+    if channel.is_full() {
+        warn!("Binary: command queue full");
+        return Err(NackReason::Busy);
+    }
+    Ok(())
+}
+
+/// Queue `command` for its task, like an SCPI command.
+fn queue<T>(
+    commands: &'static CommandTracker,
+    channel: &CommandChannel<T>,
+    command: T,
+) -> Result<(), NackReason> {
+    channel.try_send((command, commands.track())).map_err(|_| {
+        warn!("Binary: command queue full");
+        NackReason::Busy
+    })
+}
+
+fn telemetry() -> Telemetry {
+    Telemetry {
+        uptime_ms: Instant::now().as_millis() as u32,
+        rail_mv: RAIL_STATS.lock(|rail| rail.get()).mean.min(0xFFFF) as u16,
+        temperature: TEMPERATURE.lock(|temperature| temperature.get()) as i16,
+        probe_temperature: PROBE_TEMPERATURE.lock(|temperature| temperature.get()) as i16,
+        fan_rpm: FAN_RPM.lock(|fan| fan.get()),
+        fan_speed: FAN_SPEED.lock(|fan| fan.get()) as u8,
+        power: match POWER_SOURCE.lock(|source| source.get()) {
+            PowerState::OFF => PowerSource::Off,
+            PowerState::DCDC => PowerSource::DcDc,
+            PowerState::ACDC => PowerSource::AcDc,
+        },
+        led: LED_ON.lock(|led| led.get()),
+        cooling: COOLING_ON.lock(|cooling| cooling.get()),
+        faults: fault_flags(),
+    }
+}
+
+/// Binary protocol session (see the `binproto` crate): COBS frames with a
+/// sequence number, type, payload and CRC-16.
+///
+/// Requests go to the device tasks through the same command channels as
+/// SCPI and are answered with `Ack` once applied, or `Nack`. Between
+/// requests the session sends `Telemetry` at the configured period.
+pub struct BinarySession {
+    frames: FrameReader,
+    /// Telemetry period, `None` while stopped
+    interval: Option<Duration>,
+    next_telemetry: Instant,
+    telemetry_seq: u8,
+    frame: [u8; MAX_FRAME],
+    /// Commands queued by this session, awaited before the Ack
+    commands: &'static CommandTracker,
+}
+
+impl BinarySession {
+    pub fn new(interval_ms: u16, commands: &'static CommandTracker) -> Self {
+        let mut session = Self {
+            frames: FrameReader::new(),
+            interval: None,
+            next_telemetry: Instant::now(),
+            telemetry_seq: 0,
+            frame: [0; MAX_FRAME],
+            commands,
+        };
+        session.set_interval(interval_ms);
+        session
+    }
+
+    fn set_interval(&mut self, interval_ms: u16) {
+        self.interval = (interval_ms > 0).then(|| Duration::from_millis(u64::from(interval_ms)));
+        self.next_telemetry = Instant::now();
+    }
+
+    /// Serve requests and send telemetry until the transport fails.
+    pub async fn run<R: Read, W: Write>(
+        &mut self,
+        rx: &mut R,
+        tx: &mut W,
+    ) -> Result<(), SessionError<R::Error, W::Error>> {
+        let mut chunk = [0u8; 64];
+        loop {
+            let due = self.interval.map(|_| self.next_telemetry);
+            let telemetry_due = async move {
+                match due {
+                    Some(at) => Timer::at(at).await,
+                    None => core::future::pending().await,
+                }
+            };
+
+            match select(rx.read(&mut chunk), telemetry_due).await {
+                Either::First(Ok(n)) => {
+                    for &byte in &chunk[..n] {
+                        let request = match self.frames.push(byte) {
+                            Some(frame) => decode_frame(frame),
+                            None => continue,
+                        };
+                        if let Some((seq, reply)) = self.request(request).await {
+                            self.send(tx, seq, &reply).await.map_err(SessionError::Write)?;
+                        }
+                    }
+                }
+                Either::First(Err(e)) => {
+                    self.frames = FrameReader::new();
+                    return Err(SessionError::Read(e));
+                }
+                Either::Second(()) => {
+                    let Some(interval) = self.interval else { continue };
+                    // Skip periods missed while busy instead of sending a burst
+                    self.next_telemetry = (self.next_telemetry + interval).max(Instant::now());
+                    let seq = self.telemetry_seq;
+                    self.telemetry_seq = seq.wrapping_add(1);
+                    let message = Message::Telemetry(telemetry());
+                    self.send(tx, seq, &message).await.map_err(SessionError::Write)?;
+                }
+            }
+        }
+    }
+
+    /// Execute a decoded request; returns the reply and its sequence number.
+    async fn request(
+        &mut self,
+        request: Result<(u8, Message), DecodeError>,
+    ) -> Option<(u8, Message)> {
+        let (seq, message) = match request {
+            Ok(request) => request,
+            Err(DecodeError::UnknownType { seq, kind }) => {
+                warn!("Binary: unknown message type {}", kind);
+                return Some((seq, Message::Nack(NackReason::UnknownType)));
+            }
+            Err(DecodeError::BadPayload { seq, kind }) => {
+                warn!("Binary: bad payload for type {}", kind as u8);
+                return Some((seq, Message::Nack(NackReason::BadPayload)));
+            }
+            // Without an intact sequence number there is nobody to answer
+            Err(_) => {
+                debug!("Binary: corrupt frame dropped");
+                return None;
+            }
+        };
+
+        let result = match message {
+            Message::Ping => Ok(()),
+            Message::SetPower(source) => {
+                info!("Binary: set power {}", source as u8);
+                let state = match source {
+                    PowerSource::Off => PowerState::OFF,
+                    PowerSource::DcDc => PowerState::DCDC,
+                    PowerSource::AcDc => PowerState::ACDC,
+                };
+                queue(self.commands, &POWER_CHANNEL, state)
+            }
+            Message::SetSpeed(percent) => {
+                info!("Binary: set speed {}%", percent);
+                check_room(&COOLING_CHANNEL)
+                    .and_then(|()| queue(self.commands, &SPEED_CHANNEL, u16::from(percent)))
+                    .and_then(|()| queue(self.commands, &COOLING_CHANNEL, CoolingState::On))
+            }
+            Message::SetTelemetry { interval_ms } => {
+                info!("Binary: telemetry every {} ms", interval_ms);
+                self.set_interval(interval_ms);
+                Ok(())
+            }
+            // Device-to-host messages are not requests
+            Message::Ack | Message::Nack(_) | Message::Telemetry(_) => {
+                Err(NackReason::UnknownType)
+            }
+        };
+
+        match result {
+            Ok(()) => match self.commands.all_applied().await {
+                Ok(()) => Some((seq, Message::Ack)),
+                Err(_) => {
+                    warn!("Binary: request not applied in time");
+                    Some((seq, Message::Nack(NackReason::Timeout)))
+                }
+            },
+            Err(reason) => Some((seq, Message::Nack(reason))),
+        }
+    }
+
+    async fn send<W: Write>(
+        &mut self,
+        tx: &mut W,
+        seq: u8,
+        message: &Message,
+    ) -> Result<(), W::Error> {
+        let len = encode_frame(seq, message, &mut self.frame);
+        tx.write_all(&self.frame[..len]).await?;
+        tx.flush().await
+    }
+}
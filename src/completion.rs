@@ -0,0 +1,77 @@
+use core::cell::Cell;
+
+use embassy_sync::blocking_mutex::raw::ThreadModeRawMutex;
+use embassy_sync::blocking_mutex::Mutex;
+use embassy_sync::signal::Signal;
+use embassy_time::{with_timeout, Duration, TimeoutError};
+
+/// Longest wait for the device tasks to apply queued commands, so that a
+/// stuck task turns `*OPC?` and protocol acknowledgements into errors
+/// instead of hanging the session.
+pub const APPLY_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Commands queued by one session and not applied yet.
+///
+/// Every session owns its tracker, so it only waits for its own commands
+/// and is the only task waiting on `done`.
+pub struct CommandTracker {
+    pending: Mutex<ThreadModeRawMutex, Cell<u16>>,
+    done: Signal<ThreadModeRawMutex, ()>,
+}
+
+impl CommandTracker {
+    pub const fn new() -> Self {
+        Self {
+            pending: Mutex::new(Cell::new(0)),
+            done: Signal::new(),
+        }
+    }
+
+    /// Handle for a command this session is about to queue.
+    pub fn track(&'static self) -> Completion {
+        self.pending.lock(|pending| pending.set(pending.get() + 1));
+        Completion(Some(self))
+    }
+
+    /// Wait until every command this session queued so far has been
+    /// applied, at most `APPLY_TIMEOUT`.
+    pub async fn all_applied(&self) -> Result<(), TimeoutError> {
+        with_timeout(APPLY_TIMEOUT, async {
+            // The signal may be left over from an earlier idle moment, so re-check
+            while self.pending.lock(|pending| pending.get()) != 0 {
+                self.done.wait().await;
+            }
+        })
+        .await
+    }
+}
+
+/// Handle carried by a command queued to a device task.
+///
+/// The task drops it once the command is applied, which is what `*OPC?` and
+/// `*WAI` wait for. A command that is never delivered (full queue) drops its
+/// handle as well, so nothing waits for it.
+pub struct Completion(Option<&'static CommandTracker>);
+
+impl Completion {
+    /// Handle for a command a device task queues on its own; no session waits for it.
+    pub fn untracked() -> Self {
+        Self(None)
+    }
+}
+
+impl Drop for Completion {
+    fn drop(&mut self) {
+        let Some(tracker) = self.0 else {
+            return;
+        };
+        let left = tracker.pending.lock(|pending| {
+            let left = pending.get() - 1;
+            pending.set(left);
+            left
+        });
+        if left == 0 {
+            tracker.done.signal(());
+        }
+    }
+}
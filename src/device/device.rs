@@ -1,28 +1,2795 @@
-use defmt::info;
-use scpi::{cmd_nquery, cmd_qonly, error::Error, tree::prelude::*, Branch, Leaf, Root};
-
+use defmt::{info, warn, Format};
+use heapless::Deque;
+use scpi::error::{Error, ErrorCode};
+use scpi::parser::format::Arbitrary;
+use scpi::{cmd_both, cmd_nquery, cmd_qonly, tree::prelude::*, Branch, Leaf, Root};
+
+use crate::capture::{CaptureConfig, CaptureState, TriggerMode, CAPTURE_DEPTH};
+use crate::completion::CommandTracker;
+use crate::duty::Duty;
+use crate::events::Event;
+use crate::pid::{gain_from_f32, gain_to_f32, PidConfig};
+use crate::ramp::RampProfile;
+use crate::scpi_session::SessionDevice;
 use crate::shared::{
-    CoolingState, LedState, PowerState, COOLING_CHANNEL, LED_CHANNEL, POWER_CHANNEL, SPEED_CHANNEL,
+    AdcSyncConfig, CommandChannel, CoolingState, LedState, PowerState, RailFault, ADC_SYNC,
+    CALIBRATION_REQUEST, CALIBRATION_STATE, CAPTURE, COOLING_CHANNEL, COOLING_ON, DAC_CALIBRATION,
+    DAC_CALIBRATION_REQUEST, DAC_CALIBRATION_STATE, DAC_CONFIG, DAC_STATUS, DAC_UPDATE, EVENT_LOG,
+    FAN_CALIBRATION, FAN_FAULT, FAN_FAULT_CLEAR, FAN_PID, FAN_RPM, FAN_SPEED, LED_CHANNEL, LED_ON,
+    POWER_CHANNEL, POWER_SOURCE, PROBE_TEMPERATURE, PWM_BREAK_CLEAR, PWM_CONFIG, PWM_INPUT,
+    PWM_INPUT_CONFIG, PWM_STATUS, PWM_UPDATE, RAIL_STATS, RPM_TARGET_CHANNEL, SCPI_LOCK,
+    SERIAL_CONFIG, SERIAL_STORED, SERIAL_UPDATE, SETTINGS_SAVE, SHARED_DUTY, SPEED_CHANNEL,
+    STALL_CONFIG, SYNC_STATS, TACH_CONFIG, TEMPERATURE, THERMAL_CONFIG, TREND_LOG, WAVEFORM_CONFIG,
+    WAVEFORM_TABLE,
+};
+use crate::tasks::adc_task::configure_sync;
+use crate::tasks::calibration::CalibrationState;
+use crate::tasks::dac::{DacConfig, DAC_CHANNEL, DAC_MAX_MV, DAC_MIN_CARRIER_HZ};
+use crate::tasks::pwm::{
+    sync_owns, PwmChannel, PwmConfig, PWM_FREQUENCY_MAX_HZ, PWM_FREQUENCY_MIN_HZ,
+};
+use crate::tasks::pwm_input::{self, PwmInputMeasurement};
+use crate::tasks::rx_tx::{
+    SerialConfig, SerialMode, SerialParity, SerialProtocol, SerialStopBits, Terminator,
+    BUS_ADDRESSES, SERIAL_BAUDRATES,
+};
+use crate::tasks::tach::TachConfig;
+use crate::thermal::{CurvePoint, TemperatureSource, ThermalConfig, CURVE_POINTS};
+use crate::waveform::{
+    WaveShape, WaveTable, WaveformConfig, WAVEFORM_MAX_POINTS, WAVEFORM_MIN_POINTS,
 };
+use crate::stall::{FanFault, StallConfig};
+use crate::stats::WindowStats;
+
+/// Entries kept in the error queue of a session
+const ERROR_QUEUE_LEN: usize = 8;
+
+/// Encoding of block-capable queries such as `TRACe:DATA?`
+#[derive(Debug, Clone, Copy, PartialEq, Format)]
+enum DataFormat {
+    /// Comma-separated numbers
+    Ascii,
+    /// Definite-length block of big-endian u16
+    Integer,
+}
 
 /// Main device structure implementing SCPI Device trait
-pub struct MyDevice;
+///
+/// One instance per session (connected transport): it carries the state SCPI
+/// keeps per connection, i.e. the error queue, the data format and the
+/// session's claim on the control lock.
+pub struct MyDevice {
+    /// Transport this session runs on, as recorded in `SCPI_LOCK`
+    session: u8,
+    /// Commands this session queued, for `*OPC?`/`*WAI`
+    commands: &'static CommandTracker,
+    errors: Deque<Error, ERROR_QUEUE_LEN>,
+    format: DataFormat,
+    /// `*RST` ran; the session handler resets the parser context after the line
+    reset: bool,
+    /// `*OPC?`/`*WAI` ran; the response waits until this session's commands are applied
+    wait: bool,
+}
+
+impl MyDevice {
+    pub const fn new(session: u8, commands: &'static CommandTracker) -> Self {
+        Self {
+            session,
+            commands,
+            errors: Deque::new(),
+            format: DataFormat::Ascii,
+            reset: false,
+            wait: false,
+        }
+    }
+}
 
 impl Device for MyDevice {
-    fn handle_error(&mut self, _err: Error) {
-        info!("SCPI Error occurred");
+    fn handle_error(&mut self, err: Error) {
+        info!("SCPI Error occurred: {}", err.get_code());
+        if self.errors.is_full() {
+            // SCPI: the newest entry is replaced by the overflow marker
+            self.errors.pop_back();
+            let _ = self.errors.push_back(ErrorCode::QueueOverflow.into());
+        } else {
+            let _ = self.errors.push_back(err);
+        }
+    }
+}
+
+impl SessionDevice for MyDevice {
+    fn reset_session(&mut self) {
+        SCPI_LOCK.lock(|lock| {
+            if lock.get() == Some(self.session) {
+                lock.set(None);
+            }
+        });
+        self.errors.clear();
+        self.format = DataFormat::Ascii;
+        self.reset = false;
+        self.wait = false;
+    }
+
+    fn take_reset(&mut self) -> bool {
+        core::mem::take(&mut self.reset)
+    }
+
+    fn may_control(&self) -> bool {
+        SCPI_LOCK.lock(|lock| lock.get()).is_none_or(|owner| owner == self.session)
+    }
+
+    async fn settle(&mut self) -> bool {
+        if core::mem::take(&mut self.wait) && self.commands.all_applied().await.is_err() {
+            warn!("SCPI: queued commands not applied in time");
+            self.handle_error(ErrorCode::ExecutionError.into());
+            return false;
+        }
+        true
+    }
+
+    fn pause(&self) -> bool {
+        SERIAL_UPDATE.signaled()
+    }
+}
+
+/// A command could not be queued to its task: reported as an execution
+/// error, the command is not carried out.
+fn queue_full() -> Error {
+    warn!("SCPI: command queue full");
+    ErrorCode::ExecutionError.into()
+}
+
+/// Fail now if `channel` could not take another command, so that a command
+/// feeding several tasks is queued completely or not at all.
+fn check_room<T>(channel: &CommandChannel<T>) -> Result<(), Error> {
+    if channel.is_full() {
+        return Err(queue_full());
+    }
+    Ok(())
+}
+
+impl MyDevice {
+    /// Queue `command` for its task, with a completion for `*OPC?`/`*WAI`.
+    fn queue<T>(&self, channel: &CommandChannel<T>, command: T) -> Result<(), Error> {
+        let completion = self.commands.track();
+        channel.try_send((command, completion)).map_err(|_| queue_full())
+    }
+}
+
+/// Match character data such as `RIS` or `rising` against an SCPI mnemonic
+/// written in mixed case (`RISing`): either the upper-case short form or the
+/// complete long form is accepted, case-insensitively.
+fn mnemonic_match(mnemonic: &[u8], data: &[u8]) -> bool {
+    let short_len = mnemonic
+        .iter()
+        .take_while(|b| !b.is_ascii_lowercase())
+        .count();
+    data.eq_ignore_ascii_case(mnemonic) || data.eq_ignore_ascii_case(&mnemonic[..short_len])
+}
+
+/// Read one character-data parameter and map it through `choices`.
+fn next_choice<T: Copy>(params: &mut Parameters, choices: &[(&[u8], T)]) -> Result<T, Error> {
+    let data: &[u8] = params.next_data()?;
+    choices
+        .iter()
+        .find(|(mnemonic, _)| mnemonic_match(mnemonic, data))
+        .map(|&(_, value)| value)
+        .ok_or_else(|| ErrorCode::IllegalParameterValue.into())
+}
+
+// ============================================================================
+// IDENTIFICATION COMMANDS
+// ============================================================================
+
+/// *IDN? - Device identification query
+/// Returns: "PowerModule version 0.1.0"
+struct IdnCommand;
+
+impl Command<MyDevice> for IdnCommand {
+    cmd_qonly!();
+
+    fn query(
+        &self,
+        _device: &mut MyDevice,
+        _context: &mut Context,
+        _params: Parameters,
+        mut resp: ResponseUnit,
+    ) -> scpi::error::Result<()> {
+        resp.data(b"PowerModule version 0.1.0".as_slice()).finish()
+    }
+}
+
+/// *RST - Reset the session: data format and parser context (the error queue
+/// and the control lock are kept)
+struct RstCommand;
+
+impl Command<MyDevice> for RstCommand {
+    cmd_nquery!();
+
+    fn event(
+        &self,
+        device: &mut MyDevice,
+        _context: &mut Context,
+        _params: Parameters,
+    ) -> Result<(), Error> {
+        info!("SCPI: *RST");
+        device.format = DataFormat::Ascii;
+        device.reset = true;
+        Ok(())
+    }
+}
+
+/// *CLS - Clear the error queue of the session
+struct ClsCommand;
+
+impl Command<MyDevice> for ClsCommand {
+    cmd_nquery!();
+
+    fn event(
+        &self,
+        device: &mut MyDevice,
+        _context: &mut Context,
+        _params: Parameters,
+    ) -> Result<(), Error> {
+        device.errors.clear();
+        Ok(())
+    }
+}
+
+/// *OPC? - Returns 1 once every command sent so far has been applied
+///
+/// The response (and with it the next line) is held back until the LED,
+/// power and cooling tasks have applied the commands of this session;
+/// commands from the other ports are not waited for. If that takes longer
+/// than `APPLY_TIMEOUT`, the line is answered with `ERR` and an execution
+/// error is queued.
+struct OpcCommand;
+
+impl Command<MyDevice> for OpcCommand {
+    cmd_qonly!();
+
+    fn query(
+        &self,
+        device: &mut MyDevice,
+        _context: &mut Context,
+        _params: Parameters,
+        mut resp: ResponseUnit,
+    ) -> scpi::error::Result<()> {
+        device.wait = true;
+        resp.data(1u8).finish()
+    }
+}
+
+/// *WAI - Hold further lines until every command sent so far has been applied
+///
+/// Units after `*WAI` in the same line are not held back.
+struct WaiCommand;
+
+impl Command<MyDevice> for WaiCommand {
+    cmd_nquery!();
+
+    fn event(
+        &self,
+        device: &mut MyDevice,
+        _context: &mut Context,
+        _params: Parameters,
+    ) -> Result<(), Error> {
+        device.wait = true;
+        Ok(())
+    }
+}
+
+// ============================================================================
+// SESSION COMMANDS
+// ============================================================================
+
+/// SYSTem:ERRor[:NEXT]? - Oldest queued error as code,"message"; 0,"No error" when empty
+struct ErrorNextCommand;
+
+impl Command<MyDevice> for ErrorNextCommand {
+    cmd_qonly!();
+
+    fn query(
+        &self,
+        device: &mut MyDevice,
+        _context: &mut Context,
+        _params: Parameters,
+        mut resp: ResponseUnit,
+    ) -> scpi::error::Result<()> {
+        let (code, message) = match device.errors.pop_front() {
+            Some(err) => (err.get_code(), err.get_message()),
+            None => (0, b"No error".as_slice()),
+        };
+        let message = core::str::from_utf8(message).unwrap_or("");
+        resp.data(code).data(message).finish()
+    }
+}
+
+/// SYSTem:ERRor:COUNt? - Number of queued errors
+struct ErrorCountCommand;
+
+impl Command<MyDevice> for ErrorCountCommand {
+    cmd_qonly!();
+
+    fn query(
+        &self,
+        device: &mut MyDevice,
+        _context: &mut Context,
+        _params: Parameters,
+        mut resp: ResponseUnit,
+    ) -> scpi::error::Result<()> {
+        resp.data(device.errors.len() as u32).finish()
+    }
+}
+
+/// SYSTem:LOCK[:REQuest]? - Claim the control lock; 1 if this session holds it
+///
+/// While another session holds the lock, lines that change settings are
+/// refused with -203 (command protected); queries keep working.
+struct LockRequestCommand;
+
+impl Command<MyDevice> for LockRequestCommand {
+    cmd_qonly!();
+
+    fn query(
+        &self,
+        device: &mut MyDevice,
+        _context: &mut Context,
+        _params: Parameters,
+        mut resp: ResponseUnit,
+    ) -> scpi::error::Result<()> {
+        let granted = SCPI_LOCK.lock(|lock| match lock.get() {
+            None => {
+                lock.set(Some(device.session));
+                true
+            }
+            Some(owner) => owner == device.session,
+        });
+        resp.data(granted).finish()
+    }
+}
+
+/// SYSTem:LOCK:RELease - Give up the control lock (no-op if not held)
+struct LockReleaseCommand;
+
+impl Command<MyDevice> for LockReleaseCommand {
+    cmd_nquery!();
+
+    fn event(
+        &self,
+        device: &mut MyDevice,
+        _context: &mut Context,
+        _params: Parameters,
+    ) -> Result<(), Error> {
+        SCPI_LOCK.lock(|lock| {
+            if lock.get() == Some(device.session) {
+                lock.set(None);
+            }
+        });
+        Ok(())
+    }
+}
+
+/// SYSTem:LOCK:OWNer? - Session holding the control lock, 0 if free
+struct LockOwnerCommand;
+
+impl Command<MyDevice> for LockOwnerCommand {
+    cmd_qonly!();
+
+    fn query(
+        &self,
+        _device: &mut MyDevice,
+        _context: &mut Context,
+        _params: Parameters,
+        mut resp: ResponseUnit,
+    ) -> scpi::error::Result<()> {
+        let owner = SCPI_LOCK.lock(|lock| lock.get()).unwrap_or(0);
+        resp.data(owner).finish()
+    }
+}
+
+const DATA_FORMATS: &[(&[u8], DataFormat)] =
+    &[(b"ASCii", DataFormat::Ascii), (b"INTeger", DataFormat::Integer)];
+
+/// FORMat[:DATA] <ASCii|INTeger> - Set/query the encoding of TRACe:DATA? for this session
+struct FormatDataCommand;
+
+impl Command<MyDevice> for FormatDataCommand {
+    cmd_both!();
+
+    fn event(
+        &self,
+        device: &mut MyDevice,
+        _context: &mut Context,
+        mut params: Parameters,
+    ) -> Result<(), Error> {
+        device.format = next_choice(&mut params, DATA_FORMATS)?;
+        Ok(())
+    }
+
+    fn query(
+        &self,
+        device: &mut MyDevice,
+        _context: &mut Context,
+        _params: Parameters,
+        mut resp: ResponseUnit,
+    ) -> scpi::error::Result<()> {
+        let format: &[u8] = match device.format {
+            DataFormat::Ascii => b"ASC",
+            DataFormat::Integer => b"INT",
+        };
+        resp.data(format).finish()
+    }
+}
+
+// ============================================================================
+// SERIAL PORT COMMANDS
+// ============================================================================
+
+/// Store new USART1 settings; the serial session applies them once the
+/// current response is out. They start from the live port, so an explicit
+/// change also makes the PB5 jumper defaults the stored configuration.
+fn update_serial(update: impl FnOnce(&mut SerialConfig)) {
+    SERIAL_STORED.lock(|stored| stored.set(None));
+    SERIAL_CONFIG.lock(|serial| {
+        let mut config = serial.get();
+        update(&mut config);
+        serial.set(config);
+    });
+    SERIAL_UPDATE.signal(());
+    SETTINGS_SAVE.signal(());
+}
+
+/// SYSTem:COMMunicate:SERial:BAUD <rate> - Set/query the USART1 baud rate (1200..115200)
+struct SerialBaudCommand;
+
+impl Command<MyDevice> for SerialBaudCommand {
+    cmd_both!();
+
+    fn event(
+        &self,
+        _device: &mut MyDevice,
+        _context: &mut Context,
+        mut params: Parameters,
+    ) -> Result<(), Error> {
+        let baudrate: u32 = params.next_data()?;
+        if !SERIAL_BAUDRATES.contains(&baudrate) {
+            return Err(ErrorCode::DataOutOfRange.into());
+        }
+        info!("SCPI: SERIAL BAUD {}", baudrate);
+        update_serial(|config| config.baudrate = baudrate);
+        Ok(())
+    }
+
+    fn query(
+        &self,
+        _device: &mut MyDevice,
+        _context: &mut Context,
+        _params: Parameters,
+        mut resp: ResponseUnit,
+    ) -> scpi::error::Result<()> {
+        let baudrate = SERIAL_CONFIG.lock(|serial| serial.get()).baudrate;
+        resp.data(baudrate).finish()
+    }
+}
+
+const SERIAL_PARITIES: &[(&[u8], SerialParity)] = &[
+    (b"NONE", SerialParity::None),
+    (b"EVEN", SerialParity::Even),
+    (b"ODD", SerialParity::Odd),
+];
+
+/// SYSTem:COMMunicate:SERial:PARity <NONE|EVEN|ODD> - Set/query the USART1 parity
+struct SerialParityCommand;
+
+impl Command<MyDevice> for SerialParityCommand {
+    cmd_both!();
+
+    fn event(
+        &self,
+        _device: &mut MyDevice,
+        _context: &mut Context,
+        mut params: Parameters,
+    ) -> Result<(), Error> {
+        let parity = next_choice(&mut params, SERIAL_PARITIES)?;
+        info!("SCPI: SERIAL PARITY {}", parity);
+        update_serial(|config| config.parity = parity);
+        Ok(())
+    }
+
+    fn query(
+        &self,
+        _device: &mut MyDevice,
+        _context: &mut Context,
+        _params: Parameters,
+        mut resp: ResponseUnit,
+    ) -> scpi::error::Result<()> {
+        let parity: &[u8] = match SERIAL_CONFIG.lock(|serial| serial.get()).parity {
+            SerialParity::None => b"NONE",
+            SerialParity::Even => b"EVEN",
+            SerialParity::Odd => b"ODD",
+        };
+        resp.data(parity).finish()
+    }
+}
+
+/// SYSTem:COMMunicate:SERial:SBITs <1|2> - Set/query the USART1 stop bits
+struct SerialStopBitsCommand;
+
+impl Command<MyDevice> for SerialStopBitsCommand {
+    cmd_both!();
+
+    fn event(
+        &self,
+        _device: &mut MyDevice,
+        _context: &mut Context,
+        mut params: Parameters,
+    ) -> Result<(), Error> {
+        let stop_bits = match params.next_data::<u8>()? {
+            1 => SerialStopBits::One,
+            2 => SerialStopBits::Two,
+            _ => return Err(ErrorCode::DataOutOfRange.into()),
+        };
+        info!("SCPI: SERIAL STOP BITS {}", stop_bits);
+        update_serial(|config| config.stop_bits = stop_bits);
+        Ok(())
+    }
+
+    fn query(
+        &self,
+        _device: &mut MyDevice,
+        _context: &mut Context,
+        _params: Parameters,
+        mut resp: ResponseUnit,
+    ) -> scpi::error::Result<()> {
+        let stop_bits: u8 = match SERIAL_CONFIG.lock(|serial| serial.get()).stop_bits {
+            SerialStopBits::One => 1,
+            SerialStopBits::Two => 2,
+        };
+        resp.data(stop_bits).finish()
+    }
+}
+
+const TERMINATORS: &[(&[u8], Terminator)] = &[
+    (b"LF", Terminator::Lf),
+    (b"CRLF", Terminator::CrLf),
+    (b"CR", Terminator::Cr),
+];
+
+/// SYSTem:COMMunicate:SERial:TERMinator <LF|CRLF|CR> - Set/query the response terminator
+///
+/// Input lines are accepted with any of them.
+struct SerialTerminatorCommand;
+
+impl Command<MyDevice> for SerialTerminatorCommand {
+    cmd_both!();
+
+    fn event(
+        &self,
+        _device: &mut MyDevice,
+        _context: &mut Context,
+        mut params: Parameters,
+    ) -> Result<(), Error> {
+        let terminator = next_choice(&mut params, TERMINATORS)?;
+        info!("SCPI: SERIAL TERMINATOR {}", terminator);
+        update_serial(|config| config.terminator = terminator);
+        Ok(())
+    }
+
+    fn query(
+        &self,
+        _device: &mut MyDevice,
+        _context: &mut Context,
+        _params: Parameters,
+        mut resp: ResponseUnit,
+    ) -> scpi::error::Result<()> {
+        let terminator: &[u8] = match SERIAL_CONFIG.lock(|serial| serial.get()).terminator {
+            Terminator::Lf => b"LF",
+            Terminator::CrLf => b"CRLF",
+            Terminator::Cr => b"CR",
+        };
+        resp.data(terminator).finish()
+    }
+}
+
+const SERIAL_MODES: &[(&[u8], SerialMode)] = &[
+    (b"RS232", SerialMode::Rs232),
+    (b"RS485", SerialMode::Rs485),
+];
+
+/// SYSTem:COMMunicate:SERial:MODE <RS232|RS485> - Set/query the USART1 line mode
+///
+/// RS485 is half duplex: the transceiver driver (PB8) is enabled only while
+/// a reply goes out, and lines without an address prefix are not answered.
+struct SerialModeCommand;
+
+impl Command<MyDevice> for SerialModeCommand {
+    cmd_both!();
+
+    fn event(
+        &self,
+        _device: &mut MyDevice,
+        _context: &mut Context,
+        mut params: Parameters,
+    ) -> Result<(), Error> {
+        let mode = next_choice(&mut params, SERIAL_MODES)?;
+        info!("SCPI: SERIAL MODE {}", mode);
+        update_serial(|config| config.mode = mode);
+        Ok(())
+    }
+
+    fn query(
+        &self,
+        _device: &mut MyDevice,
+        _context: &mut Context,
+        _params: Parameters,
+        mut resp: ResponseUnit,
+    ) -> scpi::error::Result<()> {
+        let mode: &[u8] = match SERIAL_CONFIG.lock(|serial| serial.get()).mode {
+            SerialMode::Rs232 => b"RS232",
+            SerialMode::Rs485 => b"RS485",
+        };
+        resp.data(mode).finish()
+    }
+}
+
+const SERIAL_PROTOCOLS: &[(&[u8], SerialProtocol)] = &[
+    (b"SCPI", SerialProtocol::Scpi),
+    (b"MODBus", SerialProtocol::Modbus),
+    (b"BINary", SerialProtocol::Binary),
+];
+
+/// SYSTem:COMMunicate:SERial:PROTocol <SCPI|MODBus|BINary> - Set/query the USART1 protocol
+///
+/// MODBus turns the port into a Modbus RTU slave at the bus address, BINary
+/// into a binary command/telemetry port like USART3. There is no way back
+/// from either: the safe-default jumper (PB5) restores SCPI.
+struct SerialProtocolCommand;
+
+impl Command<MyDevice> for SerialProtocolCommand {
+    cmd_both!();
+
+    fn event(
+        &self,
+        _device: &mut MyDevice,
+        _context: &mut Context,
+        mut params: Parameters,
+    ) -> Result<(), Error> {
+        let protocol = next_choice(&mut params, SERIAL_PROTOCOLS)?;
+        info!("SCPI: SERIAL PROTOCOL {}", protocol);
+        update_serial(|config| config.protocol = protocol);
+        Ok(())
+    }
+
+    fn query(
+        &self,
+        _device: &mut MyDevice,
+        _context: &mut Context,
+        _params: Parameters,
+        mut resp: ResponseUnit,
+    ) -> scpi::error::Result<()> {
+        let protocol: &[u8] = match SERIAL_CONFIG.lock(|serial| serial.get()).protocol {
+            SerialProtocol::Scpi => b"SCPI",
+            SerialProtocol::Modbus => b"MODBUS",
+            SerialProtocol::Binary => b"BINARY",
+        };
+        resp.data(protocol).finish()
+    }
+}
+
+/// SYSTem:COMMunicate:ADDRess <1..247> - Set/query the unit address on the bus
+///
+/// `@<address> <command>` is handled by that unit only, `@0 <command>` by all
+/// units without reply.
+struct BusAddressCommand;
+
+impl Command<MyDevice> for BusAddressCommand {
+    cmd_both!();
+
+    fn event(
+        &self,
+        _device: &mut MyDevice,
+        _context: &mut Context,
+        mut params: Parameters,
+    ) -> Result<(), Error> {
+        let address: u8 = params.next_data()?;
+        if !BUS_ADDRESSES.contains(&address) {
+            return Err(ErrorCode::DataOutOfRange.into());
+        }
+        info!("SCPI: BUS ADDRESS {}", address);
+        update_serial(|config| config.address = address);
+        Ok(())
+    }
+
+    fn query(
+        &self,
+        _device: &mut MyDevice,
+        _context: &mut Context,
+        _params: Parameters,
+        mut resp: ResponseUnit,
+    ) -> scpi::error::Result<()> {
+        let address = SERIAL_CONFIG.lock(|serial| serial.get()).address;
+        resp.data(address).finish()
+    }
+}
+
+// ============================================================================
+// LED CONTROL COMMANDS
+// ============================================================================
+
+/// LED:TOGGle - Toggle LED state
+struct LedToggleCommand;
+
+impl Command<MyDevice> for LedToggleCommand {
+    cmd_nquery!();
+
+    fn event(
+        &self,
+        device: &mut MyDevice,
+        _context: &mut Context,
+        _params: Parameters,
+    ) -> Result<(), Error> {
+        info!("SCPI: LED TOGGLE");
+        device.queue(&LED_CHANNEL, LedState::Toggle)?;
+        Ok(())
+    }
+}
+
+/// LED:ON - Turn LED on
+struct LedOnCommand;
+
+impl Command<MyDevice> for LedOnCommand {
+    cmd_nquery!();
+
+    fn event(
+        &self,
+        device: &mut MyDevice,
+        _context: &mut Context,
+        _params: Parameters,
+    ) -> Result<(), Error> {
+        info!("SCPI: LED ON");
+        device.queue(&LED_CHANNEL, LedState::On)?;
+        Ok(())
+    }
+}
+
+/// LED:OFF - Turn LED off
+struct LedOffCommand;
+
+impl Command<MyDevice> for LedOffCommand {
+    cmd_nquery!();
+
+    fn event(
+        &self,
+        device: &mut MyDevice,
+        _context: &mut Context,
+        _params: Parameters,
+    ) -> Result<(), Error> {
+        info!("SCPI: LED OFF");
+        device.queue(&LED_CHANNEL, LedState::Off)?;
+        Ok(())
+    }
+}
+
+fn on_off(on: bool) -> &'static [u8] {
+    if on {
+        b"ON"
+    } else {
+        b"OFF"
+    }
+}
+
+/// LED? - Query LED status (ON|OFF)
+struct LedStatusCommand;
+
+impl Command<MyDevice> for LedStatusCommand {
+    cmd_qonly!();
+
+    fn query(
+        &self,
+        _device: &mut MyDevice,
+        _context: &mut Context,
+        _params: Parameters,
+        mut resp: ResponseUnit,
+    ) -> scpi::error::Result<()> {
+        let on = LED_ON.lock(|led| led.get());
+        resp.data(on_off(on)).finish()
+    }
+}
+
+// ============================================================================
+// POWER CONTROL COMMANDS
+// ============================================================================
+
+/// POWEr:ON - Turn power on (ACDC or DCDC based on current state)
+///
+/// Any power command also clears a latched rail fault, which otherwise keeps
+/// the automatic source selection suspended.
+struct PowerOnCommand;
+
+impl Command<MyDevice> for PowerOnCommand {
+    cmd_nquery!();
+
+    fn event(
+        &self,
+        device: &mut MyDevice,
+        _context: &mut Context,
+        _params: Parameters,
+    ) -> Result<(), Error> {
+        info!("SCPI: POWER ON");
+        // Default to ACDC when turning power on
+        let target_state = PowerState::ACDC;
+        device.queue(&POWER_CHANNEL, target_state)?;
+        Ok(())
+    }
+}
+
+/// POWEr:OFF - Turn power off
+struct PowerOffCommand;
+
+impl Command<MyDevice> for PowerOffCommand {
+    cmd_nquery!();
+
+    fn event(
+        &self,
+        device: &mut MyDevice,
+        _context: &mut Context,
+        _params: Parameters,
+    ) -> Result<(), Error> {
+        info!("SCPI: POWER OFF");
+        device.queue(&POWER_CHANNEL, PowerState::OFF)?;
+        Ok(())
+    }
+}
+
+/// POWEr? - Query the active source (ACDC|DCDC|OFF)
+struct PowerStatusCommand;
+
+impl Command<MyDevice> for PowerStatusCommand {
+    cmd_qonly!();
+
+    fn query(
+        &self,
+        _device: &mut MyDevice,
+        _context: &mut Context,
+        _params: Parameters,
+        mut resp: ResponseUnit,
+    ) -> scpi::error::Result<()> {
+        let source: &[u8] = match POWER_SOURCE.lock(|power| power.get()) {
+            PowerState::ACDC => b"ACDC",
+            PowerState::DCDC => b"DCDC",
+            PowerState::OFF => b"OFF",
+        };
+        resp.data(source).finish()
+    }
+}
+
+// ============================================================================
+// DCDC POWER CONTROL COMMANDS
+// ============================================================================
+
+/// POWEr:DCDC:ON - Turn DCDC power on
+struct DcdcOnCommand;
+
+impl Command<MyDevice> for DcdcOnCommand {
+    cmd_nquery!();
+
+    fn event(
+        &self,
+        device: &mut MyDevice,
+        _context: &mut Context,
+        _params: Parameters,
+    ) -> Result<(), Error> {
+        info!("SCPI: DCDC ON");
+        device.queue(&POWER_CHANNEL, PowerState::DCDC)?;
+        Ok(())
+    }
+}
+
+/// POWEr:DCDC:OFF - Turn DCDC power off
+struct DcdcOffCommand;
+
+impl Command<MyDevice> for DcdcOffCommand {
+    cmd_nquery!();
+
+    fn event(
+        &self,
+        device: &mut MyDevice,
+        _context: &mut Context,
+        _params: Parameters,
+    ) -> Result<(), Error> {
+        info!("SCPI: DCDC OFF");
+        device.queue(&POWER_CHANNEL, PowerState::OFF)?;
+        Ok(())
+    }
+}
+
+/// POWEr:DCDC? - Query whether DCDC is the active source (1|0)
+struct DcdcStatusCommand;
+
+impl Command<MyDevice> for DcdcStatusCommand {
+    cmd_qonly!();
+
+    fn query(
+        &self,
+        _device: &mut MyDevice,
+        _context: &mut Context,
+        _params: Parameters,
+        mut resp: ResponseUnit,
+    ) -> scpi::error::Result<()> {
+        let active = POWER_SOURCE.lock(|power| power.get()) == PowerState::DCDC;
+        resp.data(active).finish()
+    }
+}
+
+/// POWEr:DCDC:VAL? - Query DCDC voltage value
+struct DcdcValueCommand;
+
+impl Command<MyDevice> for DcdcValueCommand {
+    cmd_qonly!();
+
+    fn query(
+        &self,
+        _device: &mut MyDevice,
+        _context: &mut Context,
+        _params: Parameters,
+        mut resp: ResponseUnit,
+    ) -> scpi::error::Result<()> {
+        // Return default voltage value
+        resp.data(b"0".as_slice()).finish()
+    }
+}
+
+// ============================================================================
+// ACDC POWER CONTROL COMMANDS
+// ============================================================================
+
+/// POWEr:ACDC:ON - Turn ACDC power on
+struct AcdcOnCommand;
+
+impl Command<MyDevice> for AcdcOnCommand {
+    cmd_nquery!();
+
+    fn event(
+        &self,
+        device: &mut MyDevice,
+        _context: &mut Context,
+        _params: Parameters,
+    ) -> Result<(), Error> {
+        info!("SCPI: ACDC ON");
+        device.queue(&POWER_CHANNEL, PowerState::ACDC)?;
+        Ok(())
+    }
+}
+
+/// POWEr:ACDC:OFF - Turn ACDC power off
+struct AcdcOffCommand;
+
+impl Command<MyDevice> for AcdcOffCommand {
+    cmd_nquery!();
+
+    fn event(
+        &self,
+        device: &mut MyDevice,
+        _context: &mut Context,
+        _params: Parameters,
+    ) -> Result<(), Error> {
+        info!("SCPI: ACDC OFF");
+        device.queue(&POWER_CHANNEL, PowerState::OFF)?;
+        Ok(())
+    }
+}
+
+/// POWEr:ACDC? - Query whether ACDC is the active source (1|0)
+struct AcdcStatusCommand;
+
+impl Command<MyDevice> for AcdcStatusCommand {
+    cmd_qonly!();
+
+    fn query(
+        &self,
+        _device: &mut MyDevice,
+        _context: &mut Context,
+        _params: Parameters,
+        mut resp: ResponseUnit,
+    ) -> scpi::error::Result<()> {
+        let active = POWER_SOURCE.lock(|power| power.get()) == PowerState::ACDC;
+        resp.data(active).finish()
+    }
+}
+
+/// POWEr:ACDC:VAL? - Query ACDC voltage value
+struct AcdcValueCommand;
+
+impl Command<MyDevice> for AcdcValueCommand {
+    cmd_qonly!();
+
+    fn query(
+        &self,
+        _device: &mut MyDevice,
+        _context: &mut Context,
+        _params: Parameters,
+        mut resp: ResponseUnit,
+    ) -> scpi::error::Result<()> {
+        // Return default voltage value
+        resp.data(b"0".as_slice()).finish()
+    }
+}
+
+// ============================================================================
+// COOLING CONTROL COMMANDS
+// ============================================================================
+
+/// SPEEd:ON - Turn cooling on
+struct SpeedOnCommand;
+
+impl Command<MyDevice> for SpeedOnCommand {
+    cmd_nquery!();
+
+    fn event(
+        &self,
+        device: &mut MyDevice,
+        _context: &mut Context,
+        _params: Parameters,
+    ) -> Result<(), Error> {
+        info!("SCPI: COOLING ON");
+        device.queue(&COOLING_CHANNEL, CoolingState::On)?;
+        Ok(())
+    }
+}
+
+/// SPEEd:OFF - Turn cooling off
+struct SpeedOffCommand;
+
+impl Command<MyDevice> for SpeedOffCommand {
+    cmd_nquery!();
+
+    fn event(
+        &self,
+        device: &mut MyDevice,
+        _context: &mut Context,
+        _params: Parameters,
+    ) -> Result<(), Error> {
+        info!("SCPI: COOLING OFF");
+        device.queue(&COOLING_CHANNEL, CoolingState::Off)?;
+        Ok(())
+    }
+}
+
+/// SPEEd? - Query cooling status and speed setpoint (ON|OFF,<percent>)
+struct SpeedStatusCommand;
+
+impl Command<MyDevice> for SpeedStatusCommand {
+    cmd_qonly!();
+
+    fn query(
+        &self,
+        _device: &mut MyDevice,
+        _context: &mut Context,
+        _params: Parameters,
+        mut resp: ResponseUnit,
+    ) -> scpi::error::Result<()> {
+        let on = COOLING_ON.lock(|cooling| cooling.get());
+        let speed = FAN_SPEED.lock(|fan| fan.get());
+        resp.data(on_off(on)).data(speed).finish()
+    }
+}
+
+/// SPEEd <percent> - Set cooling speed (0..100 %) and turn cooling on
+struct SpeedValueCommand;
+
+impl Command<MyDevice> for SpeedValueCommand {
+    cmd_nquery!();
+
+    fn event(
+        &self,
+        device: &mut MyDevice,
+        _context: &mut Context,
+        mut params: Parameters,
+    ) -> Result<(), Error> {
+        let speed: u16 = params.next_data()?;
+        if speed > 100 {
+            return Err(ErrorCode::DataOutOfRange.into());
+        }
+        info!("SCPI: COOLING SPEED {}%", speed);
+        check_room(&COOLING_CHANNEL)?;
+        device.queue(&SPEED_CHANNEL, speed)?;
+        device.queue(&COOLING_CHANNEL, CoolingState::On)?;
+        Ok(())
+    }
+}
+
+/// SPEEd:RPM[:MEASured]? - Fan speed measured from the tach input
+struct SpeedRpmCommand;
+
+impl Command<MyDevice> for SpeedRpmCommand {
+    cmd_qonly!();
+
+    fn query(
+        &self,
+        _device: &mut MyDevice,
+        _context: &mut Context,
+        _params: Parameters,
+        mut resp: ResponseUnit,
+    ) -> scpi::error::Result<()> {
+        let rpm = FAN_RPM.lock(|fan| fan.get());
+        resp.data(rpm).finish()
+    }
+}
+
+/// SPEEd:TACHometer:PPR <n> - Set/query tach pulses per revolution
+struct TachPprCommand;
+
+impl Command<MyDevice> for TachPprCommand {
+    cmd_both!();
+
+    fn event(
+        &self,
+        _device: &mut MyDevice,
+        _context: &mut Context,
+        mut params: Parameters,
+    ) -> Result<(), Error> {
+        let pulses_per_rev: u8 = params.next_data()?;
+        if pulses_per_rev == 0 {
+            return Err(ErrorCode::DataOutOfRange.into());
+        }
+        info!("SCPI: TACH PPR {}", pulses_per_rev);
+        TACH_CONFIG.lock(|tach| {
+            let config = tach.get();
+            tach.set(TachConfig { pulses_per_rev, ..config });
+        });
+        Ok(())
+    }
+
+    fn query(
+        &self,
+        _device: &mut MyDevice,
+        _context: &mut Context,
+        _params: Parameters,
+        mut resp: ResponseUnit,
+    ) -> scpi::error::Result<()> {
+        let pulses_per_rev = TACH_CONFIG.lock(|tach| tach.get()).pulses_per_rev;
+        resp.data(pulses_per_rev).finish()
+    }
+}
+
+/// SPEEd:TACHometer:AVERage <n> - Set/query number of tach periods averaged (1..16)
+struct TachAverageCommand;
+
+impl Command<MyDevice> for TachAverageCommand {
+    cmd_both!();
+
+    fn event(
+        &self,
+        _device: &mut MyDevice,
+        _context: &mut Context,
+        mut params: Parameters,
+    ) -> Result<(), Error> {
+        let average: u8 = params.next_data()?;
+        if !(1..=16).contains(&average) {
+            return Err(ErrorCode::DataOutOfRange.into());
+        }
+        info!("SCPI: TACH AVERAGE {}", average);
+        TACH_CONFIG.lock(|tach| {
+            let config = tach.get();
+            tach.set(TachConfig { average, ..config });
+        });
+        Ok(())
+    }
+
+    fn query(
+        &self,
+        _device: &mut MyDevice,
+        _context: &mut Context,
+        _params: Parameters,
+        mut resp: ResponseUnit,
+    ) -> scpi::error::Result<()> {
+        let average = TACH_CONFIG.lock(|tach| tach.get()).average;
+        resp.data(average).finish()
+    }
+}
+
+/// SPEEd:RPM:TARGet <rpm> - Regulate the fan to an RPM (closed loop) and turn cooling on
+struct RpmTargetCommand;
+
+impl Command<MyDevice> for RpmTargetCommand {
+    cmd_nquery!();
+
+    fn event(
+        &self,
+        device: &mut MyDevice,
+        _context: &mut Context,
+        mut params: Parameters,
+    ) -> Result<(), Error> {
+        let target_rpm: u16 = params.next_data()?;
+        info!("SCPI: COOLING RPM TARGET {}", target_rpm);
+        check_room(&COOLING_CHANNEL)?;
+        device.queue(&RPM_TARGET_CHANNEL, target_rpm)?;
+        device.queue(&COOLING_CHANNEL, CoolingState::On)?;
+        Ok(())
+    }
+}
+
+#[derive(Clone, Copy)]
+enum PidGain {
+    Kp,
+    Ki,
+    Kd,
+}
+
+/// SPEEd:PID:KP / KI / KD <value> - Set/query fan PID gains
+struct PidGainCommand(PidGain);
+
+impl Command<MyDevice> for PidGainCommand {
+    cmd_both!();
+
+    fn event(
+        &self,
+        _device: &mut MyDevice,
+        _context: &mut Context,
+        mut params: Parameters,
+    ) -> Result<(), Error> {
+        let value: f32 = params.next_data()?;
+        if !(0.0..1000.0).contains(&value) {
+            return Err(ErrorCode::DataOutOfRange.into());
+        }
+        let gain = gain_from_f32(value);
+        FAN_PID.lock(|pid| {
+            let config = pid.get();
+            pid.set(match self.0 {
+                PidGain::Kp => PidConfig { kp: gain, ..config },
+                PidGain::Ki => PidConfig { ki: gain, ..config },
+                PidGain::Kd => PidConfig { kd: gain, ..config },
+            });
+        });
+        Ok(())
+    }
+
+    fn query(
+        &self,
+        _device: &mut MyDevice,
+        _context: &mut Context,
+        _params: Parameters,
+        mut resp: ResponseUnit,
+    ) -> scpi::error::Result<()> {
+        let config = FAN_PID.lock(|pid| pid.get());
+        let gain = match self.0 {
+            PidGain::Kp => config.kp,
+            PidGain::Ki => config.ki,
+            PidGain::Kd => config.kd,
+        };
+        resp.data(gain_to_f32(gain)).finish()
+    }
+}
+
+/// SPEEd:PID:PERiod <ms> - Set/query fan PID update period
+struct PidPeriodCommand;
+
+impl Command<MyDevice> for PidPeriodCommand {
+    cmd_both!();
+
+    fn event(
+        &self,
+        _device: &mut MyDevice,
+        _context: &mut Context,
+        mut params: Parameters,
+    ) -> Result<(), Error> {
+        let period_ms: u32 = params.next_data()?;
+        if !(10..=10_000).contains(&period_ms) {
+            return Err(ErrorCode::DataOutOfRange.into());
+        }
+        FAN_PID.lock(|pid| {
+            let config = pid.get();
+            pid.set(PidConfig { period_ms, ..config });
+        });
+        Ok(())
+    }
+
+    fn query(
+        &self,
+        _device: &mut MyDevice,
+        _context: &mut Context,
+        _params: Parameters,
+        mut resp: ResponseUnit,
+    ) -> scpi::error::Result<()> {
+        let period_ms = FAN_PID.lock(|pid| pid.get()).period_ms;
+        resp.data(period_ms).finish()
+    }
+}
+
+// ============================================================================
+// FAN STALL COMMANDS
+// ============================================================================
+
+/// SPEEd:FAULt? - Fan fault state (OK|STALL|FAIL)
+struct FanFaultCommand;
+
+impl Command<MyDevice> for FanFaultCommand {
+    cmd_qonly!();
+
+    fn query(
+        &self,
+        _device: &mut MyDevice,
+        _context: &mut Context,
+        _params: Parameters,
+        mut resp: ResponseUnit,
+    ) -> scpi::error::Result<()> {
+        let fault: &[u8] = match FAN_FAULT.lock(|fault| fault.get()) {
+            FanFault::Ok => b"OK",
+            FanFault::Stalled => b"STALL",
+            FanFault::Failed => b"FAIL",
+        };
+        resp.data(fault).finish()
+    }
+}
+
+/// SPEEd:FAULt:CLEar - Clear a latched fan failure and resume supervision
+struct FanFaultClearCommand;
+
+impl Command<MyDevice> for FanFaultClearCommand {
+    cmd_nquery!();
+
+    fn event(
+        &self,
+        _device: &mut MyDevice,
+        _context: &mut Context,
+        _params: Parameters,
+    ) -> Result<(), Error> {
+        info!("SCPI: FAN FAULT CLEAR");
+        FAN_FAULT_CLEAR.signal(());
+        Ok(())
+    }
+}
+
+#[derive(Clone, Copy)]
+enum StallSetting {
+    Timeout,
+    Retries,
+    Kick,
+}
+
+/// SPEEd:STALl:TIMEout <s> / RETRies <n> / KICK <ms> - Set/query stall supervision
+struct StallSettingCommand(StallSetting);
+
+impl Command<MyDevice> for StallSettingCommand {
+    cmd_both!();
+
+    fn event(
+        &self,
+        _device: &mut MyDevice,
+        _context: &mut Context,
+        mut params: Parameters,
+    ) -> Result<(), Error> {
+        let value: u16 = params.next_data()?;
+        let mut config = STALL_CONFIG.lock(|stall| stall.get());
+        match self.0 {
+            StallSetting::Timeout if value == 0 => return Err(ErrorCode::DataOutOfRange.into()),
+            StallSetting::Timeout => config.timeout_s = value,
+            StallSetting::Retries => {
+                config.retries = u8::try_from(value).map_err(|_| ErrorCode::DataOutOfRange)?
+            }
+            StallSetting::Kick => config.kick_ms = value,
+        }
+        STALL_CONFIG.lock(|stall| stall.set(config));
+        Ok(())
+    }
+
+    fn query(
+        &self,
+        _device: &mut MyDevice,
+        _context: &mut Context,
+        _params: Parameters,
+        mut resp: ResponseUnit,
+    ) -> scpi::error::Result<()> {
+        let config = STALL_CONFIG.lock(|stall| stall.get());
+        let value = match self.0 {
+            StallSetting::Timeout => config.timeout_s,
+            StallSetting::Retries => u16::from(config.retries),
+            StallSetting::Kick => config.kick_ms,
+        };
+        resp.data(value).finish()
+    }
+}
+
+/// SPEEd:STALl:POWeroff <ON|OFF> - Switch the power OFF when a fan failure is latched
+struct StallPowerOffCommand;
+
+impl Command<MyDevice> for StallPowerOffCommand {
+    cmd_both!();
+
+    fn event(
+        &self,
+        _device: &mut MyDevice,
+        _context: &mut Context,
+        mut params: Parameters,
+    ) -> Result<(), Error> {
+        let power_off: bool = params.next_data()?;
+        STALL_CONFIG.lock(|stall| {
+            let config = stall.get();
+            stall.set(StallConfig { power_off, ..config });
+        });
+        Ok(())
+    }
+
+    fn query(
+        &self,
+        _device: &mut MyDevice,
+        _context: &mut Context,
+        _params: Parameters,
+        mut resp: ResponseUnit,
+    ) -> scpi::error::Result<()> {
+        let power_off = STALL_CONFIG.lock(|stall| stall.get()).power_off;
+        resp.data(power_off).finish()
+    }
+}
+
+// ============================================================================
+// RAMP COMMANDS
+// ============================================================================
+
+#[derive(Clone, Copy)]
+enum RampSetting {
+    Rate,
+    SoftStart,
+}
+
+const RAMP_PROFILES: &[(&[u8], RampProfile)] = &[
+    (b"LINear", RampProfile::Linear),
+    (b"SCURve", RampProfile::SCurve),
+];
+
+/// SOURce<n>:RAMP:RATE <%/s> / SOFTstart <ms> - Set/query ramp limits (SPEEd:RAMP = SOURce1)
+struct RampSettingCommand(PwmChannel, RampSetting);
+
+impl Command<MyDevice> for RampSettingCommand {
+    cmd_both!();
+
+    fn event(
+        &self,
+        _device: &mut MyDevice,
+        _context: &mut Context,
+        mut params: Parameters,
+    ) -> Result<(), Error> {
+        let value: u16 = params.next_data()?;
+        let channel = self.0.ramp();
+        let mut config = channel.config();
+        match self.1 {
+            RampSetting::Rate => config.rate_percent_per_s = value,
+            RampSetting::SoftStart => config.soft_start_ms = value,
+        }
+        channel.set_config(config);
+        Ok(())
+    }
+
+    fn query(
+        &self,
+        _device: &mut MyDevice,
+        _context: &mut Context,
+        _params: Parameters,
+        mut resp: ResponseUnit,
+    ) -> scpi::error::Result<()> {
+        let config = self.0.ramp().config();
+        let value = match self.1 {
+            RampSetting::Rate => config.rate_percent_per_s,
+            RampSetting::SoftStart => config.soft_start_ms,
+        };
+        resp.data(value).finish()
+    }
+}
+
+/// SOURce<n>:RAMP:PROFile <LINear|SCURve> - Set/query soft-start shape
+struct RampProfileCommand(PwmChannel);
+
+impl Command<MyDevice> for RampProfileCommand {
+    cmd_both!();
+
+    fn event(
+        &self,
+        _device: &mut MyDevice,
+        _context: &mut Context,
+        mut params: Parameters,
+    ) -> Result<(), Error> {
+        let profile = next_choice(&mut params, RAMP_PROFILES)?;
+        let channel = self.0.ramp();
+        let mut config = channel.config();
+        config.profile = profile;
+        channel.set_config(config);
+        Ok(())
+    }
+
+    fn query(
+        &self,
+        _device: &mut MyDevice,
+        _context: &mut Context,
+        _params: Parameters,
+        mut resp: ResponseUnit,
+    ) -> scpi::error::Result<()> {
+        let profile: &[u8] = match self.0.ramp().config().profile {
+            RampProfile::Linear => b"LIN",
+            RampProfile::SCurve => b"SCUR",
+        };
+        resp.data(profile).finish()
+    }
+}
+
+/// SOURce<n>:RAMP:STATe? - 1 while the output is ramping
+///
+/// The end of each ramp is logged as `RAMP<n>` in `SYSTem:EVENt:DATA?`.
+struct RampStateCommand(PwmChannel);
+
+impl Command<MyDevice> for RampStateCommand {
+    cmd_qonly!();
+
+    fn query(
+        &self,
+        _device: &mut MyDevice,
+        _context: &mut Context,
+        _params: Parameters,
+        mut resp: ResponseUnit,
+    ) -> scpi::error::Result<()> {
+        resp.data(self.0.ramp().is_active()).finish()
+    }
+}
+
+// ============================================================================
+// PWM OUTPUT COMMANDS
+// ============================================================================
+
+fn update_pwm(update: impl FnOnce(&mut PwmConfig)) {
+    PWM_CONFIG.lock(|pwm| {
+        let mut config = pwm.get();
+        update(&mut config);
+        pwm.set(config);
+    });
+    PWM_UPDATE.signal(());
+}
+
+/// CH2 shares its compare unit with the PWM-synchronised ADC trigger.
+fn check_sync_conflict(channel: PwmChannel) -> Result<(), Error> {
+    if sync_owns(channel) {
+        return Err(ErrorCode::SettingsConflict.into());
+    }
+    Ok(())
+}
+
+/// SOURce<n>:PWM:DUTY <percent> - Set/query channel duty (CH1 is owned by SPEEd, query only)
+///
+/// Kept as a fraction of the period, so it does not change with the frequency.
+struct PwmDutyCommand(PwmChannel);
+
+impl Command<MyDevice> for PwmDutyCommand {
+    cmd_both!();
+
+    fn event(
+        &self,
+        _device: &mut MyDevice,
+        _context: &mut Context,
+        mut params: Parameters,
+    ) -> Result<(), Error> {
+        let percent: f32 = params.next_data()?;
+        if self.0 == PwmChannel::Ch1 {
+            return Err(ErrorCode::SettingsConflict.into());
+        }
+        check_sync_conflict(self.0)?;
+        check_dac_conflict(self.0)?;
+        if !(0.0..=100.0).contains(&percent) {
+            return Err(ErrorCode::DataOutOfRange.into());
+        }
+        let duty = Duty::from_fraction(percent / 100.0);
+        info!("SCPI: PWM {} DUTY {}", self.0, duty);
+        SHARED_DUTY.try_send((self.0, duty)).map_err(|_| queue_full())?;
+        Ok(())
+    }
+
+    fn query(
+        &self,
+        _device: &mut MyDevice,
+        _context: &mut Context,
+        _params: Parameters,
+        mut resp: ResponseUnit,
+    ) -> scpi::error::Result<()> {
+        let duty = PWM_STATUS.lock(|pwm| pwm.get()).duty[self.0.index()];
+        resp.data(duty.fraction() * 100.0).finish()
+    }
+}
+
+/// SOURce<n>:PWM:STATe <ON|OFF> - Enable/query the channel outputs (CHx and/or CHxN)
+struct PwmStateCommand(PwmChannel);
+
+impl Command<MyDevice> for PwmStateCommand {
+    cmd_both!();
+
+    fn event(
+        &self,
+        _device: &mut MyDevice,
+        _context: &mut Context,
+        mut params: Parameters,
+    ) -> Result<(), Error> {
+        let enabled: bool = params.next_data()?;
+        if enabled {
+            check_sync_conflict(self.0)?;
+        } else {
+            check_dac_conflict(self.0)?;
+        }
+        info!("SCPI: PWM {} STATE {}", self.0, enabled);
+        update_pwm(|config| config.enabled[self.0.index()] = enabled);
+        Ok(())
+    }
+
+    fn query(
+        &self,
+        _device: &mut MyDevice,
+        _context: &mut Context,
+        _params: Parameters,
+        mut resp: ResponseUnit,
+    ) -> scpi::error::Result<()> {
+        let enabled = PWM_CONFIG.lock(|pwm| pwm.get()).enabled[self.0.index()];
+        resp.data(enabled).finish()
+    }
+}
+
+/// Polarity mnemonics, mapped to `PwmConfig::inverted`
+const PWM_POLARITIES: &[(&[u8], bool)] = &[(b"NORMal", false), (b"INVerted", true)];
+
+/// SOURce<n>:PWM:POLarity <NORMal|INVerted> - Set/query channel output polarity (stored)
+struct PwmPolarityCommand(PwmChannel);
+
+impl Command<MyDevice> for PwmPolarityCommand {
+    cmd_both!();
+
+    fn event(
+        &self,
+        _device: &mut MyDevice,
+        _context: &mut Context,
+        mut params: Parameters,
+    ) -> Result<(), Error> {
+        let inverted = next_choice(&mut params, PWM_POLARITIES)?;
+        check_dac_conflict(self.0)?;
+        info!("SCPI: PWM {} POLARITY inverted={}", self.0, inverted);
+        update_pwm(|config| config.inverted[self.0.index()] = inverted);
+        SETTINGS_SAVE.signal(());
+        Ok(())
+    }
+
+    fn query(
+        &self,
+        _device: &mut MyDevice,
+        _context: &mut Context,
+        _params: Parameters,
+        mut resp: ResponseUnit,
+    ) -> scpi::error::Result<()> {
+        let inverted = PWM_CONFIG.lock(|pwm| pwm.get()).inverted[self.0.index()];
+        let polarity: &[u8] = if inverted { b"INV" } else { b"NORM" };
+        resp.data(polarity).finish()
+    }
+}
+
+/// SOURce:PWM:FREQuency <Hz> - Set/query the TIM1 carrier frequency (stored)
+///
+/// All channels share the timer; duties keep their percentage across a change.
+struct PwmFrequencyCommand;
+
+impl Command<MyDevice> for PwmFrequencyCommand {
+    cmd_both!();
+
+    fn event(
+        &self,
+        _device: &mut MyDevice,
+        _context: &mut Context,
+        mut params: Parameters,
+    ) -> Result<(), Error> {
+        let frequency_hz: u32 = params.next_data()?;
+        if !(PWM_FREQUENCY_MIN_HZ..=PWM_FREQUENCY_MAX_HZ).contains(&frequency_hz) {
+            return Err(ErrorCode::DataOutOfRange.into());
+        }
+        // The PWM DAC needs a fast carrier for its resolution and ripple
+        if frequency_hz < DAC_MIN_CARRIER_HZ && DAC_CONFIG.lock(|dac| dac.get()).enabled {
+            return Err(ErrorCode::SettingsConflict.into());
+        }
+        info!("SCPI: PWM FREQUENCY {} Hz", frequency_hz);
+        update_pwm(|config| config.frequency_hz = frequency_hz);
+        SETTINGS_SAVE.signal(());
+        Ok(())
+    }
+
+    fn query(
+        &self,
+        _device: &mut MyDevice,
+        _context: &mut Context,
+        _params: Parameters,
+        mut resp: ResponseUnit,
+    ) -> scpi::error::Result<()> {
+        let frequency_hz = PWM_CONFIG.lock(|pwm| pwm.get()).frequency_hz;
+        resp.data(frequency_hz).finish()
+    }
+}
+
+/// SOURce:PWM:MAXimum? - Duty value of 100 % (timer ticks)
+struct PwmMaxDutyCommand;
+
+impl Command<MyDevice> for PwmMaxDutyCommand {
+    cmd_qonly!();
+
+    fn query(
+        &self,
+        _device: &mut MyDevice,
+        _context: &mut Context,
+        _params: Parameters,
+        mut resp: ResponseUnit,
+    ) -> scpi::error::Result<()> {
+        let max_duty = PWM_STATUS.lock(|pwm| pwm.get()).max_duty;
+        resp.data(max_duty).finish()
+    }
+}
+
+/// SOURce:PWM:DTIMe <ns> - Set/query dead time between CHx and CHxN, in steps
+/// of the TIM1 clock (125 ns on the 8 MHz HSI clock)
+struct PwmDeadTimeCommand;
+
+impl Command<MyDevice> for PwmDeadTimeCommand {
+    cmd_both!();
+
+    fn event(
+        &self,
+        _device: &mut MyDevice,
+        _context: &mut Context,
+        mut params: Parameters,
+    ) -> Result<(), Error> {
+        let dead_time_ns: u16 = params.next_data()?;
+        info!("SCPI: PWM DEAD TIME {} ns", dead_time_ns);
+        update_pwm(|config| config.dead_time_ns = dead_time_ns);
+        Ok(())
+    }
+
+    fn query(
+        &self,
+        _device: &mut MyDevice,
+        _context: &mut Context,
+        _params: Parameters,
+        mut resp: ResponseUnit,
+    ) -> scpi::error::Result<()> {
+        let dead_time_ns = PWM_CONFIG.lock(|pwm| pwm.get()).dead_time_ns;
+        resp.data(dead_time_ns).finish()
+    }
+}
+
+/// SOURce:PWM:BREak[:STATe] <ON|OFF> - Enable/query the break input (PB12, active low)
+struct PwmBreakStateCommand;
+
+impl Command<MyDevice> for PwmBreakStateCommand {
+    cmd_both!();
+
+    fn event(
+        &self,
+        _device: &mut MyDevice,
+        _context: &mut Context,
+        mut params: Parameters,
+    ) -> Result<(), Error> {
+        let enabled: bool = params.next_data()?;
+        info!("SCPI: PWM BREAK {}", enabled);
+        update_pwm(|config| config.break_enabled = enabled);
+        Ok(())
+    }
+
+    fn query(
+        &self,
+        _device: &mut MyDevice,
+        _context: &mut Context,
+        _params: Parameters,
+        mut resp: ResponseUnit,
+    ) -> scpi::error::Result<()> {
+        let enabled = PWM_CONFIG.lock(|pwm| pwm.get()).break_enabled;
+        resp.data(enabled).finish()
+    }
+}
+
+/// SOURce:PWM:BREak:TRIPped? - 1 while the break has the outputs shut down
+struct PwmBreakTrippedCommand;
+
+impl Command<MyDevice> for PwmBreakTrippedCommand {
+    cmd_qonly!();
+
+    fn query(
+        &self,
+        _device: &mut MyDevice,
+        _context: &mut Context,
+        _params: Parameters,
+        mut resp: ResponseUnit,
+    ) -> scpi::error::Result<()> {
+        let tripped = PWM_STATUS.lock(|pwm| pwm.get()).break_tripped;
+        resp.data(tripped).finish()
+    }
+}
+
+/// SOURce:PWM:BREak:CLEar - Re-enable the outputs after a break
+struct PwmBreakClearCommand;
+
+impl Command<MyDevice> for PwmBreakClearCommand {
+    cmd_nquery!();
+
+    fn event(
+        &self,
+        _device: &mut MyDevice,
+        _context: &mut Context,
+        _params: Parameters,
+    ) -> Result<(), Error> {
+        info!("SCPI: PWM BREAK CLEAR");
+        PWM_BREAK_CLEAR.signal(());
+        Ok(())
+    }
+}
+
+// ============================================================================
+// FUNCTION GENERATOR COMMANDS
+// ============================================================================
+
+fn update_waveform(update: impl FnOnce(&mut WaveformConfig)) {
+    WAVEFORM_CONFIG.lock(|wave| {
+        let mut config = wave.get();
+        update(&mut config);
+        wave.set(config);
+    });
+    PWM_UPDATE.signal(());
+}
+
+/// A user table needs at least two samples before it can be played.
+fn check_user_table(shape: WaveShape) -> Result<(), Error> {
+    let len = WAVEFORM_TABLE.lock(|table| table.borrow().len());
+    if shape == WaveShape::User && len < WAVEFORM_MIN_POINTS {
+        return Err(ErrorCode::SettingsConflict.into());
+    }
+    Ok(())
+}
+
+const WAVE_SHAPES: &[(&[u8], WaveShape)] = &[
+    (b"SINusoid", WaveShape::Sine),
+    (b"TRIangle", WaveShape::Triangle),
+    (b"RAMP", WaveShape::Ramp),
+    (b"USER", WaveShape::User),
+];
+
+/// SOURce:FUNCtion[:SHAPe] <SINusoid|TRIangle|RAMP|USER> - Set/query waveform
+struct FunctionShapeCommand;
+
+impl Command<MyDevice> for FunctionShapeCommand {
+    cmd_both!();
+
+    fn event(
+        &self,
+        _device: &mut MyDevice,
+        _context: &mut Context,
+        mut params: Parameters,
+    ) -> Result<(), Error> {
+        let shape = next_choice(&mut params, WAVE_SHAPES)?;
+        if WAVEFORM_CONFIG.lock(|wave| wave.get()).enabled {
+            check_user_table(shape)?;
+        }
+        info!("SCPI: FUNCTION SHAPE {}", shape);
+        update_waveform(|config| config.shape = shape);
+        Ok(())
+    }
+
+    fn query(
+        &self,
+        _device: &mut MyDevice,
+        _context: &mut Context,
+        _params: Parameters,
+        mut resp: ResponseUnit,
+    ) -> scpi::error::Result<()> {
+        let shape: &[u8] = match WAVEFORM_CONFIG.lock(|wave| wave.get()).shape {
+            WaveShape::Sine => b"SIN",
+            WaveShape::Triangle => b"TRI",
+            WaveShape::Ramp => b"RAMP",
+            WaveShape::User => b"USER",
+        };
+        resp.data(shape).finish()
+    }
+}
+
+/// SOURce:FUNCtion:STATe <ON|OFF> - Start/stop/query the function generator
+struct FunctionStateCommand;
+
+impl Command<MyDevice> for FunctionStateCommand {
+    cmd_both!();
+
+    fn event(
+        &self,
+        _device: &mut MyDevice,
+        _context: &mut Context,
+        mut params: Parameters,
+    ) -> Result<(), Error> {
+        let enabled: bool = params.next_data()?;
+        if enabled {
+            let config = WAVEFORM_CONFIG.lock(|wave| wave.get());
+            check_sync_conflict(config.channel)?;
+            check_dac_conflict(config.channel)?;
+            check_user_table(config.shape)?;
+        }
+        info!("SCPI: FUNCTION STATE {}", enabled);
+        update_waveform(|config| config.enabled = enabled);
+        Ok(())
+    }
+
+    fn query(
+        &self,
+        _device: &mut MyDevice,
+        _context: &mut Context,
+        _params: Parameters,
+        mut resp: ResponseUnit,
+    ) -> scpi::error::Result<()> {
+        let enabled = WAVEFORM_CONFIG.lock(|wave| wave.get()).enabled;
+        resp.data(enabled).finish()
+    }
+}
+
+/// SOURce:FUNCtion:CHANnel <2..4> - Set/query the output playing the waveform
+struct FunctionChannelCommand;
+
+impl Command<MyDevice> for FunctionChannelCommand {
+    cmd_both!();
+
+    fn event(
+        &self,
+        _device: &mut MyDevice,
+        _context: &mut Context,
+        mut params: Parameters,
+    ) -> Result<(), Error> {
+        let number: u8 = params.next_data()?;
+        if !(2..=4).contains(&number) {
+            return Err(ErrorCode::DataOutOfRange.into());
+        }
+        let channel = PwmChannel::ALL[usize::from(number) - 1];
+        if WAVEFORM_CONFIG.lock(|wave| wave.get()).enabled {
+            check_sync_conflict(channel)?;
+            check_dac_conflict(channel)?;
+        }
+        info!("SCPI: FUNCTION CHANNEL {}", channel);
+        update_waveform(|config| config.channel = channel);
+        Ok(())
+    }
+
+    fn query(
+        &self,
+        _device: &mut MyDevice,
+        _context: &mut Context,
+        _params: Parameters,
+        mut resp: ResponseUnit,
+    ) -> scpi::error::Result<()> {
+        let channel = WAVEFORM_CONFIG.lock(|wave| wave.get()).channel;
+        resp.data(channel.index() as u8 + 1).finish()
+    }
+}
+
+/// SOURce:FUNCtion:POINts <n> - Set/query samples per period of the built-in shapes
+struct FunctionPointsCommand;
+
+impl Command<MyDevice> for FunctionPointsCommand {
+    cmd_both!();
+
+    fn event(
+        &self,
+        _device: &mut MyDevice,
+        _context: &mut Context,
+        mut params: Parameters,
+    ) -> Result<(), Error> {
+        let points: u16 = params.next_data()?;
+        if !(WAVEFORM_MIN_POINTS..=WAVEFORM_MAX_POINTS).contains(&usize::from(points)) {
+            return Err(ErrorCode::DataOutOfRange.into());
+        }
+        info!("SCPI: FUNCTION POINTS {}", points);
+        update_waveform(|config| config.points = points);
+        Ok(())
+    }
+
+    fn query(
+        &self,
+        _device: &mut MyDevice,
+        _context: &mut Context,
+        _params: Parameters,
+        mut resp: ResponseUnit,
+    ) -> scpi::error::Result<()> {
+        let points = WAVEFORM_CONFIG.lock(|wave| wave.get()).points;
+        resp.data(points).finish()
+    }
+}
+
+#[derive(Clone, Copy)]
+enum WaveLevel {
+    Amplitude,
+    Offset,
+}
+
+/// SOURce:FUNCtion:AMPLitude <%> / OFFSet <%> - Set/query peak-to-peak swing
+/// and centre of the built-in shapes (percent duty)
+struct FunctionLevelCommand(WaveLevel);
+
+impl Command<MyDevice> for FunctionLevelCommand {
+    cmd_both!();
+
+    fn event(
+        &self,
+        _device: &mut MyDevice,
+        _context: &mut Context,
+        mut params: Parameters,
+    ) -> Result<(), Error> {
+        let percent: f32 = params.next_data()?;
+        if !(0.0..=100.0).contains(&percent) {
+            return Err(ErrorCode::DataOutOfRange.into());
+        }
+        let level = Duty::from_fraction(percent / 100.0);
+        update_waveform(|config| match self.0 {
+            WaveLevel::Amplitude => config.amplitude = level,
+            WaveLevel::Offset => config.offset = level,
+        });
+        Ok(())
+    }
+
+    fn query(
+        &self,
+        _device: &mut MyDevice,
+        _context: &mut Context,
+        _params: Parameters,
+        mut resp: ResponseUnit,
+    ) -> scpi::error::Result<()> {
+        let config = WAVEFORM_CONFIG.lock(|wave| wave.get());
+        let level = match self.0 {
+            WaveLevel::Amplitude => config.amplitude,
+            WaveLevel::Offset => config.offset,
+        };
+        resp.data(level.fraction() * 100.0).finish()
+    }
+}
+
+/// SOURce:FUNCtion:FREQuency? - Output frequency: PWM frequency / samples per period
+struct FunctionFrequencyCommand;
+
+impl Command<MyDevice> for FunctionFrequencyCommand {
+    cmd_qonly!();
+
+    fn query(
+        &self,
+        _device: &mut MyDevice,
+        _context: &mut Context,
+        _params: Parameters,
+        mut resp: ResponseUnit,
+    ) -> scpi::error::Result<()> {
+        let config = WAVEFORM_CONFIG.lock(|wave| wave.get());
+        let points = match config.shape {
+            WaveShape::User => WAVEFORM_TABLE.lock(|table| table.borrow().len()),
+            _ => usize::from(config.points),
+        };
+        let frequency_hz = PWM_CONFIG.lock(|pwm| pwm.get()).frequency_hz;
+        let frequency = if points == 0 { 0.0 } else { frequency_hz as f32 / points as f32 };
+        resp.data(frequency).finish()
+    }
+}
+
+/// Parse a table upload: 16-bit big-endian duty values in 0.01 % steps.
+fn parse_table(params: &mut Parameters, table: &mut WaveTable) -> Result<(), Error> {
+    let Arbitrary(data) = params.next_data()?;
+    if data.len() % 2 != 0 {
+        return Err(ErrorCode::IllegalParameterValue.into());
+    }
+    for pair in data.chunks_exact(2) {
+        let raw = u16::from_be_bytes([pair[0], pair[1]]);
+        if raw > Duty::SCALE {
+            return Err(ErrorCode::DataOutOfRange.into());
+        }
+        table
+            .push(Duty::from_raw(raw))
+            .map_err(|_| Error::from(ErrorCode::TooMuchData))?;
+    }
+    Ok(())
+}
+
+/// Re-render the table if it is being played.
+fn table_changed() {
+    let config = WAVEFORM_CONFIG.lock(|wave| wave.get());
+    if config.enabled && config.shape == WaveShape::User {
+        PWM_UPDATE.signal(());
+    }
+}
+
+/// SOURce:FUNCtion:DATA <block> - Replace/query the USER table
+///
+/// The block holds 16-bit big-endian duty values in 0.01 % steps (0..10000);
+/// the query returns them as numbers.
+struct FunctionDataCommand;
+
+impl Command<MyDevice> for FunctionDataCommand {
+    cmd_both!();
+
+    fn event(
+        &self,
+        _device: &mut MyDevice,
+        _context: &mut Context,
+        mut params: Parameters,
+    ) -> Result<(), Error> {
+        let mut table = WaveTable::new();
+        parse_table(&mut params, &mut table)?;
+        let config = WAVEFORM_CONFIG.lock(|wave| wave.get());
+        if config.enabled && config.shape == WaveShape::User && table.len() < WAVEFORM_MIN_POINTS {
+            return Err(ErrorCode::SettingsConflict.into());
+        }
+        info!("SCPI: FUNCTION DATA {} points", table.len());
+        WAVEFORM_TABLE.lock(|stored| *stored.borrow_mut() = table);
+        table_changed();
+        Ok(())
+    }
+
+    fn query(
+        &self,
+        _device: &mut MyDevice,
+        _context: &mut Context,
+        _params: Parameters,
+        mut resp: ResponseUnit,
+    ) -> scpi::error::Result<()> {
+        WAVEFORM_TABLE.lock(|table| {
+            for duty in table.borrow().iter() {
+                resp.data(duty.raw());
+            }
+            resp.finish()
+        })
+    }
+}
+
+/// SOURce:FUNCtion:DATA:APPend <block> - Add samples to the USER table, for
+/// uploads longer than one command line
+struct FunctionDataAppendCommand;
+
+impl Command<MyDevice> for FunctionDataAppendCommand {
+    cmd_nquery!();
+
+    fn event(
+        &self,
+        _device: &mut MyDevice,
+        _context: &mut Context,
+        mut params: Parameters,
+    ) -> Result<(), Error> {
+        let mut table = WAVEFORM_TABLE.lock(|table| table.borrow().clone());
+        parse_table(&mut params, &mut table)?;
+        info!("SCPI: FUNCTION DATA APPEND, {} points", table.len());
+        WAVEFORM_TABLE.lock(|stored| *stored.borrow_mut() = table);
+        table_changed();
+        Ok(())
+    }
+}
+
+// ============================================================================
+// PWM DAC COMMANDS
+// ============================================================================
+
+fn update_dac(update: impl FnOnce(&mut DacConfig)) {
+    DAC_CONFIG.lock(|dac| {
+        let mut config = dac.get();
+        update(&mut config);
+        dac.set(config);
+    });
+    DAC_UPDATE.signal(());
+}
+
+/// The DAC owns its channel while it is on.
+fn check_dac_conflict(channel: PwmChannel) -> Result<(), Error> {
+    if channel == DAC_CHANNEL && DAC_CONFIG.lock(|dac| dac.get()).enabled {
+        return Err(ErrorCode::SettingsConflict.into());
+    }
+    Ok(())
+}
+
+/// Claim the DAC channel: refused while the function generator plays on it,
+/// with an inverted output or with a carrier below `DAC_MIN_CARRIER_HZ`,
+/// otherwise its output is switched on.
+fn claim_dac_channel() -> Result<(), Error> {
+    let wave = WAVEFORM_CONFIG.lock(|wave| wave.get());
+    if wave.enabled && wave.channel == DAC_CHANNEL {
+        return Err(ErrorCode::SettingsConflict.into());
+    }
+    let pwm = PWM_CONFIG.lock(|pwm| pwm.get());
+    if pwm.inverted[DAC_CHANNEL.index()] || pwm.frequency_hz < DAC_MIN_CARRIER_HZ {
+        return Err(ErrorCode::SettingsConflict.into());
+    }
+    if !pwm.enabled[DAC_CHANNEL.index()] {
+        update_pwm(|config| config.enabled[DAC_CHANNEL.index()] = true);
+    }
+    Ok(())
+}
+
+/// SOURce:VOLTage[:LEVel] <V> - Set (and switch on)/query the PWM DAC output
+///
+/// Output is CH4 (PA11) through an RC filter; carrier is `SOURce:PWM:FREQuency`,
+/// which must be at least 10 kHz. While the DAC is on, the other users of CH4
+/// (`SOURce4:PWM`, the function generator) are rejected.
+struct DacLevelCommand;
+
+impl Command<MyDevice> for DacLevelCommand {
+    cmd_both!();
+
+    fn event(
+        &self,
+        _device: &mut MyDevice,
+        _context: &mut Context,
+        mut params: Parameters,
+    ) -> Result<(), Error> {
+        let volts: f32 = params.next_data()?;
+        let max_volts = DAC_MAX_MV as f32 / 1000.0;
+        if !(0.0..=max_volts).contains(&volts) {
+            return Err(ErrorCode::DataOutOfRange.into());
+        }
+        claim_dac_channel()?;
+        let target_mv = (volts * 1000.0 + 0.5) as u32;
+        info!("SCPI: DAC VOLTAGE {} mV", target_mv);
+        update_dac(|config| {
+            config.target_mv = target_mv;
+            config.enabled = true;
+        });
+        Ok(())
+    }
+
+    fn query(
+        &self,
+        _device: &mut MyDevice,
+        _context: &mut Context,
+        _params: Parameters,
+        mut resp: ResponseUnit,
+    ) -> scpi::error::Result<()> {
+        let target_mv = DAC_CONFIG.lock(|dac| dac.get()).target_mv;
+        resp.data(target_mv as f32 / 1000.0).finish()
+    }
+}
+
+/// SOURce:VOLTage:STATe <ON|OFF> - Switch/query the PWM DAC (OFF frees CH4)
+struct DacStateCommand;
+
+impl Command<MyDevice> for DacStateCommand {
+    cmd_both!();
+
+    fn event(
+        &self,
+        _device: &mut MyDevice,
+        _context: &mut Context,
+        mut params: Parameters,
+    ) -> Result<(), Error> {
+        let enabled: bool = params.next_data()?;
+        if enabled {
+            claim_dac_channel()?;
+        }
+        info!("SCPI: DAC STATE {}", enabled);
+        update_dac(|config| config.enabled = enabled);
+        Ok(())
+    }
+
+    fn query(
+        &self,
+        _device: &mut MyDevice,
+        _context: &mut Context,
+        _params: Parameters,
+        mut resp: ResponseUnit,
+    ) -> scpi::error::Result<()> {
+        let enabled = DAC_CONFIG.lock(|dac| dac.get()).enabled;
+        resp.data(enabled).finish()
+    }
+}
+
+/// SOURce:VOLTage:CORRection <ON|OFF> - Enable/query closed-loop trim from the PA2 readback
+struct DacCorrectionCommand;
+
+impl Command<MyDevice> for DacCorrectionCommand {
+    cmd_both!();
+
+    fn event(
+        &self,
+        _device: &mut MyDevice,
+        _context: &mut Context,
+        mut params: Parameters,
+    ) -> Result<(), Error> {
+        let correction: bool = params.next_data()?;
+        info!("SCPI: DAC CORRECTION {}", correction);
+        update_dac(|config| config.correction = correction);
+        Ok(())
+    }
+
+    fn query(
+        &self,
+        _device: &mut MyDevice,
+        _context: &mut Context,
+        _params: Parameters,
+        mut resp: ResponseUnit,
+    ) -> scpi::error::Result<()> {
+        let correction = DAC_CONFIG.lock(|dac| dac.get()).correction;
+        resp.data(correction).finish()
+    }
+}
+
+/// MEASure:VOLTage:DAC? - PWM DAC output read back on PA2 (mV)
+struct DacReadbackCommand;
+
+impl Command<MyDevice> for DacReadbackCommand {
+    cmd_qonly!();
+
+    fn query(
+        &self,
+        _device: &mut MyDevice,
+        _context: &mut Context,
+        _params: Parameters,
+        mut resp: ResponseUnit,
+    ) -> scpi::error::Result<()> {
+        let status = DAC_STATUS.lock(|dac| dac.get());
+        resp.data(status.readback_mv).finish()
+    }
+}
+
+/// CALibration:VOLTage - Start the DAC calibration; query returns IDLE|RUN|PASS|FAIL
+///
+/// Drives CH4 to 25 % and 75 % and reads PA2, so the filter output must be
+/// wired to PA2.
+struct DacCalibrationCommand;
+
+impl Command<MyDevice> for DacCalibrationCommand {
+    cmd_both!();
+
+    fn event(
+        &self,
+        _device: &mut MyDevice,
+        _context: &mut Context,
+        _params: Parameters,
+    ) -> Result<(), Error> {
+        let state = DAC_CALIBRATION_STATE.lock(|state| state.get());
+        if state == CalibrationState::Running {
+            return Err(ErrorCode::SettingsConflict.into());
+        }
+        claim_dac_channel()?;
+        info!("SCPI: CALIBRATION VOLTAGE");
+        DAC_CALIBRATION_REQUEST.signal(());
+        Ok(())
+    }
+
+    fn query(
+        &self,
+        _device: &mut MyDevice,
+        _context: &mut Context,
+        _params: Parameters,
+        mut resp: ResponseUnit,
+    ) -> scpi::error::Result<()> {
+        let state = DAC_CALIBRATION_STATE.lock(|state| state.get());
+        resp.data(calibration_state_name(state)).finish()
+    }
+}
+
+/// CALibration:VOLTage:DATA? - DAC full scale,offset (mV)
+struct DacCalibrationDataCommand;
+
+impl Command<MyDevice> for DacCalibrationDataCommand {
+    cmd_qonly!();
+
+    fn query(
+        &self,
+        _device: &mut MyDevice,
+        _context: &mut Context,
+        _params: Parameters,
+        mut resp: ResponseUnit,
+    ) -> scpi::error::Result<()> {
+        let calibration = DAC_CALIBRATION.lock(|dac| dac.get());
+        resp.data(calibration.full_scale_mv)
+            .data(calibration.offset_mv)
+            .finish()
+    }
+}
+
+// ============================================================================
+// THERMAL POLICY COMMANDS
+// ============================================================================
+
+const TEMPERATURE_SOURCES: &[(&[u8], TemperatureSource)] = &[
+    (b"INTernal", TemperatureSource::Internal),
+    (b"EXTernal", TemperatureSource::External),
+];
+
+fn deci_from_celsius(celsius: f32) -> i32 {
+    (celsius * 10.0) as i32
+}
+
+fn celsius_from_deci(deci: i32) -> f32 {
+    deci as f32 / 10.0
+}
+
+fn update_thermal(update: impl FnOnce(&mut ThermalConfig)) {
+    THERMAL_CONFIG.lock(|thermal| {
+        let mut config = thermal.get();
+        update(&mut config);
+        thermal.set(config);
+    });
+}
+
+/// MEASure:TEMPerature? - Temperature of the selected thermal source (°C)
+struct TemperatureCommand;
+
+impl Command<MyDevice> for TemperatureCommand {
+    cmd_qonly!();
+
+    fn query(
+        &self,
+        _device: &mut MyDevice,
+        _context: &mut Context,
+        _params: Parameters,
+        mut resp: ResponseUnit,
+    ) -> scpi::error::Result<()> {
+        let temperature = match THERMAL_CONFIG.lock(|thermal| thermal.get()).source {
+            TemperatureSource::Internal => TEMPERATURE.lock(|temperature| temperature.get()),
+            TemperatureSource::External => PROBE_TEMPERATURE.lock(|temperature| temperature.get()),
+        };
+        resp.data(celsius_from_deci(temperature)).finish()
+    }
+}
+
+/// THERmal:STATe <ON|OFF> - Enable/query automatic fan control
+struct ThermalStateCommand;
+
+impl Command<MyDevice> for ThermalStateCommand {
+    cmd_both!();
+
+    fn event(
+        &self,
+        _device: &mut MyDevice,
+        _context: &mut Context,
+        mut params: Parameters,
+    ) -> Result<(), Error> {
+        let enabled: bool = params.next_data()?;
+        info!("SCPI: THERMAL STATE {}", enabled);
+        update_thermal(|config| config.enabled = enabled);
+        Ok(())
+    }
+
+    fn query(
+        &self,
+        _device: &mut MyDevice,
+        _context: &mut Context,
+        _params: Parameters,
+        mut resp: ResponseUnit,
+    ) -> scpi::error::Result<()> {
+        let enabled = THERMAL_CONFIG.lock(|thermal| thermal.get()).enabled;
+        resp.data(enabled).finish()
+    }
+}
+
+/// THERmal:SOURce <INTernal|EXTernal> - Set/query the temperature input
+struct ThermalSourceCommand;
+
+impl Command<MyDevice> for ThermalSourceCommand {
+    cmd_both!();
+
+    fn event(
+        &self,
+        _device: &mut MyDevice,
+        _context: &mut Context,
+        mut params: Parameters,
+    ) -> Result<(), Error> {
+        let source = next_choice(&mut params, TEMPERATURE_SOURCES)?;
+        info!("SCPI: THERMAL SOURCE {:?}", source);
+        update_thermal(|config| config.source = source);
+        Ok(())
+    }
+
+    fn query(
+        &self,
+        _device: &mut MyDevice,
+        _context: &mut Context,
+        _params: Parameters,
+        mut resp: ResponseUnit,
+    ) -> scpi::error::Result<()> {
+        let source: &[u8] = match THERMAL_CONFIG.lock(|thermal| thermal.get()).source {
+            TemperatureSource::Internal => b"INT",
+            TemperatureSource::External => b"EXT",
+        };
+        resp.data(source).finish()
+    }
+}
+
+/// THERmal:CURVe <t1>,<s1>[,<t2>,<s2>...] - Set/query fan curve (°C, %), up to 4 points
+struct ThermalCurveCommand;
+
+impl Command<MyDevice> for ThermalCurveCommand {
+    cmd_both!();
+
+    fn event(
+        &self,
+        _device: &mut MyDevice,
+        _context: &mut Context,
+        mut params: Parameters,
+    ) -> Result<(), Error> {
+        let mut curve = [CurvePoint { temperature: 0, speed: 0 }; CURVE_POINTS];
+        let mut points = 0;
+        while let Some(celsius) = params.next_optional_data::<f32>()? {
+            let speed: u16 = params.next_data()?;
+            if points == CURVE_POINTS {
+                return Err(ErrorCode::TooMuchData.into());
+            }
+            curve[points] = CurvePoint {
+                temperature: deci_from_celsius(celsius),
+                speed,
+            };
+            points += 1;
+        }
+
+        let mut config = THERMAL_CONFIG.lock(|thermal| thermal.get());
+        if !config.set_curve(&curve[..points]) {
+            return Err(ErrorCode::IllegalParameterValue.into());
+        }
+        info!("SCPI: THERMAL CURVE, {} points", points);
+        THERMAL_CONFIG.lock(|thermal| thermal.set(config));
+        Ok(())
+    }
+
+    fn query(
+        &self,
+        _device: &mut MyDevice,
+        _context: &mut Context,
+        _params: Parameters,
+        mut resp: ResponseUnit,
+    ) -> scpi::error::Result<()> {
+        let config = THERMAL_CONFIG.lock(|thermal| thermal.get());
+        for point in config.curve() {
+            resp.data(celsius_from_deci(point.temperature)).data(point.speed);
+        }
+        resp.finish()
+    }
+}
+
+#[derive(Clone, Copy)]
+enum ThermalLimit {
+    Hysteresis,
+    Critical,
+}
+
+/// THERmal:HYSTeresis / THERmal:CRITical <°C> - Set/query turn-off hysteresis / full-speed limit
+struct ThermalLimitCommand(ThermalLimit);
+
+impl Command<MyDevice> for ThermalLimitCommand {
+    cmd_both!();
+
+    fn event(
+        &self,
+        _device: &mut MyDevice,
+        _context: &mut Context,
+        mut params: Parameters,
+    ) -> Result<(), Error> {
+        let value = deci_from_celsius(params.next_data::<f32>()?);
+        match self.0 {
+            ThermalLimit::Hysteresis if value < 0 => Err(ErrorCode::DataOutOfRange.into()),
+            ThermalLimit::Hysteresis => {
+                update_thermal(|config| config.hysteresis = value);
+                Ok(())
+            }
+            ThermalLimit::Critical => {
+                update_thermal(|config| config.critical = value);
+                Ok(())
+            }
+        }
+    }
+
+    fn query(
+        &self,
+        _device: &mut MyDevice,
+        _context: &mut Context,
+        _params: Parameters,
+        mut resp: ResponseUnit,
+    ) -> scpi::error::Result<()> {
+        let config = THERMAL_CONFIG.lock(|thermal| thermal.get());
+        let value = match self.0 {
+            ThermalLimit::Hysteresis => config.hysteresis,
+            ThermalLimit::Critical => config.critical,
+        };
+        resp.data(celsius_from_deci(value)).finish()
+    }
+}
+
+/// THERmal:MINRun <s> - Set/query minimum fan run time once started
+struct ThermalMinRunCommand;
+
+impl Command<MyDevice> for ThermalMinRunCommand {
+    cmd_both!();
+
+    fn event(
+        &self,
+        _device: &mut MyDevice,
+        _context: &mut Context,
+        mut params: Parameters,
+    ) -> Result<(), Error> {
+        let min_run_s: u32 = params.next_data()?;
+        update_thermal(|config| config.min_run_s = min_run_s);
+        Ok(())
+    }
+
+    fn query(
+        &self,
+        _device: &mut MyDevice,
+        _context: &mut Context,
+        _params: Parameters,
+        mut resp: ResponseUnit,
+    ) -> scpi::error::Result<()> {
+        let min_run_s = THERMAL_CONFIG.lock(|thermal| thermal.get()).min_run_s;
+        resp.data(min_run_s).finish()
+    }
+}
+
+// ============================================================================
+// CALIBRATION COMMANDS
+// ============================================================================
+
+fn calibration_state_name(state: CalibrationState) -> &'static [u8] {
+    match state {
+        CalibrationState::Idle => b"IDLE",
+        CalibrationState::Running => b"RUN",
+        CalibrationState::Passed => b"PASS",
+        CalibrationState::Failed => b"FAIL",
+    }
+}
+
+/// CALibration:COOLing - Start the fan calibration; query returns IDLE|RUN|PASS|FAIL
+struct CoolingCalibrationCommand;
+
+impl Command<MyDevice> for CoolingCalibrationCommand {
+    cmd_both!();
+
+    fn event(
+        &self,
+        _device: &mut MyDevice,
+        _context: &mut Context,
+        _params: Parameters,
+    ) -> Result<(), Error> {
+        let state = CALIBRATION_STATE.lock(|state| state.get());
+        if state == CalibrationState::Running {
+            return Err(ErrorCode::SettingsConflict.into());
+        }
+        info!("SCPI: CALIBRATION COOLING");
+        CALIBRATION_REQUEST.signal(());
+        Ok(())
+    }
+
+    fn query(
+        &self,
+        _device: &mut MyDevice,
+        _context: &mut Context,
+        _params: Parameters,
+        mut resp: ResponseUnit,
+    ) -> scpi::error::Result<()> {
+        let state = CALIBRATION_STATE.lock(|state| state.get());
+        resp.data(calibration_state_name(state)).finish()
+    }
+}
+
+/// CALibration:COOLing:DATA? - min duty,max duty (%),min rpm,max rpm
+struct CoolingCalibrationDataCommand;
+
+impl Command<MyDevice> for CoolingCalibrationDataCommand {
+    cmd_qonly!();
+
+    fn query(
+        &self,
+        _device: &mut MyDevice,
+        _context: &mut Context,
+        _params: Parameters,
+        mut resp: ResponseUnit,
+    ) -> scpi::error::Result<()> {
+        let calibration = FAN_CALIBRATION.lock(|fan| fan.get());
+        resp.data(calibration.min_duty_percent)
+            .data(calibration.max_duty_percent)
+            .data(calibration.min_rpm)
+            .data(calibration.max_rpm)
+            .finish()
+    }
+}
+
+/// CALibration:COOLing:TABLe? - Measured duty (%),rpm pairs; empty before the first calibration
+struct CoolingCalibrationTableCommand;
+
+impl Command<MyDevice> for CoolingCalibrationTableCommand {
+    cmd_qonly!();
+
+    fn query(
+        &self,
+        _device: &mut MyDevice,
+        _context: &mut Context,
+        _params: Parameters,
+        mut resp: ResponseUnit,
+    ) -> scpi::error::Result<()> {
+        let calibration = FAN_CALIBRATION.lock(|fan| fan.get());
+        if calibration.is_calibrated() {
+            for point in calibration.table {
+                resp.data(point.duty_percent).data(point.rpm);
+            }
+        }
+        resp.finish()
+    }
+}
+
+// ============================================================================
+// EVENT LOG COMMANDS
+// ============================================================================
+
+fn event_mnemonic(event: Event) -> &'static [u8] {
+    match event {
+        Event::FanStall => b"FSTALL",
+        Event::FanRecovered => b"FRECOVER",
+        Event::FanFailure => b"FFAIL",
+        Event::RailTrip(RailFault::UnderVoltage) => b"RUNDER",
+        Event::RailTrip(RailFault::OverVoltage) => b"ROVER",
+        Event::PwmBreak => b"PBREAK",
+        Event::RampDone(PwmChannel::Ch1) => b"RAMP1",
+        Event::RampDone(PwmChannel::Ch2) => b"RAMP2",
+        Event::RampDone(PwmChannel::Ch3) => b"RAMP3",
+        Event::RampDone(PwmChannel::Ch4) => b"RAMP4",
+    }
+}
+
+/// SYSTem:EVENt:COUNt? - Number of stored events
+struct EventCountCommand;
+
+impl Command<MyDevice> for EventCountCommand {
+    cmd_qonly!();
+
+    fn query(
+        &self,
+        _device: &mut MyDevice,
+        _context: &mut Context,
+        _params: Parameters,
+        mut resp: ResponseUnit,
+    ) -> scpi::error::Result<()> {
+        let count = EVENT_LOG.lock(|log| log.borrow().len());
+        resp.data(count as u32).finish()
+    }
+}
+
+/// SYSTem:EVENt:DATA? - time (s since boot),event pairs, oldest first
+struct EventDataCommand;
+
+impl Command<MyDevice> for EventDataCommand {
+    cmd_qonly!();
+
+    fn query(
+        &self,
+        _device: &mut MyDevice,
+        _context: &mut Context,
+        _params: Parameters,
+        mut resp: ResponseUnit,
+    ) -> scpi::error::Result<()> {
+        EVENT_LOG.lock(|log| {
+            let log = log.borrow();
+            for index in 0..log.len() {
+                if let Some(record) = log.get(index) {
+                    resp.data(record.time_s).data(event_mnemonic(record.event));
+                }
+            }
+        });
+        resp.finish()
+    }
+}
+
+/// SYSTem:EVENt:CLEar - Drop all stored events
+struct EventClearCommand;
+
+impl Command<MyDevice> for EventClearCommand {
+    cmd_nquery!();
+
+    fn event(
+        &self,
+        _device: &mut MyDevice,
+        _context: &mut Context,
+        _params: Parameters,
+    ) -> Result<(), Error> {
+        info!("SCPI: EVENT CLEAR");
+        EVENT_LOG.lock(|log| log.borrow_mut().clear());
+        Ok(())
     }
 }
 
 // ============================================================================
-// IDENTIFICATION COMMANDS
+// MEASUREMENT COMMANDS
 // ============================================================================
 
-/// *IDN? - Device identification query
-/// Returns: "PowerModule version 0.1.0"
-struct IdnCommand;
+/// Query of a single rail window statistic in mV, e.g. MEASure:VOLTage:RIPPle?
+struct RailStatisticCommand(fn(&WindowStats) -> u32);
 
-impl Command<MyDevice> for IdnCommand {
+impl Command<MyDevice> for RailStatisticCommand {
     cmd_qonly!();
 
     fn query(
@@ -32,73 +2799,112 @@ impl Command<MyDevice> for IdnCommand {
         _params: Parameters,
         mut resp: ResponseUnit,
     ) -> scpi::error::Result<()> {
-        resp.data(b"PowerModule version 0.1.0".as_slice()).finish()
+        let stats = RAIL_STATS.lock(|rail| rail.get());
+        resp.data((self.0)(&stats)).finish()
     }
 }
 
-// ============================================================================
-// LED CONTROL COMMANDS
-// ============================================================================
-
-/// LED:TOGGle - Toggle LED state
-struct LedToggleCommand;
+/// CALCulate:AVERage:ALL? - min,max,mean,rms,sdev,ptpeak of the last rail window (mV)
+struct RailStatisticsAllCommand;
 
-impl Command<MyDevice> for LedToggleCommand {
-    cmd_nquery!();
+impl Command<MyDevice> for RailStatisticsAllCommand {
+    cmd_qonly!();
 
-    fn event(
+    fn query(
         &self,
         _device: &mut MyDevice,
         _context: &mut Context,
         _params: Parameters,
-    ) -> Result<(), Error> {
-        info!("SCPI: LED TOGGLE");
-        let _ = LED_CHANNEL.try_send(LedState::Toggle);
-        Ok(())
+        mut resp: ResponseUnit,
+    ) -> scpi::error::Result<()> {
+        let stats = RAIL_STATS.lock(|rail| rail.get());
+        resp.data(stats.min)
+            .data(stats.max)
+            .data(stats.mean)
+            .data(stats.rms)
+            .data(stats.std_dev)
+            .data(stats.ripple)
+            .finish()
     }
 }
 
-/// LED:ON - Turn LED on
-struct LedOnCommand;
+/// MEASure:VOLTage:SYNChronous? - Mean of the PWM-synchronised rail samples (mV)
+struct SyncVoltageCommand;
 
-impl Command<MyDevice> for LedOnCommand {
-    cmd_nquery!();
+impl Command<MyDevice> for SyncVoltageCommand {
+    cmd_qonly!();
 
-    fn event(
+    fn query(
         &self,
         _device: &mut MyDevice,
         _context: &mut Context,
         _params: Parameters,
-    ) -> Result<(), Error> {
-        info!("SCPI: LED ON");
-        let _ = LED_CHANNEL.try_send(LedState::On);
-        Ok(())
+        mut resp: ResponseUnit,
+    ) -> scpi::error::Result<()> {
+        let stats = SYNC_STATS.lock(|sync| sync.get());
+        resp.data(stats.mean).data(stats.ripple).finish()
     }
 }
 
-/// LED:OFF - Turn LED off
-struct LedOffCommand;
+/// Query of the external PWM signal on PA6, e.g. MEASure:FREQuency? (Hz) or
+/// MEASure:DUTY? (%); 0 Hz while no edges arrive
+struct PwmInputCommand(fn(&PwmInputMeasurement) -> f32);
 
-impl Command<MyDevice> for LedOffCommand {
-    cmd_nquery!();
+impl Command<MyDevice> for PwmInputCommand {
+    cmd_qonly!();
 
-    fn event(
+    fn query(
         &self,
         _device: &mut MyDevice,
         _context: &mut Context,
         _params: Parameters,
-    ) -> Result<(), Error> {
-        info!("SCPI: LED OFF");
-        let _ = LED_CHANNEL.try_send(LedState::Off);
-        Ok(())
+        mut resp: ResponseUnit,
+    ) -> scpi::error::Result<()> {
+        let measurement = PWM_INPUT.lock(|pwm_input| pwm_input.get());
+        resp.data((self.0)(&measurement)).finish()
     }
 }
 
-/// LED? - Query LED status
-struct LedStatusCommand;
+#[derive(Clone, Copy)]
+enum PwmInputSetting {
+    Prescaler,
+    Timeout,
+}
 
-impl Command<MyDevice> for LedStatusCommand {
-    cmd_qonly!();
+/// SENSe:PWM:PRESCaler <1..max> / TIMEout <ms> - Set/query PWM-input tick
+/// divider and no-signal timeout. The largest divider follows from the TIM3
+/// clock (8192 on the 8 MHz HSI clock).
+struct PwmInputSettingCommand(PwmInputSetting);
+
+impl Command<MyDevice> for PwmInputSettingCommand {
+    cmd_both!();
+
+    fn event(
+        &self,
+        _device: &mut MyDevice,
+        _context: &mut Context,
+        mut params: Parameters,
+    ) -> Result<(), Error> {
+        let value: u16 = params.next_data()?;
+        let mut config = PWM_INPUT_CONFIG.lock(|pwm_input| pwm_input.get());
+        match self.0 {
+            PwmInputSetting::Prescaler => {
+                if !(1..=pwm_input::prescaler_max()).contains(&value) {
+                    return Err(ErrorCode::DataOutOfRange.into());
+                }
+                config.prescaler = value;
+            }
+            PwmInputSetting::Timeout => {
+                if value == 0 {
+                    return Err(ErrorCode::DataOutOfRange.into());
+                }
+                config.timeout_ms = value;
+            }
+        }
+        info!("SCPI: PWM INPUT {}", config);
+        PWM_INPUT_CONFIG.lock(|pwm_input| pwm_input.set(config));
+        Ok(())
+    }
 
     fn query(
         &self,
@@ -107,59 +2913,83 @@ impl Command<MyDevice> for LedStatusCommand {
         _params: Parameters,
         mut resp: ResponseUnit,
     ) -> scpi::error::Result<()> {
-        // For now, return a default status since we can't access signals in non-async context
-        // In a real implementation, you might want to use a different approach like shared memory
-        resp.data(b"OFF".as_slice()).finish()
+        let config = PWM_INPUT_CONFIG.lock(|pwm_input| pwm_input.get());
+        let value = match self.0 {
+            PwmInputSetting::Prescaler => config.prescaler,
+            PwmInputSetting::Timeout => config.timeout_ms,
+        };
+        resp.data(value).finish()
     }
 }
 
-// ============================================================================
-// POWER CONTROL COMMANDS
-// ============================================================================
+fn update_sync(update: impl FnOnce(&mut AdcSyncConfig)) {
+    let config = ADC_SYNC.lock(|sync| {
+        let mut config = sync.get();
+        update(&mut config);
+        sync.set(config);
+        config
+    });
+    configure_sync(config);
+}
 
-/// POWEr:ON - Turn power on (ACDC or DCDC based on current state)
-struct PowerOnCommand;
+/// SENSe:SYNChronous:STATe <ON|OFF> - Enable/query sampling locked to the PWM carrier
+struct SyncStateCommand;
 
-impl Command<MyDevice> for PowerOnCommand {
-    cmd_nquery!();
+impl Command<MyDevice> for SyncStateCommand {
+    cmd_both!();
 
     fn event(
         &self,
         _device: &mut MyDevice,
         _context: &mut Context,
-        _params: Parameters,
+        mut params: Parameters,
     ) -> Result<(), Error> {
-        info!("SCPI: POWER ON");
-        // Default to ACDC when turning power on
-        let target_state = PowerState::ACDC;
-        let _ = POWER_CHANNEL.try_send(target_state);
+        let enabled: bool = params.next_data()?;
+        // The trigger takes over CCR2, so CH2 must neither drive PB14 nor play a waveform
+        let wave = WAVEFORM_CONFIG.lock(|wave| wave.get());
+        let ch2_busy = PWM_CONFIG.lock(|pwm| pwm.get()).enabled[PwmChannel::Ch2.index()]
+            || (wave.enabled && wave.channel == PwmChannel::Ch2);
+        if enabled && ch2_busy {
+            return Err(ErrorCode::SettingsConflict.into());
+        }
+        info!("SCPI: SYNC STATE {}", enabled);
+        update_sync(|config| config.enabled = enabled);
         Ok(())
     }
+
+    fn query(
+        &self,
+        _device: &mut MyDevice,
+        _context: &mut Context,
+        _params: Parameters,
+        mut resp: ResponseUnit,
+    ) -> scpi::error::Result<()> {
+        let enabled = ADC_SYNC.lock(|sync| sync.get()).enabled;
+        resp.data(enabled).finish()
+    }
 }
 
-/// POWEr:OFF - Turn power off
-struct PowerOffCommand;
+/// SENSe:SYNChronous:PHASe <percent> - Set/query sampling point within the PWM period
+struct SyncPhaseCommand;
 
-impl Command<MyDevice> for PowerOffCommand {
-    cmd_nquery!();
+impl Command<MyDevice> for SyncPhaseCommand {
+    cmd_both!();
 
     fn event(
         &self,
         _device: &mut MyDevice,
         _context: &mut Context,
-        _params: Parameters,
+        mut params: Parameters,
     ) -> Result<(), Error> {
-        info!("SCPI: POWER OFF");
-        let _ = POWER_CHANNEL.try_send(PowerState::OFF);
+        let percent: f32 = params.next_data()?;
+        if !(0.0..100.0).contains(&percent) {
+            return Err(ErrorCode::DataOutOfRange.into());
+        }
+        let phase_permille = (percent * 10.0) as u16;
+        info!("SCPI: SYNC PHASE {} permille", phase_permille);
+        update_sync(|config| config.phase_permille = phase_permille);
         Ok(())
     }
-}
-
-/// POWEr? - Query power status
-struct PowerStatusCommand;
-
-impl Command<MyDevice> for PowerStatusCommand {
-    cmd_qonly!();
 
     fn query(
         &self,
@@ -168,55 +2998,55 @@ impl Command<MyDevice> for PowerStatusCommand {
         _params: Parameters,
         mut resp: ResponseUnit,
     ) -> scpi::error::Result<()> {
-        // Return default power status
-        resp.data(b"OFF".as_slice()).finish()
+        let phase_permille = ADC_SYNC.lock(|sync| sync.get()).phase_permille;
+        resp.data(f32::from(phase_permille) / 10.0).finish()
     }
 }
 
 // ============================================================================
-// DCDC POWER CONTROL COMMANDS
+// TREND LOG COMMANDS
 // ============================================================================
 
-/// POWEr:DCDC:ON - Turn DCDC power on
-struct DcdcOnCommand;
+/// Records returned by a single TRENd:DATA? query (keeps the response buffer small)
+const TREND_RECORDS_PER_QUERY: usize = 32;
 
-impl Command<MyDevice> for DcdcOnCommand {
-    cmd_nquery!();
+/// TRENd:INTerval <seconds> - Set/query aggregation interval (clears the log)
+struct TrendIntervalCommand;
+
+impl Command<MyDevice> for TrendIntervalCommand {
+    cmd_both!();
 
     fn event(
         &self,
         _device: &mut MyDevice,
         _context: &mut Context,
-        _params: Parameters,
+        mut params: Parameters,
     ) -> Result<(), Error> {
-        info!("SCPI: DCDC ON");
-        let _ = POWER_CHANNEL.try_send(PowerState::DCDC);
+        let interval_s: u32 = params.next_data()?;
+        if interval_s == 0 {
+            return Err(ErrorCode::DataOutOfRange.into());
+        }
+        info!("SCPI: TREND INTERVAL {} s", interval_s);
+        TREND_LOG.lock(|log| log.borrow_mut().set_interval_s(interval_s));
         Ok(())
     }
-}
-
-/// POWEr:DCDC:OFF - Turn DCDC power off
-struct DcdcOffCommand;
-
-impl Command<MyDevice> for DcdcOffCommand {
-    cmd_nquery!();
 
-    fn event(
+    fn query(
         &self,
         _device: &mut MyDevice,
         _context: &mut Context,
         _params: Parameters,
-    ) -> Result<(), Error> {
-        info!("SCPI: DCDC OFF");
-        let _ = POWER_CHANNEL.try_send(PowerState::OFF);
-        Ok(())
+        mut resp: ResponseUnit,
+    ) -> scpi::error::Result<()> {
+        let interval_s = TREND_LOG.lock(|log| log.borrow().interval_s());
+        resp.data(interval_s).finish()
     }
 }
 
-/// POWEr:DCDC? - Query DCDC power status
-struct DcdcStatusCommand;
+/// TRENd:COUNt? - Number of stored records
+struct TrendCountCommand;
 
-impl Command<MyDevice> for DcdcStatusCommand {
+impl Command<MyDevice> for TrendCountCommand {
     cmd_qonly!();
 
     fn query(
@@ -226,37 +3056,111 @@ impl Command<MyDevice> for DcdcStatusCommand {
         _params: Parameters,
         mut resp: ResponseUnit,
     ) -> scpi::error::Result<()> {
-        // Return default DCDC status
-        resp.data(b"0".as_slice()).finish()
+        let count = TREND_LOG.lock(|log| log.borrow().len());
+        resp.data(count as u32).finish()
     }
 }
 
-/// POWEr:DCDC:VAL? - Query DCDC voltage value
-struct DcdcValueCommand;
+/// TRENd:CLEar - Drop all stored records
+struct TrendClearCommand;
 
-impl Command<MyDevice> for DcdcValueCommand {
+impl Command<MyDevice> for TrendClearCommand {
+    cmd_nquery!();
+
+    fn event(
+        &self,
+        _device: &mut MyDevice,
+        _context: &mut Context,
+        _params: Parameters,
+    ) -> Result<(), Error> {
+        info!("SCPI: TREND CLEAR");
+        TREND_LOG.lock(|log| log.borrow_mut().clear());
+        Ok(())
+    }
+}
+
+/// TRENd:DATA? [<start>[,<count>]] - Download records, oldest first.
+///
+/// Each record is 11 values: rail min,max,avg (mV), temperature min,max,avg
+/// (0.1 °C), fan min,max,avg (RPM), source (0=OFF,1=DCDC,2=ACDC), switches.
+struct TrendDataCommand;
+
+impl Command<MyDevice> for TrendDataCommand {
     cmd_qonly!();
 
     fn query(
         &self,
         _device: &mut MyDevice,
         _context: &mut Context,
-        _params: Parameters,
+        mut params: Parameters,
         mut resp: ResponseUnit,
     ) -> scpi::error::Result<()> {
-        // Return default voltage value
-        resp.data(b"0".as_slice()).finish()
+        let start: u32 = params.next_optional_data()?.unwrap_or(0);
+        let count: u32 = params
+            .next_optional_data()?
+            .unwrap_or(TREND_RECORDS_PER_QUERY as u32);
+        let count = (count as usize).min(TREND_RECORDS_PER_QUERY);
+
+        TREND_LOG.lock(|log| {
+            let log = log.borrow();
+            for index in (start as usize)..(start as usize).saturating_add(count) {
+                let Some(record) = log.get(index) else {
+                    break;
+                };
+                let source: u8 = match record.source {
+                    PowerState::OFF => 0,
+                    PowerState::DCDC => 1,
+                    PowerState::ACDC => 2,
+                };
+                resp.data(record.rail_mv.min)
+                    .data(record.rail_mv.max)
+                    .data(record.rail_mv.avg)
+                    .data(record.temperature.min)
+                    .data(record.temperature.max)
+                    .data(record.temperature.avg)
+                    .data(record.fan_rpm.min)
+                    .data(record.fan_rpm.max)
+                    .data(record.fan_rpm.avg)
+                    .data(source)
+                    .data(record.switches);
+            }
+        });
+        resp.finish()
     }
 }
 
 // ============================================================================
-// ACDC POWER CONTROL COMMANDS
+// WAVEFORM CAPTURE COMMANDS
 // ============================================================================
 
-/// POWEr:ACDC:ON - Turn ACDC power on
-struct AcdcOnCommand;
+const TRIGGER_MODES: &[(&[u8], TriggerMode)] = &[
+    (b"LEVel", TriggerMode::Level),
+    (b"RISing", TriggerMode::Rising),
+    (b"FALLing", TriggerMode::Falling),
+    (b"POWer", TriggerMode::PowerChange),
+];
 
-impl Command<MyDevice> for AcdcOnCommand {
+/// Change the capture settings. `Capture::configure` stops a running
+/// capture, so one that was waiting for (or recording after) the trigger is
+/// re-armed with the new settings. Returns `false` if they were rejected.
+fn update_capture(update: impl FnOnce(&mut CaptureConfig)) -> bool {
+    CAPTURE.lock(|capture| {
+        let mut capture = capture.borrow_mut();
+        let mut config = capture.config();
+        update(&mut config);
+        let running = matches!(capture.state(), CaptureState::Armed | CaptureState::Triggered);
+        let accepted = capture.configure(config);
+        if accepted && running {
+            capture.arm();
+        }
+        accepted
+    })
+}
+
+/// TRACe:ARM - Clear the record and wait for the trigger
+struct TraceArmCommand;
+
+impl Command<MyDevice> for TraceArmCommand {
     cmd_nquery!();
 
     fn event(
@@ -265,16 +3169,16 @@ impl Command<MyDevice> for AcdcOnCommand {
         _context: &mut Context,
         _params: Parameters,
     ) -> Result<(), Error> {
-        info!("SCPI: ACDC ON");
-        let _ = POWER_CHANNEL.try_send(PowerState::ACDC);
+        info!("SCPI: TRACE ARM");
+        CAPTURE.lock(|capture| capture.borrow_mut().arm());
         Ok(())
     }
 }
 
-/// POWEr:ACDC:OFF - Turn ACDC power off
-struct AcdcOffCommand;
+/// TRACe:TRIGger:FORCe - Trigger an armed capture immediately
+struct TraceForceCommand;
 
-impl Command<MyDevice> for AcdcOffCommand {
+impl Command<MyDevice> for TraceForceCommand {
     cmd_nquery!();
 
     fn event(
@@ -283,16 +3187,16 @@ impl Command<MyDevice> for AcdcOffCommand {
         _context: &mut Context,
         _params: Parameters,
     ) -> Result<(), Error> {
-        info!("SCPI: ACDC OFF");
-        let _ = POWER_CHANNEL.try_send(PowerState::OFF);
+        info!("SCPI: TRACE FORCE");
+        CAPTURE.lock(|capture| capture.borrow_mut().force_trigger());
         Ok(())
     }
 }
 
-/// POWEr:ACDC? - Query ACDC power status
-struct AcdcStatusCommand;
+/// TRACe:STATe? - Query capture state (IDLE, ARMED, TRIGGERED, DONE)
+struct TraceStateCommand;
 
-impl Command<MyDevice> for AcdcStatusCommand {
+impl Command<MyDevice> for TraceStateCommand {
     cmd_qonly!();
 
     fn query(
@@ -302,16 +3206,36 @@ impl Command<MyDevice> for AcdcStatusCommand {
         _params: Parameters,
         mut resp: ResponseUnit,
     ) -> scpi::error::Result<()> {
-        // Return default ACDC status
-        resp.data(b"0".as_slice()).finish()
+        let state: &[u8] = match CAPTURE.lock(|capture| capture.borrow().state()) {
+            CaptureState::Idle => b"IDLE",
+            CaptureState::Armed => b"ARMED",
+            CaptureState::Triggered => b"TRIGGERED",
+            CaptureState::Done => b"DONE",
+        };
+        resp.data(state).finish()
     }
 }
 
-/// POWEr:ACDC:VAL? - Query ACDC voltage value
-struct AcdcValueCommand;
+/// TRACe:TRIGger:MODE <LEVel|RISing|FALLing|POWer> - Set/query trigger source
+struct TraceModeCommand;
 
-impl Command<MyDevice> for AcdcValueCommand {
-    cmd_qonly!();
+impl Command<MyDevice> for TraceModeCommand {
+    cmd_both!();
+
+    fn event(
+        &self,
+        _device: &mut MyDevice,
+        _context: &mut Context,
+        mut params: Parameters,
+    ) -> Result<(), Error> {
+        let mode = next_choice(&mut params, TRIGGER_MODES)?;
+        info!("SCPI: TRACE MODE {:?}", mode);
+        if update_capture(|config| config.mode = mode) {
+            Ok(())
+        } else {
+            Err(ErrorCode::ExecutionError.into())
+        }
+    }
 
     fn query(
         &self,
@@ -320,56 +3244,78 @@ impl Command<MyDevice> for AcdcValueCommand {
         _params: Parameters,
         mut resp: ResponseUnit,
     ) -> scpi::error::Result<()> {
-        // Return default voltage value
-        resp.data(b"0".as_slice()).finish()
+        let mode: &[u8] = match CAPTURE.lock(|capture| capture.borrow().config().mode) {
+            TriggerMode::Level => b"LEV",
+            TriggerMode::Rising => b"RIS",
+            TriggerMode::Falling => b"FALL",
+            TriggerMode::PowerChange => b"POW",
+        };
+        resp.data(mode).finish()
     }
 }
 
-// ============================================================================
-// COOLING CONTROL COMMANDS
-// ============================================================================
-
-/// SPEEd:ON - Turn cooling on
-struct SpeedOnCommand;
+/// TRACe:TRIGger:LEVel <mV> - Set/query trigger level
+struct TraceLevelCommand;
 
-impl Command<MyDevice> for SpeedOnCommand {
-    cmd_nquery!();
+impl Command<MyDevice> for TraceLevelCommand {
+    cmd_both!();
 
     fn event(
         &self,
         _device: &mut MyDevice,
         _context: &mut Context,
-        _params: Parameters,
+        mut params: Parameters,
     ) -> Result<(), Error> {
-        info!("SCPI: COOLING ON");
-        let _ = COOLING_CHANNEL.try_send(CoolingState::On);
-        Ok(())
+        let level: u16 = params.next_data()?;
+        info!("SCPI: TRACE LEVEL {}", level);
+        if update_capture(|config| config.level = level) {
+            Ok(())
+        } else {
+            Err(ErrorCode::ExecutionError.into())
+        }
+    }
+
+    fn query(
+        &self,
+        _device: &mut MyDevice,
+        _context: &mut Context,
+        _params: Parameters,
+        mut resp: ResponseUnit,
+    ) -> scpi::error::Result<()> {
+        let level = CAPTURE.lock(|capture| capture.borrow().config().level);
+        resp.data(level).finish()
     }
 }
 
-/// SPEEd:OFF - Turn cooling off
-struct SpeedOffCommand;
+/// TRACe:POINts:PRE <n> / TRACe:POINts:POST <n> - Set/query samples around the trigger
+struct TracePointsCommand {
+    pre: bool,
+}
 
-impl Command<MyDevice> for SpeedOffCommand {
-    cmd_nquery!();
+impl Command<MyDevice> for TracePointsCommand {
+    cmd_both!();
 
     fn event(
         &self,
         _device: &mut MyDevice,
         _context: &mut Context,
-        _params: Parameters,
+        mut params: Parameters,
     ) -> Result<(), Error> {
-        info!("SCPI: COOLING OFF");
-        let _ = COOLING_CHANNEL.try_send(CoolingState::Off);
-        Ok(())
+        let points: u32 = params.next_data()?;
+        let points = points as usize;
+        let accepted = update_capture(|config| {
+            if self.pre {
+                config.pre_trigger = points;
+            } else {
+                config.post_trigger = points;
+            }
+        });
+        if accepted {
+            Ok(())
+        } else {
+            Err(ErrorCode::DataOutOfRange.into())
+        }
     }
-}
-
-/// SPEEd? - Query cooling status and speed
-struct SpeedStatusCommand;
-
-impl Command<MyDevice> for SpeedStatusCommand {
-    cmd_qonly!();
 
     fn query(
         &self,
@@ -378,28 +3324,52 @@ impl Command<MyDevice> for SpeedStatusCommand {
         _params: Parameters,
         mut resp: ResponseUnit,
     ) -> scpi::error::Result<()> {
-        // Return default cooling status
-        resp.data(b"OFF,0".as_slice()).finish()
+        let config = CAPTURE.lock(|capture| capture.borrow().config());
+        let points = if self.pre { config.pre_trigger } else { config.post_trigger };
+        resp.data(points as u32).finish()
     }
 }
 
-/// SPEEd <value> - Set cooling speed
-struct SpeedValueCommand;
+/// TRACe:DATA? - Download the captured record in mV, oldest sample first
+///
+/// Samples are 4 ticks of the 32.768 kHz time base apart (8192 Sa/s).
+///
+/// Comma-separated, or a block of big-endian u16 with `FORMat INTeger`.
+struct TraceDataCommand;
 
-impl Command<MyDevice> for SpeedValueCommand {
-    cmd_nquery!();
+impl Command<MyDevice> for TraceDataCommand {
+    cmd_qonly!();
 
-    fn event(
+    fn query(
         &self,
-        _device: &mut MyDevice,
+        device: &mut MyDevice,
         _context: &mut Context,
         _params: Parameters,
-    ) -> Result<(), Error> {
-        // For now, just send a default speed since parameter parsing is complex
-        // In a real implementation, you would parse the parameters properly
-        let _ = SPEED_CHANNEL.try_send(50); // Default speed
-        let _ = COOLING_CHANNEL.try_send(CoolingState::On);
-        Ok(())
+        mut resp: ResponseUnit,
+    ) -> scpi::error::Result<()> {
+        CAPTURE.lock(|capture| {
+            let capture = capture.borrow();
+            if capture.state() != CaptureState::Done {
+                return Err(ErrorCode::DataCorruptOrStale.into());
+            }
+            match device.format {
+                DataFormat::Ascii => {
+                    for sample in capture.record() {
+                        resp.data(sample);
+                    }
+                }
+                DataFormat::Integer => {
+                    let mut block = [0u8; 2 * CAPTURE_DEPTH];
+                    let mut len = 0;
+                    for sample in capture.record() {
+                        block[len..len + 2].copy_from_slice(&sample.to_be_bytes());
+                        len += 2;
+                    }
+                    resp.data(Arbitrary(&block[..len]));
+                }
+            }
+            resp.finish()
+        })
     }
 }
 
@@ -411,27 +3381,152 @@ impl Command<MyDevice> for SpeedValueCommand {
 ///
 /// Supported commands:
 /// - *IDN?                    -> Device identification
+/// - *RST                    -> Reset the session (data format, parser context)
+/// - *CLS                    -> Clear the session's error queue
+/// - *OPC?                   -> 1 once all sent commands are applied
+/// - *WAI                    -> Hold further lines until all sent commands are applied
+/// - SYSTem:ERRor?           -> Next error of this session (code,"message")
+/// - SYSTem:ERRor:COUNt?     -> Number of queued errors
+/// - SYSTem:LOCK?            -> Claim the control lock (1 if held by this session)
+/// - SYSTem:LOCK:RELease     -> Release the control lock
+/// - SYSTem:LOCK:OWNer?      -> Session holding the lock, 0 if free
+/// - FORMat                  -> Set/query TRACe:DATA? encoding (ASCii|INTeger)
+/// - SYSTem:COMMunicate:SERial:BAUD -> Set/query USART1 baud rate (applied after the response)
+/// - SYSTem:COMMunicate:SERial:PARity -> Set/query USART1 parity (NONE|EVEN|ODD)
+/// - SYSTem:COMMunicate:SERial:SBITs -> Set/query USART1 stop bits (1|2)
+/// - SYSTem:COMMunicate:SERial:TERMinator -> Set/query response terminator (LF|CRLF|CR)
+/// - SYSTem:COMMunicate:SERial:MODE -> Set/query RS232 or RS485 (half duplex, DE on PB8)
+/// - SYSTem:COMMunicate:ADDRess -> Set/query the bus address (1..247) for `@<addr>` lines
+/// - SYSTem:COMMunicate:SERial:PROTocol -> Set/query SCPI, MODBus (RTU slave) or BINary
 /// - LED:TOGGle              -> Toggle LED
 /// - LED:ON                  -> Turn LED on
 /// - LED:OFF                 -> Turn LED off
-/// - LED?                    -> Query LED status
+/// - LED?                    -> Query LED status (ON|OFF)
 /// - POWEr:ON                -> Turn power on
 /// - POWEr:OFF               -> Turn power off
-/// - POWEr?                  -> Query power status
+/// - POWEr?                  -> Query active source (ACDC|DCDC|OFF)
 /// - POWEr:DCDC:ON           -> Turn DCDC on
 /// - POWEr:DCDC:OFF          -> Turn DCDC off
-/// - POWEr:DCDC?             -> Query DCDC status
+/// - POWEr:DCDC?             -> Query whether DCDC is active (1|0)
 /// - POWEr:DCDC:VAL?         -> Query DCDC voltage
 /// - POWEr:ACDC:ON           -> Turn ACDC on
 /// - POWEr:ACDC:OFF          -> Turn ACDC off
-/// - POWEr:ACDC?             -> Query ACDC status
+/// - POWEr:ACDC?             -> Query whether ACDC is active (1|0)
 /// - POWEr:ACDC:VAL?         -> Query ACDC voltage
 /// - SPEEd:ON                -> Turn cooling on
 /// - SPEEd:OFF               -> Turn cooling off
-/// - SPEEd?                  -> Query cooling status
-/// - SPEEd <value>           -> Set cooling speed
+/// - SPEEd?                  -> Query cooling state and speed (ON|OFF,<percent>)
+/// - SPEEd[:VALue] <percent> -> Set cooling speed
+/// - SPEEd:RPM?              -> Measured fan speed (tach)
+/// - SPEEd:RPM:TARGet <rpm>  -> Closed-loop fan speed
+/// - SPEEd:PID:KP/KI/KD      -> Set/query fan PID gains
+/// - SPEEd:PID:PERiod        -> Set/query fan PID update period (ms)
+/// - SPEEd:TACHometer:PPR    -> Set/query tach pulses per revolution
+/// - SPEEd:TACHometer:AVERage -> Set/query tach periods averaged
+/// - SPEEd:RAMP:RATE         -> Set/query fan slew-rate limit (%/s, 0 = off)
+/// - SPEEd:RAMP:SOFTstart    -> Set/query fan soft-start time (ms)
+/// - SPEEd:RAMP:PROFile      -> Set/query soft-start shape (LINear|SCURve)
+/// - SPEEd:RAMP:STATe?       -> 1 while the fan duty is ramping
+/// - SPEEd:FAULt?            -> Fan fault state (OK|STALL|FAIL)
+/// - SPEEd:FAULt:CLEar       -> Clear a latched fan failure
+/// - SPEEd:STALl:TIMEout     -> Set/query stall detection time (s)
+/// - SPEEd:STALl:RETRies     -> Set/query restart attempts
+/// - SPEEd:STALl:KICK        -> Set/query restart kick length (ms)
+/// - SPEEd:STALl:POWeroff    -> Set/query power OFF on fan failure
+/// - SOURce<n>:PWM:DUTY      -> Set/query channel duty (%, 0.01 steps; n = 1..4, CH1 query only)
+/// - SOURce<n>:PWM:STATe     -> Enable/query channel outputs (CH1+CH1N, CH2N, CH3N, CH4)
+/// - SOURce<n>:PWM:POLarity  -> Set/query output polarity (NORMal|INVerted, stored)
+/// - SOURce<n>:RAMP:RATE / SOFTstart / PROFile / STATe? -> Per-channel ramp (SOURce1 = SPEEd:RAMP)
+/// - SOURce:PWM:FREQuency    -> Set/query carrier frequency (Hz, all channels, stored)
+/// - SOURce:PWM:POLarity     -> Same as SOURce1:PWM:POLarity
+/// - SOURce:PWM:MAXimum?     -> Duty of 100 % (ticks)
+/// - SOURce:PWM:DTIMe        -> Set/query CHx/CHxN dead time (ns)
+/// - SOURce:PWM:BREak        -> Enable/query break input (PB12, active low)
+/// - SOURce:PWM:BREak:TRIPped? -> 1 while outputs are shut down by the break
+/// - SOURce:PWM:BREak:CLEar  -> Re-enable outputs after a break
+/// - SOURce:FUNCtion         -> Set/query waveform (SINusoid|TRIangle|RAMP|USER)
+/// - SOURce:FUNCtion:STATe   -> Start/stop/query the function generator (DMA on TIM1_UP)
+/// - SOURce:FUNCtion:CHANnel -> Set/query output playing the waveform (2..4)
+/// - SOURce:FUNCtion:POINts  -> Set/query samples per period (built-in shapes)
+/// - SOURce:FUNCtion:AMPLitude / OFFSet -> Set/query swing and centre (% duty)
+/// - SOURce:FUNCtion:FREQuency? -> Output frequency (PWM frequency / points)
+/// - SOURce:FUNCtion:DATA    -> Upload (block, u16 BE, 0.01 %)/query USER table
+/// - SOURce:FUNCtion:DATA:APPend -> Append to the USER table
+/// - SOURce:VOLTage          -> Set/query PWM DAC output on CH4/PA11 (V, switches it on)
+/// - SOURce:VOLTage:STATe    -> Switch/query the PWM DAC
+/// - SOURce:VOLTage:CORRection -> Enable/query closed-loop trim from the PA2 readback
+/// - SYSTem:EVENt:COUNt?     -> Number of stored events
+/// - SYSTem:EVENt:DATA?      -> Stored events (time s,event)
+/// - SYSTem:EVENt:CLEar      -> Clear the event log
+/// - THERmal:STATe           -> Enable/query automatic fan control
+/// - THERmal:SOURce          -> Set/query temperature input (INTernal|EXTernal)
+/// - THERmal:CURVe           -> Set/query fan curve (°C,% pairs)
+/// - THERmal:HYSTeresis      -> Set/query turn-off hysteresis (°C)
+/// - THERmal:MINRun          -> Set/query minimum fan run time (s)
+/// - THERmal:CRITical        -> Set/query full-speed temperature (°C)
+/// - CALibration:COOLing     -> Run fan calibration / query state (IDLE|RUN|PASS|FAIL)
+/// - CALibration:COOLing:DATA? -> Stored fan calibration values
+/// - CALibration:COOLing:TABLe? -> Measured duty (%),rpm pairs
+/// - CALibration:VOLTage     -> Run PWM DAC calibration / query state (IDLE|RUN|PASS|FAIL)
+/// - CALibration:VOLTage:DATA? -> Stored DAC full scale,offset (mV)
+/// - MEASure:TEMPerature?    -> Temperature of the thermal source (°C)
+/// - MEASure:VOLTage?        -> Rail mean voltage (mV)
+/// - MEASure:VOLTage:RIPPle? -> Rail peak-to-peak ripple (mV)
+/// - MEASure:VOLTage:SYNChronous? -> PWM-synchronised rail mean,ripple (mV)
+/// - MEASure:VOLTage:DAC?    -> PWM DAC output read back on PA2 (mV)
+/// - MEASure:FREQuency?      -> Frequency of the PWM input on PA6 (Hz, 0 = no signal)
+/// - MEASure:PERiod? / PWIDth? -> Period / high time of the PWM input (s)
+/// - MEASure:DUTY?           -> Duty cycle of the PWM input (%)
+/// - SENSe:SYNChronous:STATe -> Enable/query PWM-synchronised sampling (takes CH2)
+/// - SENSe:SYNChronous:PHASe -> Set/query sampling phase (% of PWM period)
+/// - SENSe:PWM:PRESCaler     -> Set/query PWM input tick divider (1 MHz / n)
+/// - SENSe:PWM:TIMEout       -> Set/query PWM input no-signal timeout (ms)
+/// - CALCulate:AVERage?      -> min,max,mean,rms,sdev,ptpeak of the last window (mV)
+/// - CALCulate:AVERage:MINimum? / MAXimum? / MEAN? / RMS? / SDEViation? / PTPeak?
+/// - TRENd:INTerval          -> Set/query trend aggregation interval (s, default 600)
+/// - TRENd:COUNt?            -> Number of stored trend records
+/// - TRENd:DATA? [start,cnt] -> Download trend records
+/// - TRENd:CLEar             -> Clear trend records
+/// - TRACe:ARM               -> Arm rail waveform capture
+/// - TRACe:STATe?            -> Query capture state
+/// - TRACe:TRIGger:MODE      -> Set/query trigger (LEVel|RISing|FALLing|POWer)
+/// - TRACe:TRIGger:LEVel     -> Set/query trigger level (mV)
+/// - TRACe:TRIGger:FORCe     -> Force trigger
+/// - TRACe:POINts:PRE        -> Set/query pre-trigger samples
+/// - TRACe:POINts:POST       -> Set/query post-trigger samples
+/// - TRACe:DATA?             -> Download captured record (mV)
+/// `RAMP` subtree of one PWM channel
+macro_rules! ramp_branch {
+    ($channel:expr) => {
+        Branch![b"RAMP";
+            Leaf!(b"RATE" => &RampSettingCommand($channel, RampSetting::Rate)),
+            Leaf!(b"SOFTstart" => &RampSettingCommand($channel, RampSetting::SoftStart)),
+            Leaf!(b"PROFile" => &RampProfileCommand($channel)),
+            Leaf!(b"STATe" => &RampStateCommand($channel))
+        ]
+    };
+}
+
+/// `SOURce<n>` subtree; the channel suffix is spelled out per branch
+macro_rules! source_branch {
+    ($mnemonic:literal, $channel:expr) => {
+        Branch![$mnemonic;
+            Branch![b"PWM";
+                Leaf!(b"DUTY" => &PwmDutyCommand($channel)),
+                Leaf!(b"STATe" => &PwmStateCommand($channel)),
+                Leaf!(b"POLarity" => &PwmPolarityCommand($channel))
+            ],
+            ramp_branch!($channel)
+        ]
+    };
+}
+
 pub const MYTREE: Node<MyDevice> = Root![
     Leaf!(b"*IDN" => &IdnCommand),
+    Leaf!(b"*RST" => &RstCommand),
+    Leaf!(b"*CLS" => &ClsCommand),
+    Leaf!(b"*OPC" => &OpcCommand),
+    Leaf!(b"*WAI" => &WaiCommand),
     Branch![b"LED";
         Leaf!(default b"TOGGle" => &LedToggleCommand),
         Leaf!(default b"ON" => &LedOnCommand),
@@ -458,9 +3553,172 @@ pub const MYTREE: Node<MyDevice> = Root![
         ]
     ],
     Branch![b"SPEEd";
-        Leaf!(default b"ON" => &SpeedOnCommand),
-        Leaf!(default b"OFF" => &SpeedOffCommand),
+        Leaf!(b"ON" => &SpeedOnCommand),
+        Leaf!(b"OFF" => &SpeedOffCommand),
         Leaf!(b"?" => &SpeedStatusCommand),
-        Leaf!(default b"<Value>" => &SpeedValueCommand)
+        Leaf!(default b"VALue" => &SpeedValueCommand),
+        Branch![b"RPM";
+            Leaf!(default b"MEASured" => &SpeedRpmCommand),
+            Leaf!(b"TARGet" => &RpmTargetCommand)
+        ],
+        Branch![b"PID";
+            Leaf!(b"KP" => &PidGainCommand(PidGain::Kp)),
+            Leaf!(b"KI" => &PidGainCommand(PidGain::Ki)),
+            Leaf!(b"KD" => &PidGainCommand(PidGain::Kd)),
+            Leaf!(b"PERiod" => &PidPeriodCommand)
+        ],
+        Branch![b"TACHometer";
+            Leaf!(b"PPR" => &TachPprCommand),
+            Leaf!(b"AVERage" => &TachAverageCommand)
+        ],
+        Branch![b"FAULt";
+            Leaf!(default b"STATe" => &FanFaultCommand),
+            Leaf!(b"CLEar" => &FanFaultClearCommand)
+        ],
+        Branch![b"STALl";
+            Leaf!(b"TIMEout" => &StallSettingCommand(StallSetting::Timeout)),
+            Leaf!(b"RETRies" => &StallSettingCommand(StallSetting::Retries)),
+            Leaf!(b"KICK" => &StallSettingCommand(StallSetting::Kick)),
+            Leaf!(b"POWeroff" => &StallPowerOffCommand)
+        ],
+        ramp_branch!(PwmChannel::Ch1)
+    ],
+    Branch![b"SOURce";
+        Branch![b"PWM";
+            Leaf!(b"FREQuency" => &PwmFrequencyCommand),
+            Leaf!(b"POLarity" => &PwmPolarityCommand(PwmChannel::Ch1)),
+            Leaf!(b"MAXimum" => &PwmMaxDutyCommand),
+            Leaf!(b"DTIMe" => &PwmDeadTimeCommand),
+            Branch![b"BREak";
+                Leaf!(default b"STATe" => &PwmBreakStateCommand),
+                Leaf!(b"TRIPped" => &PwmBreakTrippedCommand),
+                Leaf!(b"CLEar" => &PwmBreakClearCommand)
+            ]
+        ],
+        Branch![b"FUNCtion";
+            Leaf!(default b"SHAPe" => &FunctionShapeCommand),
+            Leaf!(b"STATe" => &FunctionStateCommand),
+            Leaf!(b"CHANnel" => &FunctionChannelCommand),
+            Leaf!(b"POINts" => &FunctionPointsCommand),
+            Leaf!(b"AMPLitude" => &FunctionLevelCommand(WaveLevel::Amplitude)),
+            Leaf!(b"OFFSet" => &FunctionLevelCommand(WaveLevel::Offset)),
+            Leaf!(b"FREQuency" => &FunctionFrequencyCommand),
+            Branch![b"DATA";
+                Leaf!(default b"DATA" => &FunctionDataCommand),
+                Leaf!(b"APPend" => &FunctionDataAppendCommand)
+            ]
+        ],
+        Branch![b"VOLTage";
+            Leaf!(default b"LEVel" => &DacLevelCommand),
+            Leaf!(b"STATe" => &DacStateCommand),
+            Leaf!(b"CORRection" => &DacCorrectionCommand)
+        ]
+    ],
+    source_branch!(b"SOURce1", PwmChannel::Ch1),
+    source_branch!(b"SOURce2", PwmChannel::Ch2),
+    source_branch!(b"SOURce3", PwmChannel::Ch3),
+    source_branch!(b"SOURce4", PwmChannel::Ch4),
+    Branch![b"FORMat";
+        Leaf!(default b"DATA" => &FormatDataCommand)
+    ],
+    Branch![b"SYSTem";
+        Branch![b"ERRor";
+            Leaf!(default b"NEXT" => &ErrorNextCommand),
+            Leaf!(b"COUNt" => &ErrorCountCommand)
+        ],
+        Branch![b"LOCK";
+            Leaf!(default b"REQuest" => &LockRequestCommand),
+            Leaf!(b"RELease" => &LockReleaseCommand),
+            Leaf!(b"OWNer" => &LockOwnerCommand)
+        ],
+        Branch![b"COMMunicate";
+            Branch![b"SERial";
+                Leaf!(b"BAUD" => &SerialBaudCommand),
+                Leaf!(b"PARity" => &SerialParityCommand),
+                Leaf!(b"SBITs" => &SerialStopBitsCommand),
+                Leaf!(b"TERMinator" => &SerialTerminatorCommand),
+                Leaf!(b"MODE" => &SerialModeCommand),
+                Leaf!(b"PROTocol" => &SerialProtocolCommand)
+            ],
+            Leaf!(b"ADDRess" => &BusAddressCommand)
+        ],
+        Branch![b"EVENt";
+            Leaf!(b"COUNt" => &EventCountCommand),
+            Leaf!(b"DATA" => &EventDataCommand),
+            Leaf!(b"CLEar" => &EventClearCommand)
+        ]
+    ],
+    Branch![b"THERmal";
+        Leaf!(default b"STATe" => &ThermalStateCommand),
+        Leaf!(b"SOURce" => &ThermalSourceCommand),
+        Leaf!(b"CURVe" => &ThermalCurveCommand),
+        Leaf!(b"HYSTeresis" => &ThermalLimitCommand(ThermalLimit::Hysteresis)),
+        Leaf!(b"CRITical" => &ThermalLimitCommand(ThermalLimit::Critical)),
+        Leaf!(b"MINRun" => &ThermalMinRunCommand)
+    ],
+    Branch![b"CALibration";
+        Branch![b"COOLing";
+            Leaf!(default b"STARt" => &CoolingCalibrationCommand),
+            Leaf!(b"DATA" => &CoolingCalibrationDataCommand),
+            Leaf!(b"TABLe" => &CoolingCalibrationTableCommand)
+        ],
+        Branch![b"VOLTage";
+            Leaf!(default b"STARt" => &DacCalibrationCommand),
+            Leaf!(b"DATA" => &DacCalibrationDataCommand)
+        ]
+    ],
+    Branch![b"MEASure";
+        Leaf!(b"TEMPerature" => &TemperatureCommand),
+        Branch![b"VOLTage";
+            Leaf!(default b"DC" => &RailStatisticCommand(|stats| stats.mean)),
+            Leaf!(b"RIPPle" => &RailStatisticCommand(|stats| stats.ripple)),
+            Leaf!(b"SYNChronous" => &SyncVoltageCommand),
+            Leaf!(b"DAC" => &DacReadbackCommand)
+        ],
+        Leaf!(b"FREQuency" => &PwmInputCommand(PwmInputMeasurement::frequency_hz)),
+        Leaf!(b"PERiod" => &PwmInputCommand(PwmInputMeasurement::period_s)),
+        Leaf!(b"PWIDth" => &PwmInputCommand(PwmInputMeasurement::width_s)),
+        Leaf!(b"DUTY" => &PwmInputCommand(PwmInputMeasurement::duty_percent))
+    ],
+    Branch![b"SENSe";
+        Branch![b"SYNChronous";
+            Leaf!(default b"STATe" => &SyncStateCommand),
+            Leaf!(b"PHASe" => &SyncPhaseCommand)
+        ],
+        Branch![b"PWM";
+            Leaf!(b"PRESCaler" => &PwmInputSettingCommand(PwmInputSetting::Prescaler)),
+            Leaf!(b"TIMEout" => &PwmInputSettingCommand(PwmInputSetting::Timeout))
+        ]
+    ],
+    Branch![b"CALCulate";
+        Branch![b"AVERage";
+            Leaf!(default b"ALL" => &RailStatisticsAllCommand),
+            Leaf!(b"MINimum" => &RailStatisticCommand(|stats| stats.min)),
+            Leaf!(b"MAXimum" => &RailStatisticCommand(|stats| stats.max)),
+            Leaf!(b"MEAN" => &RailStatisticCommand(|stats| stats.mean)),
+            Leaf!(b"RMS" => &RailStatisticCommand(|stats| stats.rms)),
+            Leaf!(b"SDEViation" => &RailStatisticCommand(|stats| stats.std_dev)),
+            Leaf!(b"PTPeak" => &RailStatisticCommand(|stats| stats.ripple))
+        ]
+    ],
+    Branch![b"TRENd";
+        Leaf!(b"INTerval" => &TrendIntervalCommand),
+        Leaf!(b"COUNt" => &TrendCountCommand),
+        Leaf!(b"DATA" => &TrendDataCommand),
+        Leaf!(b"CLEar" => &TrendClearCommand)
+    ],
+    Branch![b"TRACe";
+        Leaf!(b"ARM" => &TraceArmCommand),
+        Leaf!(b"STATe" => &TraceStateCommand),
+        Leaf!(b"DATA" => &TraceDataCommand),
+        Branch![b"TRIGger";
+            Leaf!(b"MODE" => &TraceModeCommand),
+            Leaf!(b"LEVel" => &TraceLevelCommand),
+            Leaf!(b"FORCe" => &TraceForceCommand)
+        ],
+        Branch![b"POINts";
+            Leaf!(b"PRE" => &TracePointsCommand { pre: true }),
+            Leaf!(b"POST" => &TracePointsCommand { pre: false })
+        ]
     ]
 ];
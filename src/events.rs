@@ -0,0 +1,78 @@
+use defmt::Format;
+use embassy_time::Instant;
+
+use crate::shared::{RailFault, EVENT_LOG};
+use crate::tasks::pwm::PwmChannel;
+
+pub const EVENT_CAPACITY: usize = 32;
+
+#[derive(Debug, Clone, Copy, PartialEq, Format)]
+pub enum Event {
+    FanStall,
+    FanRecovered,
+    FanFailure,
+    RailTrip(RailFault),
+    PwmBreak,
+    /// A duty ramp or soft start of the output reached its target
+    RampDone(PwmChannel),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Format)]
+pub struct EventRecord {
+    /// Seconds since boot
+    pub time_s: u32,
+    pub event: Event,
+}
+
+/// Fixed-size ring of events, oldest overwritten first.
+pub struct EventLog {
+    records: [EventRecord; EVENT_CAPACITY],
+    head: usize,
+    len: usize,
+}
+
+impl EventLog {
+    pub const fn new() -> Self {
+        Self {
+            records: [EventRecord {
+                time_s: 0,
+                event: Event::FanStall,
+            }; EVENT_CAPACITY],
+            head: 0,
+            len: 0,
+        }
+    }
+
+    pub fn clear(&mut self) {
+        self.head = 0;
+        self.len = 0;
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn push(&mut self, record: EventRecord) {
+        self.records[self.head] = record;
+        self.head = (self.head + 1) % EVENT_CAPACITY;
+        self.len = (self.len + 1).min(EVENT_CAPACITY);
+    }
+
+    /// Record `index`, counted from the oldest one.
+    pub fn get(&self, index: usize) -> Option<&EventRecord> {
+        if index >= self.len {
+            return None;
+        }
+        let start = (self.head + EVENT_CAPACITY - self.len) % EVENT_CAPACITY;
+        Some(&self.records[(start + index) % EVENT_CAPACITY])
+    }
+}
+
+/// Timestamp `event` and append it to `EVENT_LOG`.
+pub fn log_event(event: Event) {
+    let record = EventRecord {
+        time_s: Instant::now().as_secs() as u32,
+        event,
+    };
+    EVENT_LOG.lock(|log| log.borrow_mut().push(record));
+}
@@ -3,18 +3,23 @@
 
 use defmt::*;
 use embassy_executor::Spawner;
-use embassy_stm32::gpio::{AfioRemap, OutputType};
+use embassy_stm32::gpio::{AfioRemap, Input, Level, Output, OutputType, Pull, Speed};
 use embassy_stm32::peripherals;
-use embassy_stm32::time::khz;
+use embassy_stm32::time::Hertz;
+use embassy_stm32::timer::input_capture::{CapturePin, InputCapture};
+use embassy_stm32::timer::pwm_input::PwmInput;
+use embassy_stm32::timer::low_level::CountingMode;
+use embassy_stm32::timer::complementary_pwm::{ComplementaryPwm, ComplementaryPwmPin};
 use embassy_stm32::timer::simple_pwm::PwmPin;
-use embassy_stm32::timer::Ch1;
+use embassy_stm32::timer::{Ch1, Ch2, Ch3, Ch4};
 use embassy_time::Timer;
 use {defmt_rtt as _, panic_probe as _};
 
 use embassy_stm32::adc::Adc;
-use embassy_stm32::usart::{BufferedUart, Config};
-use embassy_stm32::peripherals::{ADC1, USART1};
-use embassy_stm32::{adc, usart, bind_interrupts};
+use embassy_stm32::flash::Flash;
+use embassy_stm32::usart::BufferedUart;
+use embassy_stm32::peripherals::{ADC1, TIM2, USART1, USART3};
+use embassy_stm32::{adc, bind_interrupts, timer, usart};
 
 
 use embedded_alloc::TlsfHeap as Heap;
@@ -28,33 +33,62 @@ static mut HEAP_MEM: [u8; 8 * 1024] = [0; 8 * 1024];
 
 extern crate alloc;
 
+use logic::{capture, crc, duty, pid, power, session, stall, stats, thermal, trend};
+
+mod binary;
+mod completion;
 mod device;
+mod events;
+mod modbus;
+mod ramp;
+mod scpi_session;
+mod settings;
 mod shared;
+mod waveform;
 mod tasks {
     pub mod adc_task;
     pub mod blinky;
+    pub mod calibration;
     pub mod cooling;
+    pub mod dac;
     pub mod led;
     pub mod power;
     pub mod pwm;
+    pub mod pwm_input;
     pub mod rx_tx;
+    pub mod settings;
+    pub mod tach;
+    pub mod thermal;
+    pub mod trend;
 }
 
-use shared::{SHARED_DUTY, SHARED_MESSAGE, TX_MESSAGE_CHANNEL};
+use settings::Settings;
+use shared::{SERIAL_CONFIG, SERIAL_STORED};
 use tasks::{
-    adc_task::measure_voltage, blinky::blinky, cooling::cooling_controller, led::led_controller,
-    power::change_power_source, pwm::change_duty_cycle, rx_tx::{rx_task, tx_task}
+    adc_task::{measure_voltage, AnalogWatchdogHandler, InjectedConversionHandler},
+    blinky::blinky, calibration::fan_calibration, cooling::cooling_controller,
+    dac::pwm_dac, led::led_controller, power::change_power_source,
+    pwm::{change_duty_cycle, BreakHandler, PWM_FREQUENCY_HZ},
+    pwm_input::{measure_pwm_input, PWM_INPUT_TICK_HZ},
+    rx_tx::{binary_port, serial_session, SerialConfig, BINARY_BAUDRATE},
+    settings::persist_settings,
+    tach::{measure_fan_speed, TACH_TICK_HZ}, thermal::thermal_policy, trend::record_trends,
 };
 
 bind_interrupts!(struct Irqs {
-    ADC1_2 => adc::InterruptHandler<ADC1>;
+    ADC1_2 => adc::InterruptHandler<ADC1>, AnalogWatchdogHandler, InjectedConversionHandler;
     USART1 => usart::BufferedInterruptHandler<USART1>;
+    USART3 => usart::BufferedInterruptHandler<USART3>;
+    TIM2 => timer::CaptureCompareInterruptHandler<TIM2>;
+    TIM1_BRK => BreakHandler;
 });
 
 
 // Static buffers for UART (fixed lifetime issues)
 static mut TX_BUF: [u8; 256] = [0; 256];
 static mut RX_BUF: [u8; 256] = [0; 256];
+static mut BINARY_TX_BUF: [u8; 128] = [0; 128];
+static mut BINARY_RX_BUF: [u8; 128] = [0; 128];
 
 #[embassy_executor::main]
 async fn main(spawner: Spawner) {
@@ -64,8 +98,21 @@ async fn main(spawner: Spawner) {
 
     let p = embassy_stm32::init(Default::default());
 
-    let mut config = Config::default();
-    config.baudrate = 9600;
+    // Restore persisted settings before any task reads them
+    let mut flash = Flash::new_blocking(p.FLASH);
+    let stored = Settings::load(&mut flash);
+    stored.apply();
+
+    // PB5 jumpered to GND at boot: serial port at the safe defaults (9600 8N1, RS-232)
+    let serial_default = Input::new(p.PB5, Pull::Up);
+    Timer::after_millis(1).await;
+    if serial_default.is_low() {
+        info!("Serial defaults jumper set");
+        // Live port only: a settings save keeps the stored configuration
+        SERIAL_STORED.lock(|serial| serial.set(Some(stored.serial)));
+        SERIAL_CONFIG.lock(|serial| serial.set(SerialConfig::DEFAULT));
+    }
+    let config = SERIAL_CONFIG.lock(|serial| serial.get()).uart_config();
 
     // Initialize buffered UART with static buffers
     let usart = unsafe {
@@ -82,48 +129,99 @@ async fn main(spawner: Spawner) {
     };
 
     let (tx, rx) = usart.split();
+    // RS-485 transceiver DE and /RE, low (receiving) unless a reply goes out
+    let bus_de = Output::new(p.PB8, Level::Low, Speed::Low);
 
-    // Get senders/receivers
-    let tx_sender = TX_MESSAGE_CHANNEL.sender();
-    let tx_receiver = TX_MESSAGE_CHANNEL.receiver();
-
+    // USART3 carries the binary protocol next to SCPI on USART1
+    let mut binary_config = usart::Config::default();
+    binary_config.baudrate = BINARY_BAUDRATE;
+    let binary_usart = unsafe {
+        BufferedUart::new(
+            p.USART3,
+            p.PB11, // RX
+            p.PB10, // TX
+            &mut BINARY_TX_BUF,
+            &mut BINARY_RX_BUF,
+            Irqs,
+            binary_config,
+        )
+        .unwrap()
+    };
+    let (binary_tx, binary_rx) = binary_usart.split();
 
+    // CH1 (+CH1N) drives the fan, CH2N/CH3N/CH4 are generic outputs. PA9/PA10
+    // (CH2/CH3) stay with USART1; PB12 is the break input.
     let pwm_pin: PwmPin<'_, peripherals::TIM1, Ch1, AfioRemap<0>> =
         PwmPin::new(p.PA8, OutputType::PushPull);
-    let pwm = embassy_stm32::timer::simple_pwm::SimplePwm::new(
+    let pwm_pin_n: ComplementaryPwmPin<'_, peripherals::TIM1, Ch1, AfioRemap<0>> =
+        ComplementaryPwmPin::new(p.PB13, OutputType::PushPull);
+    let ch2_pin_n: ComplementaryPwmPin<'_, peripherals::TIM1, Ch2, AfioRemap<0>> =
+        ComplementaryPwmPin::new(p.PB14, OutputType::PushPull);
+    let ch3_pin_n: ComplementaryPwmPin<'_, peripherals::TIM1, Ch3, AfioRemap<0>> =
+        ComplementaryPwmPin::new(p.PB15, OutputType::PushPull);
+    let aux_pwm_pin: PwmPin<'_, peripherals::TIM1, Ch4, AfioRemap<0>> =
+        PwmPin::new(p.PA11, OutputType::PushPull);
+    let pwm = ComplementaryPwm::new(
         p.TIM1,
         Some(pwm_pin),
+        Some(pwm_pin_n),
         None,
+        Some(ch2_pin_n),
         None,
+        Some(ch3_pin_n),
+        Some(aux_pwm_pin),
         None,
-        khz(1),
-        Default::default(),
+        Hertz(PWM_FREQUENCY_HZ),
+        CountingMode::EdgeAlignedUp,
     );
+    let break_pin = Input::new(p.PB12, Pull::Up);
 
     // Blink Task
     spawner.spawn((blinky(p.PC13, 10)).unwrap());
     // PWM task
-    spawner.spawn(change_duty_cycle(pwm).unwrap());
-    SHARED_DUTY.signal(50);
+    spawner.spawn(change_duty_cycle(pwm, break_pin, p.DMA1_CH5).unwrap());
     // ADC Task
     let adc = Adc::new(p.ADC1);
     let pin = p.PA4;
-    spawner.spawn(measure_voltage(adc, pin).unwrap());
+    spawner.spawn(measure_voltage(adc, pin, p.PA1, p.PA2).unwrap());
     // Power Task
     spawner.spawn(change_power_source(p.PB0, p.PB1, 100).unwrap());
     // LED controller task (using PA5)
     spawner.spawn(led_controller(p.PA5).unwrap());
     // Cooling controller task (using PB2)
     spawner.spawn(cooling_controller(p.PB2).unwrap());
+    // Fan tach task (TIM2 CH1 input capture on PA0)
+    let tach_pin: CapturePin<'_, TIM2, Ch1, AfioRemap<0>> = CapturePin::new(p.PA0, Pull::Up);
+    let tach = InputCapture::new(
+        p.TIM2,
+        Some(tach_pin),
+        None,
+        None,
+        None,
+        Irqs,
+        Hertz(TACH_TICK_HZ),
+        CountingMode::EdgeAlignedUp,
+    );
+    spawner.spawn(measure_fan_speed(tach).unwrap());
+    // PWM input task (TIM3 CH1/CH2 paired capture on PA6)
+    let pwm_input = PwmInput::new_ch1(p.TIM3, p.PA6, Pull::None, Hertz(PWM_INPUT_TICK_HZ));
+    spawner.spawn(measure_pwm_input(pwm_input).unwrap());
+    // PWM DAC task (TIM1 CH4 on PA11, readback on PA2)
+    spawner.spawn(pwm_dac().unwrap());
+    // Fan calibration task (on request)
+    spawner.spawn(fan_calibration().unwrap());
+    // Settings task (flash writer)
+    spawner.spawn(persist_settings(flash, stored).unwrap());
+    // Thermal policy task
+    spawner.spawn(thermal_policy().unwrap());
+    // Trend recorder task
+    spawner.spawn(record_trends().unwrap());
     // USART Task
-    spawner.spawn(rx_task(rx, tx_sender).unwrap());
-    spawner.spawn(tx_task(tx, tx_receiver).unwrap());
+    spawner.spawn(serial_session(rx, tx, bus_de).unwrap());
+    // Binary protocol task (USART3)
+    spawner.spawn(binary_port(binary_rx, binary_tx).unwrap());
     
     loop {
-        // Simple test
-        // for i in 0..4 {
-        //     SHARED_MESSAGE.signal(i);
-            Timer::after_millis(1000).await;
-        // }
+        Timer::after_millis(1000).await;
     }
 }
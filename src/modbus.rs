@@ -0,0 +1,505 @@
+use binproto::fault;
+use defmt::*;
+use embassy_time::{with_timeout, Duration};
+use embedded_io_async::{Read, Write};
+
+use crate::completion::CommandTracker;
+use crate::crc::crc16;
+use crate::session::SessionError;
+use crate::shared::{
+    CommandChannel, CoolingState, LedState, PowerState, RailFault, COOLING_CHANNEL, COOLING_ON,
+    FAN_FAULT, FAN_RPM, FAN_SPEED, LED_CHANNEL, LED_ON, POWER_CHANNEL, POWER_SOURCE,
+    PROBE_TEMPERATURE, PWM_STATUS, RAIL_LIMITS, RAIL_STATS, SPEED_CHANNEL, TEMPERATURE,
+    THERMAL_CONFIG,
+};
+use crate::stall::FanFault;
+use crate::tasks::rx_tx::SerialConfig;
+
+/// Longest RTU frame: address, PDU (up to 253 bytes) and CRC
+const FRAME_LEN: usize = 256;
+/// Frames to this address are executed by every unit and never answered
+const BROADCAST: u8 = 0;
+
+mod function {
+    pub const READ_COILS: u8 = 0x01;
+    pub const READ_HOLDING_REGISTERS: u8 = 0x03;
+    pub const READ_INPUT_REGISTERS: u8 = 0x04;
+    pub const WRITE_SINGLE_COIL: u8 = 0x05;
+    pub const WRITE_SINGLE_REGISTER: u8 = 0x06;
+    pub const WRITE_MULTIPLE_COILS: u8 = 0x0F;
+    pub const WRITE_MULTIPLE_REGISTERS: u8 = 0x10;
+}
+
+/// Coils (read/write bits)
+mod coil {
+    /// Status LED on PA5
+    pub const LED: u16 = 0;
+    /// Power relay to the AC/DC source; clearing it switches the power off
+    pub const POWER_ACDC: u16 = 1;
+    /// Power relay to the DC/DC source; clearing it switches the power off
+    pub const POWER_DCDC: u16 = 2;
+    /// Fan supply (cooling on/off)
+    pub const COOLING: u16 = 3;
+    pub const COUNT: u16 = 4;
+}
+
+/// Holding registers (read/write words); temperatures in signed 0.1 °C
+mod holding {
+    /// Open-loop fan speed, percent; writing it also switches cooling on
+    pub const FAN_SPEED: u16 = 0;
+    /// 0 = manual fan control, 1 = thermal policy
+    pub const MODE: u16 = 1;
+    /// Fan turn-on temperature (first point of the thermal curve)
+    pub const TURN_ON: u16 = 2;
+    /// Full fan speed at or above this temperature
+    pub const CRITICAL: u16 = 3;
+    /// Turn-off hysteresis below the turn-on temperature
+    pub const HYSTERESIS: u16 = 4;
+    pub const COUNT: u16 = 5;
+}
+
+/// Input registers (read-only words)
+mod input {
+    /// Averaged rail voltage on PA4, mV
+    pub const RAIL_MV: u16 = 0;
+    /// Internal temperature sensor, signed 0.1 °C
+    pub const TEMPERATURE: u16 = 1;
+    /// External probe on PA1, signed 0.1 °C
+    pub const PROBE_TEMPERATURE: u16 = 2;
+    /// Fan speed from the tach input
+    pub const FAN_RPM: u16 = 3;
+    /// Bit field, see `binproto::fault`
+    pub const FAULTS: u16 = 4;
+    pub const COUNT: u16 = 5;
+}
+
+/// Exception codes sent back with the function code's top bit set.
+#[derive(Debug, Clone, Copy, PartialEq, Format)]
+pub enum Exception {
+    IllegalFunction = 1,
+    IllegalDataAddress = 2,
+    IllegalDataValue = 3,
+    /// A command queue was full, or the writes were not applied in time
+    DeviceFailure = 4,
+}
+
+/// Silence that ends a frame: 3.5 character times, fixed at 1.75 ms above
+/// 19200 baud as the Modbus serial line specification recommends.
+pub fn frame_gap(config: &SerialConfig) -> Duration {
+    if config.baudrate > 19200 {
+        return Duration::from_micros(1750);
+    }
+    config.char_time() * 7 / 2
+}
+
+fn word(data: &[u8], at: usize) -> u16 {
+    u16::from_be_bytes([data[at], data[at + 1]])
+}
+
+/// Check that `count` items from `start` lie within a table of `len` items.
+fn check_range(start: u16, count: u16, max_count: u16, len: u16) -> Result<(), Exception> {
+    if count == 0 || count > max_count {
+        return Err(Exception::IllegalDataValue);
+    }
+    if u32::from(start) + u32::from(count) > u32::from(len) {
+        return Err(Exception::IllegalDataAddress);
+    }
+    Ok(())
+}
+
+fn check_room<T>(channel: &CommandChannel<T>) -> Result<(), Exception> {
+    if channel.is_full() {
+        warn!("Modbus: command queue full");
+        return Err(Exception::DeviceFailure);
+    }
+    Ok(())
+}
+
+/// Queue `command` for its task, like an SCPI command.
+fn queue<T>(
+    commands: &'static CommandTracker,
+    channel: &CommandChannel<T>,
+    command: T,
+) -> Result<(), Exception> {
+    channel.try_send((command, commands.track())).map_err(|_| {
+        warn!("Modbus: command queue full");
+        Exception::DeviceFailure
+    })
+}
+
+fn read_coil(address: u16) -> bool {
+    match address {
+        coil::LED => LED_ON.lock(|led| led.get()),
+        coil::POWER_ACDC => POWER_SOURCE.lock(|source| source.get()) == PowerState::ACDC,
+        coil::POWER_DCDC => POWER_SOURCE.lock(|source| source.get()) == PowerState::DCDC,
+        coil::COOLING => COOLING_ON.lock(|cooling| cooling.get()),
+        _ => false,
+    }
+}
+
+fn read_holding(address: u16) -> u16 {
+    let thermal = THERMAL_CONFIG.lock(|thermal| thermal.get());
+    match address {
+        holding::FAN_SPEED => FAN_SPEED.lock(|fan| fan.get()),
+        holding::MODE => u16::from(thermal.enabled),
+        holding::TURN_ON => thermal.curve[0].temperature as i16 as u16,
+        holding::CRITICAL => thermal.critical as i16 as u16,
+        holding::HYSTERESIS => thermal.hysteresis as i16 as u16,
+        _ => 0,
+    }
+}
+
+fn read_input(address: u16) -> u16 {
+    match address {
+        input::RAIL_MV => RAIL_STATS.lock(|rail| rail.get()).mean.min(0xFFFF) as u16,
+        input::TEMPERATURE => TEMPERATURE.lock(|temperature| temperature.get()) as i16 as u16,
+        input::PROBE_TEMPERATURE => {
+            PROBE_TEMPERATURE.lock(|temperature| temperature.get()) as i16 as u16
+        }
+        input::FAN_RPM => FAN_RPM.lock(|fan| fan.get()),
+        input::FAULTS => fault_flags(),
+        _ => 0,
+    }
+}
+
+/// Current faults, also sent in binary telemetry.
+pub fn fault_flags() -> u16 {
+    let mut flags = match FAN_FAULT.lock(|fault| fault.get()) {
+        FanFault::Ok => 0,
+        FanFault::Stalled => fault::FAN_STALLED,
+        FanFault::Failed => fault::FAN_FAILED,
+    };
+    if PWM_STATUS.lock(|pwm| pwm.get()).break_tripped {
+        flags |= fault::PWM_BREAK;
+    }
+    // The rail is only supervised while a source is connected
+    if POWER_SOURCE.lock(|source| source.get()) != PowerState::OFF {
+        match RAIL_LIMITS.check(RAIL_STATS.lock(|rail| rail.get()).mean) {
+            Some(RailFault::UnderVoltage) => flags |= fault::RAIL_UNDER,
+            Some(RailFault::OverVoltage) => flags |= fault::RAIL_OVER,
+            None => {}
+        }
+    }
+    flags
+}
+
+/// Validate a holding register value before anything of a request is applied.
+fn check_holding(address: u16, value: u16) -> Result<(), Exception> {
+    let valid = match address {
+        holding::FAN_SPEED => value <= 100,
+        holding::MODE => value <= 1,
+        holding::HYSTERESIS => (value as i16) >= 0,
+        holding::TURN_ON => {
+            let mut config = THERMAL_CONFIG.lock(|thermal| thermal.get());
+            let mut curve = config.curve;
+            curve[0].temperature = i32::from(value as i16);
+            config.set_curve(&curve[..config.points])
+        }
+        _ => true,
+    };
+    if !valid {
+        return Err(Exception::IllegalDataValue);
+    }
+    Ok(())
+}
+
+fn write_holding(
+    commands: &'static CommandTracker,
+    address: u16,
+    value: u16,
+) -> Result<(), Exception> {
+    info!("Modbus: write register {} = {}", address, value);
+    let temperature = i32::from(value as i16);
+    match address {
+        holding::FAN_SPEED => {
+            check_room(&COOLING_CHANNEL)?;
+            queue(commands, &SPEED_CHANNEL, value)?;
+            queue(commands, &COOLING_CHANNEL, CoolingState::On)?;
+        }
+        _ => THERMAL_CONFIG.lock(|thermal| {
+            let mut config = thermal.get();
+            match address {
+                holding::MODE => config.enabled = value == 1,
+                holding::TURN_ON => {
+                    let mut curve = config.curve;
+                    curve[0].temperature = temperature;
+                    config.set_curve(&curve[..config.points]);
+                }
+                holding::CRITICAL => config.critical = temperature,
+                holding::HYSTERESIS => config.hysteresis = temperature,
+                _ => return Err(Exception::IllegalDataAddress),
+            }
+            thermal.set(config);
+            Ok(())
+        })?,
+    }
+    Ok(())
+}
+
+/// Modbus RTU slave on a serial transport.
+///
+/// Frames are delimited by `frame_gap` of silence and checked with the
+/// CRC-16 also used for the settings image. Coil and register writes go to
+/// the device tasks through the same command channels as SCPI, and the
+/// reply only goes out once they are applied (as after `*OPC?`).
+pub struct ModbusSlave {
+    address: u8,
+    gap: Duration,
+    frame: [u8; FRAME_LEN],
+    len: usize,
+    /// The frame in progress did not fit and is dropped
+    overrun: bool,
+    reply: [u8; FRAME_LEN],
+    /// Power source as queued by this request, for relay coils cleared after set
+    power: PowerState,
+    wrote: bool,
+    /// Commands queued by this session, awaited before the reply
+    commands: &'static CommandTracker,
+}
+
+impl ModbusSlave {
+    pub const fn new(commands: &'static CommandTracker) -> Self {
+        Self {
+            address: 1,
+            gap: Duration::from_micros(1750),
+            frame: [0; FRAME_LEN],
+            len: 0,
+            overrun: false,
+            reply: [0; FRAME_LEN],
+            power: PowerState::OFF,
+            wrote: false,
+            commands,
+        }
+    }
+
+    pub fn set_config(&mut self, config: &SerialConfig) {
+        self.address = config.address;
+        self.gap = frame_gap(config);
+    }
+
+    /// Serve frames until the transport fails.
+    pub async fn run<R: Read, W: Write>(
+        &mut self,
+        rx: &mut R,
+        tx: &mut W,
+    ) -> Result<(), SessionError<R::Error, W::Error>> {
+        let mut chunk = [0u8; 64];
+        loop {
+            let read = if self.len == 0 && !self.overrun {
+                Ok(rx.read(&mut chunk).await)
+            } else {
+                with_timeout(self.gap, rx.read(&mut chunk)).await
+            };
+            match read {
+                Ok(Ok(n)) => {
+                    if self.len + n > FRAME_LEN {
+                        self.overrun = true;
+                        self.len = 0;
+                    } else if !self.overrun {
+                        self.frame[self.len..self.len + n].copy_from_slice(&chunk[..n]);
+                        self.len += n;
+                    }
+                }
+                Ok(Err(e)) => {
+                    self.len = 0;
+                    self.overrun = false;
+                    return Err(SessionError::Read(e));
+                }
+                // Line silent for 3.5 characters: the frame is complete
+                Err(_) => {
+                    let len = core::mem::take(&mut self.len);
+                    if core::mem::take(&mut self.overrun) {
+                        warn!("Modbus: frame too long, dropped");
+                        continue;
+                    }
+                    if let Some(reply_len) = self.handle_frame(len).await {
+                        tx.write_all(&self.reply[..reply_len]).await.map_err(SessionError::Write)?;
+                        tx.flush().await.map_err(SessionError::Write)?;
+                    }
+                }
+            }
+        }
+    }
+
+    /// Handle one received frame; returns the length of the reply, if any.
+    async fn handle_frame(&mut self, len: usize) -> Option<usize> {
+        if len < 4 {
+            return None;
+        }
+        let (body, crc) = self.frame[..len].split_at(len - 2);
+        if crc16(body) != u16::from_le_bytes([crc[0], crc[1]]) {
+            debug!("Modbus: CRC error, frame dropped");
+            return None;
+        }
+        let address = body[0];
+        if address != self.address && address != BROADCAST {
+            return None;
+        }
+
+        self.wrote = false;
+        let pdu_len = len - 3;
+        let mut pdu = [0u8; FRAME_LEN - 3];
+        pdu[..pdu_len].copy_from_slice(&self.frame[1..1 + pdu_len]);
+        let mut result = self.execute(&pdu[..pdu_len]);
+        if self.wrote && self.commands.all_applied().await.is_err() {
+            warn!("Modbus: writes not applied in time");
+            result = Err(Exception::DeviceFailure);
+        }
+        if address == BROADCAST {
+            return None;
+        }
+
+        self.reply[0] = self.address;
+        let reply_len = match result {
+            Ok(pdu_len) => 1 + pdu_len,
+            Err(exception) => {
+                warn!("Modbus: function {} failed: {}", pdu[0], exception);
+                self.reply[1] = pdu[0] | 0x80;
+                self.reply[2] = exception as u8;
+                3
+            }
+        };
+        let crc = crc16(&self.reply[..reply_len]);
+        self.reply[reply_len..reply_len + 2].copy_from_slice(&crc.to_le_bytes());
+        Some(reply_len + 2)
+    }
+
+    /// Execute a request PDU and leave the reply PDU after the address in
+    /// `reply`; returns its length.
+    fn execute(&mut self, pdu: &[u8]) -> Result<usize, Exception> {
+        let function = pdu[0];
+        let out = &mut self.reply[1..FRAME_LEN - 2];
+        out[0] = function;
+        let supported = matches!(
+            function,
+            function::READ_COILS
+                | function::READ_HOLDING_REGISTERS
+                | function::READ_INPUT_REGISTERS
+                | function::WRITE_SINGLE_COIL
+                | function::WRITE_SINGLE_REGISTER
+                | function::WRITE_MULTIPLE_COILS
+                | function::WRITE_MULTIPLE_REGISTERS
+        );
+        if !supported {
+            return Err(Exception::IllegalFunction);
+        }
+        // Every supported request starts with an address and a count (or value)
+        if pdu.len() < 5 {
+            return Err(Exception::IllegalDataValue);
+        }
+        let start = word(pdu, 1);
+        let count = word(pdu, 3);
+
+        match function {
+            function::READ_COILS => {
+                check_range(start, count, 2000, coil::COUNT)?;
+                let bytes = usize::from(count).div_ceil(8);
+                out[1] = bytes as u8;
+                out[2..2 + bytes].fill(0);
+                for index in 0..count {
+                    if read_coil(start + index) {
+                        out[2 + usize::from(index / 8)] |= 1 << (index % 8);
+                    }
+                }
+                Ok(2 + bytes)
+            }
+            function::READ_HOLDING_REGISTERS | function::READ_INPUT_REGISTERS => {
+                let (len, read): (u16, fn(u16) -> u16) = match function {
+                    function::READ_HOLDING_REGISTERS => (holding::COUNT, read_holding),
+                    _ => (input::COUNT, read_input),
+                };
+                check_range(start, count, 125, len)?;
+                out[1] = (count * 2) as u8;
+                for index in 0..count {
+                    let at = 2 + 2 * usize::from(index);
+                    out[at..at + 2].copy_from_slice(&read(start + index).to_be_bytes());
+                }
+                Ok(2 + 2 * usize::from(count))
+            }
+            function::WRITE_SINGLE_COIL => {
+                let on = match count {
+                    0xFF00 => true,
+                    0x0000 => false,
+                    _ => return Err(Exception::IllegalDataValue),
+                };
+                check_range(start, 1, 1, coil::COUNT)?;
+                self.power = POWER_SOURCE.lock(|source| source.get());
+                self.wrote = true;
+                self.write_coil(start, on)?;
+                self.reply[1..6].copy_from_slice(&pdu[..5]);
+                Ok(5)
+            }
+            function::WRITE_SINGLE_REGISTER => {
+                check_range(start, 1, 1, holding::COUNT)?;
+                check_holding(start, count)?;
+                self.wrote = true;
+                write_holding(self.commands, start, count)?;
+                self.reply[1..6].copy_from_slice(&pdu[..5]);
+                Ok(5)
+            }
+            function::WRITE_MULTIPLE_COILS => {
+                let bytes = usize::from(count).div_ceil(8);
+                if pdu.len() < 6 || usize::from(pdu[5]) != bytes || pdu.len() != 6 + bytes {
+                    return Err(Exception::IllegalDataValue);
+                }
+                check_range(start, count, 1968, coil::COUNT)?;
+                self.power = POWER_SOURCE.lock(|source| source.get());
+                self.wrote = true;
+                for index in 0..count {
+                    let on = pdu[6 + usize::from(index / 8)] & (1 << (index % 8)) != 0;
+                    self.write_coil(start + index, on)?;
+                }
+                self.reply[1..6].copy_from_slice(&pdu[..5]);
+                Ok(5)
+            }
+            function::WRITE_MULTIPLE_REGISTERS => {
+                let bytes = 2 * usize::from(count);
+                if pdu.len() < 6 || usize::from(pdu[5]) != bytes || pdu.len() != 6 + bytes {
+                    return Err(Exception::IllegalDataValue);
+                }
+                check_range(start, count, 123, holding::COUNT)?;
+                for index in 0..count {
+                    check_holding(start + index, word(pdu, 6 + 2 * usize::from(index)))?;
+                }
+                self.wrote = true;
+                for index in 0..count {
+                    let value = word(pdu, 6 + 2 * usize::from(index));
+                    write_holding(self.commands, start + index, value)?;
+                }
+                self.reply[1..6].copy_from_slice(&pdu[..5]);
+                Ok(5)
+            }
+            _ => Err(Exception::IllegalFunction),
+        }
+    }
+
+    fn write_coil(&mut self, address: u16, on: bool) -> Result<(), Exception> {
+        info!("Modbus: write coil {} = {}", address, on);
+        match address {
+            coil::LED => {
+                let state = if on { LedState::On } else { LedState::Off };
+                queue(self.commands, &LED_CHANNEL, state)
+            }
+            coil::COOLING => {
+                let state = if on { CoolingState::On } else { CoolingState::Off };
+                queue(self.commands, &COOLING_CHANNEL, state)
+            }
+            coil::POWER_ACDC | coil::POWER_DCDC => {
+                let source = match address {
+                    coil::POWER_ACDC => PowerState::ACDC,
+                    _ => PowerState::DCDC,
+                };
+                // Clearing the relay that is not connected changes nothing
+                let target = match (on, self.power == source) {
+                    (true, _) => source,
+                    (false, true) => PowerState::OFF,
+                    (false, false) => return Ok(()),
+                };
+                queue(self.commands, &POWER_CHANNEL, target)?;
+                self.power = target;
+                Ok(())
+            }
+            _ => Err(Exception::IllegalDataAddress),
+        }
+    }
+}
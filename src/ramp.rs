@@ -0,0 +1,171 @@
+use core::cell::Cell;
+
+use defmt::Format;
+use embassy_sync::blocking_mutex::raw::ThreadModeRawMutex;
+use embassy_sync::blocking_mutex::Mutex;
+
+/// Update interval of the tasks while a ramp is running
+pub const RAMP_TICK_MS: u64 = 10;
+
+/// Shape of the soft-start curve
+#[derive(Debug, Clone, Copy, PartialEq, Format)]
+pub enum RampProfile {
+    Linear,
+    /// Smoothstep: gentle at both ends, steepest in the middle
+    SCurve,
+}
+
+impl RampProfile {
+    /// Fraction of the way at `t` (both in 0.1 %, 0..=1000).
+    fn shape(self, t: u32) -> u32 {
+        match self {
+            RampProfile::Linear => t,
+            RampProfile::SCurve => t * t * (3000 - 2 * t) / 1_000_000,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Format)]
+pub struct RampConfig {
+    /// Slew-rate limit in % of full scale per second; 0 = unlimited
+    pub rate_percent_per_s: u16,
+    /// Soft-start time when the output is enabled from 0; 0 = none
+    pub soft_start_ms: u16,
+    pub profile: RampProfile,
+}
+
+impl RampConfig {
+    pub const DEFAULT: Self = Self {
+        rate_percent_per_s: 0,
+        soft_start_ms: 0,
+        profile: RampProfile::Linear,
+    };
+}
+
+/// Slew-rate limited setpoint with soft start, in the units of the channel
+/// (percent for the fan, `Duty` steps for the generic outputs).
+///
+/// The position is kept in 1/1000 units so slow rates still advance at a
+/// 10 ms update interval.
+#[derive(Debug, Clone, Copy)]
+pub struct Ramp {
+    config: RampConfig,
+    full_scale: u16,
+    position: u32,
+    target: u16,
+    soft_start_at_ms: Option<u64>,
+    last_ms: u64,
+}
+
+impl Ramp {
+    pub const fn new(full_scale: u16) -> Self {
+        Self {
+            config: RampConfig::DEFAULT,
+            full_scale,
+            position: 0,
+            target: 0,
+            soft_start_at_ms: None,
+            last_ms: 0,
+        }
+    }
+
+    pub fn set_config(&mut self, config: RampConfig) {
+        self.config = config;
+    }
+
+    pub fn output(&self) -> u16 {
+        ((self.position + 500) / 1000) as u16
+    }
+
+    pub fn is_active(&self) -> bool {
+        self.soft_start_at_ms.is_some() || self.position != u32::from(self.target) * 1000
+    }
+
+    /// Move towards `target`. Enabling from 0 runs the soft-start profile;
+    /// a new target during soft start continues at the slew-rate limit.
+    pub fn set_target(&mut self, target: u16, now_ms: u64) {
+        let target = target.min(self.full_scale);
+        if !self.is_active() {
+            self.last_ms = now_ms;
+        }
+        let enabling = self.position == 0 && target > 0;
+        self.soft_start_at_ms = (enabling && self.config.soft_start_ms > 0).then_some(now_ms);
+        self.target = target;
+    }
+
+    /// Set the output immediately, bypassing limits (restart kick, shutdown).
+    pub fn jump(&mut self, value: u16) {
+        self.target = value.min(self.full_scale);
+        self.position = u32::from(self.target) * 1000;
+        self.soft_start_at_ms = None;
+    }
+
+    /// Advance to `now_ms`; returns the output when it changed.
+    pub fn update(&mut self, now_ms: u64) -> Option<u16> {
+        let before = self.output();
+        let dt_ms = now_ms.saturating_sub(self.last_ms);
+        self.last_ms = now_ms;
+        let goal = u32::from(self.target) * 1000;
+
+        if let Some(started) = self.soft_start_at_ms {
+            let duration = u64::from(self.config.soft_start_ms);
+            let elapsed = now_ms.saturating_sub(started);
+            if elapsed >= duration {
+                self.position = goal;
+                self.soft_start_at_ms = None;
+            } else {
+                let t = (elapsed * 1000 / duration) as u32;
+                self.position = u32::from(self.target) * self.config.profile.shape(t);
+            }
+        } else if self.config.rate_percent_per_s == 0 {
+            self.position = goal;
+        } else {
+            let rate = u64::from(self.config.rate_percent_per_s);
+            let step = u64::from(self.full_scale) * rate * dt_ms / 100;
+            let step = step.min(u64::from(u32::MAX)) as u32;
+            self.position = if self.position < goal {
+                self.position.saturating_add(step).min(goal)
+            } else {
+                self.position.saturating_sub(step).max(goal)
+            };
+        }
+
+        let after = self.output();
+        (after != before).then_some(after)
+    }
+}
+
+/// Shared ramp settings and status of one output.
+pub struct RampChannel {
+    config: Mutex<ThreadModeRawMutex, Cell<RampConfig>>,
+    active: Mutex<ThreadModeRawMutex, Cell<bool>>,
+}
+
+impl RampChannel {
+    pub const fn new() -> Self {
+        Self {
+            config: Mutex::new(Cell::new(RampConfig::DEFAULT)),
+            active: Mutex::new(Cell::new(false)),
+        }
+    }
+
+    pub fn config(&self) -> RampConfig {
+        self.config.lock(|config| config.get())
+    }
+
+    pub fn set_config(&self, config: RampConfig) {
+        self.config.lock(|cell| cell.set(config));
+    }
+
+    pub fn is_active(&self) -> bool {
+        self.active.lock(|active| active.get())
+    }
+
+    /// Publish the state of `ramp`; returns `true` when a ramp just finished,
+    /// for the owning task to log `Event::RampDone`.
+    pub fn publish(&self, ramp: &Ramp) -> bool {
+        let active = ramp.is_active();
+        let was_active = self.active.lock(|cell| cell.replace(active));
+        was_active && !active
+    }
+}
@@ -0,0 +1,119 @@
+use alloc::vec::Vec;
+use defmt::warn;
+use scpi::error::ErrorCode;
+use scpi::tree::prelude::{Context, Device, Node};
+
+use crate::session::LineHandler;
+
+/// Per-connection state of an SCPI device (error queue, formats, locks),
+/// kept by the device instance of a session.
+pub trait SessionDevice: Device {
+    /// Back to the state of a new connection.
+    fn reset_session(&mut self) {}
+
+    /// Whether `*RST` ran since the last call; the parser context is then
+    /// reset as well.
+    fn take_reset(&mut self) -> bool {
+        false
+    }
+
+    /// False while another session holds the control lock.
+    fn may_control(&self) -> bool {
+        true
+    }
+
+    /// Wait until the response of the last line may go out (`*OPC?`, `*WAI`).
+    /// False if the wait failed; the line is then answered with `ERR`.
+    async fn settle(&mut self) -> bool {
+        true
+    }
+
+    /// The transport settings changed; stop after the current response.
+    fn pause(&self) -> bool {
+        false
+    }
+}
+
+/// True if every unit of the line is a query or a common command, so the
+/// line changes no settings.
+fn is_query_only(line: &[u8]) -> bool {
+    line.split(|&byte| byte == b';').all(|unit| {
+        let unit = unit.trim_ascii_start();
+        let header = unit.split(|byte| byte.is_ascii_whitespace()).next().unwrap_or(&[]);
+        header.is_empty() || header.starts_with(b"*") || header.ends_with(b"?")
+    })
+}
+
+/// Runs each line through an SCPI command tree.
+///
+/// Takes the raw bytes, so block data may be binary. The `Context` lives as
+/// long as the session, together with the device instance. A failing
+/// command line is answered with `ERR`.
+pub struct ScpiHandler<'a, D: SessionDevice> {
+    tree: &'a Node<'a, D>,
+    device: D,
+    context: Context<'static>,
+    response: Vec<u8>,
+}
+
+impl<'a, D: SessionDevice> ScpiHandler<'a, D> {
+    pub fn new(tree: &'a Node<'a, D>, device: D) -> Self {
+        Self {
+            tree,
+            device,
+            context: Context::default(),
+            response: Vec::new(),
+        }
+    }
+
+    fn error(&mut self) {
+        self.response.clear();
+        self.response.extend_from_slice(b"ERR\r\n");
+    }
+}
+
+impl<D: SessionDevice> LineHandler for ScpiHandler<'_, D> {
+    fn line(&mut self, line: &[u8]) {
+        if !self.device.may_control() && !is_query_only(line) {
+            warn!("SCPI line refused, locked by another session");
+            self.device.handle_error(ErrorCode::CommandProtected.into());
+            self.error();
+            return;
+        }
+
+        self.response.clear();
+        let res = self.tree.run(line, &mut self.device, &mut self.context, &mut self.response);
+        if self.device.take_reset() {
+            self.context = Context::default();
+        }
+        if res.is_err() {
+            warn!("SCPI run error");
+            self.error();
+        }
+    }
+
+    fn overrun(&mut self) {
+        warn!("SCPI line too long, dropped");
+        self.device.handle_error(ErrorCode::InputBufferOverrun.into());
+        self.error();
+    }
+
+    async fn settle(&mut self) {
+        if !self.device.settle().await {
+            self.error();
+        }
+    }
+
+    fn response(&self) -> &[u8] {
+        &self.response
+    }
+
+    fn pause(&mut self) -> bool {
+        self.device.pause()
+    }
+
+    fn reset(&mut self) {
+        self.device.reset_session();
+        self.context = Context::default();
+    }
+}
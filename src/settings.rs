@@ -0,0 +1,383 @@
+use defmt::*;
+use embassy_stm32::flash::{self, Blocking, Flash, FLASH_SIZE};
+
+use crate::crc::crc16;
+use crate::shared::{DAC_CALIBRATION, FAN_CALIBRATION, PWM_CONFIG, SERIAL_CONFIG, SERIAL_STORED};
+use crate::tasks::cooling::{CalibrationPoint, CoolerCalibration, CALIBRATION_POINTS};
+use crate::tasks::dac::DacCalibration;
+use crate::tasks::pwm::{PwmConfig, PWM_FREQUENCY_MAX_HZ, PWM_FREQUENCY_MIN_HZ};
+use crate::tasks::rx_tx::{
+    SerialConfig, SerialMode, SerialParity, SerialProtocol, SerialStopBits, Terminator,
+    BUS_ADDRESSES, SERIAL_BAUDRATES,
+};
+
+/// Settings occupy the last 1 KiB page of flash, which memory.x keeps out of
+/// the linker's FLASH region; the image must stay below it.
+const PAGE_SIZE: u32 = 1024;
+const SETTINGS_OFFSET: u32 = FLASH_SIZE as u32 - PAGE_SIZE;
+const MAGIC: u32 = 0x5354_4731; // "STG1"
+/// magic (4) | body length (2) | CRC-16 of the body (2)
+const HEADER_LEN: usize = 8;
+/// Encoded image size; even, as flash is written in half-words
+const IMAGE_LEN: usize = 256;
+
+/// Record tags. A record is `tag, length, payload`; unknown tags are skipped
+/// on load so older firmware can read newer images and vice versa.
+mod tag {
+    pub const FAN_CALIBRATION: u8 = 1;
+    pub const PWM: u8 = 2;
+    pub const DAC_CALIBRATION: u8 = 3;
+    pub const SERIAL: u8 = 4;
+    pub const BUS: u8 = 5;
+}
+
+/// Everything kept across resets.
+#[derive(Debug, Clone, Copy, PartialEq, Format)]
+pub struct Settings {
+    pub fan_calibration: CoolerCalibration,
+    pub pwm_frequency_hz: u32,
+    pub pwm_inverted: [bool; 4],
+    pub dac_calibration: DacCalibration,
+    pub serial: SerialConfig,
+}
+
+struct Writer<'a> {
+    buf: &'a mut [u8],
+    pos: usize,
+}
+
+impl Writer<'_> {
+    fn u8(&mut self, value: u8) {
+        self.buf[self.pos] = value;
+        self.pos += 1;
+    }
+
+    fn u16(&mut self, value: u16) {
+        self.buf[self.pos..self.pos + 2].copy_from_slice(&value.to_le_bytes());
+        self.pos += 2;
+    }
+
+    fn u32(&mut self, value: u32) {
+        self.buf[self.pos..self.pos + 4].copy_from_slice(&value.to_le_bytes());
+        self.pos += 4;
+    }
+
+    /// Write one record; `encode` fills in the payload.
+    fn record(&mut self, tag: u8, encode: impl FnOnce(&mut Self)) {
+        self.u8(tag);
+        let length_at = self.pos;
+        self.u8(0);
+        encode(self);
+        self.buf[length_at] = (self.pos - length_at - 1) as u8;
+    }
+}
+
+struct Reader<'a> {
+    buf: &'a [u8],
+    pos: usize,
+}
+
+impl Reader<'_> {
+    fn u8(&mut self) -> Option<u8> {
+        let value = *self.buf.get(self.pos)?;
+        self.pos += 1;
+        Some(value)
+    }
+
+    fn u16(&mut self) -> Option<u16> {
+        let bytes = self.buf.get(self.pos..self.pos + 2)?;
+        self.pos += 2;
+        Some(u16::from_le_bytes([bytes[0], bytes[1]]))
+    }
+
+    fn u32(&mut self) -> Option<u32> {
+        let bytes = self.buf.get(self.pos..self.pos + 4)?;
+        self.pos += 4;
+        Some(u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+    }
+}
+
+fn encode_fan_calibration(out: &mut Writer, calibration: &CoolerCalibration) {
+    out.u16(calibration.min_duty_percent);
+    out.u16(calibration.max_duty_percent);
+    out.u16(calibration.min_rpm);
+    out.u16(calibration.max_rpm);
+    for point in calibration.table {
+        out.u8(point.duty_percent);
+        out.u16(point.rpm);
+    }
+}
+
+fn decode_fan_calibration(input: &mut Reader) -> Option<CoolerCalibration> {
+    let mut calibration = CoolerCalibration {
+        min_duty_percent: input.u16()?,
+        max_duty_percent: input.u16()?,
+        min_rpm: input.u16()?,
+        max_rpm: input.u16()?,
+        table: [CalibrationPoint { duty_percent: 0, rpm: 0 }; CALIBRATION_POINTS],
+    };
+    for point in calibration.table.iter_mut() {
+        point.duty_percent = input.u8()?;
+        point.rpm = input.u16()?;
+    }
+    // A record of another layout (table size) is ignored, keeping the defaults
+    (input.pos == input.buf.len()).then_some(calibration)
+}
+
+fn encode_dac_calibration(out: &mut Writer, calibration: &DacCalibration) {
+    out.u16(calibration.full_scale_mv);
+    out.u16(calibration.offset_mv as u16);
+}
+
+fn decode_dac_calibration(input: &mut Reader) -> Option<DacCalibration> {
+    let full_scale_mv = input.u16()?;
+    if full_scale_mv == 0 {
+        return None;
+    }
+    Some(DacCalibration {
+        full_scale_mv,
+        offset_mv: input.u16()? as i16,
+    })
+}
+
+fn encode_serial(out: &mut Writer, serial: &SerialConfig) {
+    out.u32(serial.baudrate);
+    out.u8(match serial.parity {
+        SerialParity::None => 0,
+        SerialParity::Even => 1,
+        SerialParity::Odd => 2,
+    });
+    out.u8(match serial.stop_bits {
+        SerialStopBits::One => 1,
+        SerialStopBits::Two => 2,
+    });
+    out.u8(match serial.terminator {
+        Terminator::Lf => 0,
+        Terminator::CrLf => 1,
+        Terminator::Cr => 2,
+    });
+}
+
+fn decode_serial(input: &mut Reader) -> Option<SerialConfig> {
+    let baudrate = input.u32()?;
+    if !SERIAL_BAUDRATES.contains(&baudrate) {
+        return None;
+    }
+    let parity = match input.u8()? {
+        0 => SerialParity::None,
+        1 => SerialParity::Even,
+        2 => SerialParity::Odd,
+        _ => return None,
+    };
+    let stop_bits = match input.u8()? {
+        1 => SerialStopBits::One,
+        2 => SerialStopBits::Two,
+        _ => return None,
+    };
+    let terminator = match input.u8()? {
+        0 => Terminator::Lf,
+        1 => Terminator::CrLf,
+        2 => Terminator::Cr,
+        _ => return None,
+    };
+    Some(SerialConfig {
+        baudrate,
+        parity,
+        stop_bits,
+        terminator,
+        ..SerialConfig::DEFAULT
+    })
+}
+
+fn encode_bus(out: &mut Writer, serial: &SerialConfig) {
+    out.u8(match serial.mode {
+        SerialMode::Rs232 => 0,
+        SerialMode::Rs485 => 1,
+    });
+    out.u8(serial.address);
+    out.u8(match serial.protocol {
+        SerialProtocol::Scpi => 0,
+        SerialProtocol::Modbus => 1,
+        SerialProtocol::Binary => 2,
+    });
+}
+
+fn decode_bus(input: &mut Reader) -> Option<(SerialMode, u8, SerialProtocol)> {
+    let mode = match input.u8()? {
+        0 => SerialMode::Rs232,
+        1 => SerialMode::Rs485,
+        _ => return None,
+    };
+    let address = input.u8()?;
+    if !BUS_ADDRESSES.contains(&address) {
+        return None;
+    }
+    let protocol = match input.u8()? {
+        0 => SerialProtocol::Scpi,
+        1 => SerialProtocol::Modbus,
+        2 => SerialProtocol::Binary,
+        _ => return None,
+    };
+    Some((mode, address, protocol))
+}
+
+fn encode_pwm(out: &mut Writer, frequency_hz: u32, inverted: &[bool; 4]) {
+    out.u32(frequency_hz);
+    let mask = inverted
+        .iter()
+        .enumerate()
+        .fold(0u8, |mask, (index, &inverted)| mask | (u8::from(inverted) << index));
+    out.u8(mask);
+}
+
+fn decode_pwm(input: &mut Reader) -> Option<(u32, [bool; 4])> {
+    let frequency_hz = input.u32()?;
+    if !(PWM_FREQUENCY_MIN_HZ..=PWM_FREQUENCY_MAX_HZ).contains(&frequency_hz) {
+        return None;
+    }
+    let mask = input.u8()?;
+    Some((frequency_hz, core::array::from_fn(|index| mask & (1 << index) != 0)))
+}
+
+impl Settings {
+    pub const DEFAULT: Self = Self {
+        fan_calibration: CoolerCalibration::DEFAULT,
+        pwm_frequency_hz: PwmConfig::DEFAULT.frequency_hz,
+        pwm_inverted: PwmConfig::DEFAULT.inverted,
+        dac_calibration: DacCalibration::DEFAULT,
+        serial: SerialConfig::DEFAULT,
+    };
+
+    /// Snapshot of the live values in `shared`.
+    pub fn capture() -> Self {
+        let pwm = PWM_CONFIG.lock(|pwm| pwm.get());
+        Self {
+            fan_calibration: FAN_CALIBRATION.lock(|fan| fan.get()),
+            pwm_frequency_hz: pwm.frequency_hz,
+            pwm_inverted: pwm.inverted,
+            dac_calibration: DAC_CALIBRATION.lock(|dac| dac.get()),
+            serial: SERIAL_STORED
+                .lock(|stored| stored.get())
+                .unwrap_or_else(|| SERIAL_CONFIG.lock(|serial| serial.get())),
+        }
+    }
+
+    /// Publish the values to `shared`, where the tasks pick them up.
+    pub fn apply(&self) {
+        FAN_CALIBRATION.lock(|fan| fan.set(self.fan_calibration));
+        DAC_CALIBRATION.lock(|dac| dac.set(self.dac_calibration));
+        SERIAL_CONFIG.lock(|serial| serial.set(self.serial));
+        PWM_CONFIG.lock(|pwm| {
+            let config = pwm.get();
+            pwm.set(PwmConfig {
+                frequency_hz: self.pwm_frequency_hz,
+                inverted: self.pwm_inverted,
+                ..config
+            });
+        });
+    }
+
+    fn encode(&self, image: &mut [u8; IMAGE_LEN]) {
+        image.fill(0xFF);
+        let (header, body) = image.split_at_mut(HEADER_LEN);
+        let mut out = Writer { buf: body, pos: 0 };
+        out.record(tag::FAN_CALIBRATION, |out| {
+            encode_fan_calibration(out, &self.fan_calibration)
+        });
+        out.record(tag::PWM, |out| {
+            encode_pwm(out, self.pwm_frequency_hz, &self.pwm_inverted)
+        });
+        out.record(tag::DAC_CALIBRATION, |out| {
+            encode_dac_calibration(out, &self.dac_calibration)
+        });
+        out.record(tag::SERIAL, |out| encode_serial(out, &self.serial));
+        out.record(tag::BUS, |out| encode_bus(out, &self.serial));
+        let body_len = out.pos;
+
+        header[0..4].copy_from_slice(&MAGIC.to_le_bytes());
+        header[4..6].copy_from_slice(&(body_len as u16).to_le_bytes());
+        header[6..8].copy_from_slice(&crc16(&body[..body_len]).to_le_bytes());
+    }
+
+    /// Decode an image; fields without a valid record keep their defaults.
+    fn decode(image: &[u8; IMAGE_LEN]) -> Option<Self> {
+        let (header, body) = image.split_at(HEADER_LEN);
+        if u32::from_le_bytes([header[0], header[1], header[2], header[3]]) != MAGIC {
+            return None;
+        }
+        let body_len = usize::from(u16::from_le_bytes([header[4], header[5]]));
+        let body = body.get(..body_len)?;
+        if crc16(body) != u16::from_le_bytes([header[6], header[7]]) {
+            return None;
+        }
+
+        let mut settings = Self::DEFAULT;
+        let mut input = Reader { buf: body, pos: 0 };
+        while let (Some(tag), Some(length)) = (input.u8(), input.u8()) {
+            let payload = body.get(input.pos..input.pos + usize::from(length))?;
+            input.pos += usize::from(length);
+            let mut record = Reader { buf: payload, pos: 0 };
+            match tag {
+                tag::FAN_CALIBRATION => {
+                    if let Some(calibration) = decode_fan_calibration(&mut record) {
+                        settings.fan_calibration = calibration;
+                    }
+                }
+                tag::PWM => {
+                    if let Some((frequency_hz, inverted)) = decode_pwm(&mut record) {
+                        settings.pwm_frequency_hz = frequency_hz;
+                        settings.pwm_inverted = inverted;
+                    }
+                }
+                tag::DAC_CALIBRATION => {
+                    if let Some(calibration) = decode_dac_calibration(&mut record) {
+                        settings.dac_calibration = calibration;
+                    }
+                }
+                tag::SERIAL => {
+                    if let Some(serial) = decode_serial(&mut record) {
+                        settings.serial = SerialConfig {
+                            mode: settings.serial.mode,
+                            address: settings.serial.address,
+                            protocol: settings.serial.protocol,
+                            ..serial
+                        };
+                    }
+                }
+                tag::BUS => {
+                    if let Some((mode, address, protocol)) = decode_bus(&mut record) {
+                        settings.serial.mode = mode;
+                        settings.serial.address = address;
+                        settings.serial.protocol = protocol;
+                    }
+                }
+                _ => debug!("Settings: skipping unknown tag {}", tag),
+            }
+        }
+        Some(settings)
+    }
+
+    /// Read the stored settings, or the defaults if the page is blank or corrupt.
+    pub fn load(flash: &mut Flash<'_, Blocking>) -> Self {
+        let mut image = [0u8; IMAGE_LEN];
+        if let Err(error) = flash.blocking_read(SETTINGS_OFFSET, &mut image) {
+            warn!("Settings: read failed: {}", error);
+            return Self::DEFAULT;
+        }
+        match Self::decode(&image) {
+            Some(settings) => settings,
+            None => {
+                info!("Settings: none stored, using defaults");
+                Self::DEFAULT
+            }
+        }
+    }
+
+    /// Erase the settings page and write the current image.
+    pub fn save(&self, flash: &mut Flash<'_, Blocking>) -> Result<(), flash::Error> {
+        let mut image = [0u8; IMAGE_LEN];
+        self.encode(&mut image);
+        flash.blocking_erase(SETTINGS_OFFSET, SETTINGS_OFFSET + PAGE_SIZE)?;
+        flash.blocking_write(SETTINGS_OFFSET, &image)
+    }
+}
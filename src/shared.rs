@@ -1,16 +1,33 @@
+use core::cell::{Cell, RefCell};
+
 use defmt::Format;
-use embassy_sync::blocking_mutex::raw::ThreadModeRawMutex;
+use embassy_sync::blocking_mutex::Mutex;
+use embassy_sync::blocking_mutex::raw::{CriticalSectionRawMutex, ThreadModeRawMutex};
 use embassy_sync::channel::Channel;
 use embassy_sync::signal::Signal;
 
+use crate::capture::Capture;
+use crate::completion::{CommandTracker, Completion};
+use crate::duty::Duty;
+use crate::events::EventLog;
+use crate::pid::PidConfig;
+use crate::ramp::RampChannel;
+use crate::stall::{FanFault, StallConfig};
+use crate::stats::{WindowAccumulator, WindowStats};
+use crate::tasks::calibration::CalibrationState;
+use crate::tasks::cooling::CoolerCalibration;
+use crate::tasks::dac::{DacCalibration, DacConfig, DacStatus};
+use crate::tasks::pwm::{PwmChannel, PwmConfig, PwmStatus};
+use crate::tasks::pwm_input::{PwmInputConfig, PwmInputMeasurement};
+use crate::tasks::rx_tx::SerialConfig;
+use crate::tasks::tach::TachConfig;
+use crate::thermal::ThermalConfig;
+use crate::trend::TrendLog;
+use crate::waveform::{WaveTable, WaveformConfig};
+
 
 // Power control types
-#[derive(Debug, Clone, Copy, PartialEq, Format)]
-pub enum PowerState {
-    DCDC,
-    ACDC,
-    OFF,
-}
+pub use crate::power::PowerState;
 
 #[derive(Debug, Clone, Copy, PartialEq, Format)]
 pub enum LedState {
@@ -25,19 +42,96 @@ pub enum CoolingState {
     Off,
 }
 
+/// Allowed window for the rail voltage on PA4, in millivolts.
+///
+/// The power task checks the averaged reading against it and the ADC analog
+/// watchdog is programmed from the same values, so both react to one limit.
+#[derive(Debug, Clone, Copy, PartialEq, Format)]
+pub struct RailLimits {
+    pub under_mv: u32,
+    pub over_mv: u32,
+}
+
+impl RailLimits {
+    pub fn check(&self, voltage: u32) -> Option<RailFault> {
+        if voltage < self.under_mv {
+            Some(RailFault::UnderVoltage)
+        } else if voltage > self.over_mv {
+            Some(RailFault::OverVoltage)
+        } else {
+            None
+        }
+    }
+}
+
+pub const RAIL_LIMITS: RailLimits = RailLimits {
+    under_mv: 100,
+    over_mv: 3000,
+};
+
+/// Rail sampling locked to the TIM1 PWM carrier (injected conversion on TIM1 TRGO = OC2REF,
+/// so CH2 is unavailable as an output while enabled)
+#[derive(Debug, Clone, Copy, PartialEq, Format)]
+pub struct AdcSyncConfig {
+    pub enabled: bool,
+    /// Sampling point within the PWM period, 0.1 % steps (0..=999)
+    pub phase_permille: u16,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Format)]
+pub enum RailFault {
+    UnderVoltage,
+    OverVoltage,
+}
+
 // Shared async primitives
-pub static SHARED_DUTY: Signal<ThreadModeRawMutex, u16> = Signal::new();
+// Duty targets for the generic TIM1 outputs (CH2..CH4)
+pub static SHARED_DUTY: Channel<ThreadModeRawMutex, (PwmChannel, Duty), 4> = Channel::new();
+// Fan PWM duty, produced only by the cooling controller
+pub static FAN_DUTY: Signal<ThreadModeRawMutex, Duty> = Signal::new();
+// Averaged rail voltage (mV) of each ADC window, drives the power task
 pub static SHARED_ADC_VALUE: Signal<ThreadModeRawMutex, u32> = Signal::new();
+// Optional forced source for the automatic selection (1 DCDC, 2 ACDC, 3 OFF,
+// 0 back to the rail voltage); picked up with the next reading
 pub static SHARED_MESSAGE: Signal<ThreadModeRawMutex, u32> = Signal::new();
 
-// Device control channels
-pub static LED_CHANNEL: Channel<ThreadModeRawMutex, LedState, 4> = Channel::new();
-pub static POWER_CHANNEL: Channel<ThreadModeRawMutex, PowerState, 4> = Channel::new();
-pub static COOLING_CHANNEL: Channel<ThreadModeRawMutex, CoolingState, 4> = Channel::new();
-pub static SPEED_CHANNEL: Channel<ThreadModeRawMutex, u16, 4> = Channel::new();
+// Slew-rate limit / soft start of the fan (percent) and the generic outputs CH2..CH4
+pub static FAN_RAMP: RampChannel = RampChannel::new();
+pub static OUTPUT_RAMPS: [RampChannel; 3] =
+    [RampChannel::new(), RampChannel::new(), RampChannel::new()];
+
+// TIM1 output settings (applied on `PWM_UPDATE`) and the state the PWM task applied
+pub static PWM_CONFIG: Mutex<ThreadModeRawMutex, Cell<PwmConfig>> =
+    Mutex::new(Cell::new(PwmConfig::DEFAULT));
+pub static PWM_UPDATE: Signal<ThreadModeRawMutex, ()> = Signal::new();
+pub static PWM_STATUS: Mutex<ThreadModeRawMutex, Cell<PwmStatus>> =
+    Mutex::new(Cell::new(PwmStatus::EMPTY));
+// Raised from the TIM1_BRK interrupt when the break input shuts the outputs down
+pub static PWM_BREAK: Signal<CriticalSectionRawMutex, ()> = Signal::new();
+pub static PWM_BREAK_CLEAR: Signal<ThreadModeRawMutex, ()> = Signal::new();
+
+// Function generator on one TIM1 output, also applied on `PWM_UPDATE`
+pub static WAVEFORM_CONFIG: Mutex<ThreadModeRawMutex, Cell<WaveformConfig>> =
+    Mutex::new(Cell::new(WaveformConfig::DEFAULT));
+pub static WAVEFORM_TABLE: Mutex<ThreadModeRawMutex, RefCell<WaveTable>> =
+    Mutex::new(RefCell::new(WaveTable::new()));
 
-// Channel to change LED delay dynamically
-pub static DELAY_CHANNEL: Channel<ThreadModeRawMutex, u64, 4> = Channel::new();
+// Device control channels; the receiving task drops the `Completion` once applied
+pub type CommandChannel<T> = Channel<ThreadModeRawMutex, (T, Completion), 4>;
+pub static LED_CHANNEL: CommandChannel<LedState> = Channel::new();
+pub static POWER_CHANNEL: CommandChannel<PowerState> = Channel::new();
+pub static COOLING_CHANNEL: CommandChannel<CoolingState> = Channel::new();
+pub static SPEED_CHANNEL: CommandChannel<u16> = Channel::new();
+// Fan RPM target, switches the cooling controller to closed loop
+pub static RPM_TARGET_CHANNEL: CommandChannel<u16> = Channel::new();
+// Commands queued and not applied yet, per session: USART1 (SCPI, Modbus or
+// binary) and the USART3 binary port
+pub static SERIAL_COMMANDS: CommandTracker = CommandTracker::new();
+pub static BINARY_COMMANDS: CommandTracker = CommandTracker::new();
+
+// Blink delay of the status LED for the power state; only the latest value
+// matters, so a new one replaces one the blinky task has not picked up yet
+pub static LED_DELAY: Signal<ThreadModeRawMutex, u64> = Signal::new();
 
 // Device status signals
 pub static POWER_STATUS: Signal<ThreadModeRawMutex, PowerState> = Signal::new();
@@ -45,5 +139,110 @@ pub static LED_STATUS: Signal<ThreadModeRawMutex, bool> = Signal::new();
 pub static COOLING_STATUS: Signal<ThreadModeRawMutex, CoolingState> = Signal::new();
 pub static CURRENT_SPEED: Signal<ThreadModeRawMutex, u16> = Signal::new();
 
-// Channel to send messages from RX to TX
-pub static TX_MESSAGE_CHANNEL: Channel<ThreadModeRawMutex, heapless::String<64>, 4> = Channel::new();
\ No newline at end of file
+// Last applied output states, readable at any time (Modbus coils and registers)
+pub static LED_ON: Mutex<ThreadModeRawMutex, Cell<bool>> = Mutex::new(Cell::new(false));
+pub static POWER_SOURCE: Mutex<ThreadModeRawMutex, Cell<PowerState>> =
+    Mutex::new(Cell::new(PowerState::OFF));
+pub static COOLING_ON: Mutex<ThreadModeRawMutex, Cell<bool>> = Mutex::new(Cell::new(false));
+// Open-loop fan speed setpoint, percent
+pub static FAN_SPEED: Mutex<ThreadModeRawMutex, Cell<u16>> = Mutex::new(Cell::new(0));
+
+// Raised from the ADC1_2 interrupt (analog watchdog), hence the critical-section mutex
+pub static RAIL_FAULT: Signal<CriticalSectionRawMutex, RailFault> = Signal::new();
+// Raised by the analog watchdog interrupt; the ADC task judges the direction
+pub static RAIL_WATCHDOG: Signal<CriticalSectionRawMutex, ()> = Signal::new();
+
+// Statistics of the last rail averaging window, readable from SCPI queries
+pub static RAIL_STATS: Mutex<ThreadModeRawMutex, Cell<WindowStats>> =
+    Mutex::new(Cell::new(WindowStats::EMPTY));
+
+// PWM-synchronised rail sampling: configuration, ISR accumulator and last window
+pub static ADC_SYNC: Mutex<ThreadModeRawMutex, Cell<AdcSyncConfig>> =
+    Mutex::new(Cell::new(AdcSyncConfig {
+        enabled: false,
+        phase_permille: 500,
+    }));
+pub static SYNC_WINDOW: Mutex<CriticalSectionRawMutex, Cell<WindowAccumulator>> =
+    Mutex::new(Cell::new(WindowAccumulator::new()));
+pub static SYNC_STATS: Mutex<ThreadModeRawMutex, Cell<WindowStats>> =
+    Mutex::new(Cell::new(WindowStats::EMPTY));
+
+// Fan speed measured from the tach input, and its configuration
+pub static FAN_RPM: Mutex<ThreadModeRawMutex, Cell<u16>> = Mutex::new(Cell::new(0));
+pub static TACH_CONFIG: Mutex<ThreadModeRawMutex, Cell<TachConfig>> =
+    Mutex::new(Cell::new(TachConfig::DEFAULT));
+
+// PWM DAC on TIM1 CH4: settings (applied on `DAC_UPDATE`), filter calibration
+// (loaded from flash at boot), applied state and the PA2 readback of each ADC window
+pub static DAC_CONFIG: Mutex<ThreadModeRawMutex, Cell<DacConfig>> =
+    Mutex::new(Cell::new(DacConfig::DEFAULT));
+pub static DAC_UPDATE: Signal<ThreadModeRawMutex, ()> = Signal::new();
+pub static DAC_CALIBRATION: Mutex<ThreadModeRawMutex, Cell<DacCalibration>> =
+    Mutex::new(Cell::new(DacCalibration::DEFAULT));
+pub static DAC_CALIBRATION_REQUEST: Signal<ThreadModeRawMutex, ()> = Signal::new();
+pub static DAC_CALIBRATION_STATE: Mutex<ThreadModeRawMutex, Cell<CalibrationState>> =
+    Mutex::new(Cell::new(CalibrationState::Idle));
+pub static DAC_STATUS: Mutex<ThreadModeRawMutex, Cell<DacStatus>> =
+    Mutex::new(Cell::new(DacStatus::EMPTY));
+pub static DAC_READBACK: Signal<ThreadModeRawMutex, u32> = Signal::new();
+
+// External PWM signal measured on TIM3 CH1 (PA6), and its configuration
+pub static PWM_INPUT: Mutex<ThreadModeRawMutex, Cell<PwmInputMeasurement>> =
+    Mutex::new(Cell::new(PwmInputMeasurement::EMPTY));
+pub static PWM_INPUT_CONFIG: Mutex<ThreadModeRawMutex, Cell<PwmInputConfig>> =
+    Mutex::new(Cell::new(PwmInputConfig::DEFAULT));
+pub static FAN_PID: Mutex<ThreadModeRawMutex, Cell<PidConfig>> =
+    Mutex::new(Cell::new(PidConfig::DEFAULT));
+
+// Fan calibration: current values (loaded from flash at boot), the duty the
+// calibration task forces while it runs, and the calibration run itself
+pub static FAN_CALIBRATION: Mutex<ThreadModeRawMutex, Cell<CoolerCalibration>> =
+    Mutex::new(Cell::new(CoolerCalibration::DEFAULT));
+pub static FAN_OVERRIDE: Signal<ThreadModeRawMutex, Option<u16>> = Signal::new();
+pub static CALIBRATION_REQUEST: Signal<ThreadModeRawMutex, ()> = Signal::new();
+pub static CALIBRATION_STATE: Mutex<ThreadModeRawMutex, Cell<CalibrationState>> =
+    Mutex::new(Cell::new(CalibrationState::Idle));
+
+// Fan stall supervision: settings, current fault (latched on failure) and the
+// request to clear a latched failure
+pub static STALL_CONFIG: Mutex<ThreadModeRawMutex, Cell<StallConfig>> =
+    Mutex::new(Cell::new(StallConfig::DEFAULT));
+pub static FAN_FAULT: Mutex<ThreadModeRawMutex, Cell<FanFault>> =
+    Mutex::new(Cell::new(FanFault::Ok));
+pub static FAN_FAULT_CLEAR: Signal<ThreadModeRawMutex, ()> = Signal::new();
+
+// Fault, recovery and ramp-complete events, read by SCPI `SYSTem:EVENt` commands
+pub static EVENT_LOG: Mutex<ThreadModeRawMutex, RefCell<EventLog>> =
+    Mutex::new(RefCell::new(EventLog::new()));
+
+// USART1 line settings, applied by the serial session on `SERIAL_UPDATE`
+pub static SERIAL_CONFIG: Mutex<ThreadModeRawMutex, Cell<SerialConfig>> =
+    Mutex::new(Cell::new(SerialConfig::DEFAULT));
+pub static SERIAL_UPDATE: Signal<ThreadModeRawMutex, ()> = Signal::new();
+// Stored USART1 settings hidden by the PB5 defaults jumper; persisted instead
+// of the live defaults until a serial command replaces them
+pub static SERIAL_STORED: Mutex<ThreadModeRawMutex, Cell<Option<SerialConfig>>> =
+    Mutex::new(Cell::new(None));
+
+// SCPI session (transport) holding the control lock, see `SYSTem:LOCK`
+pub static SCPI_LOCK: Mutex<ThreadModeRawMutex, Cell<Option<u8>>> = Mutex::new(Cell::new(None));
+
+// Ask the settings task to write the current settings to flash
+pub static SETTINGS_SAVE: Signal<ThreadModeRawMutex, ()> = Signal::new();
+
+// Internal temperature sensor, 0.1 °C
+pub static TEMPERATURE: Mutex<ThreadModeRawMutex, Cell<i32>> = Mutex::new(Cell::new(0));
+// External analog probe on PA1, 0.1 °C
+pub static PROBE_TEMPERATURE: Mutex<ThreadModeRawMutex, Cell<i32>> = Mutex::new(Cell::new(0));
+
+// Automatic fan control from temperature
+pub static THERMAL_CONFIG: Mutex<ThreadModeRawMutex, Cell<ThermalConfig>> =
+    Mutex::new(Cell::new(ThermalConfig::DEFAULT));
+
+// Long-term per-interval aggregates, filled by the trend task
+pub static TREND_LOG: Mutex<ThreadModeRawMutex, RefCell<TrendLog>> =
+    Mutex::new(RefCell::new(TrendLog::new()));
+
+// Rail waveform capture, fed by the ADC task and read by SCPI `TRACe` commands
+pub static CAPTURE: Mutex<ThreadModeRawMutex, RefCell<Capture>> =
+    Mutex::new(RefCell::new(Capture::new()));
@@ -1,38 +1,122 @@
 use defmt::*;
 use embassy_executor::task;
-use embassy_stm32::adc::Adc;
-use embassy_stm32::{peripherals, Peri};
-use embassy_time::Timer;
+use embassy_stm32::adc::{Adc, SampleTime, Temperature};
+use embassy_stm32::pac::adc::vals::Jextsel;
+use embassy_stm32::pac::timer::vals::{Mms, Ocm};
+use embassy_stm32::{interrupt, pac, peripherals, Peri};
+use embassy_time::{Duration, Ticker, Timer};
 
 use heapless::Vec;
 
-use crate::shared::SHARED_ADC_VALUE;
+use crate::shared::{
+    AdcSyncConfig, RailFault, RailLimits, ADC_SYNC, CAPTURE, DAC_READBACK, PROBE_TEMPERATURE,
+    PWM_STATUS, RAIL_FAULT, RAIL_LIMITS, RAIL_STATS, RAIL_WATCHDOG, SHARED_ADC_VALUE, SYNC_STATS,
+    SYNC_WINDOW, TEMPERATURE,
+};
+use crate::stats::{Scale, WindowAccumulator};
+use crate::tasks::pwm::PwmChannel;
 
 const NUM_SAMPLES: usize = 300;
+/// Rail sampling period: 4 ticks of the 32.768 kHz time base, i.e. 8192 Sa/s
+const RAIL_SAMPLE_PERIOD: Duration = Duration::from_ticks(4);
+const VREFINT_MV: u32 = 1200;
+const TEMPERATURE_SAMPLES: u32 = 16;
+
+// Internal temperature sensor (STM32F103 datasheet): 1.43 V at 25 °C, 4.3 mV/°C
+const V25_MV: i32 = 1430;
+const AVG_SLOPE_UV_PER_C: i32 = 4300;
+
+// External probe on PA1: LM35-type linear sensor, 10 mV/°C, i.e. 1 mV per 0.1 °C
+const PROBE_SAMPLES: u32 = 16;
+// PWM DAC output read back on PA2 after its RC filter
+const DAC_SAMPLES: u32 = 16;
+
+const RAIL_SAMPLE_TIME: SampleTime = SampleTime::CYCLES1_5;
+// The temperature sensor needs at least 17.1 us of sampling
+const TEMPERATURE_SAMPLE_TIME: SampleTime = SampleTime::CYCLES239_5;
+
+/// ADC1 channel of the rail input (PA4 = ADC12_IN4)
+const RAIL_CHANNEL: u8 = 4;
 
 #[task]
 pub async fn measure_voltage(
     mut adc: Adc<'static, peripherals::ADC1>,
     mut pin: Peri<'static, peripherals::PA4>,
+    mut probe_pin: Peri<'static, peripherals::PA1>,
+    mut dac_pin: Peri<'static, peripherals::PA2>,
 ) {
     let vrefint_sample = calibrate_vrefint(&mut adc).await;
     info!("VREFINT calibration sample: {}", vrefint_sample);
 
     let to_ml = create_voltage_converter(vrefint_sample);
+    let to_raw = create_raw_converter(vrefint_sample);
+
+    let watchdog_high = configure_watchdog(&RAIL_LIMITS, to_raw);
+    configure_sync(ADC_SYNC.lock(|sync| sync.get()));
+
+    let mut temperature_channel = adc.enable_temperature();
+    adc.set_sample_time(RAIL_SAMPLE_TIME);
 
+    let scale = Scale {
+        num: VREFINT_MV,
+        den: u32::from(vrefint_sample),
+    };
+
+    // Rail samples are paced by the ticker, not by the conversions, so the
+    // TRACe record has a fixed time base. The slower inputs are converted
+    // one per period in the slack after the rail sample.
+    let mut ticker = Ticker::every(RAIL_SAMPLE_PERIOD);
     loop {
-        let mut samples = Vec::<u16, NUM_SAMPLES>::new();
-        for _ in 0..NUM_SAMPLES {
-            let _ = samples.push(adc.read(&mut pin).await);
-            Timer::after_micros(1).await;
+        let mut window = WindowAccumulator::new();
+        let mut temperature_sum: u32 = 0;
+        let mut probe_sum: u32 = 0;
+        let mut dac_sum: u32 = 0;
+        for index in 0..NUM_SAMPLES as u32 {
+            ticker.next().await;
+            let sample = adc.read(&mut pin).await;
+            check_watchdog(sample, watchdog_high);
+            window.push(sample);
+            CAPTURE.lock(|capture| capture.borrow_mut().push(to_ml(sample) as u16));
+
+            if index < TEMPERATURE_SAMPLES {
+                let channel = &mut temperature_channel;
+                temperature_sum += read_temperature_sample(&mut adc, channel).await;
+            } else if index < TEMPERATURE_SAMPLES + PROBE_SAMPLES {
+                probe_sum += u32::from(adc.read(&mut probe_pin).await);
+            } else if index < TEMPERATURE_SAMPLES + PROBE_SAMPLES + DAC_SAMPLES {
+                dac_sum += u32::from(adc.read(&mut dac_pin).await);
+            }
         }
 
-        let avg = samples.iter().map(|&sample| sample as u32).sum::<u32>() / NUM_SAMPLES as u32;
-        let avg_mv = to_ml(avg as u16);
-        SHARED_ADC_VALUE.signal(avg_mv);
+        let stats = window.finish(scale);
+        RAIL_STATS.lock(|rail| rail.set(stats));
+        SHARED_ADC_VALUE.signal(stats.mean);
+
+        let sync_window = SYNC_WINDOW.lock(|sync| sync.replace(WindowAccumulator::new()));
+        SYNC_STATS.lock(|sync| sync.set(sync_window.finish(scale)));
+
+        let sensor_mv = to_ml((temperature_sum / TEMPERATURE_SAMPLES) as u16) as i32;
+        let temperature = 250 + (V25_MV - sensor_mv) * 10_000 / AVG_SLOPE_UV_PER_C;
+        TEMPERATURE.lock(|cell| cell.set(temperature));
+
+        let probe_mv = to_ml((probe_sum / PROBE_SAMPLES) as u16);
+        PROBE_TEMPERATURE.lock(|cell| cell.set(probe_mv as i32));
+
+        DAC_READBACK.signal(to_ml((dac_sum / DAC_SAMPLES) as u16));
     }
 }
 
+/// One conversion of the internal temperature sensor, which needs the long sampling time
+async fn read_temperature_sample(
+    adc: &mut Adc<'static, peripherals::ADC1>,
+    channel: &mut Temperature,
+) -> u32 {
+    adc.set_sample_time(TEMPERATURE_SAMPLE_TIME);
+    let sample = adc.read(channel).await;
+    adc.set_sample_time(RAIL_SAMPLE_TIME);
+    u32::from(sample)
+}
+
 async fn calibrate_vrefint(adc: &mut Adc<'static, peripherals::ADC1>) -> u16 {
     let mut vrefint = adc.enable_vref();
     let mut samples = Vec::<u16, NUM_SAMPLES>::new();
@@ -45,6 +129,156 @@ async fn calibrate_vrefint(adc: &mut Adc<'static, peripherals::ADC1>) -> u16 {
 }
 
 fn create_voltage_converter(vrefint_sample: u16) -> impl Fn(u16) -> u32 {
-    const VREFINT_MV: u32 = 1200;
     move |sample: u16| -> u32 { u32::from(sample) * VREFINT_MV / u32::from(vrefint_sample) }
 }
+
+fn create_raw_converter(vrefint_sample: u16) -> impl Fn(u32) -> u16 {
+    move |mv: u32| -> u16 { (mv * u32::from(vrefint_sample) / VREFINT_MV).min(0x0FFF) as u16 }
+}
+
+// ============================================================================
+// ANALOG WATCHDOG
+// ============================================================================
+
+/// Program the ADC1 analog watchdog on the rail channel.
+///
+/// Every regular conversion of PA4 is compared in hardware, so an excursion is
+/// reported on the first offending sample instead of after a full averaging window.
+/// Returns the raw upper threshold.
+fn configure_watchdog(limits: &RailLimits, to_raw: impl Fn(u32) -> u16) -> u16 {
+    let low = to_raw(limits.under_mv);
+    let high = to_raw(limits.over_mv);
+    info!("Analog watchdog window: {} .. {} (raw)", low, high);
+
+    let adc = pac::ADC1;
+    adc.ltr().write(|w| w.set_lt(low));
+    adc.htr().write(|w| w.set_ht(high));
+    adc.cr1().modify(|w| {
+        w.set_awdch(RAIL_CHANNEL);
+        w.set_awdsgl(true);
+        w.set_awden(true);
+    });
+    high
+}
+
+/// Report a watchdog trip flagged by the interrupt as a `RAIL_FAULT`. Only
+/// rail conversions are watched, so `sample` is the reading that tripped.
+fn check_watchdog(sample: u16, high: u16) {
+    if RAIL_WATCHDOG.try_take().is_some() {
+        let fault = if sample > high {
+            RailFault::OverVoltage
+        } else {
+            RailFault::UnderVoltage
+        };
+        RAIL_FAULT.signal(fault);
+    }
+}
+
+/// Enable the watchdog interrupt. It is one-shot: the handler disables it again
+/// so a rail that stays out of range does not flood the CPU. The power task
+/// re-arms it whenever it connects a source.
+pub fn arm_watchdog() {
+    let adc = pac::ADC1;
+    adc.sr().modify(|w| w.set_awd(false));
+    RAIL_WATCHDOG.reset();
+    adc.cr1().modify(|w| w.set_awdie(true));
+}
+
+pub fn disarm_watchdog() {
+    pac::ADC1.cr1().modify(|w| w.set_awdie(false));
+}
+
+/// ADC1_2 handler for the analog watchdog flag, bound next to `adc::InterruptHandler`.
+///
+/// It leaves the data register alone: reading DR would clear EOC under the
+/// pending `adc.read()`. The ADC task gets the sample from that read and
+/// decides between over- and undervoltage (`check_watchdog`).
+pub struct AnalogWatchdogHandler;
+
+impl interrupt::typelevel::Handler<interrupt::typelevel::ADC1_2> for AnalogWatchdogHandler {
+    unsafe fn on_interrupt() {
+        let adc = pac::ADC1;
+        if !adc.sr().read().awd() || !adc.cr1().read().awdie() {
+            return;
+        }
+
+        adc.cr1().modify(|w| w.set_awdie(false));
+        adc.sr().modify(|w| w.set_awd(false));
+        RAIL_WATCHDOG.signal(());
+    }
+}
+
+// ============================================================================
+// PWM-SYNCHRONISED SAMPLING
+// ============================================================================
+
+/// Trigger an injected conversion of the rail from TIM1, placed at
+/// `phase_permille` of the PWM period, so readings can avoid (or deliberately
+/// hit) the switching edges of the fan PWM on CH1.
+///
+/// TIM1 has no spare compare unit, so CH2 is borrowed: in PWM mode 2 its
+/// reference rises at CCR2 and is routed to TRGO. CH2N (PB14) is the generic
+/// output of that unit, so while sync is on it stays disabled and the PWM task
+/// leaves CCR2 alone (`sync_owns`). Disabling sync hands CCR2 back with the
+/// duty the CH2 ramp has tracked meanwhile.
+pub fn configure_sync(config: AdcSyncConfig) {
+    let adc = pac::ADC1;
+    let tim = pac::TIM1;
+
+    if !config.enabled {
+        adc.cr2().modify(|w| w.set_jexttrig(false));
+        adc.cr1().modify(|w| w.set_jeocie(false));
+        tim.cr2().modify(|w| w.set_mms(Mms::RESET));
+        let status = PWM_STATUS.lock(|pwm| pwm.get());
+        let duty = status.duty[PwmChannel::Ch2.index()].to_ticks(status.max_duty);
+        tim.ccmr_output(0).modify(|w| w.set_ocm(1, Ocm::PWM_MODE1));
+        tim.ccr(1).write(|w| w.set_ccr(duty));
+        info!("ADC sync disabled");
+        return;
+    }
+
+    let period = u32::from(tim.arr().read().arr()) + 1;
+    // A compare value of 0 keeps OC2REF permanently high, i.e. no edge at all
+    let compare = (period * u32::from(config.phase_permille.min(999)) / 1000).max(1);
+
+    tim.ccmr_output(0).modify(|w| w.set_ocm(1, Ocm::PWM_MODE2));
+    tim.ccr(1).write(|w| w.set_ccr(compare as u16));
+    tim.cr2().modify(|w| w.set_mms(Mms::COMPARE_OC2));
+
+    // Single injected conversion (JL = 0 converts JSQ4) of the rail channel
+    adc.jsqr().write(|w| {
+        w.set_jl(0);
+        w.set_jsq(3, RAIL_CHANNEL);
+    });
+    adc.cr2().modify(|w| {
+        w.set_jextsel(Jextsel::TIM1_TRGO);
+        w.set_jexttrig(true);
+    });
+    adc.sr().modify(|w| w.set_jeoc(false));
+    adc.cr1().modify(|w| w.set_jeocie(true));
+
+    info!(
+        "ADC sync at {} permille of the PWM period (CCR2 = {})",
+        config.phase_permille, compare
+    );
+}
+
+/// ADC1_2 handler collecting the injected (PWM-synchronised) rail samples.
+pub struct InjectedConversionHandler;
+
+impl interrupt::typelevel::Handler<interrupt::typelevel::ADC1_2> for InjectedConversionHandler {
+    unsafe fn on_interrupt() {
+        let adc = pac::ADC1;
+        if !adc.sr().read().jeoc() {
+            return;
+        }
+        adc.sr().modify(|w| w.set_jeoc(false));
+
+        let sample = adc.jdr(0).read().jdata();
+        SYNC_WINDOW.lock(|sync| {
+            let mut window = sync.get();
+            window.push(sample);
+            sync.set(window);
+        });
+    }
+}
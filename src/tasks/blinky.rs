@@ -4,7 +4,11 @@ use embassy_stm32::gpio::{Level, Output, Speed};
 use embassy_stm32::{peripherals, Peri};
 use embassy_time::Timer;
 
-use crate::shared::DELAY_CHANNEL;
+use crate::shared::{FAN_FAULT, LED_DELAY};
+use crate::stall::FanFault;
+
+/// Fast blink while a fan failure is latched, overriding the power-state rate
+const FAULT_DELAY_MS: u64 = 50;
 
 #[task]
 pub async fn blinky(led: Peri<'static, peripherals::PC13>, initial_delay: u64) {
@@ -12,14 +16,19 @@ pub async fn blinky(led: Peri<'static, peripherals::PC13>, initial_delay: u64) {
     let mut current_delay = initial_delay;
 
     loop {
-        if let Ok(new_delay) = DELAY_CHANNEL.try_receive() {
+        if let Some(new_delay) = LED_DELAY.try_take() {
             current_delay = new_delay;
             info!("LED delay updated to {} ms", current_delay);
         }
 
+        let delay = match FAN_FAULT.lock(|fault| fault.get()) {
+            FanFault::Failed => FAULT_DELAY_MS,
+            _ => current_delay,
+        };
+
         led.set_high();
-        Timer::after_millis(current_delay).await;
+        Timer::after_millis(delay).await;
         led.set_low();
-        Timer::after_millis(current_delay).await;
+        Timer::after_millis(delay).await;
     }
 }
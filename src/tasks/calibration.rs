@@ -0,0 +1,126 @@
+use defmt::*;
+use embassy_executor::task;
+use embassy_time::Timer;
+
+use crate::shared::{
+    CALIBRATION_REQUEST, CALIBRATION_STATE, FAN_CALIBRATION, FAN_OVERRIDE, FAN_RPM, SETTINGS_SAVE,
+};
+use crate::tasks::cooling::{CalibrationPoint, CoolerCalibration, CALIBRATION_POINTS};
+
+/// Time for the fan to spin down completely
+const STOP_MS: u64 = 3000;
+/// Time for the fan to settle after a duty change
+const SETTLE_MS: u64 = 2000;
+/// Hold time per 1 % step while searching the start duty; longer than the
+/// tach timeout so a fan that does not turn reads 0 RPM
+const START_STEP_MS: u64 = 600;
+/// Readings averaged per measurement, one every 250 ms
+const MEASURE_READINGS: u32 = 4;
+
+#[derive(Debug, Clone, Copy, PartialEq, Format)]
+pub enum CalibrationState {
+    Idle,
+    Running,
+    Passed,
+    Failed,
+}
+
+#[derive(Debug, Clone, Copy, Format)]
+enum CalibrationError {
+    /// No tach pulses at 100 % duty
+    NoTach,
+    /// The fan did not start below 100 %
+    NoStart,
+}
+
+/// Force `duty` (percent), wait `settle_ms`, then average the tach RPM.
+async fn measure(duty: u16, settle_ms: u64) -> u16 {
+    FAN_OVERRIDE.signal(Some(duty));
+    Timer::after_millis(settle_ms).await;
+
+    let mut rpm = 0u32;
+    for _ in 0..MEASURE_READINGS {
+        Timer::after_millis(250).await;
+        rpm += u32::from(FAN_RPM.lock(|fan| fan.get()));
+    }
+    (rpm / MEASURE_READINGS) as u16
+}
+
+/// Ramp from standstill in 1 % steps until the tach reports rotation.
+async fn find_start_duty() -> Option<u16> {
+    measure(0, STOP_MS).await;
+    for duty in 1..=100 {
+        FAN_OVERRIDE.signal(Some(duty));
+        Timer::after_millis(START_STEP_MS).await;
+        if FAN_RPM.lock(|fan| fan.get()) > 0 {
+            return Some(duty);
+        }
+    }
+    None
+}
+
+/// The procedure of the former `cooling` example, on the tach: the speed at
+/// 100 %, the minimum start duty, then a 20..100 % sweep.
+async fn run_calibration() -> Result<CoolerCalibration, CalibrationError> {
+    let mut calibration = CoolerCalibration::DEFAULT;
+
+    let max_rpm = measure(100, SETTLE_MS).await;
+    if max_rpm == 0 {
+        return Err(CalibrationError::NoTach);
+    }
+    calibration.max_rpm = max_rpm;
+    info!("Calibration: 100% -> {} RPM", max_rpm);
+
+    let start_duty = find_start_duty().await.ok_or(CalibrationError::NoStart)?;
+    let min_rpm = measure(start_duty, SETTLE_MS).await;
+    calibration.min_duty_percent = start_duty;
+    calibration.min_rpm = min_rpm;
+    info!("Calibration: starts at {}% -> {} RPM", start_duty, min_rpm);
+
+    measure(0, STOP_MS).await;
+    for (index, point) in calibration.table.iter_mut().enumerate() {
+        let duty = 20 + index * 80 / (CALIBRATION_POINTS - 1);
+        let rpm = measure(duty as u16, SETTLE_MS).await;
+        *point = CalibrationPoint {
+            duty_percent: duty as u8,
+            rpm,
+        };
+        info!("Calibration: {}% -> {} RPM", duty, rpm);
+    }
+
+    Ok(calibration)
+}
+
+/// Runs the fan calibration on `CALIBRATION_REQUEST` (SCPI `CALibration:COOLing`).
+///
+/// The fan is driven through `FAN_OVERRIDE`, so the cooling controller stays
+/// its only owner and resumes its previous setting afterwards. A successful
+/// result replaces `FAN_CALIBRATION` and is saved to flash; on failure the
+/// previous calibration is kept.
+#[task]
+pub async fn fan_calibration() {
+    info!("Fan calibration task started");
+
+    loop {
+        CALIBRATION_REQUEST.wait().await;
+        CALIBRATION_STATE.lock(|state| state.set(CalibrationState::Running));
+        info!("Fan calibration started");
+
+        let result = run_calibration().await;
+        FAN_OVERRIDE.signal(None);
+
+        let state = match result {
+            Ok(calibration) => {
+                FAN_CALIBRATION.lock(|fan| fan.set(calibration));
+                SETTINGS_SAVE.signal(());
+                info!("Fan calibration passed: {}", calibration);
+                CalibrationState::Passed
+            }
+            Err(error) => {
+                warn!("Fan calibration failed: {}", error);
+                CalibrationState::Failed
+            }
+        };
+        CALIBRATION_STATE.lock(|cell| cell.set(state));
+    }
+}
@@ -2,45 +2,282 @@ use defmt::*;
 use embassy_executor::task;
 use embassy_stm32::gpio::{Level, Output, Speed};
 use embassy_stm32::{peripherals, Peri};
-use embassy_time::Timer;
+use embassy_time::{Duration, Instant, Timer};
 
-use crate::shared::{CoolingState, COOLING_CHANNEL, COOLING_STATUS, CURRENT_SPEED, SPEED_CHANNEL};
+use crate::completion::Completion;
+use crate::duty::Duty;
+use crate::events::{log_event, Event};
+use crate::pid::Pid;
+use crate::ramp::Ramp;
+use crate::shared::{
+    CoolingState, PowerState, COOLING_CHANNEL, COOLING_ON, COOLING_STATUS, CURRENT_SPEED,
+    FAN_CALIBRATION, FAN_DUTY, FAN_FAULT, FAN_FAULT_CLEAR, FAN_OVERRIDE, FAN_PID, FAN_RAMP, FAN_RPM,
+    FAN_SPEED, POWER_CHANNEL, RPM_TARGET_CHANNEL, SPEED_CHANNEL, STALL_CONFIG,
+};
+use crate::stall::{StallAction, StallDetector};
+use crate::tasks::pwm::PwmChannel;
 
+/// Duty steps of the calibration sweep (20, 30, ... 100 %)
+pub const CALIBRATION_POINTS: usize = 9;
+
+/// One point of the measured duty -> RPM curve.
+#[derive(Debug, Clone, Copy, PartialEq, Format)]
+pub struct CalibrationPoint {
+    pub duty_percent: u8,
+    pub rpm: u16,
+}
+
+/// Fan characteristics, measured by the `fan_calibration` task.
+#[derive(Debug, Clone, Copy, PartialEq, Format)]
+pub struct CoolerCalibration {
+    pub min_duty_percent: u16, // Minimum duty that reliably starts the fan
+    pub max_duty_percent: u16, // Typically 100%
+    pub min_rpm: u16,          // RPM at min duty
+    pub max_rpm: u16,          // RPM at max duty
+    /// Duty -> RPM sweep; all zero until the fan has been calibrated
+    pub table: [CalibrationPoint; CALIBRATION_POINTS],
+}
+
+impl CoolerCalibration {
+    pub const DEFAULT: Self = Self {
+        min_duty_percent: 20,
+        max_duty_percent: 100,
+        min_rpm: 800,
+        max_rpm: 3400,
+        table: [CalibrationPoint { duty_percent: 0, rpm: 0 }; CALIBRATION_POINTS],
+    };
+
+    pub fn is_calibrated(&self) -> bool {
+        self.table.iter().any(|point| point.duty_percent != 0)
+    }
+
+    /// Map a speed setpoint (0..=100 %) onto the usable duty range.
+    ///
+    /// 0 % stops the fan; anything above starts at the calibrated minimum
+    /// start duty so low setpoints never leave the fan stalled.
+    pub fn duty_percent_for_speed(&self, speed_percent: u16) -> u16 {
+        if speed_percent == 0 {
+            return 0;
+        }
+        let speed = u32::from(speed_percent.min(100));
+        let min = u32::from(self.min_duty_percent);
+        let max = u32::from(self.max_duty_percent.max(self.min_duty_percent));
+        (min + (max - min) * speed / 100) as u16
+    }
+}
+
+impl Default for CoolerCalibration {
+    fn default() -> Self {
+        Self::DEFAULT
+    }
+}
+
+/// Rotation is judged from the tach alone: PA4 carries the rail, not a fan
+/// sense voltage, so it says nothing about the fan.
+fn fan_spinning() -> bool {
+    FAN_RPM.lock(|fan| fan.get()) > 0
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Format)]
+enum FanMode {
+    /// Duty follows the SPEEd percentage
+    OpenLoop,
+    /// PID on the tach RPM
+    ClosedLoop { target_rpm: u16 },
+}
+
+/// Owns the fan: PB2 switches its supply, TIM1 CH1 (PA8) sets the speed.
+///
+/// The duty is handed to the PWM task through `FAN_DUTY`; this task is the
+/// only producer for it, so SCPI and the generic PWM output cannot fight
+/// over the fan channel. `SPEEd <percent>` selects open loop, an RPM target
+/// closes the loop over the tach measurement. While `FAN_OVERRIDE` holds a
+/// duty (fan calibration) the supply is on and that duty is applied as is;
+/// commands received meanwhile take effect once the override is released.
+///
+/// A fan driven above its start duty without rotation for `StallConfig::timeout_s`
+/// gets a 100 % kick; when the restarts are used up the failure is latched in
+/// `FAN_FAULT` (and optionally the power switched OFF) until `SPEEd:FAULt:CLEar`.
+///
+/// Regular duty changes go through the `FAN_RAMP` slew-rate limit and soft
+/// start; the calibration override and restart kicks bypass it.
 #[task]
 pub async fn cooling_controller(cooling_pin: Peri<'static, peripherals::PB2>) {
     let mut cooling_output = Output::new(cooling_pin, Level::Low, Speed::Low);
+    let mut calibration = FAN_CALIBRATION.lock(|fan| fan.get());
+    let mut override_duty: Option<u16> = None;
     let mut current_state = CoolingState::Off;
     let mut current_speed = 0u16;
+    FAN_SPEED.lock(|fan| fan.set(current_speed));
+    let mut mode = FanMode::OpenLoop;
+    let mut current_duty = 0u16;
+
+    let mut pid = Pid::new(
+        FAN_PID.lock(|config| config.get()),
+        i32::from(calibration.min_duty_percent),
+        i32::from(calibration.max_duty_percent),
+    );
+    let mut last_update = Instant::now();
+    let mut stall = StallDetector::new();
+    let mut kick_until: Option<Instant> = None;
+    let mut ramp = Ramp::new(100);
+
+    FAN_DUTY.signal(Duty::ZERO);
 
     loop {
-        // Check for cooling state commands
-        if let Ok(command) = COOLING_CHANNEL.try_receive() {
-            match command {
-                CoolingState::On => {
-                    cooling_output.set_high();
-                    current_state = CoolingState::On;
-                    info!("Cooling turned ON");
+        let mut changed = false;
+
+        // Pick up a new calibration
+        let latest = FAN_CALIBRATION.lock(|fan| fan.get());
+        if latest != calibration {
+            calibration = latest;
+            pid.set_limits(
+                i32::from(calibration.min_duty_percent),
+                i32::from(calibration.max_duty_percent),
+            );
+            changed = true;
+        }
+
+        // Check for cooling state commands. The completions drop at the end of
+        // each block; pins and duty follow below without yielding in between.
+        if let Ok((command, _done)) = COOLING_CHANNEL.try_receive() {
+            info!("Cooling turned {}", command);
+            current_state = command;
+            COOLING_STATUS.signal(current_state);
+            COOLING_ON.lock(|cooling| cooling.set(current_state == CoolingState::On));
+            changed = true;
+        }
+
+        // Check for speed commands
+        if let Ok((speed, _done)) = SPEED_CHANNEL.try_receive() {
+            current_speed = speed.min(100);
+            FAN_SPEED.lock(|fan| fan.set(current_speed));
+            mode = FanMode::OpenLoop;
+            info!("Cooling speed set to {}%", current_speed);
+            changed = true;
+        }
+
+        // Check for RPM targets (closed loop)
+        if let Ok((target_rpm, _done)) = RPM_TARGET_CHANNEL.try_receive() {
+            if mode == FanMode::OpenLoop {
+                // Start from the current duty instead of winding up from zero
+                pid.preload(i32::from(ramp.output()));
+            }
+            mode = FanMode::ClosedLoop { target_rpm };
+            info!("Cooling RPM target set to {}", target_rpm);
+            changed = true;
+        }
+
+        if let Some(request) = FAN_OVERRIDE.try_take() {
+            override_duty = request;
+            changed = true;
+        }
+
+        if changed {
+            if override_duty.is_some() || current_state == CoolingState::On {
+                cooling_output.set_high();
+            } else {
+                cooling_output.set_low();
+            }
+        }
+
+        if let Some(duty) = override_duty {
+            if changed {
+                current_duty = duty;
+                ramp.jump(duty);
+                FAN_DUTY.signal(Duty::from_percent(duty));
+            }
+            Timer::after_millis(10).await;
+            continue;
+        }
+
+        if FAN_FAULT_CLEAR.try_take().is_some() {
+            stall.clear();
+            info!("Fan fault cleared");
+        }
+
+        // Hold the restart kick, then re-apply the regular duty
+        if let Some(until) = kick_until {
+            if Instant::now() < until {
+                Timer::after_millis(10).await;
+                continue;
+            }
+            kick_until = None;
+            changed = true;
+        }
+
+        match (mode, current_state) {
+            (FanMode::OpenLoop, _) | (_, CoolingState::Off) => {
+                if changed {
+                    let speed = match current_state {
+                        CoolingState::On => current_speed,
+                        CoolingState::Off => 0,
+                    };
+                    current_duty = calibration.duty_percent_for_speed(speed);
+                    info!("Fan duty {}% for speed {}%", current_duty, speed);
+                    ramp.set_target(current_duty, Instant::now().as_millis());
+                    CURRENT_SPEED.signal(speed);
                 }
-                CoolingState::Off => {
-                    cooling_output.set_low();
-                    current_state = CoolingState::Off;
-                    current_speed = 0;
-                    info!("Cooling turned OFF, speed reset to {}", current_speed);
+            }
+            (FanMode::ClosedLoop { target_rpm }, CoolingState::On) => {
+                let config = FAN_PID.lock(|config| config.get());
+                pid.set_config(config);
+                let period = Duration::from_millis(config.period_ms as u64);
+                if changed || last_update.elapsed() >= period {
+                    last_update = Instant::now();
+                    let duty = if target_rpm == 0 {
+                        pid.reset();
+                        0
+                    } else {
+                        let rpm = FAN_RPM.lock(|fan| fan.get());
+                        pid.update(i32::from(target_rpm), i32::from(rpm)) as u16
+                    };
+                    if duty != current_duty || changed {
+                        current_duty = duty;
+                        ramp.set_target(duty, Instant::now().as_millis());
+                        CURRENT_SPEED.signal(duty);
+                    }
                 }
             }
-            COOLING_STATUS.signal(current_state);
         }
 
-        // Check for speed commands
-        if let Ok(speed) = SPEED_CHANNEL.try_receive() {
-            current_speed = speed;
-            if current_state == CoolingState::On {
-                info!("Cooling speed set to {}", current_speed);
-                // In a real implementation, you might control PWM duty cycle here
-                // For now, we just store the speed value
+        ramp.set_config(FAN_RAMP.config());
+        if let Some(duty) = ramp.update(Instant::now().as_millis()) {
+            FAN_DUTY.signal(Duty::from_percent(duty));
+        }
+        // Closed loop retargets the ramp every PID period, which is not worth an event
+        if FAN_RAMP.publish(&ramp) && mode == FanMode::OpenLoop {
+            log_event(Event::RampDone(PwmChannel::Ch1));
+        }
+
+        let stall_config = STALL_CONFIG.lock(|config| config.get());
+        let driven =
+            current_state == CoolingState::On && ramp.output() >= calibration.min_duty_percent;
+        let spinning = fan_spinning();
+        match stall.update(&stall_config, driven, spinning, Instant::now().as_millis()) {
+            StallAction::None => {}
+            StallAction::Kick => {
+                warn!("Fan stalled at {}% duty, restart kick", current_duty);
+                log_event(Event::FanStall);
+                ramp.jump(100);
+                FAN_DUTY.signal(Duty::FULL);
+                kick_until =
+                    Some(Instant::now() + Duration::from_millis(u64::from(stall_config.kick_ms)));
+            }
+            StallAction::Recovered => {
+                info!("Fan recovered");
+                log_event(Event::FanRecovered);
+            }
+            StallAction::Fail => {
+                error!("Fan failed to restart, fault latched");
+                log_event(Event::FanFailure);
+                if stall_config.power_off {
+                    // Protective: wait for room in the queue rather than drop it
+                    POWER_CHANNEL.send((PowerState::OFF, Completion::untracked())).await;
+                }
             }
-            CURRENT_SPEED.signal(current_speed);
         }
+        FAN_FAULT.lock(|fault| fault.set(stall.fault()));
 
         Timer::after_millis(10).await;
     }
@@ -0,0 +1,184 @@
+use defmt::*;
+use embassy_executor::task;
+use embassy_futures::select::{select3, Either3};
+use embassy_time::Timer;
+
+use crate::duty::Duty;
+use crate::shared::{
+    DAC_CALIBRATION, DAC_CALIBRATION_REQUEST, DAC_CALIBRATION_STATE, DAC_CONFIG, DAC_READBACK,
+    DAC_STATUS, DAC_UPDATE, SETTINGS_SAVE, SHARED_DUTY,
+};
+use crate::tasks::calibration::CalibrationState;
+use crate::tasks::pwm::PwmChannel;
+
+/// TIM1 output driving the RC filter (PA11)
+pub const DAC_CHANNEL: PwmChannel = PwmChannel::Ch4;
+/// Highest target accepted by `SOURce:VOLTage`
+pub const DAC_MAX_MV: u32 = 3300;
+/// Lowest TIM1 carrier while the DAC is on: 800 duty steps at the 8 MHz
+/// timer clock, and a ripple the RC filter can smooth
+pub const DAC_MIN_CARRIER_HZ: u32 = 10_000;
+
+/// Time for the RC filter to settle after a duty step during calibration
+const SETTLE_MS: u64 = 500;
+/// Calibration points, as duty
+const CALIBRATION_LOW: Duty = Duty::from_percent(25);
+const CALIBRATION_HIGH: Duty = Duty::from_percent(75);
+/// Limit of the closed-loop correction
+const CORRECTION_MAX_MV: i32 = 500;
+
+#[derive(Debug, Clone, Copy, PartialEq, Format)]
+pub struct DacConfig {
+    pub enabled: bool,
+    pub target_mv: u32,
+    /// Trim the output with the filtered voltage read back on PA2
+    pub correction: bool,
+}
+
+impl DacConfig {
+    pub const DEFAULT: Self = Self {
+        enabled: false,
+        target_mv: 0,
+        correction: false,
+    };
+}
+
+/// Open-loop transfer of the filter: `mv = offset_mv + full_scale_mv * duty`.
+#[derive(Debug, Clone, Copy, PartialEq, Format)]
+pub struct DacCalibration {
+    /// Output at 100 % duty minus the output at 0 % (the supply for an ideal driver)
+    pub full_scale_mv: u16,
+    pub offset_mv: i16,
+}
+
+impl DacCalibration {
+    pub const DEFAULT: Self = Self {
+        full_scale_mv: 3300,
+        offset_mv: 0,
+    };
+
+    fn duty_for(&self, mv: i32) -> Duty {
+        let fraction = (mv - i32::from(self.offset_mv)) as f32 / f32::from(self.full_scale_mv);
+        Duty::from_fraction(fraction)
+    }
+}
+
+/// Applied state, published by the DAC task.
+#[derive(Debug, Clone, Copy, PartialEq, Format)]
+pub struct DacStatus {
+    pub duty: Duty,
+    /// Filtered output read back through the ADC
+    pub readback_mv: u32,
+    /// Closed-loop trim added to the target
+    pub correction_mv: i32,
+}
+
+impl DacStatus {
+    pub const EMPTY: Self = Self {
+        duty: Duty::ZERO,
+        readback_mv: 0,
+        correction_mv: 0,
+    };
+}
+
+/// Read back the output once the filter has settled at `duty`.
+async fn measure(duty: Duty) -> u32 {
+    SHARED_DUTY.send((DAC_CHANNEL, duty)).await;
+    Timer::after_millis(SETTLE_MS).await;
+    // Skip an ADC window that may have started before the filter settled
+    DAC_READBACK.reset();
+    DAC_READBACK.wait().await;
+    DAC_READBACK.wait().await
+}
+
+/// Two-point calibration of the filter through the PA2 readback.
+async fn run_calibration() -> Option<DacCalibration> {
+    let low_mv = measure(CALIBRATION_LOW).await as f32;
+    let high_mv = measure(CALIBRATION_HIGH).await as f32;
+    info!("DAC calibration: {} mV .. {} mV", low_mv, high_mv);
+
+    let span = CALIBRATION_HIGH.fraction() - CALIBRATION_LOW.fraction();
+    let full_scale_mv = (high_mv - low_mv) / span;
+    // Less than 1 V swing: no filter connected to PA2, or a shorted output
+    if !(1000.0..=f32::from(u16::MAX)).contains(&full_scale_mv) {
+        return None;
+    }
+    let offset_mv = low_mv - full_scale_mv * CALIBRATION_LOW.fraction();
+    Some(DacCalibration {
+        full_scale_mv: full_scale_mv as u16,
+        offset_mv: offset_mv.clamp(f32::from(i16::MIN), f32::from(i16::MAX)) as i16,
+    })
+}
+
+/// PWM DAC on TIM1 CH4 (PA11) with an external RC filter; the filtered
+/// voltage is fed back to PA2.
+///
+/// The target is converted to duty through `DAC_CALIBRATION`. With
+/// correction enabled, every ADC window trims the target by the remaining
+/// error (integral action), which also covers load and supply drift.
+///
+/// The carrier is the shared TIM1 frequency, kept at `DAC_MIN_CARRIER_HZ` or
+/// above while the DAC is on. TIM1 counts the APB2 timer clock, 8 MHz from
+/// the HSI with the default clock tree (`embassy_stm32::init(Default::default())`),
+/// so a carrier of f gives 8 MHz / f duty steps: the higher it is, the smaller
+/// the filter ripple, at the cost of resolution.
+#[task]
+pub async fn pwm_dac() {
+    let mut status = DacStatus::EMPTY;
+
+    info!("PWM DAC task started");
+
+    loop {
+        let event =
+            select3(DAC_UPDATE.wait(), DAC_READBACK.wait(), DAC_CALIBRATION_REQUEST.wait()).await;
+        let config = DAC_CONFIG.lock(|dac| dac.get());
+        // Re-send the duty even if unchanged: CH4 may have been driven by others meanwhile
+        let mut force = false;
+        match event {
+            Either3::First(()) => {
+                status.correction_mv = 0;
+                force = true;
+            }
+            Either3::Second(readback_mv) => {
+                status.readback_mv = readback_mv;
+                if config.enabled && config.correction {
+                    let error = config.target_mv as i32 - readback_mv as i32;
+                    status.correction_mv = (status.correction_mv + error / 2)
+                        .clamp(-CORRECTION_MAX_MV, CORRECTION_MAX_MV);
+                }
+            }
+            Either3::Third(()) => {
+                DAC_CALIBRATION_STATE.lock(|state| state.set(CalibrationState::Running));
+                info!("DAC calibration started");
+                let result = run_calibration().await;
+                SHARED_DUTY.send((DAC_CHANNEL, status.duty)).await;
+                let state = match result {
+                    Some(calibration) => {
+                        DAC_CALIBRATION.lock(|dac| dac.set(calibration));
+                        SETTINGS_SAVE.signal(());
+                        info!("DAC calibration passed: {}", calibration);
+                        CalibrationState::Passed
+                    }
+                    None => {
+                        warn!("DAC calibration failed");
+                        CalibrationState::Failed
+                    }
+                };
+                DAC_CALIBRATION_STATE.lock(|cell| cell.set(state));
+                status.correction_mv = 0;
+            }
+        }
+
+        if config.enabled {
+            let calibration = DAC_CALIBRATION.lock(|dac| dac.get());
+            let duty = calibration.duty_for(config.target_mv as i32 + status.correction_mv);
+            if force || duty != status.duty {
+                status.duty = duty;
+                SHARED_DUTY.send((DAC_CHANNEL, duty)).await;
+            }
+        } else {
+            status.correction_mv = 0;
+        }
+        DAC_STATUS.lock(|dac| dac.set(status));
+    }
+}
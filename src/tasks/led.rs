@@ -4,7 +4,7 @@ use embassy_stm32::gpio::{Level, Output, Speed};
 use embassy_stm32::{peripherals, Peri};
 use embassy_time::Timer;
 
-use crate::shared::{LedState, LED_CHANNEL, LED_STATUS};
+use crate::shared::{LedState, LED_CHANNEL, LED_ON, LED_STATUS};
 
 #[task]
 pub async fn led_controller(led: Peri<'static, peripherals::PA5>) {
@@ -12,7 +12,8 @@ pub async fn led_controller(led: Peri<'static, peripherals::PA5>) {
     let mut current_state = false; // LED is off initially
 
     loop {
-        if let Ok(command) = LED_CHANNEL.try_receive() {
+        // `_done` completes the command at the end of this block
+        if let Ok((command, _done)) = LED_CHANNEL.try_receive() {
             match command {
                 LedState::On => {
                     led_output.set_high(); // Turn LED on
@@ -37,6 +38,7 @@ pub async fn led_controller(led: Peri<'static, peripherals::PA5>) {
                 }
             }
             LED_STATUS.signal(current_state);
+            LED_ON.lock(|led| led.set(current_state));
         }
 
         Timer::after_millis(10).await;
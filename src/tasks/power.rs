@@ -1,62 +1,67 @@
 use defmt::*;
 use embassy_executor::task;
+use embassy_futures::select::{select, select3, Either3};
 use embassy_stm32::gpio::{Level, Output, Speed};
 use embassy_stm32::{peripherals, Peri};
 use embassy_time::Timer;
 
+use crate::events::{log_event, Event};
 use crate::shared::{
-    PowerState, DELAY_CHANNEL, POWER_CHANNEL, POWER_STATUS, SHARED_ADC_VALUE, SHARED_MESSAGE,
+    PowerState, RailFault, CAPTURE, LED_DELAY, POWER_CHANNEL, POWER_SOURCE, POWER_STATUS,
+    RAIL_FAULT, RAIL_LIMITS, SHARED_ADC_VALUE, SHARED_MESSAGE,
 };
-
-impl PowerState {
-    pub const ACDC_THRESHOLD: u32 = 760;
-
-    fn from_voltage(voltage: u32) -> Self {
-        if voltage > Self::ACDC_THRESHOLD {
-            PowerState::ACDC
-        } else {
-            PowerState::DCDC
+use crate::tasks::adc_task::{arm_watchdog, disarm_watchdog};
+
+fn set_pins(state: PowerState, acdc_pin: &mut Output<'_>, dcdc_pin: &mut Output<'_>) {
+    match state {
+        PowerState::ACDC => {
+            info!("ACDC");
+            acdc_pin.set_low();
+            dcdc_pin.set_high();
         }
-    }
-
-    pub fn determine_state(message: u32, voltage: u32) -> Self {
-        match message {
-            1 => PowerState::DCDC,
-            2 => PowerState::ACDC,
-            3 => PowerState::OFF,
-            _ => Self::from_voltage(voltage),
+        PowerState::DCDC => {
+            info!("DCDC");
+            acdc_pin.set_high();
+            dcdc_pin.set_low();
         }
-    }
-
-    pub fn get_led_delay(&self) -> u64 {
-        match self {
-            PowerState::ACDC => 500,
-            PowerState::DCDC => 100,
-            PowerState::OFF => 1000,
+        PowerState::OFF => {
+            info!("OFF");
+            acdc_pin.set_low();
+            dcdc_pin.set_low();
         }
     }
+}
 
-    pub fn set_pins(&self, acdc_pin: &mut Output<'_>, dcdc_pin: &mut Output<'_>) {
-        match self {
-            PowerState::ACDC => {
-                info!("ACDC");
-                acdc_pin.set_low();
-                dcdc_pin.set_high();
-            }
-            PowerState::DCDC => {
-                info!("DCDC");
-                acdc_pin.set_high();
-                dcdc_pin.set_low();
-            }
-            PowerState::OFF => {
-                info!("OFF");
-                acdc_pin.set_low();
-                dcdc_pin.set_low();
-            }
-        }
+/// Drive the relays for `state` and publish it. The analog watchdog only
+/// guards the rail while a source is connected.
+fn apply_state(state: PowerState, acdc_pin: &mut Output<'_>, dcdc_pin: &mut Output<'_>) {
+    set_pins(state, acdc_pin, dcdc_pin);
+    POWER_STATUS.signal(state);
+    POWER_SOURCE.lock(|source| source.set(state));
+    CAPTURE.lock(|capture| capture.borrow_mut().notify_power_change());
+
+    LED_DELAY.signal(state.get_led_delay());
+
+    match state {
+        PowerState::OFF => disarm_watchdog(),
+        _ => arm_watchdog(),
     }
 }
 
+/// Wait `delay` ms between measurements, cut short by a queued command.
+async fn pause(delay: i32) {
+    select(Timer::after_millis(delay as u64), POWER_CHANNEL.ready_to_receive()).await;
+}
+
+/// Drives the source relays (PB0 ACDC, PB1 DCDC) from power commands and,
+/// until the first command, from the averaged rail measurement. A command
+/// holds its source; the software rail limits are checked on every reading
+/// either way.
+///
+/// A rail fault (analog watchdog or limit check) switches to OFF and latches:
+/// automatic selection stays suspended until the next power command, from
+/// SCPI, Modbus or the binary protocol. Clearing the latch needs a command on
+/// purpose, so a rail that recovers by itself does not reconnect a source.
 #[task]
 pub async fn change_power_source(
     acdc_pin: Peri<'static, peripherals::PB0>,
@@ -66,37 +71,73 @@ pub async fn change_power_source(
     let mut acdc_output = Output::new(acdc_pin, Level::Low, Speed::Low);
     let mut dcdc_output = Output::new(dcdc_pin, Level::Low, Speed::Low);
     let mut previous_state: Option<PowerState> = None;
+    // Set on a rail fault; automatic source selection stays suspended until
+    // the next power command.
+    let mut tripped: Option<RailFault> = None;
+    // Forced selection from `SHARED_MESSAGE`, 0 = follow the rail voltage
+    let mut message = 0;
+    // A power command was received; the rail voltage no longer picks the source
+    let mut manual = false;
 
     loop {
-        // Check for SCPI power commands first
-        if let Ok(scpi_command) = POWER_CHANNEL.try_receive() {
-            info!("Received SCPI power command: {:?}", scpi_command);
-            scpi_command.set_pins(&mut acdc_output, &mut dcdc_output);
-            POWER_STATUS.signal(scpi_command);
-
-            let led_delay = scpi_command.get_led_delay();
-            let _ = DELAY_CHANNEL.try_send(led_delay);
+        // Commands and the watchdog interrupt preempt the (slow) averaged
+        // measurement path, so a queued command completes right away. Each arm
+        // is a single wait, so losing the race never consumes a reading.
+        let input =
+            select3(POWER_CHANNEL.receive(), RAIL_FAULT.wait(), SHARED_ADC_VALUE.wait()).await;
+
+        let voltage = match input {
+            // `_done` completes the command at the end of this arm
+            Either3::First((scpi_command, _done)) => {
+                info!("Received SCPI power command: {:?}", scpi_command);
+                tripped = None;
+                manual = true;
+                apply_state(scpi_command, &mut acdc_output, &mut dcdc_output);
+
+                previous_state = Some(scpi_command);
+                continue;
+            }
+            Either3::Second(fault) => {
+                if previous_state.is_some_and(|state| state != PowerState::OFF) {
+                    warn!("Analog watchdog: {:?}, switching power OFF", fault);
+                    log_event(Event::RailTrip(fault));
+                    apply_state(PowerState::OFF, &mut acdc_output, &mut dcdc_output);
+                    previous_state = Some(PowerState::OFF);
+                    tripped = Some(fault);
+                }
+                continue;
+            }
+            Either3::Third(reading) => reading,
+        };
+        info!("Get voltage {}", voltage);
 
-            previous_state = Some(scpi_command);
+        if tripped.is_some() {
+            pause(delay).await;
             continue;
         }
 
-        let voltage = SHARED_ADC_VALUE.wait().await;
-        let message = SHARED_MESSAGE.wait().await;
-        info!("Get voltage {}", voltage);
+        if previous_state.is_some_and(|state| state != PowerState::OFF) {
+            if let Some(fault) = RAIL_LIMITS.check(voltage) {
+                warn!("Rail {} mV out of limits: {:?}, switching power OFF", voltage, fault);
+                log_event(Event::RailTrip(fault));
+                apply_state(PowerState::OFF, &mut acdc_output, &mut dcdc_output);
+                previous_state = Some(PowerState::OFF);
+                tripped = Some(fault);
+                continue;
+            }
+        }
 
+        if let Some(forced) = SHARED_MESSAGE.try_take() {
+            message = forced;
+        }
         let state = PowerState::determine_state(message, voltage);
 
-        if previous_state != Some(state) {
-            state.set_pins(&mut acdc_output, &mut dcdc_output);
-            POWER_STATUS.signal(state);
-
-            let led_delay = state.get_led_delay();
-            let _ = DELAY_CHANNEL.try_send(led_delay);
+        if !manual && previous_state != Some(state) {
+            apply_state(state, &mut acdc_output, &mut dcdc_output);
 
             previous_state = Some(state);
         }
 
-        Timer::after_millis(delay as u64).await;
+        pause(delay).await;
     }
 }
@@ -1,20 +1,361 @@
+use core::sync::atomic::{compiler_fence, Ordering};
+
 use defmt::*;
 use embassy_executor::task;
-use embassy_stm32::peripherals;
-use embassy_stm32::timer::simple_pwm::SimplePwm;
-use embassy_time::Timer;
+use embassy_futures::select::{select3, select4, Either3, Either4};
+use embassy_stm32::gpio::Input;
+use embassy_stm32::interrupt::{self, InterruptExt};
+use embassy_stm32::pac;
+use embassy_stm32::pac::bdma::vals::{Dir, Pl, Size};
+use embassy_stm32::rcc;
+use embassy_stm32::{peripherals, Peri};
+use embassy_stm32::time::Hertz;
+use embassy_stm32::timer::complementary_pwm::ComplementaryPwm;
+use embassy_stm32::timer::Channel;
+use embassy_time::{Instant, Timer};
+
+use crate::duty::Duty;
+use crate::events::{log_event, Event};
+use crate::ramp::{Ramp, RampChannel, RAMP_TICK_MS};
+use crate::shared::{
+    ADC_SYNC, FAN_DUTY, FAN_RAMP, OUTPUT_RAMPS, PWM_BREAK, PWM_BREAK_CLEAR, PWM_CONFIG,
+    PWM_STATUS, PWM_UPDATE, SHARED_DUTY, WAVEFORM_CONFIG, WAVEFORM_TABLE,
+};
+use crate::tasks::adc_task::configure_sync;
+use crate::waveform::{self, WAVEFORM_MAX_POINTS};
+
+/// TIM1 carrier frequency at boot, before the stored settings are applied
+pub const PWM_FREQUENCY_HZ: u32 = 1000;
+/// Range accepted for `SOURce:PWM:FREQuency`
+pub const PWM_FREQUENCY_MIN_HZ: u32 = 10;
+pub const PWM_FREQUENCY_MAX_HZ: u32 = 100_000;
+/// DMA1 channel 5 (index 4) serves the TIM1_UP request on the F103
+const WAVEFORM_DMA_CHANNEL: usize = 4;
+
+#[derive(Debug, Clone, Copy, PartialEq, Format)]
+pub enum PwmChannel {
+    Ch1,
+    Ch2,
+    Ch3,
+    Ch4,
+}
+
+impl PwmChannel {
+    pub const ALL: [Self; 4] = [Self::Ch1, Self::Ch2, Self::Ch3, Self::Ch4];
+
+    pub fn index(self) -> usize {
+        self as usize
+    }
+
+    fn timer_channel(self) -> Channel {
+        match self {
+            PwmChannel::Ch1 => Channel::Ch1,
+            PwmChannel::Ch2 => Channel::Ch2,
+            PwmChannel::Ch3 => Channel::Ch3,
+            PwmChannel::Ch4 => Channel::Ch4,
+        }
+    }
+
+    /// Pins wired to the channel: (main output, complementary output).
+    ///
+    /// PA9/PA10 (CH2/CH3) carry USART1, so those channels only drive their
+    /// CHxN pins; TIM1 has no CH4N.
+    fn outputs(self) -> (bool, bool) {
+        match self {
+            PwmChannel::Ch1 => (true, true), // PA8, PB13
+            PwmChannel::Ch2 => (false, true), // PB14
+            PwmChannel::Ch3 => (false, true), // PB15
+            PwmChannel::Ch4 => (true, false), // PA11
+        }
+    }
+
+    /// Slew-rate limits of the channel; CH1 is the fan, ramped by the cooling controller.
+    pub fn ramp(self) -> &'static RampChannel {
+        match self {
+            PwmChannel::Ch1 => &FAN_RAMP,
+            PwmChannel::Ch2 => &OUTPUT_RAMPS[0],
+            PwmChannel::Ch3 => &OUTPUT_RAMPS[1],
+            PwmChannel::Ch4 => &OUTPUT_RAMPS[2],
+        }
+    }
+}
+
+/// TIM1 output settings, written by SCPI and applied on `PWM_UPDATE`.
+#[derive(Debug, Clone, Copy, PartialEq, Format)]
+pub struct PwmConfig {
+    /// Carrier frequency, common to all channels
+    pub frequency_hz: u32,
+    /// Outputs enabled per channel
+    pub enabled: [bool; 4],
+    /// Active-low outputs per channel (CHx and CHxN)
+    pub inverted: [bool; 4],
+    /// Dead time inserted between CHx and CHxN edges
+    pub dead_time_ns: u16,
+    /// Shut all outputs down when BKIN (PB12) is pulled low
+    pub break_enabled: bool,
+}
+
+impl PwmConfig {
+    pub const DEFAULT: Self = Self {
+        frequency_hz: PWM_FREQUENCY_HZ,
+        enabled: [true, false, false, true],
+        inverted: [false; 4],
+        dead_time_ns: 0,
+        break_enabled: false,
+    };
+}
+
+/// Applied TIM1 state, published by the PWM task.
+#[derive(Debug, Clone, Copy, PartialEq, Format)]
+pub struct PwmStatus {
+    pub max_duty: u16,
+    /// Duty currently applied per channel (after ramping)
+    pub duty: [Duty; 4],
+    /// The break input has shut the outputs down (MOE cleared)
+    pub break_tripped: bool,
+    /// Output currently playing the function generator waveform
+    pub waveform: Option<PwmChannel>,
+}
+
+impl PwmStatus {
+    pub const EMPTY: Self = Self {
+        max_duty: 0,
+        duty: [Duty::ZERO; 4],
+        break_tripped: false,
+        waveform: None,
+    };
+}
+
+/// While synchronised sampling is on, CCR2 holds the ADC trigger phase: the
+/// CH2 duty is still tracked in `PwmStatus` but not written to the timer.
+pub fn sync_owns(channel: PwmChannel) -> bool {
+    channel == PwmChannel::Ch2 && ADC_SYNC.lock(|sync| sync.get()).enabled
+}
+
+fn set_outputs(channel: PwmChannel, enabled: bool, inverted: bool) {
+    let (main, complementary) = channel.outputs();
+    let index = channel.index();
+    pac::TIM1.ccer().modify(|w| {
+        w.set_ccp(index, inverted);
+        w.set_ccnp(index, inverted);
+        w.set_cce(index, enabled && main);
+        w.set_ccne(index, enabled && complementary);
+    });
+}
+
+fn configure_break(enabled: bool) {
+    let tim = pac::TIM1;
+    tim.bdtr().modify(|w| {
+        w.set_bke(enabled);
+        // Active low: PB12 has a pull-up, an external switch or driver pulls it down
+        w.set_bkp(false);
+    });
+    tim.sr().modify(|w| w.set_bif(false));
+    tim.dier().modify(|w| w.set_bie(enabled));
+}
+
+/// Re-enable the outputs after a break. Fails while BKIN is still active.
+fn clear_break() -> bool {
+    let tim = pac::TIM1;
+    tim.sr().modify(|w| w.set_bif(false));
+    tim.bdtr().modify(|w| w.set_moe(true));
+    let cleared = tim.bdtr().read().moe();
+    if cleared {
+        tim.dier().modify(|w| w.set_bie(true));
+    }
+    cleared
+}
+
+/// Stream `samples` into the compare register of `channel`, one per update
+/// event, restarting from the first sample when the end is reached.
+///
+/// `samples` must stay untouched until `stop_waveform`.
+fn start_waveform(channel: PwmChannel, samples: &[u16]) {
+    stop_waveform();
+    pac::RCC.ahbenr().modify(|w| w.set_dma1en(true));
+    let dma = pac::DMA1.ch(WAVEFORM_DMA_CHANNEL);
+    dma.par().write_value(pac::TIM1.ccr(channel.index()).as_ptr() as u32);
+    dma.mar().write_value(samples.as_ptr() as u32);
+    dma.ndtr().write(|w| w.set_ndt(samples.len() as u16));
+    compiler_fence(Ordering::SeqCst);
+    dma.cr().write(|w| {
+        w.set_dir(Dir::FROM_MEMORY);
+        w.set_minc(true);
+        w.set_circ(true);
+        w.set_psize(Size::BITS16);
+        w.set_msize(Size::BITS16);
+        w.set_pl(Pl::HIGH);
+        w.set_en(true);
+    });
+    pac::TIM1.dier().modify(|w| w.set_ude(true));
+}
+
+fn stop_waveform() {
+    pac::TIM1.dier().modify(|w| w.set_ude(false));
+    pac::DMA1.ch(WAVEFORM_DMA_CHANNEL).cr().modify(|w| w.set_en(false));
+    compiler_fence(Ordering::SeqCst);
+}
 
-use crate::shared::SHARED_DUTY;
+/// TIM1_BRK handler: the hardware has already cleared MOE, this only reports it.
+pub struct BreakHandler;
+
+impl interrupt::typelevel::Handler<interrupt::typelevel::TIM1_BRK> for BreakHandler {
+    unsafe fn on_interrupt() {
+        let tim = pac::TIM1;
+        if !tim.sr().read().bif() {
+            return;
+        }
+        // One-shot, re-armed by `SOURce:PWM:BREak:CLEar`
+        tim.dier().modify(|w| w.set_bie(false));
+        tim.sr().modify(|w| w.set_bif(false));
+        PWM_BREAK.signal(());
+    }
+}
 
+/// Single owner of TIM1.
+///
+/// - CH1 (PA8, CH1N PB13): fan, duty from the cooling controller (`FAN_DUTY`)
+/// - CH2N (PB14), CH3N (PB15), CH4 (PA11): generic outputs, duty from
+///   `SHARED_DUTY`, slewed through their `OUTPUT_RAMPS` limits
+///
+/// While the function generator is on, its channel takes compare values from
+/// a table streamed by DMA (DMA1_CH5 on TIM1_UP) instead of its ramp.
+///
+/// CH2 doubles as the ADC sync trigger (OC2REF): while synchronised sampling
+/// is on, CH2N (PB14) stays off and nothing here writes CCR2 (`sync_owns`);
+/// SCPI rejects the CH2 settings that would need it. Frequency, dead time and
+/// the break input on PB12 apply to all channels. Duties are kept as `Duty`
+/// and only converted to ticks here, so a frequency change keeps them.
 #[task]
-pub async fn change_duty_cycle(mut pwm: SimplePwm<'static, peripherals::TIM1>) {
-    let mut ch1 = pwm.ch1();
-    ch1.enable();
+pub async fn change_duty_cycle(
+    mut pwm: ComplementaryPwm<'static, peripherals::TIM1>,
+    _break_pin: Input<'static>,
+    _waveform_dma: Peri<'static, peripherals::DMA1_CH5>,
+) {
+    let mut max_duty = pwm.get_max_duty();
+    let mut ramps = [Ramp::new(Duty::SCALE); 4];
+    let mut status = PwmStatus {
+        max_duty,
+        ..PwmStatus::EMPTY
+    };
+    let mut applied: Option<PwmConfig> = None;
+    let mut samples = [0u16; WAVEFORM_MAX_POINTS];
+
+    interrupt::TIM1_BRK.unpend();
+    unsafe { interrupt::TIM1_BRK.enable() };
+    PWM_UPDATE.signal(());
 
     loop {
-        let duty_cycle = SHARED_DUTY.wait().await;
-        ch1.set_duty_cycle(duty_cycle);
-        info!("PWM duty cycle {}", duty_cycle);
-        Timer::after_millis(100).await;
+        let ramping = ramps.iter().any(|ramp| ramp.is_active());
+        let tick = async {
+            if ramping {
+                Timer::after_millis(RAMP_TICK_MS).await
+            } else {
+                core::future::pending::<()>().await
+            }
+        };
+        let control = select3(PWM_UPDATE.wait(), PWM_BREAK.wait(), PWM_BREAK_CLEAR.wait());
+
+        match select4(FAN_DUTY.wait(), SHARED_DUTY.receive(), control, tick).await {
+            Either4::First(duty) => {
+                pwm.set_duty(Channel::Ch1, duty.to_ticks(max_duty));
+                status.duty[0] = duty;
+                info!("Fan PWM duty {}%", duty.percent());
+            }
+            Either4::Second((channel, duty)) => {
+                let ramp = &mut ramps[channel.index()];
+                ramp.set_target(duty.raw(), Instant::now().as_millis());
+                info!("PWM {} duty cycle target {}", channel, duty);
+            }
+            Either4::Third(Either3::First(())) => {
+                let config = PWM_CONFIG.lock(|pwm_config| pwm_config.get());
+                let previous = applied.unwrap_or(PwmConfig {
+                    frequency_hz: 0,
+                    ..config
+                });
+                let retimed = previous.frequency_hz != config.frequency_hz;
+                if retimed {
+                    pwm.set_frequency(Hertz(config.frequency_hz));
+                    max_duty = pwm.get_max_duty();
+                    status.max_duty = max_duty;
+
+                    for channel in PwmChannel::ALL {
+                        if !sync_owns(channel) {
+                            let duty = status.duty[channel.index()];
+                            pwm.set_duty(channel.timer_channel(), duty.to_ticks(max_duty));
+                        }
+                    }
+                    // The sampling phase is a compare value, i.e. relative to the old period
+                    let sync = ADC_SYNC.lock(|sync| sync.get());
+                    if sync.enabled {
+                        configure_sync(sync);
+                    }
+                    info!("PWM frequency {} Hz, max duty {}", config.frequency_hz, max_duty);
+                }
+                if applied.is_none() || previous.dead_time_ns != config.dead_time_ns {
+                    // DTG counts t_DTS = CKD x t_CK_INT regardless of PSC; the driver
+                    // takes CK_INT ticks and picks CKD and the DTG encoding itself
+                    let clock_hz = u64::from(rcc::frequency::<peripherals::TIM1>().0);
+                    let ticks = u64::from(config.dead_time_ns) * clock_hz / 1_000_000_000;
+                    pwm.set_dead_time(ticks.min(u64::from(u16::MAX)) as u16);
+                    info!("PWM dead time {} ns ({} ticks)", config.dead_time_ns, ticks);
+                }
+                for channel in PwmChannel::ALL {
+                    let enabled = config.enabled[channel.index()] && !sync_owns(channel);
+                    set_outputs(channel, enabled, config.inverted[channel.index()]);
+                }
+                configure_break(config.break_enabled);
+                applied = Some(config);
+
+                // Restart the function generator with a table for the current period
+                let wave = WAVEFORM_CONFIG.lock(|wave| wave.get());
+                stop_waveform();
+                if let Some(channel) = status.waveform.take().filter(|&ch| !sync_owns(ch)) {
+                    let duty = status.duty[channel.index()];
+                    pwm.set_duty(channel.timer_channel(), duty.to_ticks(max_duty));
+                }
+                if wave.enabled && !sync_owns(wave.channel) {
+                    let len = WAVEFORM_TABLE.lock(|table| {
+                        waveform::render(&wave, &table.borrow(), max_duty, &mut samples)
+                    });
+                    if len > 0 {
+                        start_waveform(wave.channel, &samples[..len]);
+                        status.waveform = Some(wave.channel);
+                        info!("Waveform {} on {}, {} points", wave.shape, wave.channel, len);
+                    }
+                }
+            }
+            Either4::Third(Either3::Second(())) => {
+                warn!("PWM break input active, outputs shut down");
+                log_event(Event::PwmBreak);
+                status.break_tripped = true;
+            }
+            Either4::Third(Either3::Third(())) => {
+                status.break_tripped = !clear_break();
+                if status.break_tripped {
+                    warn!("PWM break still active");
+                } else {
+                    info!("PWM outputs re-enabled after break");
+                }
+            }
+            Either4::Fourth(()) => {}
+        }
+
+        let now_ms = Instant::now().as_millis();
+        for &channel in &PwmChannel::ALL[1..] {
+            let ramp = &mut ramps[channel.index()];
+            ramp.set_config(channel.ramp().config());
+            if let Some(output) = ramp.update(now_ms) {
+                let duty = Duty::from_raw(output);
+                if status.waveform != Some(channel) && !sync_owns(channel) {
+                    pwm.set_duty(channel.timer_channel(), duty.to_ticks(max_duty));
+                }
+                status.duty[channel.index()] = duty;
+            }
+            if channel.ramp().publish(ramp) {
+                log_event(Event::RampDone(channel));
+            }
+        }
+        PWM_STATUS.lock(|pwm_status| pwm_status.set(status));
     }
 }
@@ -0,0 +1,159 @@
+use defmt::*;
+use embassy_executor::task;
+use embassy_stm32::pac;
+use embassy_stm32::pac::gpio::vals::Idr;
+use embassy_stm32::peripherals;
+use embassy_stm32::rcc;
+use embassy_stm32::timer::pwm_input::PwmInput;
+use embassy_time::{Instant, Timer};
+
+use crate::shared::{PWM_INPUT, PWM_INPUT_CONFIG};
+
+/// Counter tick rate at prescaler 1. The counter is 16 bit, so periods up to
+/// 65.5 ms (about 15 Hz) fit; slower signals need a larger prescaler.
+pub const PWM_INPUT_TICK_HZ: u32 = 1_000_000;
+
+/// TIM3 kernel clock, i.e. the APB1 timer clock the counter runs from
+fn timer_clock_hz() -> u32 {
+    rcc::frequency::<peripherals::TIM3>().0
+}
+
+/// Largest divider: PSC is 16 bit and already divides the TIM3 clock down to
+/// `PWM_INPUT_TICK_HZ` (8192 on the 8 MHz HSI clock)
+pub fn prescaler_max() -> u16 {
+    let base = (timer_clock_hz() / PWM_INPUT_TICK_HZ).max(1);
+    (0x1_0000 / base).min(u32::from(u16::MAX)) as u16
+}
+
+const POLL_MS: u64 = 20;
+
+#[derive(Debug, Clone, Copy, PartialEq, Format)]
+pub struct PwmInputConfig {
+    /// Divides `PWM_INPUT_TICK_HZ`, trading resolution for range
+    /// (1..=`prescaler_max()`)
+    pub prescaler: u16,
+    /// Without a complete period for this long the input is reported as static
+    pub timeout_ms: u16,
+}
+
+impl PwmInputConfig {
+    pub const DEFAULT: Self = Self {
+        prescaler: 1,
+        timeout_ms: 500,
+    };
+}
+
+/// Last PWM-input reading. A period of 0 means no edges within the timeout;
+/// `level` then tells whether the input sits at 0 % or 100 %.
+#[derive(Debug, Clone, Copy, PartialEq, Format)]
+pub struct PwmInputMeasurement {
+    pub tick_hz: u32,
+    pub period_ticks: u16,
+    pub width_ticks: u16,
+    pub level: bool,
+}
+
+impl PwmInputMeasurement {
+    pub const EMPTY: Self = Self {
+        tick_hz: PWM_INPUT_TICK_HZ,
+        period_ticks: 0,
+        width_ticks: 0,
+        level: false,
+    };
+
+    pub fn frequency_hz(&self) -> f32 {
+        if self.period_ticks == 0 {
+            return 0.0;
+        }
+        self.tick_hz as f32 / f32::from(self.period_ticks)
+    }
+
+    pub fn period_s(&self) -> f32 {
+        f32::from(self.period_ticks) / self.tick_hz as f32
+    }
+
+    pub fn width_s(&self) -> f32 {
+        f32::from(self.width_ticks) / self.tick_hz as f32
+    }
+
+    pub fn duty_percent(&self) -> f32 {
+        if self.period_ticks == 0 {
+            return if self.level { 100.0 } else { 0.0 };
+        }
+        f32::from(self.width_ticks) * 100.0 / f32::from(self.period_ticks)
+    }
+}
+
+/// Divide the tick rate by `prescaler` on top of the PSC the driver chose.
+/// Returns the resulting tick rate.
+fn set_prescaler(base_psc: u16, prescaler: u16) -> u32 {
+    let tim = pac::TIM3;
+    let psc = ((u32::from(base_psc) + 1) * u32::from(prescaler) - 1).min(u32::from(u16::MAX));
+    tim.psc().write_value(psc as u16);
+    // Load PSC now; URS keeps this update from looking like an overflow
+    tim.egr().write(|w| w.set_ug(true));
+    timer_clock_hz() / (psc + 1)
+}
+
+/// Measure an external PWM signal on TIM3 CH1 (PA6) in PWM-input mode.
+///
+/// TI1 feeds both capture units: CH1 latches the period on the rising edge
+/// and resets the counter, CH2 latches the high time on the falling edge.
+/// The first capture after a counter overflow or a prescaler change does not
+/// span a whole period and is dropped; a signal slower than one counter wrap
+/// therefore reads as static until the prescaler is raised.
+#[task]
+pub async fn measure_pwm_input(mut input: PwmInput<'static, peripherals::TIM3>) {
+    let tim = pac::TIM3;
+    // Only overflows set UIF, not the counter resets of the slave mode
+    tim.cr1().modify(|w| w.set_urs(pac::timer::vals::Urs::COUNTER_ONLY));
+    let base_psc = tim.psc().read();
+    let prescaler_max = prescaler_max();
+    input.enable();
+
+    let mut prescaler = 0u16;
+    let mut stale = true;
+    let mut last_period = Instant::now();
+    let mut measurement = PwmInputMeasurement::EMPTY;
+
+    info!("PWM input measurement started");
+
+    loop {
+        let config = PWM_INPUT_CONFIG.lock(|pwm_input| pwm_input.get());
+        let wanted = config.prescaler.clamp(1, prescaler_max);
+        if wanted != prescaler {
+            prescaler = wanted;
+            measurement.tick_hz = set_prescaler(base_psc, prescaler);
+            stale = true;
+        }
+
+        let sr = tim.sr().read();
+        if sr.ccif(0) {
+            // Reading CCR1 clears the flag
+            let period = input.get_period_ticks() as u16;
+            let width = input.get_width_ticks() as u16;
+            if !stale && !sr.uif() && period > 0 {
+                measurement.period_ticks = period;
+                measurement.width_ticks = width.min(period);
+                last_period = Instant::now();
+            }
+            stale = false;
+        }
+        if sr.uif() {
+            tim.sr().modify(|w| w.set_uif(false));
+            stale = true;
+        }
+
+        if last_period.elapsed().as_millis() > u64::from(config.timeout_ms) {
+            if measurement.period_ticks != 0 {
+                debug!("PWM input: no signal");
+            }
+            measurement.period_ticks = 0;
+            measurement.width_ticks = 0;
+        }
+        measurement.level = pac::GPIOA.idr().read().idr(6) == Idr::HIGH;
+        PWM_INPUT.lock(|pwm_input| pwm_input.set(measurement));
+
+        Timer::after_millis(POLL_MS).await;
+    }
+}
@@ -1,129 +1,292 @@
 use defmt::*;
 use embassy_executor::task;
-
+use embassy_stm32::gpio::Output;
+use embassy_stm32::usart::{self, BufferedUartRx, BufferedUartTx, Parity, StopBits};
 use embassy_time::{Duration, Timer};
+use embedded_io_async::{ErrorType, Write};
 
-use heapless::{String};
+use crate::binary::{BinarySession, DEFAULT_TELEMETRY_MS};
+use crate::device::device::{MyDevice, MYTREE};
+use crate::modbus::ModbusSlave;
+use crate::scpi_session::ScpiHandler;
+use crate::session::{Addressed, Session};
+use crate::shared::{BINARY_COMMANDS, SERIAL_COMMANDS, SERIAL_CONFIG, SERIAL_UPDATE};
 
-extern crate alloc;
-use alloc::vec::Vec;
+const LOG_LEVEL: &str = "[USART]";
+/// Longest command line; fits a full `SOURce:FUNCtion:DATA` table upload
+const RX_LINE_LEN: usize = 576;
+/// Session number of USART1, as reported by `SYSTem:LOCK:OWNer?`
+const SESSION_ID: u8 = 1;
+/// USART3 line settings (8N1), fixed for the binary protocol port
+pub const BINARY_BAUDRATE: u32 = 115200;
 
-use embassy_sync::blocking_mutex::raw::ThreadModeRawMutex;
-use embassy_sync::channel::{Receiver, Sender};
-use embedded_io_async::{Read, Write};
-use scpi::tree::prelude::Context;
+/// Rates accepted by `SYSTem:COMMunicate:SERial:BAUD`
+pub const SERIAL_BAUDRATES: &[u32] = &[1200, 2400, 4800, 9600, 19200, 38400, 57600, 115200];
+/// Unit addresses accepted by `SYSTem:COMMunicate:ADDRess`; 0 is the broadcast
+pub const BUS_ADDRESSES: core::ops::RangeInclusive<u8> = 1..=247;
+/// Quiet time before a reply takes the bus, in character times
+const TURNAROUND_CHARS: u32 = 2;
 
-use crate::device::device::{MyDevice, MYTREE};
+/// What the port speaks
+#[derive(Debug, Clone, Copy, PartialEq, Format)]
+pub enum SerialProtocol {
+    Scpi,
+    /// Modbus RTU slave at the unit address
+    Modbus,
+    /// COBS-framed binary commands and telemetry, see `BinarySession`
+    Binary,
+}
 
+/// Point-to-point line, or a shared half-duplex RS-485 bus
+#[derive(Debug, Clone, Copy, PartialEq, Format)]
+pub enum SerialMode {
+    Rs232,
+    Rs485,
+}
 
-const LOG_LEVEL: &str = "[USART]";
+#[derive(Debug, Clone, Copy, PartialEq, Format)]
+pub enum SerialParity {
+    None,
+    Even,
+    Odd,
+}
 
+#[derive(Debug, Clone, Copy, PartialEq, Format)]
+pub enum SerialStopBits {
+    One,
+    Two,
+}
+
+/// End of every response line
+#[derive(Debug, Clone, Copy, PartialEq, Format)]
+pub enum Terminator {
+    Lf,
+    CrLf,
+    Cr,
+}
+
+impl Terminator {
+    pub fn bytes(self) -> &'static [u8] {
+        match self {
+            Terminator::Lf => b"\n",
+            Terminator::CrLf => b"\r\n",
+            Terminator::Cr => b"\r",
+        }
+    }
+}
+
+/// USART1 line settings, always 8 data bits. `DEFAULT` is also what the
+/// safe-default jumper selects at boot.
+#[derive(Debug, Clone, Copy, PartialEq, Format)]
+pub struct SerialConfig {
+    pub baudrate: u32,
+    pub parity: SerialParity,
+    pub stop_bits: SerialStopBits,
+    pub terminator: Terminator,
+    pub mode: SerialMode,
+    /// Unit address on the bus, see `Addressed`; also the Modbus slave address
+    pub address: u8,
+    pub protocol: SerialProtocol,
+}
+
+impl SerialConfig {
+    pub const DEFAULT: Self = Self {
+        baudrate: 9600,
+        parity: SerialParity::None,
+        stop_bits: SerialStopBits::One,
+        terminator: Terminator::Lf,
+        mode: SerialMode::Rs232,
+        address: 1,
+        protocol: SerialProtocol::Scpi,
+    };
+
+    pub fn uart_config(&self) -> usart::Config {
+        let mut config = usart::Config::default();
+        config.baudrate = self.baudrate;
+        // The parity bit comes on top of the 8 data bits
+        config.parity = match self.parity {
+            SerialParity::None => Parity::ParityNone,
+            SerialParity::Even => Parity::ParityEven,
+            SerialParity::Odd => Parity::ParityOdd,
+        };
+        config.stop_bits = match self.stop_bits {
+            SerialStopBits::One => StopBits::STOP1,
+            SerialStopBits::Two => StopBits::STOP2,
+        };
+        config
+    }
+
+    /// Time on the wire of one character: start, 8 data, parity and stop bits.
+    pub fn char_time(&self) -> Duration {
+        let bits = 9
+            + u32::from(self.parity != SerialParity::None)
+            + match self.stop_bits {
+                SerialStopBits::One => 1,
+                SerialStopBits::Two => 2,
+            };
+        Duration::from_micros(u64::from(bits * 1_000_000 / self.baudrate + 1))
+    }
+}
+
+/// USART1 TX behind an RS-485 transceiver with DE and /RE tied to `de`.
+///
+/// In RS-485 mode the driver is enabled on the first write of a response,
+/// after the turnaround delay that lets the master release the bus, and
+/// disabled again by `flush` once the last stop bit is out, or as soon as a
+/// write or flush fails or is cancelled. While it is enabled the receiver is
+/// off, so the board never hears its own reply.
+/// In RS-232 mode `de` stays low.
+pub struct BusTx<'d> {
+    tx: BufferedUartTx<'d>,
+    de: Output<'d>,
+    mode: SerialMode,
+    turnaround: Duration,
+}
+
+impl<'d> BusTx<'d> {
+    pub fn new(tx: BufferedUartTx<'d>, mut de: Output<'d>, config: &SerialConfig) -> Self {
+        de.set_low();
+        Self {
+            tx,
+            de,
+            mode: config.mode,
+            turnaround: config.char_time() * TURNAROUND_CHARS,
+        }
+    }
+
+    /// Switch line settings; call with the transmitter idle (after `flush`).
+    pub fn set_config(&mut self, config: &SerialConfig) -> Result<(), usart::ConfigError> {
+        self.mode = config.mode;
+        self.turnaround = config.char_time() * TURNAROUND_CHARS;
+        self.tx.set_config(&config.uart_config())
+    }
+}
+
+impl ErrorType for BusTx<'_> {
+    type Error = usart::Error;
+}
+
+impl Write for BusTx<'_> {
+    async fn write(&mut self, buf: &[u8]) -> Result<usize, Self::Error> {
+        if self.mode == SerialMode::Rs485 && self.de.is_set_low() {
+            Timer::after(self.turnaround).await;
+            self.de.set_high();
+        }
+        let release = ReleaseDe::new(&mut self.de);
+        let result = self.tx.write(buf).await;
+        if result.is_ok() {
+            // The response continues, `flush` releases the bus
+            release.disarm();
+        }
+        result
+    }
+
+    async fn flush(&mut self) -> Result<(), Self::Error> {
+        let _release = ReleaseDe::new(&mut self.de);
+        // Waits for transmission complete, not just an empty buffer
+        self.tx.flush().await
+    }
+}
+
+/// Drives DE low when dropped, so a failed or cancelled transmission does not
+/// leave the RS-485 transceiver holding the bus.
+struct ReleaseDe<'a, 'd> {
+    de: Option<&'a mut Output<'d>>,
+}
+
+impl<'a, 'd> ReleaseDe<'a, 'd> {
+    fn new(de: &'a mut Output<'d>) -> Self {
+        Self { de: Some(de) }
+    }
+
+    fn disarm(mut self) {
+        self.de = None;
+    }
+}
+
+impl Drop for ReleaseDe<'_, '_> {
+    fn drop(&mut self) {
+        if let Some(de) = self.de.take() {
+            de.set_low();
+        }
+    }
+}
+
+fn apply_addressing<H>(handler: &mut Addressed<H>, config: &SerialConfig) {
+    handler.set_address(config.address);
+    // Several units would answer a line without address at once
+    handler.set_silent_unaddressed(config.mode == SerialMode::Rs485);
+}
+
+/// SCPI, Modbus RTU or the binary protocol over USART1: one session owns both
+/// halves, so responses go out in command order without a channel in between.
+/// The UART has no notion of a connection, so the SCPI session (error queue,
+/// format, lock) lives until reboot.
+///
+/// New line settings (`SERIAL_UPDATE`) are applied once the response of the
+/// command that changed them has been sent with the old ones. Modbus and the
+/// binary protocol have no command to switch back; the safe-default jumper
+/// restores SCPI.
 #[task]
-pub async fn rx_task(
-    mut rx: embassy_stm32::usart::BufferedUartRx<'static>,
-    tx_sender: Sender<'static, ThreadModeRawMutex, heapless::String<64>, 4>,
+pub async fn serial_session(
+    mut rx: BufferedUartRx<'static>,
+    tx: BufferedUartTx<'static>,
+    de: Output<'static>,
 ) {
-    let mut buf = [0u8; 64];
-    let mut pos = 0;
-
-    info!("{}: RX task started", LOG_LEVEL);
+    let mut config = SERIAL_CONFIG.lock(|serial| serial.get());
+    let mut tx = BusTx::new(tx, de, &config);
+    let device = MyDevice::new(SESSION_ID, &SERIAL_COMMANDS);
+    let mut handler = Addressed::new(ScpiHandler::new(&MYTREE, device));
+    apply_addressing(&mut handler, &config);
+    let mut session: Session<_, RX_LINE_LEN> = Session::new(handler);
+    session.set_terminator(config.terminator.bytes());
+    let mut modbus = ModbusSlave::new(&SERIAL_COMMANDS);
+    modbus.set_config(&config);
+    let mut binary = BinarySession::new(DEFAULT_TELEMETRY_MS, &SERIAL_COMMANDS);
 
-    let mut device: MyDevice = MyDevice;
+    info!("{}: session started, {}", LOG_LEVEL, config);
 
     loop {
-        let mut byte = [0u8; 1];
-        if let Ok(()) = rx.read_exact(&mut byte).await {
-            let b = byte[0];
-            info!("{}: Get message", LOG_LEVEL);
-            if pos >= buf.len() {
-                info!("{}: RX buffer overflow, resetting", LOG_LEVEL);
-                pos = 0;
-                continue;
-            }
+        let result = match config.protocol {
+            SerialProtocol::Scpi => session.run(&mut rx, &mut tx).await.map(|_| ()),
+            SerialProtocol::Modbus => modbus.run(&mut rx, &mut tx).await,
+            SerialProtocol::Binary => binary.run(&mut rx, &mut tx).await,
+        };
+        if let Err(e) = result {
+            warn!("{}: transport error: {:?}", LOG_LEVEL, e);
+            // Framing/noise errors: drop the line and let the line settle
+            Timer::after(Duration::from_millis(100)).await;
+        }
 
-            buf[pos] = b;
-            pos += 1;
-
-            if b == b'\n' || b == b'\r' {
-                if pos > 1 {
-                    let message_len = if buf[pos - 1] == b'\n' || buf[pos - 1] == b'\r' {
-                        pos - 1
-                    } else {
-                        pos
-                    };
-
-                    if message_len > 0 {
-                        let mut received_string = String::<64>::new();
-
-                        for &byte in &buf[..message_len] {
-                            if received_string.push(byte as char).is_err() {
-                                warn!("String conversion overflow");
-                                break;
-                            }
-                        }
-
-                        // Run SCPI command tree
-                        info!("{}: Received", LOG_LEVEL);
-
-                        let command = received_string;
-
-                        let mut context = Context::default();
-                        let mut response: Vec<u8> = Vec::new();
-
-                        let res = MYTREE.run(
-                            &command.as_bytes(),
-                            &mut device,
-                            &mut context,
-                            &mut response,
-                        );
-
-                        match res {
-                            Ok(()) => {
-                                // Forward response to TX task (ASCII-only for this demo)
-                                let mut out = String::<64>::new();
-                                for &b in response.as_slice() {
-                                    if out.push(b as char).is_err() {
-                                        break;
-                                    }
-                                }
-                                let _ = tx_sender.try_send(out);
-                            }
-                            Err(e) => {
-                                warn!("SCPI run error");
-                                let mut out = String::<64>::new();
-                                let _ = out.push_str("ERR\r\n");
-                                let _ = tx_sender.try_send(out);
-                            }
-                        }
-                    }
+        if SERIAL_UPDATE.try_take().is_some() {
+            let latest = SERIAL_CONFIG.lock(|serial| serial.get());
+            if latest != config {
+                config = latest;
+                // Normally a no-op: `run` flushed the response already
+                let _ = tx.flush().await;
+                if let Err(e) = tx.set_config(&config) {
+                    error!("{}: cannot apply {}: {:?}", LOG_LEVEL, config, e);
                 }
-                pos = 0; // reset buffer
+                session.set_terminator(config.terminator.bytes());
+                apply_addressing(session.handler_mut(), &config);
+                modbus.set_config(&config);
+                info!("{}: now {}", LOG_LEVEL, config);
             }
-        } else {
-            // Handle read error
-            Timer::after(Duration::from_millis(100)).await;
         }
     }
 }
 
+/// Binary protocol on USART3 (PB10 TX, PB11 RX), next to SCPI on USART1.
 #[task]
-pub async fn tx_task(
-    mut tx: embassy_stm32::usart::BufferedUartTx<'static>,
-    rx: Receiver<'static, ThreadModeRawMutex, heapless::String<64>, 4>,
-) {
-    info!("{}: TX task started", LOG_LEVEL);
-    loop {
-        let msg = rx.receive().await;
+pub async fn binary_port(mut rx: BufferedUartRx<'static>, mut tx: BufferedUartTx<'static>) {
+    let mut session = BinarySession::new(DEFAULT_TELEMETRY_MS, &BINARY_COMMANDS);
 
-        // Convert heapless string to bytes and send
-        if let Err(e) = tx.write_all(msg.as_bytes()).await {
-            error!("{} : TX write error: {:?}", LOG_LEVEL, e);
-        }
+    info!("[USART3]: binary session started, {} baud", BINARY_BAUDRATE);
 
-        if let Err(e) = tx.flush().await {
-            error!("{} : TX flush error: {:?}", LOG_LEVEL, e);
+    loop {
+        if let Err(e) = session.run(&mut rx, &mut tx).await {
+            warn!("[USART3]: transport error: {:?}", e);
+            Timer::after(Duration::from_millis(100)).await;
         }
-        info!("{} : Sended", LOG_LEVEL);
-        Timer::after(Duration::from_millis(100)).await;
     }
-}
\ No newline at end of file
+}
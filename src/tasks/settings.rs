@@ -0,0 +1,37 @@
+use defmt::*;
+use embassy_executor::task;
+use embassy_stm32::flash::{Blocking, Flash};
+use embassy_time::Timer;
+
+use crate::settings::Settings;
+use crate::shared::SETTINGS_SAVE;
+
+/// Requests arriving within this window are written once
+const SAVE_DEBOUNCE_MS: u64 = 1000;
+
+/// Owns the flash and writes the settings whenever `SETTINGS_SAVE` is raised.
+///
+/// Flash erase and write stall the CPU (~20 ms for the page erase), so saves
+/// are debounced and skipped when nothing changed since the last write.
+#[task]
+pub async fn persist_settings(mut flash: Flash<'static, Blocking>, mut stored: Settings) {
+    info!("Settings task started");
+
+    loop {
+        SETTINGS_SAVE.wait().await;
+        Timer::after_millis(SAVE_DEBOUNCE_MS).await;
+        SETTINGS_SAVE.reset();
+
+        let settings = Settings::capture();
+        if settings == stored {
+            continue;
+        }
+        match settings.save(&mut flash) {
+            Ok(()) => {
+                info!("Settings saved");
+                stored = settings;
+            }
+            Err(error) => warn!("Settings: save failed: {}", error),
+        }
+    }
+}
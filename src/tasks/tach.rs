@@ -0,0 +1,84 @@
+use defmt::*;
+use embassy_executor::task;
+use embassy_stm32::peripherals;
+use embassy_stm32::timer::input_capture::InputCapture;
+use embassy_stm32::timer::Channel;
+use embassy_time::{with_timeout, Duration};
+
+use crate::shared::{FAN_RPM, TACH_CONFIG};
+
+/// Capture timer tick rate. The counter is 16 bit, so one wrap is 655 ms.
+pub const TACH_TICK_HZ: u32 = 100_000;
+
+/// Without an edge for this long the fan is reported as stopped. Must stay
+/// below one counter wrap so a period can never alias.
+const TACH_TIMEOUT: Duration = Duration::from_millis(500);
+
+const MAX_AVERAGE: usize = 16;
+
+#[derive(Debug, Clone, Copy, PartialEq, Format)]
+pub struct TachConfig {
+    /// Tach pulses per fan revolution (2 for most PC fans)
+    pub pulses_per_rev: u8,
+    /// Periods averaged per reading (1..=16)
+    pub average: u8,
+}
+
+impl TachConfig {
+    pub const DEFAULT: Self = Self {
+        pulses_per_rev: 2,
+        average: 4,
+    };
+}
+
+fn rpm_from_period(period_ticks: u32, pulses_per_rev: u8) -> u16 {
+    if period_ticks == 0 || pulses_per_rev == 0 {
+        return 0;
+    }
+    let rpm = 60 * TACH_TICK_HZ / (period_ticks * u32::from(pulses_per_rev));
+    rpm.min(u32::from(u16::MAX)) as u16
+}
+
+/// Measure the fan speed from the tach pulse period on TIM2 CH1 (PA0).
+///
+/// The open-collector tach output needs a pull-up; the pin is configured
+/// with the internal one.
+#[task]
+pub async fn measure_fan_speed(mut capture: InputCapture<'static, peripherals::TIM2>) {
+    let mut periods = [0u32; MAX_AVERAGE];
+    let mut index = 0usize;
+    let mut filled = 0usize;
+    let mut last_edge: Option<u32> = None;
+
+    info!("Tach measurement started");
+
+    loop {
+        let config = TACH_CONFIG.lock(|tach| tach.get());
+        let average = usize::from(config.average).clamp(1, MAX_AVERAGE);
+
+        match with_timeout(TACH_TIMEOUT, capture.wait_for_rising_edge(Channel::Ch1)).await {
+            Ok(edge) => {
+                if let Some(previous) = last_edge {
+                    let period = edge.wrapping_sub(previous) & 0xFFFF;
+                    periods[index % average] = period;
+                    index = (index + 1) % average;
+                    filled = (filled + 1).min(average);
+
+                    let sum: u32 = periods[..filled].iter().sum();
+                    let rpm = rpm_from_period(sum / filled as u32, config.pulses_per_rev);
+                    FAN_RPM.lock(|fan| fan.set(rpm));
+                }
+                last_edge = Some(edge);
+            }
+            Err(_) => {
+                if last_edge.is_some() {
+                    info!("Tach timeout, fan stopped");
+                }
+                last_edge = None;
+                index = 0;
+                filled = 0;
+                FAN_RPM.lock(|fan| fan.set(0));
+            }
+        }
+    }
+}
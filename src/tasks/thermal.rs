@@ -0,0 +1,54 @@
+use defmt::*;
+use embassy_executor::task;
+use embassy_time::{Duration, Instant, Ticker};
+
+use crate::completion::Completion;
+use crate::shared::{
+    CoolingState, COOLING_CHANNEL, PROBE_TEMPERATURE, SPEED_CHANNEL, TEMPERATURE, THERMAL_CONFIG,
+};
+use crate::thermal::{TemperatureSource, ThermalPolicy};
+
+/// Automatic cooling: evaluates the fan curve once per second and drives the
+/// cooling controller through the same channels as the SCPI SPEEd commands.
+/// Only changes are sent, so manual commands stay in effect while the policy
+/// is disabled.
+#[task]
+pub async fn thermal_policy() {
+    let mut policy = ThermalPolicy::new();
+    let mut applied: Option<Option<u16>> = None;
+    let mut ticker = Ticker::every(Duration::from_secs(1));
+
+    info!("Thermal policy task started");
+
+    loop {
+        ticker.next().await;
+
+        let config = THERMAL_CONFIG.lock(|thermal| thermal.get());
+        if !config.enabled {
+            policy = ThermalPolicy::new();
+            applied = None;
+            continue;
+        }
+
+        let temperature = match config.source {
+            TemperatureSource::Internal => TEMPERATURE.lock(|temperature| temperature.get()),
+            TemperatureSource::External => PROBE_TEMPERATURE.lock(|temperature| temperature.get()),
+        };
+        let decision = policy.evaluate(&config, temperature, Instant::now().as_millis());
+
+        if applied != Some(decision) {
+            match decision {
+                Some(speed) => {
+                    info!("Thermal: {} (0.1 C) -> fan {}%", temperature, speed);
+                    SPEED_CHANNEL.send((speed, Completion::untracked())).await;
+                    COOLING_CHANNEL.send((CoolingState::On, Completion::untracked())).await;
+                }
+                None => {
+                    info!("Thermal: {} (0.1 C) -> fan OFF", temperature);
+                    COOLING_CHANNEL.send((CoolingState::Off, Completion::untracked())).await;
+                }
+            }
+            applied = Some(decision);
+        }
+    }
+}
@@ -0,0 +1,40 @@
+use defmt::*;
+use embassy_executor::task;
+use embassy_time::{Duration, Ticker};
+
+use crate::shared::{FAN_RPM, POWER_SOURCE, RAIL_STATS, TEMPERATURE, TREND_LOG};
+use crate::trend::{TrendAggregator, TrendSample};
+
+const SAMPLE_PERIOD_S: u32 = 1;
+
+/// Sample the published measurements once per second and append one
+/// aggregate record to `TREND_LOG` per configured interval.
+#[task]
+pub async fn record_trends() {
+    let mut aggregator = TrendAggregator::new();
+    let mut elapsed_s = 0u32;
+    let mut ticker = Ticker::every(Duration::from_secs(SAMPLE_PERIOD_S as u64));
+
+    info!("Trend recorder started");
+
+    loop {
+        ticker.next().await;
+
+        aggregator.push(TrendSample {
+            rail_mv: RAIL_STATS.lock(|rail| rail.get()).mean,
+            temperature: TEMPERATURE.lock(|temperature| temperature.get()),
+            fan_rpm: FAN_RPM.lock(|fan| fan.get()),
+            source: POWER_SOURCE.lock(|source| source.get()),
+        });
+
+        elapsed_s += SAMPLE_PERIOD_S;
+        let interval_s = TREND_LOG.lock(|log| log.borrow().interval_s());
+        if elapsed_s >= interval_s {
+            elapsed_s = 0;
+            if let Some(record) = aggregator.finish() {
+                debug!("Trend record: {:?}", record);
+                TREND_LOG.lock(|log| log.borrow_mut().push(record));
+            }
+        }
+    }
+}
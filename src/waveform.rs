@@ -0,0 +1,104 @@
+use defmt::Format;
+use heapless::Vec;
+
+use crate::duty::Duty;
+use crate::tasks::pwm::PwmChannel;
+
+/// Longest table played back; one sample per PWM period
+pub const WAVEFORM_MAX_POINTS: usize = 256;
+pub const WAVEFORM_MIN_POINTS: usize = 2;
+
+/// User-uploaded duty table
+pub type WaveTable = Vec<Duty, WAVEFORM_MAX_POINTS>;
+
+#[derive(Debug, Clone, Copy, PartialEq, Format)]
+pub enum WaveShape {
+    Sine,
+    Triangle,
+    /// Rising sawtooth
+    Ramp,
+    /// The uploaded `WaveTable`, played as is
+    User,
+}
+
+/// Function generator settings. The output frequency is the PWM frequency
+/// divided by the number of points.
+#[derive(Debug, Clone, Copy, PartialEq, Format)]
+pub struct WaveformConfig {
+    pub enabled: bool,
+    pub shape: WaveShape,
+    /// Output playing the waveform (CH2..CH4; CH1 is the fan)
+    pub channel: PwmChannel,
+    /// Samples per period of the built-in shapes
+    pub points: u16,
+    /// Peak-to-peak swing of the built-in shapes
+    pub amplitude: Duty,
+    /// Centre of the built-in shapes
+    pub offset: Duty,
+}
+
+impl WaveformConfig {
+    pub const DEFAULT: Self = Self {
+        enabled: false,
+        shape: WaveShape::Sine,
+        channel: PwmChannel::Ch4,
+        points: 100,
+        amplitude: Duty::FULL,
+        offset: Duty::from_percent(50),
+    };
+}
+
+/// sin(2π·phase) for phase in 0.0..1.0, 7th order Taylor on a quarter wave
+/// (error < 2e-4, plenty for 0.01 % duty steps).
+fn sine(phase: f32) -> f32 {
+    use core::f32::consts::PI;
+    // Fold into -1/4..1/4, where the series converges quickly
+    let folded = if phase < 0.25 {
+        phase
+    } else if phase < 0.75 {
+        0.5 - phase
+    } else {
+        phase - 1.0
+    };
+    let x = 2.0 * PI * folded;
+    let x2 = x * x;
+    x * (1.0 - x2 / 6.0 * (1.0 - x2 / 20.0 * (1.0 - x2 / 42.0)))
+}
+
+/// Value of `shape` at `phase` (0.0..1.0), in -1.0..=1.0.
+fn shape_value(shape: WaveShape, phase: f32) -> f32 {
+    match shape {
+        WaveShape::Sine => sine(phase),
+        WaveShape::Triangle => {
+            if phase < 0.5 {
+                4.0 * phase - 1.0
+            } else {
+                3.0 - 4.0 * phase
+            }
+        }
+        WaveShape::Ramp => 2.0 * phase - 1.0,
+        WaveShape::User => 0.0,
+    }
+}
+
+/// Fill `out` with compare values for a period of `max_duty` ticks; returns
+/// the number of samples, 0 if there is nothing to play.
+pub fn render(config: &WaveformConfig, user: &WaveTable, max_duty: u16, out: &mut [u16]) -> usize {
+    if config.shape == WaveShape::User {
+        let len = user.len().min(out.len());
+        for (tick, duty) in out.iter_mut().zip(user) {
+            *tick = duty.to_ticks(max_duty);
+        }
+        return if len >= WAVEFORM_MIN_POINTS { len } else { 0 };
+    }
+
+    let len = usize::from(config.points).clamp(WAVEFORM_MIN_POINTS, out.len());
+    let amplitude = config.amplitude.fraction();
+    let offset = config.offset.fraction();
+    for (index, tick) in out[..len].iter_mut().enumerate() {
+        let phase = index as f32 / len as f32;
+        let value = offset + amplitude / 2.0 * shape_value(config.shape, phase);
+        *tick = Duty::from_fraction(value).to_ticks(max_duty);
+    }
+    len
+}